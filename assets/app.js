@@ -0,0 +1,289 @@
+const uploadArea = document.getElementById('uploadArea');
+const fileInput = document.getElementById('fileInput');
+const loading = document.getElementById('loading');
+const loadingText = document.getElementById('loadingText');
+const previewContainer = document.getElementById('previewContainer');
+const previewImage = document.getElementById('previewImage');
+const captionText = document.getElementById('captionText');
+const modelName = document.getElementById('modelName');
+const processingTime = document.getElementById('processingTime');
+const errorDiv = document.getElementById('error');
+const modeSelect = document.getElementById('modeSelect');
+const confidenceToggle = document.getElementById('confidenceToggle');
+const sceneToggle = document.getElementById('sceneToggle');
+const contactSheetToggle = document.getElementById('contactSheetToggle');
+const detectAiToggle = document.getElementById('detectAiToggle');
+const exifContextToggle = document.getElementById('exifContextToggle');
+const languageSelect = document.getElementById('languageSelect');
+
+uploadArea.addEventListener('click', () => fileInput.click());
+
+uploadArea.addEventListener('dragover', (e) => {
+    e.preventDefault();
+    uploadArea.classList.add('dragover');
+});
+
+uploadArea.addEventListener('dragleave', () => {
+    uploadArea.classList.remove('dragover');
+});
+
+uploadArea.addEventListener('drop', (e) => {
+    e.preventDefault();
+    uploadArea.classList.remove('dragover');
+    const file = e.dataTransfer.files[0];
+    if (file && file.type.startsWith('image/')) {
+        handleFile(file);
+    }
+});
+
+fileInput.addEventListener('change', (e) => {
+    const file = e.target.files[0];
+    if (file) {
+        handleFile(file);
+    }
+});
+
+// Best effort: a missing estimate just leaves the plain message.
+async function showExpectedWait() {
+    loadingText.textContent = 'Generating AI caption...';
+    try {
+        const status = await (await fetch('/status')).json();
+        if (typeof status.average_caption_ms === 'number') {
+            const seconds = Math.max(1, Math.round(
+                (status.average_caption_ms + (status.estimated_wait_ms || 0)) / 1000));
+            loadingText.textContent = 'Generating AI caption... usually ~' + seconds + 's';
+        }
+    } catch (error) {}
+}
+
+// One token per upload, resent on retries so the server captions it only once.
+function uploadToken() {
+    if (window.crypto && crypto.randomUUID) {
+        return crypto.randomUUID();
+    }
+    return Date.now().toString(36) + Math.random().toString(36).slice(2);
+}
+
+// Error bodies say whether trying again may help; network errors always may.
+async function isRetryable(response) {
+    try {
+        return (await response.clone().json()).retryable === true;
+    } catch (error) {
+        return response.status >= 500;
+    }
+}
+
+// Servers with API keys answer 401; the page then asks for a key and remembers it.
+function askForApiKey() {
+    const key = window.prompt('This server needs an API key:');
+    if (key && key.trim()) {
+        localStorage.setItem('apiKey', key.trim());
+        return true;
+    }
+    localStorage.removeItem('apiKey');
+    return false;
+}
+
+// Retries network errors and retryable failures with a short backoff.
+async function uploadWithRetry(formData, token) {
+    const attempts = 3;
+    for (let attempt = 1; ; attempt++) {
+        try {
+            const headers = { 'Idempotency-Key': token };
+            const apiKey = localStorage.getItem('apiKey');
+            if (apiKey) {
+                headers['Authorization'] = 'Bearer ' + apiKey;
+            }
+            const response = await fetch('/upload', {
+                method: 'POST',
+                headers: headers,
+                body: formData
+            });
+            if (response.status === 401 && attempt < attempts && askForApiKey()) {
+                continue;
+            }
+            if (response.ok || attempt === attempts || !(await isRetryable(response))) {
+                return response;
+            }
+        } catch (error) {
+            if (attempt === attempts) {
+                throw error;
+            }
+        }
+        await new Promise((resolve) => setTimeout(resolve, 1000 * attempt));
+    }
+}
+
+async function handleFile(file) {
+    // The server turns bigger files away; no need to send them to find out.
+    const maxBytes = Number(uploadArea.dataset.maxBytes);
+    if (maxBytes && file.size > maxBytes) {
+        errorDiv.textContent = 'Error: ' + file.name + ' is larger than the ' +
+            uploadArea.dataset.maxLabel + ' limit';
+        errorDiv.style.display = 'block';
+        return;
+    }
+
+    const reader = new FileReader();
+    reader.onload = (e) => {
+        previewImage.src = e.target.result;
+    };
+    reader.readAsDataURL(file);
+
+    uploadArea.style.display = 'none';
+    loading.style.display = 'block';
+    previewContainer.style.display = 'none';
+    errorDiv.style.display = 'none';
+    showExpectedWait();
+
+    const formData = new FormData();
+    formData.append('image', file);
+    formData.append('mode', modeSelect.value);
+    formData.append('confidence', confidenceToggle.checked);
+    formData.append('scene', sceneToggle.checked);
+    formData.append('detect_ai', detectAiToggle.checked);
+    formData.append('exif_context', exifContextToggle.checked);
+    formData.append('contact_sheet', contactSheetToggle.checked);
+    formData.append('language', languageSelect.value);
+
+    try {
+        const response = await uploadWithRetry(formData, uploadToken());
+
+        if (!response.ok) {
+            const body = await response.json().catch(() => ({}));
+            const id = response.headers.get('X-Request-Id');
+            throw new Error((body.message || 'Upload failed') +
+                (id ? ' (request ID ' + id + ')' : ''));
+        }
+
+        const result = await response.json();
+
+        loading.style.display = 'none';
+        previewContainer.style.display = 'block';
+        captionText.textContent = result.decorative
+            ? 'Decorative image — use an empty alt attribute (alt="").'
+            : result.caption;
+        if (result.title) {
+            captionText.textContent = result.title + '\n\n' + result.description;
+        }
+        if (typeof result.confidence === 'number') {
+            captionText.textContent += '\n\nConfidence: ' + Math.round(result.confidence * 100) + '%';
+            if (result.uncertainties.length) {
+                captionText.textContent += ' (unsure about: ' + result.uncertainties.join('; ') + ')';
+            }
+        }
+        if (result.findings) {
+            captionText.textContent += result.findings.length
+                ? '\n\n' + result.findings
+                    .map((f) => '• [' + f.severity + '] ' + (f.element ? f.element + ': ' : '') + f.detail
+                        + (f.wcag ? ' (WCAG ' + f.wcag + ')' : ''))
+                    .join('\n')
+                : '\n\nNo visible issues found.';
+        }
+        if (result.regions) {
+            captionText.textContent += '\n\n' + (result.columns ? result.columns + ' column(s); ' : '')
+                + result.regions.length + ' region(s): '
+                + result.regions.map((region) => region.type.replace('_', ' ')).join(', ');
+            if (result.ocr_text) {
+                captionText.textContent += '\n\n' + result.ocr_text;
+            }
+        }
+        if (result.comic_panels) {
+            captionText.textContent += result.comic_panels
+                .map((panel) => '\n\n' + panel.order + '. ' + panel.description
+                    + panel.dialogue
+                        .map((line) => '\n   ' + (line.speaker ? line.speaker + ': ' : '')
+                            + (line.kind === 'speech' ? line.text : '(' + line.kind.replace('_', ' ') + ') ' + line.text))
+                        .join(''))
+                .join('');
+        }
+        if ('garment_type' in result) {
+            const colors = [result.color, ...result.secondary_colors].filter(Boolean);
+            captionText.textContent += '\n\n' + [
+                ['Type', result.garment_type],
+                ['Colors', colors.join(', ')],
+                ['Pattern', result.pattern],
+                ['Material (guess)', result.material],
+                ['Style', result.style_tags.join(', ')],
+            ].filter(([, value]) => value)
+                .map(([label, value]) => label + ': ' + value.replaceAll('_', ' '))
+                .join('\n');
+        }
+        if (result.disclaimer) {
+            captionText.textContent += '\n\n⚠️ ' + result.disclaimer;
+        }
+        if ('room_type' in result) {
+            captionText.textContent += '\n\nRoom: ' + result.room_type.replaceAll('_', ' ')
+                + (result.features.length ? '\nFeatures: ' + result.features.join(', ') : '');
+        }
+        if (result.vehicles) {
+            captionText.textContent += result.vehicles
+                .map((vehicle) => '\n\n' + [vehicle.color, vehicle.make, vehicle.model, vehicle.body_type]
+                    .filter(Boolean).join(' ')
+                    + (vehicle.damage ? '\nDamage (' + vehicle.damage_severity + '): ' + vehicle.damage : '\nNo visible damage'))
+                .join('');
+        }
+        if (result.species) {
+            captionText.textContent += result.species
+                .map((candidate) => '\n\n' + (candidate.common_name ? candidate.common_name + ' (' : '(')
+                    + candidate.scientific_name + ', ' + candidate.rank + ')'
+                    + (candidate.confidence === null ? '' : ': ' + Math.round(candidate.confidence * 100) + '%'))
+                .join('');
+            captionText.textContent += '\n\n⚠️ ' + result.caution;
+        }
+        if (result.sky_objects) {
+            captionText.textContent += result.sky_objects
+                .map((object) => '\n' + object.kind.replaceAll('_', ' ') + ': '
+                    + [object.name, object.catalog_id].filter(Boolean).join(' / '))
+                .join('');
+            if (result.moon_phase) {
+                captionText.textContent += '\nMoon phase: ' + result.moon_phase.replaceAll('_', ' ');
+            }
+        }
+        if ('attribution_guess' in result) {
+            captionText.textContent += '\n\n' + [
+                ['Medium', result.medium],
+                ['Style', result.styles.join(', ')],
+                ['Era', result.era && result.era.description],
+            ].filter(([, value]) => value)
+                .map(([label, value]) => label + ': ' + value.replaceAll('_', ' '))
+                .join('\n');
+            if (result.composition.length) {
+                captionText.textContent += '\nComposition: ' + result.composition.join('; ');
+            }
+            if (result.attribution_guess) {
+                captionText.textContent += '\n\nPossibly ' + result.attribution_guess.artist
+                    + ' (uncertain guess' + (result.attribution_guess.basis ? ': ' + result.attribution_guess.basis : '') + ')';
+            }
+        }
+        if (result.panels) {
+            captionText.textContent += '\n\n' + result.panels
+                .map((panel, index) => (index + 1) + '. ' + panel.caption)
+                .join('\n');
+        }
+        if (result.hashtags) {
+            captionText.textContent += '\n\n' + result.hashtags.join(' ')
+                + '\n\nKeywords: ' + result.keywords.join(', ');
+        }
+        if ('indoor_outdoor' in result) {
+            const scene = [result.indoor_outdoor, result.time_of_day, result.weather]
+                .filter((value) => value)
+                .map((value) => value.replace('_', ' '));
+            captionText.textContent += '\n\nScene: ' + (scene.length ? scene.join(', ') : 'unclear');
+        }
+        if (result.ai_detection) {
+            captionText.textContent += '\n\nAI-generated: ' + Math.round(result.ai_detection.score * 100) + '%';
+            if (result.ai_detection.reasons.length) {
+                captionText.textContent += ' (' + result.ai_detection.reasons.join('; ') + ')';
+            }
+        }
+        modelName.textContent = result.model.split(' ')[1];
+        processingTime.textContent = result.processing_time_ms;
+
+    } catch (error) {
+        loading.style.display = 'none';
+        uploadArea.style.display = 'block';
+        errorDiv.textContent = 'Error: ' + error.message;
+        errorDiv.style.display = 'block';
+    }
+}
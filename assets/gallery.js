@@ -0,0 +1,123 @@
+const grid = document.getElementById('grid');
+const more = document.getElementById('more');
+const empty = document.getElementById('empty');
+const errorDiv = document.getElementById('error');
+const search = document.getElementById('search');
+const query = document.getElementById('query');
+const pageSize = 24;
+let nextBefore = null;
+let searched = '';
+
+// Servers with API keys answer 401; the page then asks for a key and remembers it.
+function askForApiKey() {
+    const key = window.prompt('This server needs an API key:');
+    if (key && key.trim()) {
+        localStorage.setItem('apiKey', key.trim());
+        return true;
+    }
+    localStorage.removeItem('apiKey');
+    return false;
+}
+
+async function request(url, method) {
+    for (let attempt = 1; ; attempt++) {
+        const headers = {};
+        const apiKey = localStorage.getItem('apiKey');
+        if (apiKey) {
+            headers['Authorization'] = 'Bearer ' + apiKey;
+        }
+        const response = await fetch(url, { method: method || 'GET', headers: headers });
+        if (response.status === 401 && attempt < 3 && askForApiKey()) {
+            continue;
+        }
+        if (!response.ok) {
+            let message = response.statusText;
+            try {
+                message = (await response.json()).message || message;
+            } catch (error) {}
+            throw new Error(message);
+        }
+        return response;
+    }
+}
+
+function showError(error) {
+    errorDiv.textContent = 'Error: ' + error.message;
+    errorDiv.style.display = 'block';
+}
+
+async function showThumbnail(entry, card) {
+    const placeholder = card.querySelector('.no-thumbnail');
+    if (!entry.thumbnail_path) {
+        return;
+    }
+    try {
+        const blob = await (await request('/thumb/' + entry.id)).blob();
+        const img = document.createElement('img');
+        img.src = URL.createObjectURL(blob);
+        img.alt = entry.caption;
+        placeholder.replaceWith(img);
+    } catch (error) {
+        // The placeholder stays.
+    }
+}
+
+function addCard(entry) {
+    const card = document.createElement('div');
+    card.className = 'card';
+    card.innerHTML = '<div class="no-thumbnail">📷</div><div class="card-body">'
+        + '<div class="caption"></div><div class="meta"></div>'
+        + '<button class="delete">Delete</button></div>';
+    card.querySelector('.caption').textContent = entry.caption;
+    card.querySelector('.meta').textContent = new Date(entry.created_at).toLocaleString()
+        + ' • ' + entry.mode + ' • ' + entry.model;
+    card.querySelector('.delete').addEventListener('click', async () => {
+        if (!window.confirm('Delete this caption from the history?')) {
+            return;
+        }
+        try {
+            await request('/history/' + entry.id, 'DELETE');
+            card.remove();
+            empty.style.display = grid.children.length || nextBefore ? 'none' : 'block';
+        } catch (error) {
+            showError(error);
+        }
+    });
+    grid.appendChild(card);
+    showThumbnail(entry, card);
+}
+
+async function loadPage() {
+    more.disabled = true;
+    errorDiv.style.display = 'none';
+    try {
+        let url = searched
+            ? '/search?q=' + encodeURIComponent(searched) + '&limit=' + pageSize
+            : '/history?limit=' + pageSize;
+        if (nextBefore !== null) {
+            url += '&before=' + nextBefore;
+        }
+        const page = await (await request(url)).json();
+        page.entries.forEach(addCard);
+        nextBefore = page.next_before;
+        more.style.display = nextBefore === null ? 'none' : 'block';
+        empty.textContent = searched ? 'Nothing matches.' : 'Nothing captioned yet.';
+        empty.style.display = grid.children.length ? 'none' : 'block';
+    } catch (error) {
+        showError(error);
+    } finally {
+        more.disabled = false;
+    }
+}
+
+// A new search starts over from the newest match; an empty one shows everything again.
+search.addEventListener('submit', (e) => {
+    e.preventDefault();
+    searched = query.value.trim();
+    nextBefore = null;
+    grid.replaceChildren();
+    loadPage();
+});
+
+more.addEventListener('click', loadPage);
+loadPage();
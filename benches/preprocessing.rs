@@ -0,0 +1,214 @@
+// Benchmarks of the image preprocessing path: decoding uploads, downscaling them and encoding
+// the JPEG sent to the provider, each on its own and all together as the server does it, for
+// the formats and sizes uploads usually come in.
+//
+//   cargo bench --bench preprocessing                              # run, compare with last run
+//   cargo bench --bench preprocessing -- --save-baseline main      # keep the results as `main`
+//   cargo bench --bench preprocessing -- --baseline main           # the regression gate
+//
+// With `--baseline`, the run fails when any benchmark got slower than the baseline by more than
+// BENCH_MAX_REGRESSION percent (default 10), counting only slowdowns criterion is 95% sure of,
+// so a change made for speed (or one that might cost some) can be checked against `main` on the
+// same machine. Reports are in target/criterion.
+
+use criterion::{criterion_group, BenchmarkId, Criterion, Throughput};
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use std::hint::black_box;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use ai_image_captioner::bench::{decode, downscale, encode_jpeg, prepare};
+use ai_image_captioner::CaptionOptions;
+
+/// The default for BENCH_MAX_REGRESSION, in percent.
+const MAX_REGRESSION_PERCENT: f64 = 10.0;
+
+/// Upload sizes: a phone screenshot, a full-HD frame, a 12 MP phone photo and a 24 MP camera
+/// photo, the last two above the longest side the provider gets.
+const SIZES: &[(&str, u32, u32)] = &[
+    ("1080p", 1920, 1080),
+    ("12mp", 4000, 3000),
+    ("24mp", 6000, 4000),
+];
+
+const FORMATS: &[(&str, ImageOutputFormat)] = &[
+    ("jpeg", ImageOutputFormat::Jpeg(90)),
+    ("png", ImageOutputFormat::Png),
+    ("tiff", ImageOutputFormat::Tiff),
+];
+
+/// A photo-like image: smooth gradients with sensor-like noise, so it compresses about as well
+/// as a real photo does (a flat image would make encoding and PNG decoding look far too cheap).
+fn photo(width: u32, height: u32) -> DynamicImage {
+    let mut state = 0x2545_f491_u32;
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let noise = (state % 24) as u8;
+        let r = (x * 200 / width) as u8;
+        let g = (y * 180 / height) as u8;
+        let b = ((x + y) * 120 / (width + height)) as u8;
+        image::Rgb([r + noise, g + noise, b + noise])
+    });
+    DynamicImage::ImageRgb8(image)
+}
+
+fn encoded(image: &DynamicImage, format: &ImageOutputFormat) -> Vec<u8> {
+    let mut data = Vec::new();
+    image.write_to(&mut std::io::Cursor::new(&mut data), format.clone()).unwrap();
+    data
+}
+
+fn preprocessing(c: &mut Criterion) {
+    let photos: Vec<_> = SIZES.iter().map(|&(name, w, h)| (name, photo(w, h))).collect();
+
+    let mut group = c.benchmark_group("decode");
+    group.sample_size(20);
+    for (format_name, format) in FORMATS {
+        for (size, image) in &photos {
+            let data = encoded(image, format);
+            group.throughput(Throughput::Bytes(data.len() as u64));
+            let id = BenchmarkId::new(*format_name, size);
+            group.bench_with_input(id, &data, |b, data| b.iter(|| decode(black_box(data))));
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("downscale");
+    group.sample_size(20);
+    for (size, image) in &photos {
+        group.throughput(Throughput::Elements(u64::from(image.width() * image.height())));
+        group.bench_with_input(*size, image, |b, image| b.iter(|| downscale(black_box(image))));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(20);
+    for (size, image) in &photos {
+        // What the provider gets, after downscaling.
+        let image = downscale(image).into_owned();
+        group.throughput(Throughput::Elements(u64::from(image.width() * image.height())));
+        group.bench_with_input(*size, &image, |b, image| {
+            b.iter(|| encode_jpeg(black_box(image)))
+        });
+    }
+    group.finish();
+
+    // All of it, as the server does it: small JPEGs and PNGs go through as they are.
+    let options = CaptionOptions::default();
+    let mut group = c.benchmark_group("prepare");
+    group.sample_size(20);
+    for (format_name, format) in FORMATS {
+        for (size, image) in &photos {
+            let data = encoded(image, format);
+            group.throughput(Throughput::Bytes(data.len() as u64));
+            let id = BenchmarkId::new(*format_name, size);
+            group.bench_with_input(id, &data, |b, data| {
+                b.iter(|| prepare(black_box(data), &options))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, preprocessing);
+
+fn main() {
+    let started = SystemTime::now();
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+
+    if let Some(baseline) = baseline() {
+        std::process::exit(check_regressions(&baseline, started));
+    }
+}
+
+/// The `--baseline` the run compares with, if any.
+fn baseline() -> Option<String> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--baseline" {
+            return args.next();
+        }
+        if let Some(baseline) = arg.strip_prefix("--baseline=") {
+            return Some(baseline.to_string());
+        }
+    }
+    None
+}
+
+/// The regression gate: reports the benchmarks this run found slower than `baseline` by more
+/// than BENCH_MAX_REGRESSION percent; returns the exit code.
+fn check_regressions(baseline: &str, started: SystemTime) -> i32 {
+    let limit = std::env::var("BENCH_MAX_REGRESSION")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(MAX_REGRESSION_PERCENT);
+
+    let mut regressions = Vec::new();
+    for change in changes(&criterion_home(), started) {
+        let Ok(text) = std::fs::read_to_string(&change) else {
+            continue;
+        };
+        let Ok(estimates) = serde_json::from_str::<serde_json::Value>(&text) else {
+            continue;
+        };
+        // The change in the mean, as a fraction; its lower bound is the smallest slowdown
+        // criterion is confident of.
+        let mean = &estimates["mean"];
+        let lower = mean["confidence_interval"]["lower_bound"].as_f64().unwrap_or(0.0) * 100.0;
+        if lower > limit {
+            let point = mean["point_estimate"].as_f64().unwrap_or(0.0) * 100.0;
+            regressions.push(format!("{} (+{:.1}%)", benchmark_id(&change), point));
+        }
+    }
+
+    if regressions.is_empty() {
+        println!("No benchmark is more than {}% slower than the `{}` baseline", limit, baseline);
+        return 0;
+    }
+    eprintln!("More than {}% slower than the `{}` baseline:", limit, baseline);
+    for regression in regressions {
+        eprintln!("  {}", regression);
+    }
+    1
+}
+
+/// Where criterion keeps its results, as it decides it.
+fn criterion_home() -> PathBuf {
+    if let Some(home) = std::env::var_os("CRITERION_HOME") {
+        return PathBuf::from(home);
+    }
+    let target = std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into());
+    PathBuf::from(target).join("criterion")
+}
+
+/// The `change/estimates.json` files under `dir` written since `since`, one per benchmark
+/// this run compared.
+fn changes(dir: &Path, since: SystemTime) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return found;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let change = path.join("change").join("estimates.json");
+        let modified = change.metadata().and_then(|metadata| metadata.modified());
+        if modified.is_ok_and(|modified| modified >= since) {
+            found.push(change);
+        }
+        found.extend(changes(&path, since));
+    }
+    found
+}
+
+/// `decode/png/12mp` for `.../criterion/decode/png/12mp/change/estimates.json`.
+fn benchmark_id(change: &Path) -> String {
+    let dir = change.parent().and_then(Path::parent).unwrap_or(change);
+    let home = criterion_home();
+    dir.strip_prefix(&home).unwrap_or(dir).display().to_string()
+}
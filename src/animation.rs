@@ -0,0 +1,85 @@
+// Animated GIF and WebP handling: instead of flattening to the first frame, sample a few
+// frames spread across the animation so the model can describe the motion.
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, Frames, ImageDecoder, ImageFormat, ImageResult};
+use std::io::Cursor;
+
+use crate::imageproc;
+
+/// Frames sent to the provider for one animated image.
+pub const MAX_SAMPLED_FRAMES: usize = 6;
+
+/// Stop counting frames after this many, or once they add up to this many pixels; very long
+/// animations are sampled from their start.
+const MAX_SCANNED_FRAMES: usize = 1000;
+const MAX_SCANNED_PIXELS: u64 = 512 * 1024 * 1024;
+
+fn frames(data: &[u8], format: ImageFormat) -> ImageResult<Option<Frames<'_>>> {
+    match format {
+        ImageFormat::Gif => {
+            // A frame can claim to be bigger than the canvas; the limit keeps it from being
+            // allocated at that size.
+            let mut decoder = GifDecoder::new(Cursor::new(data))?;
+            decoder.set_limits(imageproc::frame_limits())?;
+            Ok(Some(decoder.into_frames()))
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data))?;
+            Ok(decoder.has_animation().then(|| decoder.into_frames()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Evenly spaced frame indices, always including the first and last frame.
+fn sample_indices(total: usize, max: usize) -> Vec<usize> {
+    if total <= max {
+        return (0..total).collect();
+    }
+    (0..max).map(|i| i * (total - 1) / (max - 1)).collect()
+}
+
+/// Decodes up to `max` frames spread across an animated GIF or WebP. Returns `None` for
+/// stills and other formats, which are handled as a single image.
+pub fn sample_frames(data: &[u8], max: usize) -> ImageResult<Option<Vec<DynamicImage>>> {
+    let Ok(format) = image::guess_format(data) else {
+        return Ok(None);
+    };
+    if !matches!(format, ImageFormat::Gif | ImageFormat::WebP) {
+        return Ok(None);
+    }
+    // Every frame is decoded to the size of the whole canvas.
+    let (width, height) = image::io::Reader::with_format(Cursor::new(data), format)
+        .into_dimensions()?;
+    imageproc::check_pixels(width, height)?;
+    let frame_pixels = (u64::from(width) * u64::from(height)).max(1);
+    let max_scanned = (MAX_SCANNED_PIXELS / frame_pixels).clamp(2, MAX_SCANNED_FRAMES as u64);
+
+    // First pass only counts, so memory stays bounded to the frames we keep.
+    let Some(counter) = frames(data, format)? else {
+        return Ok(None);
+    };
+    let mut total = 0;
+    for frame in counter.take(max_scanned as usize) {
+        frame?;
+        total += 1;
+    }
+    if total < 2 {
+        return Ok(None);
+    }
+
+    let wanted = sample_indices(total, max.max(2));
+    let mut sampled = Vec::with_capacity(wanted.len());
+    let frames = frames(data, format)?.expect("format was checked above");
+    for (index, frame) in frames.take(total).enumerate() {
+        let frame = frame?;
+        if wanted.contains(&index) {
+            sampled.push(DynamicImage::ImageRgba8(frame.into_buffer()));
+        }
+    }
+
+    Ok(Some(sampled))
+}
+
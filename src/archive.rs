@@ -0,0 +1,116 @@
+// ZIP uploads: pull the images out of an archive so they can be captioned as a batch.
+//
+// Entry sizes in the archive's directory can lie, so the limits below are enforced on the
+// bytes actually inflated; that is what stops zip bombs.
+
+use std::io::{Read, Seek};
+
+/// Most images captioned from one archive.
+pub const MAX_IMAGES: usize = 200;
+
+/// Entries of any kind we are willing to look at, so a directory of a million empty files
+/// can't keep the server busy.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Largest single image, and all images together, once inflated.
+const MAX_IMAGE_BYTES: u64 = 50 * 1024 * 1024;
+const MAX_TOTAL_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    /// Not a ZIP file, or an entry is corrupt, encrypted or uses an unsupported method.
+    Invalid(String),
+    /// Over one of the entry-count or inflated-size limits.
+    TooLarge(String),
+    /// The archive holds no images.
+    NoImages,
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::Invalid(message) => write!(f, "Invalid ZIP archive: {}", message),
+            ArchiveError::TooLarge(message) => write!(f, "ZIP archive too large: {}", message),
+            ArchiveError::NoImages => write!(f, "ZIP archive contains no images"),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<zip::result::ZipError> for ArchiveError {
+    fn from(e: zip::result::ZipError) -> Self {
+        ArchiveError::Invalid(e.to_string())
+    }
+}
+
+pub struct Entry {
+    /// Path inside the archive, e.g. `holiday/IMG_0001.jpg`.
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Whether an entry is macOS or dotfile clutter rather than a real file.
+fn is_hidden(name: &str) -> bool {
+    name.split('/')
+        .any(|component| component.starts_with('.') || component == "__MACOSX")
+}
+
+/// Extracts the image entries (by extension) in archive order, one at a time.
+pub fn extract_images(archive: impl Read + Seek) -> Result<Vec<Entry>, ArchiveError> {
+    let mut archive = zip::ZipArchive::new(archive)?;
+    if archive.len() > MAX_ENTRIES {
+        return Err(ArchiveError::TooLarge(format!(
+            "{} entries (limit {})",
+            archive.len(),
+            MAX_ENTRIES
+        )));
+    }
+
+    let mut images = Vec::new();
+    let mut total: u64 = 0;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let name = file.name().to_string();
+        if file.is_dir() || is_hidden(&name) || !crate::batch::is_image(name.as_ref()) {
+            continue;
+        }
+        if images.len() == MAX_IMAGES {
+            return Err(ArchiveError::TooLarge(format!(
+                "more than {} images",
+                MAX_IMAGES
+            )));
+        }
+
+        // Read one byte past the limit to tell "exactly at the limit" from "over it".
+        let mut contents = Vec::new();
+        (&mut file)
+            .take(MAX_IMAGE_BYTES + 1)
+            .read_to_end(&mut contents)
+            .map_err(|e| ArchiveError::Invalid(format!("{}: {}", name, e)))?;
+        if contents.len() as u64 > MAX_IMAGE_BYTES {
+            return Err(ArchiveError::TooLarge(format!(
+                "{} inflates to more than {} MB",
+                name,
+                MAX_IMAGE_BYTES / 1024 / 1024
+            )));
+        }
+        total += contents.len() as u64;
+        if total > MAX_TOTAL_BYTES {
+            return Err(ArchiveError::TooLarge(format!(
+                "images inflate to more than {} MB",
+                MAX_TOTAL_BYTES / 1024 / 1024
+            )));
+        }
+
+        images.push(Entry {
+            name,
+            data: contents,
+        });
+    }
+
+    if images.is_empty() {
+        return Err(ArchiveError::NoImages);
+    }
+    Ok(images)
+}
@@ -0,0 +1,61 @@
+// Artwork analysis for gallery and museum archives (the `art` mode): the medium, the styles or
+// movements it belongs to, notes on its composition and a guess at its era. The model may also
+// guess who made it, but from a photo that is a guess at best, so the guess is returned apart
+// from the caption as `attribution_guess`, always with `uncertain: true` and the reasons for
+// it, and the summary is told to leave the artist out.
+
+use serde_json::Value;
+
+use crate::results::{AttributionGuess, Era};
+
+pub(crate) const MEDIA: &[&str] = &[
+    "oil", "acrylic", "watercolor", "gouache", "tempera", "fresco", "ink", "pencil", "charcoal",
+    "pastel", "print", "photograph", "digital", "collage", "mixed_media", "sculpture", "ceramic",
+    "textile", "mosaic", "stained_glass", "other",
+];
+
+pub(crate) const STYLES: &[&str] = &[
+    "medieval", "renaissance", "mannerism", "baroque", "rococo", "neoclassicism", "romanticism",
+    "realism", "academic", "impressionism", "post_impressionism", "symbolism", "art_nouveau",
+    "expressionism", "fauvism", "cubism", "futurism", "dada", "surrealism", "art_deco",
+    "abstract", "abstract_expressionism", "pop_art", "minimalism", "conceptual", "photorealism",
+    "street_art", "contemporary", "ukiyo_e", "folk", "islamic", "chinese_ink", "indigenous",
+    "other",
+];
+
+/// Most styles and composition notes per artwork.
+pub(crate) const MAX_STYLES: usize = 3;
+pub(crate) const MAX_COMPOSITION_NOTES: usize = 5;
+
+/// The era guess: how the model put it ("late 19th century") and the years it spans, when it
+/// gave sensible ones. Years before the common era are negative.
+pub(crate) fn era(reply: &Value) -> Option<Era> {
+    let description = reply["description"].as_str().map(str::trim);
+    let description = description.filter(|text| !text.is_empty())?;
+    let year = |field: &str| reply[field].as_i64().filter(|year| (-40_000..=2100).contains(year));
+    let (from_year, to_year) = match (year("from_year"), year("to_year")) {
+        (Some(from), Some(to)) if from > to => (Some(to), Some(from)),
+        years => years,
+    };
+
+    Some(Era {
+        description: description.to_string(),
+        from_year,
+        to_year,
+    })
+}
+
+/// The attribution guess, marked uncertain; `None` when the model named no artist.
+pub(crate) fn attribution_guess(reply: &Value) -> Option<AttributionGuess> {
+    let artist = reply["artist"]
+        .as_str()
+        .map(str::trim)
+        .filter(|artist| !artist.is_empty() && !artist.eq_ignore_ascii_case("unknown"))?;
+    let basis = reply["basis"].as_str().map(str::trim).filter(|basis| !basis.is_empty());
+
+    Some(AttributionGuess {
+        artist: artist.to_string(),
+        basis: basis.map(str::to_string),
+        uncertain: true,
+    })
+}
@@ -0,0 +1,60 @@
+// The web UI. Its pages (`/` and `/gallery`) are askama templates in `templates/`, compiled in
+// and filled in per request, e.g. with the model name; their styles and scripts are plain files
+// in `assets/`, served under `/assets/`. Release builds embed `assets/` in the binary, so a
+// deploy is still a single file, and debug builds read it from the source tree, so edits show
+// on reload. ASSETS_DIR (read at startup) serves the files from another directory instead, to
+// restyle the UI without a rebuild.
+
+use askama::Template;
+use axum::{
+    extract::Path,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use rust_embed::RustEmbed;
+use tower_http::services::ServeDir;
+
+use crate::{etag, CaptionError};
+
+/// The files in `assets/`.
+#[derive(RustEmbed)]
+#[folder = "assets/"]
+struct Embedded;
+
+/// The `/assets` routes: the files in ASSETS_DIR when it is set, the built-in ones otherwise.
+pub(crate) fn routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    match crate::settings::var("ASSETS_DIR").ok().filter(|dir| !dir.trim().is_empty()) {
+        Some(dir) => {
+            tracing::info!("Serving the web UI's assets from {}", dir);
+            Router::new().nest_service("/assets", ServeDir::new(dir.trim()))
+        }
+        None => Router::new().route("/assets/*path", get(embedded)),
+    }
+}
+
+/// `GET /assets/*path` from the built-in files, revalidated by their hash.
+async fn embedded(Path(path): Path<String>, headers: HeaderMap) -> Response {
+    let Some(file) = Embedded::get(&path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let hash = hex::encode(file.metadata.sha256_hash());
+    let cache = [
+        (header::ETAG, format!("\"{}\"", hash)),
+        (header::CACHE_CONTROL, "no-cache".into()),
+    ];
+    if etag::none_match(&headers, &hash) {
+        return (StatusCode::NOT_MODIFIED, cache).into_response();
+    }
+    let content_type = file.metadata.mimetype().to_string();
+    (cache, [(header::CONTENT_TYPE, content_type)], file.data).into_response()
+}
+
+/// Renders a page's template.
+pub(crate) fn render(page: &impl Template) -> Result<Html<String>, CaptionError> {
+    page.render().map(Html).map_err(|e| {
+        tracing::error!(error = %e, "Can't render the page");
+        CaptionError::Internal(format!("Can't render the page: {}", e))
+    })
+}
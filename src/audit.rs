@@ -0,0 +1,234 @@
+// Audit log of caption operations, for deployments that caption sensitive images and must be
+// able to say who had what described, and how. With AUDIT_LOG=true every caption the server is
+// asked for, through any endpoint or job, is added to the `audit_log` table of the storage
+// (DATABASE_URL or HISTORY_DB, see `storage`), made or not: when, the request ID, the caller
+// (API key or OIDC identity, or the `X-Api-Key` header or address callers without one are told
+// apart by), the client address, the image's SHA-256, the mode and language, the prompt changes
+// (the tenant's brand voice and any content policy instructions), the model and the outcome
+// (`captioned`, `cached` or the error code, e.g. `content_refused`).
+//
+// The table is append-only: the database turns away updates and deletes, and the server has no
+// way to make them. `GET /admin/audit` (with the admin token) exports it as JSON Lines, or CSV
+// with `format=csv`, oldest first; `since` and `until` (RFC 3339 times or dates, UTC) and
+// `tenant` narrow it down. AUDIT_LOG is read at startup and needs a storage; an entry that
+// can't be written is logged as an error and the caption is still returned.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::config::{parse_flag, MODEL_LABEL};
+use crate::imageproc::content_hash;
+use crate::pipeline::Caption;
+use crate::scheduler::Caller;
+use crate::server::AppState;
+use crate::storage::{NewAuditEntry, Storage};
+use crate::voices::authorize;
+use crate::{request_id, CaptionError};
+
+/// Entries read from the storage at a time while exporting.
+const EXPORT_PAGE: usize = 1000;
+
+/// The CSV export's columns: `AuditEntry`'s fields.
+const CSV_HEADER: &[&str] = &[
+    "id",
+    "created_at",
+    "request_id",
+    "tenant",
+    "authenticated",
+    "client_ip",
+    "image_sha256",
+    "mode",
+    "language",
+    "voice",
+    "instructions",
+    "outcome",
+    "model",
+];
+
+/// Where caption attempts are recorded.
+pub(crate) struct AuditLog {
+    storage: Arc<dyn Storage>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AuditEntry {
+    pub(crate) id: i64,
+    pub(crate) created_at: String,
+    pub(crate) request_id: Option<String>,
+    pub(crate) tenant: String,
+    /// Whether the tenant is an API key or identity, rather than a header or address.
+    pub(crate) authenticated: bool,
+    pub(crate) client_ip: Option<String>,
+    pub(crate) image_sha256: String,
+    pub(crate) mode: String,
+    pub(crate) language: Option<String>,
+    /// The tenant's brand voice, as the model got it.
+    pub(crate) voice: Option<String>,
+    /// Instructions the content policy added to the prompt.
+    pub(crate) instructions: Option<String>,
+    /// `captioned`, `cached` or the error code the request failed with.
+    pub(crate) outcome: String,
+    pub(crate) model: String,
+}
+
+/// Which entries to export.
+pub(crate) struct AuditFilter {
+    pub(crate) since: Option<DateTime<Utc>>,
+    /// Entries before this time only.
+    pub(crate) until: Option<DateTime<Utc>>,
+    pub(crate) tenant: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    since: Option<String>,
+    until: Option<String>,
+    tenant: Option<String>,
+    format: Option<String>,
+}
+
+/// The audit log in `storage` when AUDIT_LOG asks for one.
+pub(crate) fn open(storage: Option<Arc<dyn Storage>>) -> Result<Option<AuditLog>, String> {
+    let value = crate::settings::var("AUDIT_LOG").unwrap_or_default();
+    let enabled = parse_flag(&value)
+        .ok_or_else(|| format!("AUDIT_LOG must be true or false, not '{}'", value))?;
+    if !enabled {
+        return Ok(None);
+    }
+    let storage = storage.ok_or("AUDIT_LOG needs DATABASE_URL or HISTORY_DB for the log")?;
+    tracing::info!("Keeping an audit log in {}", storage.backend());
+    Ok(Some(AuditLog { storage }))
+}
+
+/// Adds `caption`, asked for by `caller`, to the audit log with its outcome: the result it got,
+/// or `error`.
+pub(crate) async fn record(
+    audit: &AuditLog,
+    caller: &Caller,
+    caption: &Caption<'_>,
+    error: Option<&CaptionError>,
+) {
+    let (outcome, model) = match (error, &caption.response) {
+        (Some(e), _) => (e.code(), MODEL_LABEL.to_string()),
+        (None, Some(response)) if response.cached => ("cached", response.model.clone()),
+        (None, Some(response)) => ("captioned", response.model.clone()),
+        (None, None) => ("captioned", MODEL_LABEL.to_string()),
+    };
+    let hash = content_hash(caption.image);
+    let entry = NewAuditEntry {
+        created_at: Utc::now(),
+        request_id: request_id::current(),
+        tenant: caller.tenant().to_string(),
+        authenticated: caller.policy().is_some(),
+        client_ip: caller.ip().map(|ip| ip.to_string()),
+        image_sha256: hash.trim_start_matches("sha256:").to_string(),
+        mode: caption.options.mode.name(),
+        language: caption.options.language.clone(),
+        voice: caption.options.voice.clone(),
+        instructions: caption.policy_prompt.clone(),
+        outcome: outcome.to_string(),
+        model,
+    };
+    if let Err(e) = audit.storage.add_audit_entry(entry).await {
+        tracing::error!(tenant = caller.tenant(), error = %e, "Can't add to the audit log");
+    }
+}
+
+/// A `since` or `until` parameter: an RFC 3339 time, or a date for its start.
+fn parse_time(name: &str, value: &str) -> Result<DateTime<Utc>, CaptionError> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+        .map_err(|_| {
+            CaptionError::BadRequest(format!(
+                "{} must be an RFC 3339 time or a date such as 2026-10-01, not '{}'",
+                name, value
+            ))
+        })
+}
+
+/// `GET /admin/audit`
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, CaptionError> {
+    authorize(&headers)?;
+    let audit = state.audit.as_ref().ok_or_else(|| {
+        CaptionError::Unavailable("The audit log is kept only with AUDIT_LOG=true".into())
+    })?;
+    let csv = match query.format.as_deref().map(str::trim) {
+        None | Some("jsonl") | Some("ndjson") => false,
+        Some("csv") => true,
+        Some(other) => {
+            return Err(CaptionError::BadRequest(format!(
+                "unknown format '{}' (expected jsonl or csv)",
+                other
+            )))
+        }
+    };
+    let filter = AuditFilter {
+        since: query.since.as_deref().map(|since| parse_time("since", since)).transpose()?,
+        until: query.until.as_deref().map(|until| parse_time("until", until)).transpose()?,
+        tenant: query.tenant.filter(|tenant| !tenant.is_empty()),
+    };
+
+    let mut entries = Vec::new();
+    let mut after = 0;
+    loop {
+        let page = audit.storage.audit_entries(&filter, after, EXPORT_PAGE).await?;
+        let done = page.len() < EXPORT_PAGE;
+        after = page.last().map_or(after, |entry| entry.id);
+        entries.extend(page);
+        if done {
+            break;
+        }
+    }
+
+    let (body, content_type, extension) = if csv {
+        (to_csv(&entries)?, "text/csv; charset=utf-8", "csv")
+    } else {
+        (to_jsonl(&entries), "application/x-ndjson", "jsonl")
+    };
+    let disposition = format!("attachment; filename=\"audit-log.{}\"", extension);
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+fn to_jsonl(entries: &[AuditEntry]) -> Vec<u8> {
+    let mut jsonl = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut jsonl, entry).expect("entries always serialize");
+        jsonl.push(b'\n');
+    }
+    jsonl
+}
+
+/// A header row plus one row per entry, with empty cells for missing values.
+fn to_csv(entries: &[AuditEntry]) -> Result<Vec<u8>, CaptionError> {
+    let failed = |e: csv::Error| {
+        tracing::error!(error = %e, "Audit log export failed");
+        CaptionError::Internal(format!("Audit log export failed: {}", e))
+    };
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+    writer.write_record(CSV_HEADER).map_err(failed)?;
+    for entry in entries {
+        writer.serialize(entry).map_err(failed)?;
+    }
+    writer.into_inner().map_err(|e| failed(e.into_error().into()))
+}
@@ -0,0 +1,276 @@
+// API key authentication for the captioning endpoints (`/upload`, `/batch`, `/jobs`, `/video`
+// and the rest), needed before exposing the server on the public internet. It is off until
+// keys are configured, in either or both of:
+//
+// - API_KEYS: comma-separated `name:key` pairs, or bare keys (named by the key itself).
+// - API_KEYS_DB: a SQLite database whose `api_keys` table holds the SHA-256 hex digest of
+//   each key, so the keys themselves are never stored. The table is created at startup, and
+//   keys in it can carry their own provider key, daily quota and allowed modes (see `keys`,
+//   which also serves the admin API for creating and revoking them).
+//
+// Clients send `Authorization: Bearer <key>` or `X-Api-Key: <key>`. With OIDC_ISSUER set, a
+// bearer JWT from that identity provider works too (see `oidc`). The key's name, or the user
+// the token names, becomes the tenant for fair scheduling, brand voices and weights, and is
+// logged with requests that fail. The page at `/`, `/status`, the schemas
+// and the admin API (which has its own token) stay open; the page asks for a key when the
+// server wants one. Both settings and the table are read per request, so keys can be added or
+// revoked without a restart.
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::modes::Mode;
+use crate::{oidc, CaptionError};
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS api_keys (
+    name TEXT PRIMARY KEY,
+    key_sha256 TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    provider_key TEXT,
+    daily_quota INTEGER,
+    monthly_quota INTEGER,
+    allowed_modes TEXT
+)";
+
+/// Captions, provider tokens and their estimated cost per key and UTC day (see `usage`).
+const CREATE_USAGE_TABLE: &str = "CREATE TABLE IF NOT EXISTS key_usage (
+    name TEXT NOT NULL,
+    day TEXT NOT NULL,
+    captions INTEGER NOT NULL DEFAULT 0,
+    input_tokens INTEGER NOT NULL DEFAULT 0,
+    output_tokens INTEGER NOT NULL DEFAULT 0,
+    cost_usd REAL NOT NULL DEFAULT 0,
+    PRIMARY KEY (name, day)
+)";
+
+/// Names of revoked keys, which aren't given out again: they name the tenant's history, cached
+/// captions and brand voice, which a new key with the name would otherwise inherit.
+const CREATE_REVOKED_TABLE: &str = "CREATE TABLE IF NOT EXISTS revoked_keys (
+    name TEXT PRIMARY KEY,
+    revoked_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+)";
+
+/// Columns added to the tables after their first version, for databases made before them.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("api_keys", "provider_key", "TEXT"),
+    ("api_keys", "daily_quota", "INTEGER"),
+    ("api_keys", "monthly_quota", "INTEGER"),
+    ("api_keys", "allowed_modes", "TEXT"),
+    ("key_usage", "input_tokens", "INTEGER NOT NULL DEFAULT 0"),
+    ("key_usage", "output_tokens", "INTEGER NOT NULL DEFAULT 0"),
+    ("key_usage", "cost_usd", "REAL NOT NULL DEFAULT 0"),
+];
+
+/// The columns `KeyPolicy::from_row` reads, for queries on `api_keys`.
+pub(crate) const POLICY_COLUMNS: &str = "provider_key, daily_quota, monthly_quota, allowed_modes";
+
+/// What an authenticated caller may do. Only keys from the database have limits; keys from
+/// API_KEYS and SSO users get the default, which allows everything.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct KeyPolicy {
+    /// Gemini API key to caption with instead of GEMINI_API_KEY.
+    pub(crate) provider_key: Option<String>,
+    /// Captions allowed per UTC day.
+    pub(crate) daily_quota: Option<u64>,
+    /// Captions allowed per UTC calendar month.
+    pub(crate) monthly_quota: Option<u64>,
+    /// Modes the key may caption in; any mode when unset.
+    pub(crate) allowed_modes: Option<Vec<Mode>>,
+}
+
+impl KeyPolicy {
+    /// Reads the `POLICY_COLUMNS` starting at column `first`.
+    pub(crate) fn from_row(row: &Row, first: usize) -> rusqlite::Result<Self> {
+        let allowed_modes: Option<String> = row.get(first + 3)?;
+        Ok(KeyPolicy {
+            provider_key: row.get(first)?,
+            daily_quota: row.get(first + 1)?,
+            monthly_quota: row.get(first + 2)?,
+            allowed_modes: allowed_modes.map(|modes| {
+                modes
+                    .split(',')
+                    .filter_map(|mode| mode.parse().ok())
+                    .collect()
+            }),
+        })
+    }
+
+    pub(crate) fn check_mode(&self, mode: Mode) -> Result<(), CaptionError> {
+        match &self.allowed_modes {
+            Some(allowed) if !allowed.contains(&mode) => Err(CaptionError::Forbidden(format!(
+                "This API key can't caption in {} mode",
+                mode
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Who was authenticated, for handlers further down (see `scheduler::Tenant`).
+#[derive(Clone)]
+pub(crate) struct Identity {
+    /// The API key's name, or `oidc:` and the token's user.
+    pub(crate) name: String,
+    pub(crate) policy: Arc<KeyPolicy>,
+}
+
+pub(crate) fn database() -> Option<String> {
+    crate::settings::var("API_KEYS_DB").ok().filter(|path| !path.trim().is_empty())
+}
+
+/// `(name, key)` for each key in API_KEYS.
+fn configured_keys() -> Vec<(String, String)> {
+    let Ok(keys) = crate::settings::var("API_KEYS") else {
+        return Vec::new();
+    };
+    keys.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((name, key)) => (name.trim().to_string(), key.trim().to_string()),
+            None => (entry.to_string(), entry.to_string()),
+        })
+        .collect()
+}
+
+fn create_tables(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute(CREATE_TABLE, [])?;
+    connection.execute(CREATE_USAGE_TABLE, [])?;
+    connection.execute(CREATE_REVOKED_TABLE, [])?;
+    for (table, column, kind) in ADDED_COLUMNS {
+        let exists: bool = connection.query_row(
+            "SELECT count(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+            [table, column],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            let alter = format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, kind);
+            connection.execute(&alter, [])?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the tables (or adds their newer columns) when API_KEYS_DB is set, so the database
+/// can be filled in before the first request.
+pub fn prepare_database() -> Result<(), String> {
+    let Some(path) = database() else {
+        return Ok(());
+    };
+    Connection::open(&path)
+        .and_then(|connection| create_tables(&connection))
+        .map_err(|e| format!("Can't set up the API key database {}: {}", path, e))
+}
+
+fn presented_key(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    bearer
+        .or_else(|| headers.get("x-api-key").and_then(|value| value.to_str().ok()))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+}
+
+fn lookup(path: &str, digest: &str) -> rusqlite::Result<Option<Identity>> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    connection
+        .query_row(
+            &format!("SELECT name, {} FROM api_keys WHERE key_sha256 = ?1", POLICY_COLUMNS),
+            [digest],
+            |row| {
+                Ok(Identity {
+                    name: row.get(0)?,
+                    policy: Arc::new(KeyPolicy::from_row(row, 1)?),
+                })
+            },
+        )
+        .optional()
+}
+
+/// Who `key` belongs to, if it is one of the configured keys.
+async fn key_identity(key: &str) -> Result<Option<Identity>, CaptionError> {
+    let digest = Sha256::digest(key);
+    let configured = configured_keys()
+        .into_iter()
+        .find(|(_, expected)| Sha256::digest(expected) == digest);
+    if let Some((name, _)) = configured {
+        return Ok(Some(Identity {
+            name,
+            policy: Arc::default(),
+        }));
+    }
+    let Some(path) = database() else {
+        return Ok(None);
+    };
+    let digest = hex::encode(digest);
+    tokio::task::spawn_blocking(move || lookup(&path, &digest))
+        .await
+        .map_err(|e| CaptionError::Internal(e.to_string()))?
+        .map_err(|e| {
+            tracing::error!(error = %e, "API key lookup failed");
+            CaptionError::Internal("Can't check the API key".into())
+        })
+}
+
+fn unauthorized(error: CaptionError, challenge: &'static str) -> Response {
+    let mut response = error.into_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+    response
+}
+
+/// Who presented `key`: the user of a valid token (as `oidc:<user>`), or a configured key.
+async fn identify(key: &str) -> Result<Identity, Response> {
+    if let Some(issuer) = oidc::issuer().filter(|_| oidc::looks_like_jwt(key)) {
+        let user = oidc::identity(&issuer, key).await;
+        let identity = user.map(|name| Identity {
+            name,
+            policy: Arc::default(),
+        });
+        return identity.map_err(|e| match e {
+            CaptionError::Unauthorized(_) => unauthorized(e, "Bearer error=\"invalid_token\""),
+            e => e.into_response(),
+        });
+    }
+    match key_identity(key).await {
+        Ok(Some(identity)) => Ok(identity),
+        Ok(None) => Err(unauthorized(
+            CaptionError::Unauthorized("Missing or invalid API key".into()),
+            "Bearer",
+        )),
+        Err(e) => Err(e.into_response()),
+    }
+}
+
+/// Middleware letting requests through only with a valid API key or token, when keys or an
+/// identity provider are configured.
+pub(crate) async fn require_key(mut request: Request, next: Next) -> Response {
+    if database().is_none() && configured_keys().is_empty() && oidc::issuer().is_none() {
+        return next.run(request).await;
+    }
+    let Some(key) = presented_key(request.headers()) else {
+        return unauthorized(
+            CaptionError::Unauthorized("Missing or invalid API key".into()),
+            "Bearer",
+        );
+    };
+    let identity = match identify(&key).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    // The request's log events say whose it was (see `logging`).
+    tracing::Span::current().record("caller", identity.name.as_str());
+    request.extensions_mut().insert(identity);
+    next.run(request).await
+}
@@ -0,0 +1,999 @@
+// Batch captioning for whole directories, manifest files, S3 prefixes or Google Drive
+// folders, with a progress bar, an end-of-run summary and a machine-readable run report.
+
+use clap::{Args, ValueEnum};
+use futures::stream::{self, StreamExt};
+use indicatif::{HumanDuration, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+
+mod filters;
+mod gdrive;
+mod s3;
+pub mod sidecar;
+
+use filters::{ByteSize, Filters};
+use sidecar::Sidecar;
+
+use crate::cli::{
+    fail, parse_path_lines, CaptionRecord, ErrorBody, ErrorKind, ErrorRecord, OptionArgs,
+    OutputFormat,
+};
+use crate::modes::{CaptionOptions, Mode};
+use crate::subtitles::{self, TrackFormat};
+use crate::xmp;
+use crate::config::LOG_PROVIDER_TRAFFIC;
+use crate::handlers::BatchItem;
+use crate::imageproc::content_hash;
+use crate::providers::Usage;
+use crate::{caption_image_bytes, export, BoxError, CaptionResponse};
+
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "webp", "gif", "bmp", "tif", "tiff", "heic", "heif", "cr2", "nef",
+    "arw", "dng",
+];
+
+const REPORT_FILE_NAME: &str = "run-report.json";
+
+const FAILURES_FILE_NAME: &str = "failures.jsonl";
+
+#[derive(Args)]
+pub struct BatchArgs {
+    /// Directory to scan recursively, a manifest file listing one image path per line, an S3
+    /// prefix (s3://bucket/prefix; needs a build with `--features s3`) or a Google Drive
+    /// folder (gdrive://FOLDER_ID)
+    #[arg(required_unless_present = "retry")]
+    pub source: Option<PathBuf>,
+
+    /// Retry the images listed in a failures file from an earlier run; the file is rewritten
+    /// with whatever still fails
+    #[arg(long, value_name = "FILE", conflicts_with = "source")]
+    pub retry: Option<PathBuf>,
+
+    /// Write results to this file instead of stdout
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+
+    /// Results format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Ndjson)]
+    pub format: OutputFormat,
+
+    /// Where to write the run report (defaults to run-report.json next to --output)
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+
+    /// Where to write failed images as JSON Lines, for --retry (defaults to failures.jsonl
+    /// next to --output, or the file being retried)
+    #[arg(long, value_name = "FILE")]
+    pub failures: Option<PathBuf>,
+
+    /// Number of images to caption concurrently
+    #[arg(long, short, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=64))]
+    pub jobs: u16,
+
+    /// Process images in random order (combine with --limit to sample a subset)
+    #[arg(long, conflicts_with_all = ["sort", "track"])]
+    pub shuffle: bool,
+
+    /// Process images in this order instead of the source order
+    #[arg(long, value_enum)]
+    pub sort: Option<SortOrder>,
+
+    /// Only process the first N images (after sorting or shuffling)
+    #[arg(long)]
+    pub limit: Option<usize>,
+
+    /// Only process files matching this glob, relative to the source (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip files matching this glob, relative to the source (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Skip files smaller than this (e.g. 50K)
+    #[arg(long, value_name = "SIZE")]
+    pub min_size: Option<ByteSize>,
+
+    /// Skip files larger than this (e.g. 20M)
+    #[arg(long, value_name = "SIZE")]
+    pub max_size: Option<ByteSize>,
+
+    /// Only process files modified on or after this date (YYYY-MM-DD)
+    #[arg(long, value_name = "DATE")]
+    pub since: Option<chrono::NaiveDate>,
+
+    /// Only process files modified on or before this date (YYYY-MM-DD)
+    #[arg(long, value_name = "DATE")]
+    pub until: Option<chrono::NaiveDate>,
+
+    /// Write a JSON sidecar next to each image (photo.jpg -> photo.jpg.json)
+    #[arg(long)]
+    pub sidecar: bool,
+
+    /// Treat the images as frames of a sequence and write a descriptive caption track
+    /// (.srt or .vtt, by extension) with one cue per frame
+    #[arg(long, value_name = "FILE")]
+    pub track: Option<PathBuf>,
+
+    /// Seconds between frames for --track
+    #[arg(long, value_name = "SECONDS", default_value_t = 1.0, requires = "track")]
+    pub frame_interval: f64,
+
+    /// Write an XMP sidecar with the caption, keywords and provenance. Lightroom naming
+    /// (photo.xmp) is the default; darktable expects photo.jpg.xmp
+    #[arg(long, value_enum, value_name = "NAMING", num_args = 0..=1, require_equals = true,
+          default_missing_value = "lightroom")]
+    pub xmp: Option<XmpNaming>,
+
+    /// Re-caption images even if their sidecar is current for the same bytes and prompt
+    #[arg(long)]
+    pub force: bool,
+
+    /// Report which metadata files would be created or overwritten without writing anything.
+    /// On its own this makes no provider calls; with --diff, captions are generated to show the changes.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Show a field-by-field diff of every metadata file written (or, with --dry-run, that would be)
+    #[arg(long)]
+    pub diff: bool,
+
+    /// Also store results remotely: with each S3 object or Drive file (metadata, or the Drive
+    /// description), or as a captions.jsonl manifest under the S3 source prefix
+    #[arg(long, value_enum, value_name = "WHERE")]
+    pub write_back: Option<WriteBack>,
+
+    #[command(flatten)]
+    pub options: OptionArgs,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SortOrder {
+    /// Full path, alphabetically
+    Name,
+    /// Modification time, oldest first
+    Mtime,
+}
+
+/// How XMP sidecars are named next to the image.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum XmpNaming {
+    /// photo.xmp (Lightroom, Capture One)
+    Lightroom,
+    /// photo.jpg.xmp (darktable)
+    Darktable,
+}
+
+impl XmpNaming {
+    pub(crate) fn path(self, image: &Path) -> PathBuf {
+        match self {
+            XmpNaming::Lightroom => image.with_extension("xmp"),
+            XmpNaming::Darktable => {
+                let mut name = image.file_name().unwrap_or_default().to_os_string();
+                name.push(".xmp");
+                image.with_file_name(name)
+            }
+        }
+    }
+}
+
+/// Where results for S3 objects and Drive files go, besides the usual --output.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WriteBack {
+    /// Store the caption with each file: S3 user metadata (the object is copied onto
+    /// itself) or the Drive file description
+    Metadata,
+    /// Upload a captions.jsonl manifest next to the S3 source prefix
+    Manifest,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SkipReason {
+    NotAnImage,
+    Filtered,
+    AlreadyCaptioned,
+}
+
+#[derive(Serialize)]
+struct SkippedItem {
+    path: String,
+    reason: SkipReason,
+}
+
+/// What a write-back does to an existing metadata file.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum WriteAction {
+    Create,
+    Overwrite,
+}
+
+impl WriteAction {
+    fn for_path(path: &Path) -> Self {
+        if path.exists() {
+            WriteAction::Overwrite
+        } else {
+            WriteAction::Create
+        }
+    }
+}
+
+impl PlannedWrite {
+    fn new(target: &Path) -> Self {
+        PlannedWrite {
+            path: target.display().to_string(),
+            action: WriteAction::for_path(target),
+            applied: false,
+            diff: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PlannedWrite {
+    path: String,
+    action: WriteAction,
+    applied: bool,
+    #[serde(skip)]
+    diff: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct FailedItem {
+    path: String,
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct RunReport {
+    source: String,
+    mode: Mode,
+    started_at: String,
+    finished_at: String,
+    elapsed_ms: u128,
+    dry_run: bool,
+    succeeded: usize,
+    failed: usize,
+    skipped: usize,
+    prompt_tokens: u64,
+    output_tokens: u64,
+    total_cost_usd: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failures_path: Option<String>,
+    writes: Vec<PlannedWrite>,
+    failures: Vec<FailedItem>,
+    skipped_files: Vec<SkippedItem>,
+}
+
+/// Files found in the batch source: images to caption and everything else.
+struct Collected {
+    images: Vec<PathBuf>,
+    skipped: Vec<(PathBuf, SkipReason)>,
+    /// Modification times from a remote listing, for --sort mtime.
+    modified: HashMap<PathBuf, SystemTime>,
+}
+
+/// Whether a path is an `s3://` or `gdrive://` URL rather than a local file.
+fn is_remote(path: &Path) -> bool {
+    s3::is_url(path) || gdrive::is_url(path)
+}
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn collect_sources(source: &Path, filters: &Filters) -> Result<Collected, BoxError> {
+    let mut collected = Collected {
+        images: Vec::new(),
+        skipped: Vec::new(),
+        modified: HashMap::new(),
+    };
+
+    if source.is_dir() {
+        for entry in walkdir::WalkDir::new(source).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.into_path();
+            let relative = path.strip_prefix(source).unwrap_or(&path);
+
+            if sidecar::is_sidecar(&path) {
+                continue;
+            } else if !is_image(&path) {
+                collected.skipped.push((path, SkipReason::NotAnImage));
+            } else if !filters.matches(&path, relative) {
+                collected.skipped.push((path, SkipReason::Filtered));
+            } else {
+                collected.images.push(path);
+            }
+        }
+    } else {
+        // Manifest: relative entries are resolved against the manifest's own directory.
+        let base = source.parent().unwrap_or(Path::new("."));
+        for entry in parse_path_lines(BufReader::new(File::open(source)?))? {
+            let path = if is_remote(&entry) {
+                entry.clone()
+            } else {
+                base.join(&entry)
+            };
+            if filters.matches(&path, &entry) {
+                collected.images.push(path);
+            } else {
+                collected.skipped.push((path, SkipReason::Filtered));
+            }
+        }
+    }
+
+    Ok(collected)
+}
+
+/// One file from a remote listing (S3 or Google Drive).
+struct Listed {
+    url: PathBuf,
+    /// Path relative to the listed prefix or folder, which globs are matched against.
+    relative: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Sorts a remote listing into images and everything else, the way `collect_sources` does
+/// for a directory.
+fn collect_objects(objects: Vec<Listed>, filters: &Filters) -> Collected {
+    let mut collected = Collected {
+        images: Vec::new(),
+        skipped: Vec::new(),
+        modified: HashMap::new(),
+    };
+
+    for object in objects {
+        if !is_image(&object.url) {
+            collected.skipped.push((object.url, SkipReason::NotAnImage));
+        } else if !filters.matches_object(&object.relative, object.size, object.modified) {
+            collected.skipped.push((object.url, SkipReason::Filtered));
+        } else {
+            if let Some(modified) = object.modified {
+                collected.modified.insert(object.url.clone(), modified);
+            }
+            collected.images.push(object.url);
+        }
+    }
+
+    collected
+}
+
+/// Applies --sort / --shuffle / --limit to the collected images.
+fn order_images(collected: &mut Collected, args: &BatchArgs) {
+    let images = &mut collected.images;
+    match args.sort {
+        Some(SortOrder::Name) => images.sort(),
+        Some(SortOrder::Mtime) => images.sort_by_cached_key(|path| {
+            collected.modified.get(path).copied().unwrap_or_else(|| {
+                std::fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .unwrap_or(std::time::UNIX_EPOCH)
+            })
+        }),
+        None if args.shuffle => {
+            use rand::seq::SliceRandom;
+            images.shuffle(&mut rand::rng());
+        }
+        None => {}
+    }
+
+    if let Some(limit) = args.limit {
+        images.truncate(limit);
+    }
+}
+
+/// Settings shared by every image in the run.
+struct RunSettings<'a> {
+    options: &'a CaptionOptions,
+    api_key: &'a str,
+    prompt_hash: String,
+    sidecar: bool,
+    xmp: Option<XmpNaming>,
+    force: bool,
+    dry_run: bool,
+    diff: bool,
+    /// Connected whenever the run includes `s3://` or `gdrive://` images.
+    s3: Option<&'a s3::Client>,
+    drive: Option<&'a gdrive::Client>,
+    write_back: Option<WriteBack>,
+}
+
+enum Outcome {
+    Captioned {
+        response: Box<CaptionResponse>,
+        usage: Usage,
+        writes: Vec<PlannedWrite>,
+    },
+    /// Dry run without --diff: nothing was captioned, only the writes were planned.
+    Planned(Vec<PlannedWrite>),
+    AlreadyCaptioned,
+    Failed(BoxError),
+}
+
+/// Reads an existing XMP sidecar, refusing to replace one another tool wrote: it may hold
+/// develop settings or ratings we would otherwise destroy.
+pub(crate) fn existing_xmp(target: &Path) -> Result<Option<String>, BoxError> {
+    match std::fs::read_to_string(target) {
+        Ok(existing) if xmp::is_ours(&existing) => Ok(Some(existing)),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} was not written by the captioner; not overwriting it", target.display()),
+        )
+        .into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn process_image(path: &Path, settings: &RunSettings<'_>) -> Outcome {
+    if let Some(client) = settings.s3.filter(|_| s3::is_url(path)) {
+        return s3::process_object(client, path, settings).await;
+    }
+    if let Some(client) = settings.drive.filter(|_| gdrive::is_url(path)) {
+        return gdrive::process_file(client, path, settings).await;
+    }
+
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(e) => return Outcome::Failed(e.into()),
+    };
+
+    let content_hash = content_hash(&data);
+    let xmp_target = settings.xmp.map(|naming| naming.path(path));
+    if !settings.force
+        && sidecar::is_current(path, &content_hash, &settings.prompt_hash)
+        && xmp_target.as_deref().is_none_or(Path::exists)
+    {
+        return Outcome::AlreadyCaptioned;
+    }
+
+    let old_xmp = match xmp_target.as_deref().map(existing_xmp).transpose() {
+        Ok(old_xmp) => old_xmp.flatten(),
+        Err(e) => return Outcome::Failed(e),
+    };
+
+    if settings.dry_run && !settings.diff {
+        let sidecar_target = settings.sidecar.then(|| sidecar::sidecar_path(path));
+        let writes = sidecar_target
+            .iter()
+            .chain(&xmp_target)
+            .map(|target| PlannedWrite::new(target))
+            .collect();
+        return Outcome::Planned(writes);
+    }
+
+    let (response, usage) = match caption_image_bytes(&data, settings.options, settings.api_key).await
+    {
+        Ok(result) => result,
+        Err(e) => return Outcome::Failed(e.into()),
+    };
+
+    let mut writes = Vec::new();
+
+    if let Some(target) = xmp_target {
+        let packet = xmp::render(&xmp::XmpFields::from_response(&response));
+        let mut write = PlannedWrite::new(&target);
+        if settings.diff {
+            write.diff = xmp::diff(old_xmp.as_deref(), &packet);
+        }
+        if !settings.dry_run {
+            if let Err(e) = std::fs::write(&target, packet) {
+                return Outcome::Failed(e.into());
+            }
+            write.applied = true;
+        }
+        writes.push(write);
+    }
+
+    if !settings.sidecar {
+        return Outcome::Captioned {
+            response: Box::new(response),
+            usage,
+            writes,
+        };
+    }
+
+    let sidecar = Sidecar {
+        content_hash,
+        prompt_hash: settings.prompt_hash.clone(),
+        response,
+    };
+    let mut write = PlannedWrite::new(&sidecar::sidecar_path(path));
+    if settings.diff {
+        write.diff = sidecar::diff(sidecar::existing(path).as_ref(), &sidecar);
+    }
+
+    if !settings.dry_run {
+        if let Err(e) = sidecar::write(path, &sidecar) {
+            return Outcome::Failed(e.into());
+        }
+        write.applied = true;
+    }
+    writes.insert(0, write);
+
+    Outcome::Captioned {
+        response: Box::new(sidecar.response),
+        usage,
+        writes,
+    }
+}
+
+/// Prints a line above the progress bar (or plainly when stderr isn't a terminal and
+/// the bar is hidden, where `ProgressBar::println` would drop it).
+fn log_line(bar: &ProgressBar, line: impl std::fmt::Display) {
+    bar.suspend(|| eprintln!("{}", line));
+}
+
+/// Prints a planned or applied write (and its diff) above the progress bar.
+fn print_write(bar: &ProgressBar, write: &PlannedWrite) {
+    let verb = match (write.action, write.applied) {
+        (WriteAction::Create, true) => "created",
+        (WriteAction::Overwrite, true) => "overwrote",
+        (WriteAction::Create, false) => "would create",
+        (WriteAction::Overwrite, false) => "would overwrite",
+    };
+    log_line(bar, format!("📝 {} {}", verb, write.path));
+    for line in &write.diff {
+        log_line(bar, format!("    {}", line));
+    }
+}
+
+fn report_path(args: &BatchArgs) -> Option<PathBuf> {
+    args.report.clone().or_else(|| {
+        let output = args.output.as_ref()?;
+        Some(output.with_file_name(REPORT_FILE_NAME))
+    })
+}
+
+/// Where failed items go. A run with nowhere obvious to put them falls back to the current
+/// directory, but only once something actually failed.
+fn failures_path(args: &BatchArgs, any_failed: bool) -> Option<PathBuf> {
+    args.failures
+        .clone()
+        .or_else(|| args.retry.clone())
+        .or_else(|| Some(args.output.as_ref()?.with_file_name(FAILURES_FILE_NAME)))
+        .or_else(|| any_failed.then(|| PathBuf::from(FAILURES_FILE_NAME)))
+}
+
+/// Writes one failure per line. Paths are made absolute so the file works as a --retry
+/// manifest wherever it ends up.
+fn write_failures(path: &Path, failures: &[FailedItem]) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(File::create(path)?);
+    for failure in failures {
+        let absolute = match std::path::absolute(&failure.path) {
+            Ok(path) if !is_remote(Path::new(&failure.path)) => path.display().to_string(),
+            _ => failure.path.clone(),
+        };
+        let line = FailedItem {
+            path: absolute,
+            error: failure.error.clone(),
+        };
+        writeln!(out, "{}", serde_json::to_string(&line).expect("failures always serialize"))?;
+    }
+    out.flush()
+}
+
+/// Connects to S3, or reports why it can't and returns the exit code.
+async fn connect_s3(format: OutputFormat) -> Result<s3::Client, i32> {
+    s3::Client::connect()
+        .await
+        .map_err(|e| fail(format, ErrorKind::ConfigError, &e.to_string()))
+}
+
+/// Connects to Google Drive, or reports why it can't and returns the exit code.
+async fn connect_drive(format: OutputFormat) -> Result<gdrive::Client, i32> {
+    gdrive::Client::connect()
+        .await
+        .map_err(|e| fail(format, ErrorKind::ConfigError, &e.to_string()))
+}
+
+fn progress_bar(len: usize) -> ProgressBar {
+    let bar = ProgressBar::new(len as u64);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} ({eta}) {wide_msg}",
+        )
+        .expect("progress template is valid")
+        .progress_chars("█▉▊▋▌▍▎▏ "),
+    );
+    bar
+}
+
+/// Runs the `batch` subcommand and returns the process exit code.
+pub async fn run_batch(args: BatchArgs) -> i32 {
+    let Ok(api_key) = crate::settings::var("GEMINI_API_KEY") else {
+        return fail(
+            args.format,
+            ErrorKind::ConfigError,
+            "GEMINI_API_KEY must be set in the environment or .env file",
+        );
+    };
+
+    let options = match args.options.to_options() {
+        Ok(options) => options,
+        Err(message) => return fail(args.format, ErrorKind::InvalidInput, &message),
+    };
+
+    let track = match &args.track {
+        Some(path) => {
+            let format = TrackFormat::from_path(path);
+            let interval = Duration::try_from_secs_f64(args.frame_interval)
+                .ok()
+                .filter(|interval| !interval.is_zero());
+            match (format, interval) {
+                (Some(format), Some(interval)) => Some((path, format, interval)),
+                (None, _) => {
+                    let message = "--track must end in .srt or .vtt";
+                    return fail(args.format, ErrorKind::InvalidInput, message);
+                }
+                (_, None) => {
+                    let message = "--frame-interval must be a positive number of seconds";
+                    return fail(args.format, ErrorKind::InvalidInput, message);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let filters = match Filters::from_args(&args) {
+        Ok(filters) => filters,
+        Err(message) => return fail(args.format, ErrorKind::InvalidInput, &message),
+    };
+
+    // A failures file is a manifest: one {"path": ...} object per line.
+    let source = args
+        .retry
+        .as_ref()
+        .or(args.source.as_ref())
+        .expect("clap requires a source or --retry");
+
+    let mut s3_client = None;
+    let mut drive_client = None;
+    let collected = if s3::is_url(source) {
+        let client = match connect_s3(args.format).await {
+            Ok(client) => client,
+            Err(code) => return code,
+        };
+        let listed = client.list(source).await;
+        s3_client = Some(client);
+        listed.map(|objects| collect_objects(objects, &filters))
+    } else if gdrive::is_url(source) {
+        let client = match connect_drive(args.format).await {
+            Ok(client) => client,
+            Err(code) => return code,
+        };
+        let listed = client.list(source).await;
+        drive_client = Some(client);
+        listed.map(|files| collect_objects(files, &filters))
+    } else {
+        collect_sources(source, &filters)
+    };
+    let mut collected = match collected {
+        Ok(collected) => collected,
+        Err(e) => {
+            let message = format!("Can't read {}: {}", source.display(), e);
+            return fail(args.format, ErrorKind::InvalidInput, &message);
+        }
+    };
+
+    // Manifests and failures files can list remote files too.
+    if s3_client.is_none() && collected.images.iter().any(|path| s3::is_url(path)) {
+        match connect_s3(args.format).await {
+            Ok(client) => s3_client = Some(client),
+            Err(code) => return code,
+        }
+    }
+    if drive_client.is_none() && collected.images.iter().any(|path| gdrive::is_url(path)) {
+        match connect_drive(args.format).await {
+            Ok(client) => drive_client = Some(client),
+            Err(code) => return code,
+        }
+    }
+    let any_remote = collected.images.iter().any(|path| is_remote(path));
+    if any_remote && (args.sidecar || args.xmp.is_some() || args.track.is_some()) {
+        let message = "--sidecar, --xmp and --track need local files; use --write-back for S3 and Drive";
+        return fail(args.format, ErrorKind::InvalidInput, message);
+    }
+    if args.write_back.is_some() && !any_remote {
+        let message = "--write-back only applies to s3:// and gdrive:// images";
+        return fail(args.format, ErrorKind::InvalidInput, message);
+    }
+    let manifest = match args.write_back {
+        Some(WriteBack::Manifest) => match s3::manifest_url(source) {
+            Some(url) => Some(url),
+            None => {
+                let message = "--write-back manifest needs an s3://bucket/prefix source";
+                return fail(args.format, ErrorKind::InvalidInput, message);
+            }
+        },
+        _ => None,
+    };
+
+    order_images(&mut collected, &args);
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(std::io::BufWriter::new(file)),
+            Err(e) => {
+                let message = format!("Can't create {}: {}", path.display(), e);
+                return fail(args.format, ErrorKind::InvalidInput, &message);
+            }
+        },
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    LOG_PROVIDER_TRAFFIC.store(false, Ordering::Relaxed);
+
+    let started_at = chrono::Utc::now();
+    let start = Instant::now();
+    let bar = progress_bar(collected.images.len());
+
+    let settings = RunSettings {
+        options: &options,
+        api_key: &api_key,
+        prompt_hash: content_hash(options.fingerprint().as_bytes()),
+        sidecar: args.sidecar,
+        xmp: args.xmp,
+        force: args.force,
+        dry_run: args.dry_run,
+        diff: args.diff,
+        s3: s3_client.as_ref(),
+        drive: drive_client.as_ref(),
+        write_back: args.write_back,
+    };
+
+    let mut json_results = Vec::new();
+    let mut csv_items = Vec::new();
+    let mut manifest_lines = Vec::new();
+    let mut track_captions: Vec<Option<String>> = Vec::new();
+    let mut failures = Vec::new();
+    let mut writes = Vec::new();
+    let mut usage = Usage::default();
+    let mut succeeded = 0;
+    let mut planned = 0;
+    let mut worst: Option<ErrorKind> = None;
+
+    // `buffered` keeps results in source order while up to --jobs captions run at once.
+    let mut results = stream::iter(&collected.images)
+        .map(|path| {
+            let settings = &settings;
+            async move { (path, process_image(path, settings).await) }
+        })
+        .buffered(args.jobs.into());
+
+    while let Some((path, outcome)) = results.next().await {
+        bar.set_message(path.display().to_string());
+        let label = Some(path.display().to_string());
+
+        let value = match outcome {
+            Outcome::AlreadyCaptioned => {
+                collected
+                    .skipped
+                    .push((path.clone(), SkipReason::AlreadyCaptioned));
+                // Keep the frame in the track with the caption it already has.
+                let existing = sidecar::existing(path)
+                    .and_then(|sidecar| sidecar["caption"].as_str().map(str::to_string));
+                track_captions.push(existing);
+                None
+            }
+            Outcome::Planned(item_writes) => {
+                planned += 1;
+                track_captions.push(None);
+                for write in &item_writes {
+                    print_write(&bar, write);
+                }
+                writes.extend(item_writes);
+                None
+            }
+            Outcome::Captioned {
+                response,
+                usage: item_usage,
+                writes: item_writes,
+            } => {
+                succeeded += 1;
+                usage += item_usage;
+                track_captions.push(Some(response.caption.clone()));
+                for write in &item_writes {
+                    if args.diff || !write.applied {
+                        print_write(&bar, write);
+                    }
+                }
+                writes.extend(item_writes);
+                if args.format == OutputFormat::Text {
+                    let _ = writeln!(out, "{}: {}", path.display(), response.caption);
+                }
+                if args.format == OutputFormat::Csv {
+                    csv_items.push(BatchItem {
+                        file_name: label.clone(),
+                        response: Some((*response).clone()),
+                        xmp: None,
+                        error: None,
+                    });
+                }
+                let record = serde_json::to_value(CaptionRecord {
+                    path: label,
+                    response: *response,
+                });
+                (args.format.is_json() || manifest.is_some()).then_some(record)
+            }
+            Outcome::Failed(e) => {
+                track_captions.push(None);
+                let kind = ErrorKind::classify(&e);
+                worst = worst.max(Some(kind));
+                log_line(&bar, format!("❌ {}: {}", path.display(), e));
+                failures.push(FailedItem {
+                    path: path.display().to_string(),
+                    error: ErrorBody::new(kind, e.to_string()),
+                });
+                if args.format == OutputFormat::Csv {
+                    csv_items.push(BatchItem {
+                        file_name: label.clone(),
+                        response: None,
+                        xmp: None,
+                        error: Some(e.to_string()),
+                    });
+                }
+                (args.format.is_json() || manifest.is_some()).then(|| {
+                    serde_json::to_value(ErrorRecord {
+                        path: label,
+                        error: ErrorBody::new(kind, e.to_string()),
+                    })
+                })
+            }
+        };
+
+        if let Some(value) = value {
+            let value = value.expect("caption records always serialize");
+            if manifest.is_some() {
+                manifest_lines.push(value.to_string());
+            }
+            match args.format {
+                OutputFormat::Ndjson => {
+                    let _ = writeln!(out, "{}", value);
+                }
+                OutputFormat::Json => json_results.push(value),
+                OutputFormat::Text | OutputFormat::Csv => {}
+            }
+        }
+
+        bar.inc(1);
+    }
+
+    bar.finish_and_clear();
+
+    if args.format == OutputFormat::Json {
+        let array = serde_json::Value::Array(json_results);
+        let _ = writeln!(
+            out,
+            "{}",
+            serde_json::to_string_pretty(&array).expect("JSON values always serialize")
+        );
+    }
+    if args.format == OutputFormat::Csv {
+        match export::to_csv(&csv_items) {
+            Ok(csv) => {
+                let _ = out.write_all(&csv);
+            }
+            Err(e) => eprintln!("CSV export error: {}", e),
+        }
+    }
+    let _ = out.flush();
+
+    if let Some((path, format, interval)) = track {
+        let captions: Vec<Option<&str>> = track_captions.iter().map(Option::as_deref).collect();
+        let cues = subtitles::render(&subtitles::frame_segments(&captions, interval), format);
+        if args.dry_run {
+            eprintln!("🎞️  Dry run: not writing caption track {}", path.display());
+        } else {
+            match std::fs::write(path, cues) {
+                Ok(()) => eprintln!("🎞️  Caption track written to {}", path.display()),
+                Err(e) => eprintln!("Can't write caption track {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    if let (Some(url), Some(client)) = (&manifest, &s3_client) {
+        if args.dry_run {
+            eprintln!("🔍 Dry run: not uploading manifest {}", url.display());
+        } else {
+            let mut body = manifest_lines.join("\n");
+            body.push('\n');
+            match client.put(url, body.into_bytes(), "application/x-ndjson").await {
+                Ok(()) => eprintln!("☁️  Manifest uploaded to {}", url.display()),
+                Err(e) => eprintln!("Can't upload manifest: {}", e),
+            }
+        }
+    }
+
+    let elapsed = start.elapsed();
+    let mut report = RunReport {
+        source: source.display().to_string(),
+        mode: options.mode,
+        started_at: started_at.to_rfc3339(),
+        finished_at: chrono::Utc::now().to_rfc3339(),
+        elapsed_ms: elapsed.as_millis(),
+        dry_run: args.dry_run,
+        succeeded,
+        failed: failures.len(),
+        skipped: collected.skipped.len(),
+        prompt_tokens: usage.prompt_tokens,
+        output_tokens: usage.output_tokens,
+        total_cost_usd: usage.cost_usd(),
+        results_path: args.output.as_ref().map(|path| path.display().to_string()),
+        failures_path: None,
+        writes,
+        failures,
+        skipped_files: collected
+            .skipped
+            .iter()
+            .map(|(path, reason)| SkippedItem {
+                path: path.display().to_string(),
+                reason: *reason,
+            })
+            .collect(),
+    };
+
+    eprintln!(
+        "✅ {} succeeded  ❌ {} failed  ⏭️  {} skipped",
+        report.succeeded, report.failed, report.skipped
+    );
+    if args.dry_run {
+        let count = |action: fn(&WriteAction) -> bool| {
+            report.writes.iter().filter(|w| action(&w.action)).count()
+        };
+        eprintln!(
+            "🔍 Dry run: {} images would be captioned, {} files created, {} overwritten",
+            planned + succeeded,
+            count(|a| matches!(a, WriteAction::Create)),
+            count(|a| matches!(a, WriteAction::Overwrite)),
+        );
+    }
+    eprintln!(
+        "💰 ${:.4} estimated cost ({} tokens)  ⏱️  {}",
+        report.total_cost_usd,
+        usage.prompt_tokens + usage.output_tokens,
+        HumanDuration(elapsed)
+    );
+
+    // An existing file is rewritten even when nothing failed, so a clean retry clears it.
+    let failures_file = failures_path(&args, !report.failures.is_empty())
+        .filter(|path| !report.failures.is_empty() || path.exists());
+    if let Some(path) = failures_file {
+        match write_failures(&path, &report.failures) {
+            Ok(()) if !report.failures.is_empty() => {
+                eprintln!(
+                    "🔁 Failures written to {} (rerun with --retry {0})",
+                    path.display()
+                );
+                report.failures_path = Some(path.display().to_string());
+            }
+            Ok(()) => {}
+            Err(e) => eprintln!("Can't write failures {}: {}", path.display(), e),
+        }
+    }
+
+    if let Some(path) = report_path(&args) {
+        let json = serde_json::to_string_pretty(&report).expect("run reports always serialize");
+        match std::fs::write(&path, json) {
+            Ok(()) => eprintln!("📝 Run report written to {}", path.display()),
+            Err(e) => eprintln!("Can't write run report {}: {}", path.display(), e),
+        }
+    }
+
+    worst.map_or(0, ErrorKind::exit_code)
+}
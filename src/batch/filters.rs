@@ -0,0 +1,134 @@
+// Include/exclude globs plus size and modification-date filters for batch sources.
+
+use chrono::{Local, NaiveDate, TimeZone};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::path::Path;
+use std::time::SystemTime;
+
+use super::BatchArgs;
+
+/// A byte count given on the command line, e.g. `500K`, `12MB` or `1G` (binary units).
+#[derive(Clone, Copy, Debug)]
+pub struct ByteSize(pub u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("invalid size '{}'", s))?;
+        let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 1u64,
+            "K" | "KB" | "KIB" => 1 << 10,
+            "M" | "MB" | "MIB" => 1 << 20,
+            "G" | "GB" | "GIB" => 1 << 30,
+            _ => return Err(format!("unknown size unit '{}' (use B, K, M or G)", unit)),
+        };
+
+        Ok(ByteSize((number * multiplier as f64) as u64))
+    }
+}
+
+pub struct Filters {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+fn build_globs(patterns: &[String]) -> Result<Option<GlobSet>, String> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| format!("invalid glob '{}': {}", pattern, e))?;
+        builder.add(glob);
+    }
+    builder.build().map(Some).map_err(|e| e.to_string())
+}
+
+/// Local midnight at the start of `date`.
+fn start_of_day(date: NaiveDate) -> SystemTime {
+    let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map_or(SystemTime::UNIX_EPOCH, SystemTime::from)
+}
+
+impl Filters {
+    pub fn from_args(args: &BatchArgs) -> Result<Self, String> {
+        Ok(Filters {
+            include: build_globs(&args.include)?,
+            exclude: build_globs(&args.exclude)?,
+            min_size: args.min_size.map(|size| size.0),
+            max_size: args.max_size.map(|size| size.0),
+            since: args.since.map(start_of_day),
+            // --until is inclusive, so compare against the start of the following day.
+            until: args.until.and_then(|date| date.succ_opt()).map(start_of_day),
+        })
+    }
+
+    fn needs_metadata(&self) -> bool {
+        self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.since.is_some()
+            || self.until.is_some()
+    }
+
+    /// Whether a file passes every filter; `relative` is its path relative to the batch
+    /// source, which is what globs are matched against.
+    pub fn matches(&self, path: &Path, relative: &Path) -> bool {
+        if !self.matches_name(relative) {
+            return false;
+        }
+
+        if !self.needs_metadata() {
+            return true;
+        }
+
+        // Unreadable files are let through so the caption step reports the real error.
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return true;
+        };
+
+        self.matches_metadata(metadata.len(), metadata.modified().ok())
+    }
+
+    /// Like `matches`, for a listed object whose size and modification time are already
+    /// known (an S3 listing).
+    pub fn matches_object(&self, relative: &Path, size: u64, modified: Option<SystemTime>) -> bool {
+        self.matches_name(relative) && self.matches_metadata(size, modified)
+    }
+
+    fn matches_name(&self, relative: &Path) -> bool {
+        !(self.include.as_ref().is_some_and(|include| !include.is_match(relative))
+            || self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(relative)))
+    }
+
+    fn matches_metadata(&self, size: u64, modified: Option<SystemTime>) -> bool {
+        if self.min_size.is_some_and(|min| size < min) || self.max_size.is_some_and(|max| size > max)
+        {
+            return false;
+        }
+
+        match modified {
+            Some(modified) => {
+                !(self.since.is_some_and(|since| modified < since)
+                    || self.until.is_some_and(|until| modified >= until))
+            }
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,378 @@
+// `batch gdrive://FOLDER_ID`: caption the images in a Google Drive folder (and its
+// subfolders) through the Drive v3 REST API.
+//
+// Credentials are OAuth 2.0: either GOOGLE_DRIVE_ACCESS_TOKEN, or GOOGLE_DRIVE_CLIENT_ID,
+// GOOGLE_DRIVE_CLIENT_SECRET and GOOGLE_DRIVE_REFRESH_TOKEN, in which case access tokens are
+// refreshed as they expire. Files are addressed as `gdrive://FILE_ID/path/in/folder.jpg`; the
+// path is only there for globs, extensions and readable output.
+
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+
+use super::{Listed, Outcome, PlannedWrite, RunSettings, WriteAction, WriteBack};
+use crate::imageproc::content_hash;
+use crate::{caption_image_bytes, BoxError};
+
+const API: &str = "https://www.googleapis.com/drive/v3/files";
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+
+/// App properties stored on each captioned file, next to the description.
+const PROP_MODEL: &str = "caption-model";
+const PROP_MODE: &str = "caption-mode";
+const PROP_CONTENT_HASH: &str = "caption-content-hash";
+const PROP_PROMPT_HASH: &str = "caption-prompt-hash";
+
+/// Refresh access tokens this long before Google says they expire.
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with("gdrive://"))
+}
+
+/// The folder or file ID at the start of a `gdrive://` URL.
+fn id(path: &Path) -> Option<&str> {
+    let rest = path.to_str()?.strip_prefix("gdrive://")?;
+    let id = rest.split('/').next().unwrap_or(rest);
+    (!id.is_empty()).then_some(id)
+}
+
+/// Drive file metadata, as requested through `fields`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct File {
+    id: String,
+    name: String,
+    mime_type: String,
+    /// Int64 values come back as strings.
+    size: Option<String>,
+    modified_time: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    app_properties: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileList {
+    files: Vec<File>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+struct RefreshToken {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+}
+
+struct AccessToken {
+    token: String,
+    /// None for a token given directly, which can't be refreshed anyway.
+    expires_at: Option<Instant>,
+}
+
+/// Drive failures are reported like unreadable local files.
+fn drive_error(context: &str, message: impl std::fmt::Display) -> BoxError {
+    std::io::Error::other(format!("Google Drive: {}: {}", context, message)).into()
+}
+
+fn invalid_url(url: &Path) -> BoxError {
+    drive_error(&url.display().to_string(), "not a gdrive://ID URL")
+}
+
+/// Turns an unsuccessful API response into an error carrying Google's message.
+async fn check(response: reqwest::Response, context: &str) -> Result<reqwest::Response, BoxError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or(body);
+    Err(drive_error(context, format!("{} {}", status, message)))
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    refresh: Option<RefreshToken>,
+    access: Mutex<AccessToken>,
+}
+
+impl Client {
+    /// Reads the OAuth credentials from the environment and checks them by fetching an
+    /// access token, so bad credentials fail the run up front.
+    pub async fn connect() -> Result<Self, BoxError> {
+        let var = |name| {
+            crate::settings::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        let refresh = match (
+            var("GOOGLE_DRIVE_CLIENT_ID"),
+            var("GOOGLE_DRIVE_CLIENT_SECRET"),
+            var("GOOGLE_DRIVE_REFRESH_TOKEN"),
+        ) {
+            (Some(client_id), Some(client_secret), Some(refresh_token)) => Some(RefreshToken {
+                client_id,
+                client_secret,
+                refresh_token,
+            }),
+            _ => None,
+        };
+        let access = match (&refresh, var("GOOGLE_DRIVE_ACCESS_TOKEN")) {
+            (Some(_), _) => AccessToken {
+                token: String::new(),
+                expires_at: Some(Instant::now()),
+            },
+            (None, Some(token)) => AccessToken {
+                token,
+                expires_at: None,
+            },
+            (None, None) => {
+                return Err("Google Drive needs GOOGLE_DRIVE_ACCESS_TOKEN, or \
+                    GOOGLE_DRIVE_CLIENT_ID, GOOGLE_DRIVE_CLIENT_SECRET and \
+                    GOOGLE_DRIVE_REFRESH_TOKEN"
+                    .into())
+            }
+        };
+
+        let client = Client {
+            http: reqwest::Client::new(),
+            refresh,
+            access: Mutex::new(access),
+        };
+        client.token().await?;
+        Ok(client)
+    }
+
+    /// A current access token, refreshing it first if it is about to expire.
+    async fn token(&self) -> Result<String, BoxError> {
+        let mut access = self.access.lock().await;
+        let expired = access
+            .expires_at
+            .is_some_and(|expires_at| Instant::now() + TOKEN_MARGIN >= expires_at);
+        if let (true, Some(refresh)) = (expired, &self.refresh) {
+            let response = self
+                .http
+                .post(TOKEN_URL)
+                .form(&[
+                    ("client_id", refresh.client_id.as_str()),
+                    ("client_secret", refresh.client_secret.as_str()),
+                    ("refresh_token", refresh.refresh_token.as_str()),
+                    ("grant_type", "refresh_token"),
+                ])
+                .send()
+                .await?;
+            let token: TokenResponse = check(response, "refreshing the access token")
+                .await?
+                .json()
+                .await?;
+            *access = AccessToken {
+                token: token.access_token,
+                expires_at: Some(Instant::now() + Duration::from_secs(token.expires_in)),
+            };
+        }
+        Ok(access.token.clone())
+    }
+
+    /// Every file in the folder and its subfolders, depth first in name order.
+    pub async fn list(&self, source: &Path) -> Result<Vec<Listed>, BoxError> {
+        let folder = id(source).ok_or_else(|| invalid_url(source))?;
+        let mut listed = Vec::new();
+        let mut folders = vec![(folder.to_string(), PathBuf::new())];
+
+        while let Some((folder, relative)) = folders.pop() {
+            let mut subfolders = Vec::new();
+            let mut page_token = None;
+            loop {
+                let query = format!("'{}' in parents and trashed = false", folder);
+                let mut request = self.http.get(API).bearer_auth(self.token().await?).query(&[
+                    ("q", query.as_str()),
+                    ("orderBy", "name"),
+                    ("pageSize", "1000"),
+                    (
+                        "fields",
+                        "nextPageToken,files(id,name,mimeType,size,modifiedTime)",
+                    ),
+                    ("supportsAllDrives", "true"),
+                    ("includeItemsFromAllDrives", "true"),
+                ]);
+                if let Some(page_token) = &page_token {
+                    request = request.query(&[("pageToken", page_token)]);
+                }
+                let page: FileList = check(request.send().await?, "listing a folder")
+                    .await?
+                    .json()
+                    .await?;
+
+                for file in page.files {
+                    let path = relative.join(&file.name);
+                    if file.mime_type == FOLDER_MIME_TYPE {
+                        subfolders.push((file.id, path));
+                        continue;
+                    }
+                    listed.push(Listed {
+                        url: PathBuf::from(format!("gdrive://{}/{}", file.id, path.display())),
+                        relative: path,
+                        size: file.size.and_then(|size| size.parse().ok()).unwrap_or(0),
+                        modified: file.modified_time.map(SystemTime::from),
+                    });
+                }
+
+                page_token = page.next_page_token;
+                if page_token.is_none() {
+                    break;
+                }
+            }
+            // Popped from the end, so push in reverse to visit them in name order.
+            folders.extend(subfolders.into_iter().rev());
+        }
+        Ok(listed)
+    }
+
+    async fn metadata(&self, file_id: &str) -> Result<File, BoxError> {
+        let response = self
+            .http
+            .get(format!("{}/{}", API, file_id))
+            .bearer_auth(self.token().await?)
+            .query(&[
+                (
+                    "fields",
+                    "id,name,mimeType,size,modifiedTime,description,appProperties",
+                ),
+                ("supportsAllDrives", "true"),
+            ])
+            .send()
+            .await?;
+        Ok(check(response, file_id).await?.json().await?)
+    }
+
+    async fn download(&self, file_id: &str) -> Result<Vec<u8>, BoxError> {
+        let response = self
+            .http
+            .get(format!("{}/{}", API, file_id))
+            .bearer_auth(self.token().await?)
+            .query(&[("alt", "media"), ("supportsAllDrives", "true")])
+            .send()
+            .await?;
+        Ok(check(response, file_id).await?.bytes().await?.to_vec())
+    }
+
+    /// Sets the description and merges our app properties into the file's existing ones.
+    async fn write_description(
+        &self,
+        file_id: &str,
+        description: &str,
+        properties: HashMap<&str, String>,
+    ) -> Result<(), BoxError> {
+        let response = self
+            .http
+            .patch(format!("{}/{}", API, file_id))
+            .bearer_auth(self.token().await?)
+            .query(&[("supportsAllDrives", "true"), ("fields", "id")])
+            .json(&json!({ "description": description, "appProperties": properties }))
+            .send()
+            .await?;
+        check(response, file_id).await?;
+        Ok(())
+    }
+}
+
+/// `process_image` for a `gdrive://` URL. With `--write-back metadata` the caption goes into
+/// the file's description, and files whose app properties show a caption for the same bytes
+/// and prompt are skipped, like current sidecars.
+pub(super) async fn process_file(
+    client: &Client,
+    url: &Path,
+    settings: &RunSettings<'_>,
+) -> Outcome {
+    let Some(file_id) = id(url) else {
+        return Outcome::Failed(invalid_url(url));
+    };
+    let (file, data) = match tokio::try_join!(client.metadata(file_id), client.download(file_id)) {
+        Ok(result) => result,
+        Err(e) => return Outcome::Failed(e),
+    };
+
+    let content_hash = content_hash(&data);
+    let write_description = settings.write_back == Some(WriteBack::Metadata);
+    if write_description
+        && !settings.force
+        && file.app_properties.get(PROP_CONTENT_HASH) == Some(&content_hash)
+        && file.app_properties.get(PROP_PROMPT_HASH) == Some(&settings.prompt_hash)
+    {
+        return Outcome::AlreadyCaptioned;
+    }
+
+    let old_description = file
+        .description
+        .filter(|description| !description.is_empty());
+    let mut write = write_description.then(|| PlannedWrite {
+        path: url.display().to_string(),
+        action: match old_description {
+            Some(_) => WriteAction::Overwrite,
+            None => WriteAction::Create,
+        },
+        applied: false,
+        diff: Vec::new(),
+    });
+
+    if settings.dry_run && !settings.diff {
+        return Outcome::Planned(write.into_iter().collect());
+    }
+
+    let (response, usage) =
+        match caption_image_bytes(&data, settings.options, settings.api_key).await {
+            Ok(result) => result,
+            Err(e) => return Outcome::Failed(e.into()),
+        };
+
+    if let Some(write) = write.as_mut() {
+        if settings.diff && old_description.as_ref() != Some(&response.caption) {
+            if let Some(old_description) = &old_description {
+                write
+                    .diff
+                    .push(format!("- description: {}", old_description));
+            }
+            write
+                .diff
+                .push(format!("+ description: {}", response.caption));
+        }
+
+        if !settings.dry_run {
+            let properties = HashMap::from([
+                (PROP_MODEL, response.model.clone()),
+                (PROP_MODE, settings.options.mode.name().to_string()),
+                (PROP_CONTENT_HASH, content_hash),
+                (PROP_PROMPT_HASH, settings.prompt_hash.clone()),
+            ]);
+            if let Err(e) = client
+                .write_description(&file.id, &response.caption, properties)
+                .await
+            {
+                return Outcome::Failed(e);
+            }
+            write.applied = true;
+        }
+    }
+
+    Outcome::Captioned {
+        response: Box::new(response),
+        usage,
+        writes: write.into_iter().collect(),
+    }
+}
@@ -0,0 +1,371 @@
+// `batch s3://bucket/prefix`: caption images stored in S3 (or an S3-compatible service),
+// behind the `s3` feature.
+//
+// Credentials and region come from the standard AWS chain: AWS_ACCESS_KEY_ID /
+// AWS_SECRET_ACCESS_KEY, a profile in ~/.aws (AWS_PROFILE), or the instance/task role. Set
+// AWS_ENDPOINT_URL for S3-compatible services such as MinIO; path-style addressing is used
+// then. Objects are addressed as `s3://bucket/key` throughout the run, so results, failures
+// files and --retry work the same as for local paths.
+
+use std::path::{Path, PathBuf};
+
+use super::{Outcome, PlannedWrite, RunSettings, WriteAction, WriteBack};
+use crate::caption_image_bytes;
+use crate::imageproc::content_hash;
+
+/// Results manifest written next to the source prefix by `--write-back manifest`.
+pub const MANIFEST_NAME: &str = "captions.jsonl";
+
+/// User metadata keys written by `--write-back metadata` (S3 prefixes them `x-amz-meta-`).
+const META_CAPTION: &str = "caption";
+const META_MODEL: &str = "caption-model";
+const META_MODE: &str = "caption-mode";
+const META_CONTENT_HASH: &str = "caption-content-hash";
+const META_PROMPT_HASH: &str = "caption-prompt-hash";
+
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| path.starts_with("s3://"))
+}
+
+/// Splits `s3://bucket/key` into bucket and key (the key may be empty or a prefix).
+pub fn split_url(path: &Path) -> Option<(&str, &str)> {
+    let rest = path.to_str()?.strip_prefix("s3://")?;
+    let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+    (!bucket.is_empty()).then_some((bucket, key))
+}
+
+pub fn url(bucket: &str, key: &str) -> PathBuf {
+    PathBuf::from(format!("s3://{}/{}", bucket, key))
+}
+
+/// The manifest location for a source prefix: `s3://bucket/photos/` and `s3://bucket/photos`
+/// both give `s3://bucket/photos/captions.jsonl`.
+pub fn manifest_url(source: &Path) -> Option<PathBuf> {
+    let (bucket, prefix) = split_url(source)?;
+    let prefix = prefix.trim_end_matches('/');
+    Some(if prefix.is_empty() {
+        url(bucket, MANIFEST_NAME)
+    } else {
+        url(bucket, &format!("{}/{}", prefix, MANIFEST_NAME))
+    })
+}
+
+/// Percent-encodes everything except unreserved characters and the bytes in `keep`.
+fn percent_encode(value: &str, keep: &[u8]) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) || keep.contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// S3 user metadata must be printable ASCII, so captions are stored percent-encoded
+/// (ASCII text other than `%` is left readable).
+fn metadata_value(value: &str) -> String {
+    let readable: Vec<u8> = (b' '..=b'~').filter(|&byte| byte != b'%').collect();
+    percent_encode(value, &readable)
+}
+
+/// `process_image` for an `s3://` URL. With `--write-back metadata`, objects whose metadata
+/// already holds a caption for the same bytes and prompt are skipped, like current sidecars.
+pub(super) async fn process_object(
+    client: &Client,
+    url: &Path,
+    settings: &RunSettings<'_>,
+) -> Outcome {
+    let object = match client.get(url).await {
+        Ok(object) => object,
+        Err(e) => return Outcome::Failed(e),
+    };
+
+    let content_hash = content_hash(&object.data);
+    let write_metadata = settings.write_back == Some(WriteBack::Metadata);
+    if write_metadata
+        && !settings.force
+        && object.metadata.get(META_CONTENT_HASH) == Some(&content_hash)
+        && object.metadata.get(META_PROMPT_HASH) == Some(&settings.prompt_hash)
+    {
+        return Outcome::AlreadyCaptioned;
+    }
+
+    let old_caption = object.metadata.get(META_CAPTION);
+    let mut write = write_metadata.then(|| PlannedWrite {
+        path: url.display().to_string(),
+        action: match old_caption {
+            Some(_) => WriteAction::Overwrite,
+            None => WriteAction::Create,
+        },
+        applied: false,
+        diff: Vec::new(),
+    });
+
+    if settings.dry_run && !settings.diff {
+        return Outcome::Planned(write.into_iter().collect());
+    }
+
+    let (response, usage) =
+        match caption_image_bytes(&object.data, settings.options, settings.api_key).await {
+            Ok(result) => result,
+            Err(e) => return Outcome::Failed(e.into()),
+        };
+
+    if let Some(write) = write.as_mut() {
+        let caption = metadata_value(&response.caption);
+        if settings.diff && old_caption != Some(&caption) {
+            if let Some(old_caption) = old_caption {
+                write
+                    .diff
+                    .push(format!("- {}: {}", META_CAPTION, old_caption));
+            }
+            write.diff.push(format!("+ {}: {}", META_CAPTION, caption));
+        }
+
+        if !settings.dry_run {
+            // Other tools' metadata is kept; ours is replaced.
+            let mut metadata = object.metadata.clone();
+            metadata.insert(META_CAPTION.into(), caption);
+            metadata.insert(META_MODEL.into(), metadata_value(&response.model));
+            metadata.insert(META_MODE.into(), settings.options.mode.name().into());
+            metadata.insert(META_CONTENT_HASH.into(), content_hash);
+            metadata.insert(META_PROMPT_HASH.into(), settings.prompt_hash.clone());
+            if let Err(e) = client.write_metadata(url, &object, metadata).await {
+                return Outcome::Failed(e);
+            }
+            write.applied = true;
+        }
+    }
+
+    Outcome::Captioned {
+        response: Box::new(response),
+        usage,
+        writes: write.into_iter().collect(),
+    }
+}
+
+#[cfg(feature = "s3")]
+mod client {
+    use aws_sdk_s3::error::DisplayErrorContext;
+    use aws_sdk_s3::primitives::ByteStream;
+    use aws_sdk_s3::types::MetadataDirective;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    use super::{percent_encode, split_url, url};
+    use crate::batch::Listed;
+    use crate::BoxError;
+
+    /// A downloaded object with what is needed to write its metadata back unchanged.
+    pub struct Object {
+        pub data: Vec<u8>,
+        e_tag: Option<String>,
+        content_type: Option<String>,
+        cache_control: Option<String>,
+        content_disposition: Option<String>,
+        content_encoding: Option<String>,
+        content_language: Option<String>,
+        storage_class: Option<aws_sdk_s3::types::StorageClass>,
+        pub metadata: HashMap<String, String>,
+    }
+
+    /// S3 failures are reported like unreadable local files.
+    fn s3_error(url: &Path, e: impl std::error::Error) -> BoxError {
+        std::io::Error::other(format!("{}: {}", url.display(), DisplayErrorContext(e))).into()
+    }
+
+    fn invalid_url(url: &Path) -> BoxError {
+        std::io::Error::other(format!("{} is not an s3://bucket/key URL", url.display())).into()
+    }
+
+    pub struct Client {
+        inner: aws_sdk_s3::Client,
+    }
+
+    impl Client {
+        pub async fn connect() -> Result<Self, BoxError> {
+            let config = aws_config::load_from_env().await;
+            let custom_endpoint = std::env::var_os("AWS_ENDPOINT_URL").is_some()
+                || std::env::var_os("AWS_ENDPOINT_URL_S3").is_some();
+            let s3_config = aws_sdk_s3::config::Builder::from(&config)
+                .force_path_style(custom_endpoint)
+                .build();
+            Ok(Client {
+                inner: aws_sdk_s3::Client::from_conf(s3_config),
+            })
+        }
+
+        /// Every object under the source prefix, in key order.
+        pub async fn list(&self, source: &Path) -> Result<Vec<Listed>, BoxError> {
+            let (bucket, prefix) = split_url(source).ok_or_else(|| invalid_url(source))?;
+            let mut pages = self
+                .inner
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix)
+                .into_paginator()
+                .send();
+
+            let mut listed = Vec::new();
+            while let Some(page) = pages.next().await {
+                let page = page.map_err(|e| s3_error(source, e))?;
+                for object in page.contents() {
+                    let Some(key) = object.key() else { continue };
+                    // Zero-byte "folder" markers created by consoles.
+                    if key.ends_with('/') {
+                        continue;
+                    }
+                    let relative = key
+                        .strip_prefix(prefix)
+                        .unwrap_or(key)
+                        .trim_start_matches('/');
+                    listed.push(Listed {
+                        url: url(bucket, key),
+                        relative: PathBuf::from(relative),
+                        size: object.size().unwrap_or(0).max(0) as u64,
+                        modified: object
+                            .last_modified()
+                            .and_then(|time| SystemTime::try_from(*time).ok()),
+                    });
+                }
+            }
+            Ok(listed)
+        }
+
+        pub async fn get(&self, url: &Path) -> Result<Object, BoxError> {
+            let (bucket, key) = split_url(url).ok_or_else(|| invalid_url(url))?;
+            let output = self
+                .inner
+                .get_object()
+                .bucket(bucket)
+                .key(key)
+                .send()
+                .await
+                .map_err(|e| s3_error(url, e))?;
+            let metadata = output.metadata().cloned().unwrap_or_default();
+            let e_tag = output.e_tag().map(str::to_string);
+            let content_type = output.content_type().map(str::to_string);
+            let cache_control = output.cache_control().map(str::to_string);
+            let content_disposition = output.content_disposition().map(str::to_string);
+            let content_encoding = output.content_encoding().map(str::to_string);
+            let content_language = output.content_language().map(str::to_string);
+            let storage_class = output.storage_class().cloned();
+            let data = output
+                .body
+                .collect()
+                .await
+                .map_err(|e| s3_error(url, e))?
+                .to_vec();
+
+            Ok(Object {
+                data,
+                e_tag,
+                content_type,
+                cache_control,
+                content_disposition,
+                content_encoding,
+                content_language,
+                storage_class,
+                metadata,
+            })
+        }
+
+        /// Replaces the object's user metadata by copying it onto itself, keeping its other
+        /// headers. Fails rather than clobbering if the object changed since it was read.
+        pub async fn write_metadata(
+            &self,
+            url: &Path,
+            object: &Object,
+            metadata: HashMap<String, String>,
+        ) -> Result<(), BoxError> {
+            let (bucket, key) = split_url(url).ok_or_else(|| invalid_url(url))?;
+            self.inner
+                .copy_object()
+                .bucket(bucket)
+                .key(key)
+                .copy_source(format!("{}/{}", bucket, percent_encode(key, b"/")))
+                .set_copy_source_if_match(object.e_tag.clone())
+                .metadata_directive(MetadataDirective::Replace)
+                .set_metadata(Some(metadata))
+                .set_content_type(object.content_type.clone())
+                .set_cache_control(object.cache_control.clone())
+                .set_content_disposition(object.content_disposition.clone())
+                .set_content_encoding(object.content_encoding.clone())
+                .set_content_language(object.content_language.clone())
+                .set_storage_class(object.storage_class.clone())
+                .send()
+                .await
+                .map_err(|e| s3_error(url, e))?;
+            Ok(())
+        }
+
+        pub async fn put(
+            &self,
+            url: &Path,
+            body: Vec<u8>,
+            content_type: &str,
+        ) -> Result<(), BoxError> {
+            let (bucket, key) = split_url(url).ok_or_else(|| invalid_url(url))?;
+            self.inner
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(ByteStream::from(body))
+                .content_type(content_type)
+                .send()
+                .await
+                .map_err(|e| s3_error(url, e))?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+pub use client::Client;
+
+/// Stand-in for builds without the `s3` feature: it can't be constructed, so only
+/// `connect` (which explains how to get S3 support) is ever called.
+#[cfg(not(feature = "s3"))]
+pub enum Client {}
+
+#[cfg(not(feature = "s3"))]
+pub struct Object {
+    pub data: Vec<u8>,
+    pub metadata: std::collections::HashMap<String, String>,
+}
+
+#[cfg(not(feature = "s3"))]
+impl Client {
+    pub async fn connect() -> Result<Self, crate::BoxError> {
+        Err("this build has no S3 support; rebuild with `--features s3`".into())
+    }
+
+    pub async fn list(&self, _source: &Path) -> Result<Vec<super::Listed>, crate::BoxError> {
+        match *self {}
+    }
+
+    pub async fn get(&self, _url: &Path) -> Result<Object, crate::BoxError> {
+        match *self {}
+    }
+
+    pub async fn write_metadata(
+        &self,
+        _url: &Path,
+        _object: &Object,
+        _metadata: std::collections::HashMap<String, String>,
+    ) -> Result<(), crate::BoxError> {
+        match *self {}
+    }
+
+    pub async fn put(
+        &self,
+        _url: &Path,
+        _body: Vec<u8>,
+        _content_type: &str,
+    ) -> Result<(), crate::BoxError> {
+        match *self {}
+    }
+}
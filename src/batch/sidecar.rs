@@ -0,0 +1,102 @@
+// JSON sidecar files written next to each image (`photo.jpg` -> `photo.jpg.json`).
+//
+// Sidecars record the content hash and prompt fingerprint they were generated from, so
+// repeated batch runs can skip images whose caption is still current.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::CaptionResponse;
+
+#[derive(Serialize, Deserialize)]
+pub struct Sidecar {
+    pub content_hash: String,
+    pub prompt_hash: String,
+    #[serde(flatten)]
+    pub response: CaptionResponse,
+}
+
+pub fn sidecar_path(image: &Path) -> PathBuf {
+    let mut name = image.file_name().unwrap_or_default().to_os_string();
+    name.push(".json");
+    image.with_file_name(name)
+}
+
+/// Whether `path` is a sidecar for an image: our JSON (`photo.jpg.json`) or any XMP file.
+pub fn is_sidecar(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) if ext == "json" => super::is_image(&path.with_extension("")),
+        Some(ext) => ext.eq_ignore_ascii_case("xmp"),
+        None => false,
+    }
+}
+
+/// Whether `image` already has a sidecar generated from the same bytes and prompt.
+pub fn is_current(image: &Path, content_hash: &str, prompt_hash: &str) -> bool {
+    let Ok(json) = std::fs::read(sidecar_path(image)) else {
+        return false;
+    };
+
+    serde_json::from_slice::<Sidecar>(&json).is_ok_and(|sidecar| {
+        sidecar.content_hash == content_hash && sidecar.prompt_hash == prompt_hash
+    })
+}
+
+/// Fields that change on every run and would only add noise to a diff.
+const VOLATILE_FIELDS: &[&str] = &["processing_time_ms", "timings"];
+const VOLATILE_PROVENANCE_FIELDS: &[&str] = &["generated_at"];
+
+/// Drops the run-specific timestamps nested in the provenance record.
+fn stable_provenance(value: &Value) -> Value {
+    let mut value = value.clone();
+    if let Some(provenance) = value.as_object_mut() {
+        for field in VOLATILE_PROVENANCE_FIELDS {
+            provenance.remove(*field);
+        }
+    }
+    value
+}
+
+/// The sidecar currently on disk for `image`, if any, as raw JSON.
+pub fn existing(image: &Path) -> Option<Value> {
+    let json = std::fs::read(sidecar_path(image)).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// Line-oriented diff of top-level fields between the current and the new sidecar.
+pub fn diff(old: Option<&Value>, new: &Sidecar) -> Vec<String> {
+    let new = serde_json::to_value(new).expect("sidecars always serialize");
+    let empty = serde_json::Map::new();
+    let old = old.and_then(Value::as_object).unwrap_or(&empty);
+    let new = new.as_object().expect("sidecars serialize to objects");
+
+    let mut lines = Vec::new();
+    for (key, old_value) in old {
+        if VOLATILE_FIELDS.contains(&key.as_str()) {
+            continue;
+        }
+        match new.get(key) {
+            Some(new_value) if new_value == old_value => {}
+            Some(new_value)
+                if key == "provenance"
+                    && stable_provenance(new_value) == stable_provenance(old_value) => {}
+            Some(new_value) => {
+                lines.push(format!("- {}: {}", key, old_value));
+                lines.push(format!("+ {}: {}", key, new_value));
+            }
+            None => lines.push(format!("- {}: {}", key, old_value)),
+        }
+    }
+    for (key, new_value) in new {
+        if !old.contains_key(key) && !VOLATILE_FIELDS.contains(&key.as_str()) {
+            lines.push(format!("+ {}: {}", key, new_value));
+        }
+    }
+    lines
+}
+
+pub fn write(image: &Path, sidecar: &Sidecar) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(sidecar).expect("sidecars always serialize");
+    std::fs::write(sidecar_path(image), json)
+}
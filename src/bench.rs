@@ -0,0 +1,33 @@
+// The image preprocessing steps, public for the benchmarks in `benches/` (which, like any other
+// crate, only see the public API). Not a stable API: it follows whatever `imageproc` does.
+
+use image::{DynamicImage, ImageError};
+use std::borrow::Cow;
+
+use crate::{imageproc, orientation};
+use crate::{CaptionOptions, Timings};
+
+/// Decodes a still image, as an upload that can't be sent as is.
+pub fn decode(data: &[u8]) -> Result<DynamicImage, ImageError> {
+    imageproc::decode_still(data, None)
+}
+
+/// Downscales an image too big for the provider; returns it unchanged otherwise.
+pub fn downscale(image: &DynamicImage) -> Cow<'_, DynamicImage> {
+    imageproc::downscale(image)
+}
+
+/// Encodes an image as the JPEG sent to the provider.
+pub fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, ImageError> {
+    imageproc::encode_jpeg(image)
+}
+
+/// Gets an upload ready for the provider, as the server does; returns the time each step took
+/// and the bytes of image it sends (before base64).
+pub fn prepare(data: &[u8], options: &CaptionOptions) -> Result<(Timings, usize), ImageError> {
+    let mut timings = Timings::default();
+    let turn = orientation::read(data);
+    let prepared = imageproc::prepare_image(data, turn, options, &mut timings)?;
+    let size = prepared.frames.iter().map(|frame| frame.data.len()).sum();
+    Ok((timings, size))
+}
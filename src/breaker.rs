@@ -0,0 +1,166 @@
+// Circuit breaker around the captioning provider. While Gemini is down, every request would
+// otherwise sit through its own failed attempts; after enough consecutive failures the
+// breaker opens and calls fail at once with 503 and a Retry-After instead. When the
+// cooldown has passed, one trial request goes through (half-open): success closes the
+// breaker, failure opens it for another cooldown.
+//
+// Only failures that mean the provider is unreachable or broken count: network errors,
+// timeouts and 5xx responses. A 4xx or an unusable reply shows the provider is up, so it resets the count
+// like a success does.
+//
+// CIRCUIT_BREAKER_THRESHOLD (default 5; 0 turns the breaker off) and
+// CIRCUIT_BREAKER_COOLDOWN_SECS (default 30) are read per call, so a SIGHUP reload applies
+// them. The breaker is per process.
+
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::LOG_PROVIDER_TRAFFIC;
+use crate::CaptionError;
+
+/// The defaults for CIRCUIT_BREAKER_THRESHOLD and CIRCUIT_BREAKER_COOLDOWN_SECS.
+pub(crate) const THRESHOLD: u64 = 5;
+pub(crate) const COOLDOWN_SECS: u64 = 30;
+
+/// The breaker every Gemini call goes through.
+pub(crate) static PROVIDER: Breaker = Breaker::new();
+
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// One trial request is in flight. If it never reports back (its caller went away),
+    /// another is let through after a cooldown.
+    HalfOpen {
+        trial_started: Instant,
+    },
+}
+
+pub(crate) struct Breaker {
+    state: Mutex<State>,
+}
+
+struct Settings {
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl Settings {
+    fn from_env() -> Self {
+        let read = |name: &str, default: u64| {
+            crate::settings::var(name)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(default)
+        };
+        Settings {
+            threshold: read("CIRCUIT_BREAKER_THRESHOLD", THRESHOLD) as u32,
+            cooldown: Duration::from_secs(
+                read("CIRCUIT_BREAKER_COOLDOWN_SECS", COOLDOWN_SECS).max(1),
+            ),
+        }
+    }
+}
+
+/// Whether `error` says the provider itself is down.
+fn provider_down(error: &CaptionError) -> bool {
+    match error {
+        CaptionError::Provider { status, .. } => status.is_none_or(|status| status >= 500),
+        CaptionError::Timeout(_) => true,
+        _ => false,
+    }
+}
+
+fn log(message: &str) {
+    if LOG_PROVIDER_TRAFFIC.load(Ordering::Relaxed) {
+        tracing::warn!("{}", message);
+    }
+}
+
+impl Breaker {
+    const fn new() -> Self {
+        Breaker {
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Lets a call through, or fails it straight away while the breaker is open.
+    pub(crate) fn admit(&self) -> Result<(), CaptionError> {
+        let settings = Settings::from_env();
+        if settings.threshold == 0 {
+            return Ok(());
+        }
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now < until => Err(CaptionError::ProviderUnavailable {
+                retry_after: until - now,
+            }),
+            State::HalfOpen { trial_started }
+                if now.duration_since(trial_started) < settings.cooldown =>
+            {
+                Err(CaptionError::ProviderUnavailable {
+                    retry_after: settings.cooldown - now.duration_since(trial_started),
+                })
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                *state = State::HalfOpen { trial_started: now };
+                Ok(())
+            }
+        }
+    }
+
+    /// Records how an admitted call went: `None` for success.
+    pub(crate) fn record(&self, error: Option<&CaptionError>) {
+        let settings = Settings::from_env();
+        if settings.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        let failed = error.is_some_and(provider_down);
+        match (&*state, failed) {
+            (State::Closed { failures }, true) if failures + 1 < settings.threshold => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            (State::Closed { .. }, true) => {
+                log(&format!(
+                    "🔌 Provider failed {} times in a row; failing fast for {} s",
+                    settings.threshold,
+                    settings.cooldown.as_secs()
+                ));
+                *state = State::Open {
+                    until: Instant::now() + settings.cooldown,
+                };
+            }
+            (State::HalfOpen { .. }, true) => {
+                log("🔌 Provider trial request failed; staying open");
+                *state = State::Open {
+                    until: Instant::now() + settings.cooldown,
+                };
+            }
+            // A call admitted before the breaker opened; the cooldown stands.
+            (State::Open { .. }, _) => {}
+            (State::HalfOpen { .. }, false) => {
+                log("🔌 Provider is answering again; closing the breaker");
+                *state = State::Closed { failures: 0 };
+            }
+            (State::Closed { .. }, false) => *state = State::Closed { failures: 0 },
+        }
+    }
+
+    /// `closed`, `open` or `half_open`, for `/status`.
+    pub(crate) fn state_name(&self) -> &'static str {
+        match *self.state.lock().expect("breaker lock poisoned") {
+            State::Closed { .. } => "closed",
+            State::Open { until } if Instant::now() < until => "open",
+            State::Open { .. } | State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
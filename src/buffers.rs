@@ -0,0 +1,192 @@
+// A pool of the big buffers images are decoded into. A 24 MP photo takes 72 MB decoded, and
+// the downscaled copy sent to the provider several more, so a busy server would otherwise
+// allocate and free tens of megabytes per request, which fragments the heap and keeps memory
+// use high. Decoded and downscaled images give their buffers back here when a request is done
+// with them, and the next decode reuses one of about the right size.
+//
+// BUFFER_POOL_MAX_MB (default 256; 0 turns the pool off) caps the memory kept between requests,
+// and is read per call so a SIGHUP reload applies it. Buffers beyond it, or more than
+// `MAX_BUFFERS` of them, are freed. `/status` reports how well the pool is doing.
+
+use image::error::{ParameterError, ParameterErrorKind};
+use image::{ColorType, DynamicImage, ImageBuffer, ImageDecoder, ImageError, ImageResult};
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// The pool image decoding and downscaling share.
+pub(crate) static POOL: BufferPool = BufferPool::new();
+
+/// The default for BUFFER_POOL_MAX_MB.
+pub(crate) const MAX_MB: u64 = 256;
+
+/// Buffers kept at most, whatever their size.
+const MAX_BUFFERS: usize = 16;
+
+/// Smallest buffer worth keeping; smaller allocations are cheap anyway.
+const MIN_BYTES: usize = 1024 * 1024;
+
+pub(crate) struct BufferPool {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    buffers: Vec<Vec<u8>>,
+    stats: PoolStats,
+}
+
+/// How the pool is doing, as `/status` reports it.
+#[derive(Clone, Copy, Serialize)]
+pub(crate) struct PoolStats {
+    /// Buffers asked for.
+    pub(crate) takes: u64,
+    /// Of those, the ones served from the pool rather than allocated.
+    pub(crate) reused: u64,
+    /// Buffers given back and kept.
+    pub(crate) returned: u64,
+    /// Buffers given back and freed, because the pool was full.
+    pub(crate) discarded: u64,
+    /// Buffers kept now, and the memory they take.
+    pub(crate) retained: u64,
+    pub(crate) retained_bytes: u64,
+}
+
+impl BufferPool {
+    pub(crate) const fn new() -> Self {
+        BufferPool {
+            inner: Mutex::new(Inner {
+                buffers: Vec::new(),
+                stats: PoolStats {
+                    takes: 0,
+                    reused: 0,
+                    returned: 0,
+                    discarded: 0,
+                    retained: 0,
+                    retained_bytes: 0,
+                },
+            }),
+        }
+    }
+
+    /// A zeroed buffer of `len` bytes: a kept one when one fits, without being more than twice
+    /// as big as needed, otherwise a new one.
+    pub(crate) fn take(&self, len: usize) -> Vec<u8> {
+        let mut buffer = {
+            let mut inner = self.inner.lock().expect("buffer pool lock poisoned");
+            inner.stats.takes += 1;
+            let fits = |buffer: &Vec<u8>| buffer.capacity() >= len && buffer.capacity() / 2 <= len;
+            // The smallest buffer that fits.
+            let best = (0..inner.buffers.len())
+                .filter(|&index| fits(&inner.buffers[index]))
+                .min_by_key(|&index| inner.buffers[index].capacity());
+            match best {
+                Some(index) => {
+                    let buffer = inner.buffers.swap_remove(index);
+                    inner.stats.reused += 1;
+                    inner.stats.retained -= 1;
+                    inner.stats.retained_bytes -= buffer.capacity() as u64;
+                    buffer
+                }
+                None => Vec::new(),
+            }
+        };
+        buffer.clear();
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    /// Keeps `buffer` for a later `take` if it is big enough to be worth it and the pool has
+    /// room; frees it otherwise.
+    pub(crate) fn give(&self, buffer: Vec<u8>) {
+        if buffer.capacity() < MIN_BYTES {
+            return;
+        }
+        let max_bytes = max_bytes();
+        let mut inner = self.inner.lock().expect("buffer pool lock poisoned");
+        let bytes = buffer.capacity() as u64;
+        if inner.buffers.len() >= MAX_BUFFERS || inner.stats.retained_bytes + bytes > max_bytes {
+            inner.stats.discarded += 1;
+            return;
+        }
+        inner.buffers.push(buffer);
+        inner.stats.returned += 1;
+        inner.stats.retained += 1;
+        inner.stats.retained_bytes += bytes;
+    }
+
+    /// Gives back the pixels of an image that is no longer needed, when they are bytes.
+    pub(crate) fn recycle(&self, image: DynamicImage) {
+        let buffer = match image {
+            DynamicImage::ImageLuma8(image) => image.into_raw(),
+            DynamicImage::ImageLumaA8(image) => image.into_raw(),
+            DynamicImage::ImageRgb8(image) => image.into_raw(),
+            DynamicImage::ImageRgba8(image) => image.into_raw(),
+            _ => return,
+        };
+        self.give(buffer);
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        self.inner.lock().expect("buffer pool lock poisoned").stats
+    }
+}
+
+/// Decodes an image with `decoder` into a buffer from the pool. Images with more than 8 bits
+/// per channel are decoded the usual way.
+pub(crate) fn decode<'a>(decoder: impl ImageDecoder<'a>) -> ImageResult<DynamicImage> {
+    let (width, height) = decoder.dimensions();
+    let color = decoder.color_type();
+    if !matches!(color, ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8) {
+        return DynamicImage::from_decoder(decoder);
+    }
+    let mut buffer = POOL.take(decoder.total_bytes() as usize);
+    if let Err(e) = decoder.read_image(&mut buffer) {
+        POOL.give(buffer);
+        return Err(e);
+    }
+    let image = match color {
+        ColorType::L8 => ImageBuffer::from_raw(width, height, buffer).map(DynamicImage::ImageLuma8),
+        ColorType::La8 => {
+            ImageBuffer::from_raw(width, height, buffer).map(DynamicImage::ImageLumaA8)
+        }
+        ColorType::Rgb8 => {
+            ImageBuffer::from_raw(width, height, buffer).map(DynamicImage::ImageRgb8)
+        }
+        _ => ImageBuffer::from_raw(width, height, buffer).map(DynamicImage::ImageRgba8),
+    };
+    image.ok_or_else(|| {
+        ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch))
+    })
+}
+
+/// BUFFER_POOL_MAX_MB, in bytes.
+fn max_bytes() -> u64 {
+    let mb = crate::settings::var("BUFFER_POOL_MAX_MB")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(MAX_MB);
+    mb * 1024 * 1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_buffers_of_about_the_right_size() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.take(2 * MIN_BYTES);
+        buffer[0] = 7;
+        pool.give(buffer);
+        pool.give(vec![0; MIN_BYTES / 2]);
+
+        // Too big to be worth handing out for this.
+        assert_eq!(pool.take(MIN_BYTES / 2).capacity(), MIN_BYTES / 2);
+        let reused = pool.take(MIN_BYTES + 1);
+        assert_eq!(reused.len(), MIN_BYTES + 1);
+        assert!(reused.iter().all(|&byte| byte == 0));
+
+        let stats = pool.stats();
+        assert_eq!((stats.takes, stats.reused, stats.returned), (3, 1, 1));
+        assert_eq!((stats.retained, stats.retained_bytes), (0, 0));
+    }
+}
@@ -0,0 +1,428 @@
+// Content Credentials (C2PA): the signed provenance manifests that cameras, editors and image
+// generators embed in a file. Newsroom users want that context next to every caption, so the
+// active manifest is summarized in the result: which tool made it, who signed it, what was
+// done to the image and whether it declares itself AI-generated.
+//
+// Manifests are read, not validated. Signatures and hashes aren't checked, so what is
+// reported is what the file claims about itself. Only JPEG (APP11 segments), PNG (`caBX`
+// chunk) and WebP (`C2PA` chunk) are searched.
+//
+// AI-generation signals come from the manifest's `digitalSourceType` and from the IPTC
+// DigitalSourceType in the file's XMP, which many generators write without a manifest.
+
+use ciborium::Value;
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the IPTC digital source type vocabulary used by both C2PA and XMP.
+const IPTC_SOURCE_TYPES: &str = "http://cv.iptc.org/newscodes/digitalsourcetype/";
+
+/// IPTC digital source types meaning a generative model made all or part of the image.
+const AI_SOURCE_TYPES: &[&str] = &[
+    "trainedAlgorithmicMedia",
+    "compositeWithTrainedAlgorithmicMedia",
+    "algorithmicMedia",
+];
+
+/// COSE header label of the signing certificate chain.
+const X5CHAIN: i64 = 33;
+
+/// Object identifiers of the organization and common name in a certificate subject.
+const OID_ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0A];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// Summary of the active C2PA manifest.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ContentCredentials {
+    /// Tool that wrote the manifest, e.g. "Adobe Photoshop 25.0".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claim_generator: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Organization (or common name) on the signing certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_by: Option<String>,
+    /// Recorded actions, e.g. `c2pa.created`, `c2pa.color_adjustments`.
+    #[serde(default)]
+    pub actions: Vec<String>,
+    /// IPTC digital source type of the image, e.g. `digitalCapture`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digital_source_type: Option<String>,
+    /// Titles of the ingredients (source files) the image was made from.
+    #[serde(default)]
+    pub ingredients: Vec<String>,
+    /// Manifests in the file: one per signed step in the image's history.
+    pub manifests: usize,
+}
+
+/// One piece of embedded metadata declaring the image AI-generated.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AiSignal {
+    /// Where it was found: `c2pa` or `xmp`.
+    pub source: String,
+    /// The metadata field, e.g. `digitalSourceType`.
+    pub field: String,
+    pub value: String,
+}
+
+/// A JUMBF box: its type and payload.
+struct JumbfBox<'a> {
+    kind: [u8; 4],
+    payload: &'a [u8],
+}
+
+/// A JUMBF superbox: its label and the boxes after its description box.
+struct Superbox<'a> {
+    label: String,
+    boxes: Vec<JumbfBox<'a>>,
+}
+
+fn boxes(mut data: &[u8]) -> Vec<JumbfBox<'_>> {
+    let mut out = Vec::new();
+    while data.len() >= 8 {
+        let length = u32::from_be_bytes(data[..4].try_into().unwrap());
+        let (header, length) = match length {
+            0 => (8, data.len()),
+            1 => match data.get(8..16) {
+                Some(extended) => (
+                    16,
+                    u64::from_be_bytes(extended.try_into().unwrap()) as usize,
+                ),
+                None => break,
+            },
+            length => (8, length as usize),
+        };
+        if length < header || length > data.len() {
+            break;
+        }
+        out.push(JumbfBox {
+            kind: data[4..8].try_into().unwrap(),
+            payload: &data[header..length],
+        });
+        data = &data[length..];
+    }
+    out
+}
+
+impl<'a> Superbox<'a> {
+    fn parse(jumbf: &JumbfBox<'a>) -> Option<Self> {
+        if &jumbf.kind != b"jumb" {
+            return None;
+        }
+        let mut boxes = boxes(jumbf.payload);
+        if boxes.first().is_none_or(|first| &first.kind != b"jumd") {
+            return None;
+        }
+        // Description: content type UUID, toggles, then the label if toggle bit 1 is set.
+        let description = boxes.remove(0).payload;
+        let toggles = *description.get(16)?;
+        let label = if toggles & 0x02 != 0 {
+            let rest = description.get(17..)?;
+            let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+            String::from_utf8_lossy(&rest[..end]).into_owned()
+        } else {
+            String::new()
+        };
+        Some(Superbox { label, boxes })
+    }
+
+    fn children(&self) -> impl Iterator<Item = Superbox<'a>> + '_ {
+        self.boxes.iter().filter_map(Superbox::parse)
+    }
+
+    /// Child superboxes for `name`, whatever their version suffix (`c2pa.actions.v2`) or
+    /// duplicate counter (`c2pa.ingredient__1`).
+    fn named<'s>(&'s self, name: &'s str) -> impl Iterator<Item = Superbox<'a>> + 's {
+        self.children().filter(move |child| {
+            let label = child.label.split("__").next().unwrap_or_default();
+            let base = match label.rsplit_once(".v") {
+                Some((base, version)) if version.bytes().all(|b| b.is_ascii_digit()) => base,
+                _ => label,
+            };
+            base == name
+        })
+    }
+
+    fn cbor(&self) -> Option<Value> {
+        let content = self.boxes.iter().find(|content| &content.kind == b"cbor")?;
+        ciborium::from_reader(content.payload).ok()
+    }
+}
+
+fn field<'v>(map: &'v Value, key: &str) -> Option<&'v Value> {
+    map.as_map()?
+        .iter()
+        .find(|(name, _)| name.as_text() == Some(key))
+        .map(|(_, value)| value)
+}
+
+fn text(value: Option<&Value>) -> Option<String> {
+    value?
+        .as_text()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string)
+}
+
+/// The term of an IPTC digital source type URI (also accepted bare).
+fn source_type_term(uri: &str) -> String {
+    uri.strip_prefix(IPTC_SOURCE_TYPES)
+        .unwrap_or(uri)
+        .to_string()
+}
+
+/// The JUMBF data of a JPEG's APP11 segments, reassembled in order.
+fn jpeg_jumbf(data: &[u8]) -> Option<Vec<u8>> {
+    let mut jumbf = Vec::new();
+    let mut instance = None;
+    let mut offset = 2;
+    while let (Some(0xFF), Some(&marker)) = (data.get(offset), data.get(offset + 1)) {
+        // Fill bytes, then start of scan or end of image: no more metadata segments.
+        if marker == 0xFF {
+            offset += 1;
+            continue;
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let length = u16::from_be_bytes(data.get(offset + 2..offset + 4)?.try_into().ok()?);
+        let segment = data.get(offset + 4..offset + 2 + length as usize)?;
+        // Common identifier "JP", box instance, sequence number, then the box; later
+        // segments of the same box repeat its 8-byte header.
+        if marker == 0xEB && segment.len() > 16 && segment.starts_with(b"JP") {
+            let this_instance = &segment[2..4];
+            if *instance.get_or_insert(this_instance) == this_instance {
+                let sequence = u32::from_be_bytes(segment[4..8].try_into().unwrap());
+                jumbf.extend_from_slice(&segment[if sequence <= 1 { 8 } else { 16 }..]);
+            }
+        }
+        offset += 2 + length as usize;
+    }
+    (!jumbf.is_empty()).then_some(jumbf)
+}
+
+fn png_jumbf(data: &[u8]) -> Option<Vec<u8>> {
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let chunk = data.get(offset + 8..(offset + 8).checked_add(length)?)?;
+        if &header[4..] == b"caBX" {
+            return Some(chunk.to_vec());
+        }
+        offset += 12 + length;
+    }
+    None
+}
+
+fn webp_jumbf(data: &[u8]) -> Option<Vec<u8>> {
+    let mut offset = 12;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let chunk = data.get(offset + 8..(offset + 8).checked_add(length)?)?;
+        if &header[..4] == b"C2PA" {
+            return Some(chunk.to_vec());
+        }
+        offset += 8 + length + length % 2;
+    }
+    None
+}
+
+fn manifest_store(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_jumbf(data)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_jumbf(data)
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        webp_jumbf(data)
+    } else {
+        None
+    }
+}
+
+/// One DER element: its tag, contents and whatever follows it.
+fn der(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (length, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let bytes = first & 0x7F;
+        if bytes == 0 || bytes > 4 {
+            return None;
+        }
+        let length = data
+            .get(2..2 + bytes)?
+            .iter()
+            .fold(0usize, |length, &b| length << 8 | b as usize);
+        (length, 2 + bytes)
+    };
+    let end = start.checked_add(length)?;
+    Some((tag, data.get(start..end)?, data.get(end..)?))
+}
+
+/// Organization, or failing that common name, of an X.509 certificate's subject.
+fn certificate_subject(certificate: &[u8]) -> Option<String> {
+    let (_, certificate, _) = der(certificate)?;
+    let (_, mut tbs, _) = der(certificate)?;
+    let mut fields = Vec::new();
+    while let Some((tag, contents, rest)) = der(tbs) {
+        fields.push((tag, contents));
+        tbs = rest;
+    }
+    // The explicit version is optional; then serial, algorithm, issuer, validity, subject.
+    let skip = usize::from(fields.first()?.0 == 0xA0);
+    let mut names = fields.get(skip + 4)?.1;
+
+    let (mut organization, mut common_name) = (None, None);
+    while let Some((_, set, rest)) = der(names) {
+        let (_, attribute, _) = der(set)?;
+        let (_, oid, value) = der(attribute)?;
+        let (_, value, _) = der(value)?;
+        let value = String::from_utf8_lossy(value).trim().to_string();
+        match oid {
+            OID_ORGANIZATION => organization = Some(value),
+            OID_COMMON_NAME => common_name = Some(value),
+            _ => {}
+        }
+        names = rest;
+    }
+    organization.or(common_name).filter(|name| !name.is_empty())
+}
+
+/// Who signed a manifest, from the first certificate of its COSE_Sign1 signature.
+fn signer(signature: &Value) -> Option<String> {
+    let signature = match signature {
+        Value::Tag(_, inner) => inner.as_ref(),
+        value => value,
+    };
+    let parts = signature.as_array()?;
+    let protected: Option<Value> = parts
+        .first()
+        .and_then(Value::as_bytes)
+        .and_then(|bytes| ciborium::from_reader(bytes.as_slice()).ok());
+    let headers = protected.iter().chain(parts.get(1));
+    let chain = headers
+        .filter_map(Value::as_map)
+        .flatten()
+        .find_map(|(label, value)| (label.as_integer() == Some(X5CHAIN.into())).then_some(value))?;
+    let certificate = match chain {
+        Value::Array(certificates) => certificates.first()?.as_bytes()?,
+        value => value.as_bytes()?,
+    };
+    certificate_subject(certificate)
+}
+
+fn read_manifest(manifest: &Superbox, credentials: &mut ContentCredentials) {
+    if let Some(claim) = manifest.named("c2pa.claim").find_map(|claim| claim.cbor()) {
+        let info = match field(&claim, "claim_generator_info") {
+            Some(Value::Array(items)) => items.first(),
+            info => info,
+        };
+        let generator = info.and_then(|info| {
+            let name = text(field(info, "name"))?;
+            Some(match text(field(info, "version")) {
+                Some(version) => format!("{} {}", name, version),
+                None => name,
+            })
+        });
+        credentials.claim_generator = generator.or_else(|| text(field(&claim, "claim_generator")));
+        credentials.title =
+            text(field(&claim, "dc:title")).or_else(|| text(field(&claim, "title")));
+    }
+    if let Some(signature) = manifest
+        .named("c2pa.signature")
+        .find_map(|box_| box_.cbor())
+    {
+        credentials.signed_by = signer(&signature);
+    }
+
+    for assertions in manifest.named("c2pa.assertions") {
+        for actions in assertions
+            .named("c2pa.actions")
+            .filter_map(|box_| box_.cbor())
+        {
+            let Some(Value::Array(actions)) = field(&actions, "actions") else {
+                continue;
+            };
+            for action in actions {
+                if let Some(name) = text(field(action, "action")) {
+                    credentials.actions.push(name);
+                }
+                if let Some(source) = text(field(action, "digitalSourceType")) {
+                    credentials.digital_source_type = Some(source_type_term(&source));
+                }
+            }
+        }
+        for ingredient in assertions
+            .named("c2pa.ingredient")
+            .filter_map(|box_| box_.cbor())
+        {
+            if let Some(title) =
+                text(field(&ingredient, "dc:title")).or_else(|| text(field(&ingredient, "title")))
+            {
+                credentials.ingredients.push(title);
+            }
+        }
+    }
+}
+
+/// Summarizes the image's C2PA manifest store, if it has one.
+pub fn read(data: &[u8]) -> Option<ContentCredentials> {
+    let store = manifest_store(data)?;
+    let store = boxes(&store)
+        .iter()
+        .filter_map(Superbox::parse)
+        .find(|superbox| superbox.label == "c2pa")?;
+    let manifests: Vec<Superbox> = store.children().collect();
+
+    // The active manifest is the last one; earlier ones describe the ingredients.
+    let mut credentials = ContentCredentials {
+        manifests: manifests.len(),
+        ..ContentCredentials::default()
+    };
+    read_manifest(manifests.last()?, &mut credentials);
+    Some(credentials)
+}
+
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The file's XMP packet, found by scanning for its root element.
+pub(crate) fn xmp_packet(data: &[u8]) -> Option<&[u8]> {
+    let start = find(data, b"<x:xmpmeta")?;
+    let packet = &data[start..];
+    Some(&packet[..find(packet, b"</x:xmpmeta>")?])
+}
+
+/// The IPTC DigitalSourceType term in the image's XMP packet, if it declares one.
+fn xmp_source_type(data: &[u8]) -> Option<String> {
+    let packet = xmp_packet(data)?;
+    let field = find(packet, b"DigitalSourceType")?;
+    let after = &packet[field..];
+    let uri = &after[find(&after[..after.len().min(300)], IPTC_SOURCE_TYPES.as_bytes())?..];
+    let term: String = uri[IPTC_SOURCE_TYPES.len()..]
+        .iter()
+        .take_while(|b| b.is_ascii_alphanumeric())
+        .map(|&b| b as char)
+        .collect();
+    (!term.is_empty()).then_some(term)
+}
+
+/// Embedded metadata that declares the image AI-generated.
+pub fn ai_signals(data: &[u8], credentials: Option<&ContentCredentials>) -> Vec<AiSignal> {
+    let c2pa = credentials.and_then(|credentials| credentials.digital_source_type.clone());
+    let xmp = xmp_source_type(data);
+    [("c2pa", c2pa), ("xmp", xmp)]
+        .into_iter()
+        .filter_map(|(source, term)| {
+            let term = term.filter(|term| AI_SOURCE_TYPES.contains(&term.as_str()))?;
+            Some(AiSignal {
+                source: source.to_string(),
+                field: "digitalSourceType".to_string(),
+                value: term,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,335 @@
+// Caption cache, so an image the server has captioned before doesn't cost another provider
+// call. The key is the SHA-256 of everything the result depends on: the image as prepared for
+// the provider (the file itself when it is sent as is, otherwise the re-encoded JPEG frames, so
+// a HEIC and the same photo re-saved as TIFF match), the prompt the options make, the model,
+// the content policy file, the EXIF capture context and the image's Content Credentials. A hit
+// returns the caption made then, with `cached: true` and its age in `cache_age_secs`; it counts
+// as a caption in the usage and history, with no tokens.
+//
+// Entries are kept per tenant, so a caption is only ever handed back to whoever paid for it: in
+// Redis when REDIS_URL is set (see `shared`), so replicas share them, otherwise in the history's
+// storage (DATABASE_URL or HISTORY_DB, see `storage`). They're used for CAPTION_CACHE_DAYS days
+// (default 30; 0 turns the cache off); older ones expire in Redis and are removed from the
+// database at startup.
+//
+// With neither, the process keeps the CAPTION_CACHE_MEMORY_ENTRIES (default 500; 0 turns it
+// off) most recently used captions in memory for CAPTION_CACHE_MEMORY_TTL_SECS (default an
+// hour), which is enough to absorb the same screenshot uploaded again and again. They are lost
+// on restart.
+
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::config::MODEL_ID;
+use crate::imageproc::PreparedImage;
+use crate::shared::SharedState;
+use crate::storage::Storage;
+use crate::{c2pa, policy, CaptionError, CaptionOptions, CaptionResponse, CaptureContext};
+
+pub(crate) const DEFAULT_DAYS: i64 = 30;
+pub(crate) const DEFAULT_MEMORY_ENTRIES: usize = 500;
+pub(crate) const DEFAULT_MEMORY_TTL_SECS: i64 = 3600;
+
+/// Where cached captions are kept, and for how long.
+pub(crate) struct CaptionCache {
+    store: Store,
+    max_age: Duration,
+}
+
+enum Store {
+    Database(Arc<dyn Storage>),
+    Shared(Arc<dyn SharedState>),
+    Memory(Mutex<MemoryCache>),
+}
+
+/// The most recently used captions, for servers with nowhere else to keep them.
+struct MemoryCache {
+    entries: HashMap<(String, String), MemoryEntry>,
+    /// Each entry by its `used`, so the least recently used one is the first, without a scan
+    /// of all of them under the lock.
+    by_use: BTreeMap<u64, (String, String)>,
+    max_entries: usize,
+    /// Ticks on every use, so the least recently used entry has the lowest `used`.
+    clock: u64,
+}
+
+struct MemoryEntry {
+    created_at: DateTime<Utc>,
+    response: String,
+    used: u64,
+}
+
+impl MemoryCache {
+    fn get(
+        &mut self,
+        tenant: &str,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Option<(DateTime<Utc>, String)> {
+        let id = (tenant.to_string(), key.to_string());
+        let entry = self.entries.get_mut(&id)?;
+        self.by_use.remove(&entry.used);
+        if entry.created_at < since {
+            self.entries.remove(&id);
+            return None;
+        }
+        self.clock += 1;
+        entry.used = self.clock;
+        let found = (entry.created_at, entry.response.clone());
+        self.by_use.insert(self.clock, id);
+        Some(found)
+    }
+
+    fn put(&mut self, tenant: &str, key: &str, created_at: DateTime<Utc>, response: String) {
+        let id = (tenant.to_string(), key.to_string());
+        match self.entries.get(&id) {
+            Some(entry) => {
+                self.by_use.remove(&entry.used);
+            }
+            None if self.entries.len() >= self.max_entries => {
+                if let Some((_, oldest)) = self.by_use.pop_first() {
+                    self.entries.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        self.clock += 1;
+        let used = self.clock;
+        self.by_use.insert(used, id.clone());
+        self.entries.insert(
+            id,
+            MemoryEntry {
+                created_at,
+                response,
+                used,
+            },
+        );
+    }
+}
+
+impl CaptionCache {
+    fn backend(&self) -> &'static str {
+        match &self.store {
+            Store::Database(storage) => storage.backend(),
+            Store::Shared(shared) => shared.backend(),
+            Store::Memory(_) => "memory",
+        }
+    }
+
+    async fn get(
+        &self,
+        tenant: &str,
+        key: &str,
+    ) -> Result<Option<(DateTime<Utc>, String)>, CaptionError> {
+        let since = Utc::now() - self.max_age;
+        match &self.store {
+            Store::Database(storage) => storage.cached_caption(tenant, key, since).await,
+            Store::Shared(shared) => shared.cached_caption(tenant, key, since).await,
+            Store::Memory(memory) => {
+                let mut memory = memory.lock().expect("caption cache lock poisoned");
+                Ok(memory.get(tenant, key, since))
+            }
+        }
+    }
+
+    async fn put(&self, tenant: &str, key: &str, response: String) -> Result<(), CaptionError> {
+        let created_at = Utc::now();
+        match &self.store {
+            Store::Database(storage) => {
+                storage.cache_caption(tenant, key, created_at, response).await
+            }
+            Store::Shared(shared) => {
+                let max_age = self.max_age.to_std().unwrap_or_default();
+                shared.cache_caption(tenant, key, created_at, response, max_age).await
+            }
+            Store::Memory(memory) => {
+                let mut memory = memory.lock().expect("caption cache lock poisoned");
+                memory.put(tenant, key, created_at, response);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Removes every caption cached for `tenant`; returns how many there were.
+pub(crate) async fn forget(
+    cache: Option<&CaptionCache>,
+    tenant: &str,
+) -> Result<u64, CaptionError> {
+    let Some(cache) = cache else {
+        return Ok(0);
+    };
+    match &cache.store {
+        Store::Database(storage) => storage.delete_tenant_cache(tenant).await,
+        Store::Shared(shared) => shared.forget_cached_captions(tenant).await,
+        Store::Memory(memory) => {
+            let mut memory = memory.lock().expect("caption cache lock poisoned");
+            let before = memory.entries.len();
+            memory.entries.retain(|(owner, _), _| owner != tenant);
+            Ok((before - memory.entries.len()) as u64)
+        }
+    }
+}
+
+fn setting<T: FromStr>(name: &str, default: T) -> T {
+    crate::settings::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// The cache kept in `shared`, or else in `storage` with the expired entries removed, or else
+/// in memory; `None` when CAPTION_CACHE_DAYS is 0, or there is only memory and
+/// CAPTION_CACHE_MEMORY_ENTRIES is 0.
+pub(crate) async fn open(
+    storage: Option<Arc<dyn Storage>>,
+    shared: Option<Arc<dyn SharedState>>,
+) -> Option<CaptionCache> {
+    let days = setting("CAPTION_CACHE_DAYS", DEFAULT_DAYS);
+    if days <= 0 {
+        return None;
+    }
+    let max_age = Duration::days(days);
+    let cache = match (shared, storage) {
+        (Some(shared), _) => CaptionCache {
+            store: Store::Shared(shared),
+            max_age,
+        },
+        (None, Some(storage)) => {
+            match storage.prune_cache(Utc::now() - max_age).await {
+                Ok(0) => {}
+                Ok(removed) => tracing::info!(removed, "Removed expired cached captions"),
+                // Expired entries are never used, so they can wait for the next start.
+                Err(e) => tracing::warn!(error = %e, "Can't remove expired cached captions"),
+            }
+            CaptionCache {
+                store: Store::Database(storage),
+                max_age,
+            }
+        }
+        (None, None) => {
+            let max_entries = setting("CAPTION_CACHE_MEMORY_ENTRIES", DEFAULT_MEMORY_ENTRIES);
+            let ttl = setting("CAPTION_CACHE_MEMORY_TTL_SECS", DEFAULT_MEMORY_TTL_SECS);
+            if max_entries == 0 || ttl <= 0 {
+                return None;
+            }
+            tracing::info!("Caching up to {} captions for {} seconds in memory", max_entries, ttl);
+            let memory = MemoryCache {
+                entries: HashMap::new(),
+                by_use: BTreeMap::new(),
+                max_entries,
+                clock: 0,
+            };
+            return Some(CaptionCache {
+                store: Store::Memory(Mutex::new(memory)),
+                max_age: Duration::seconds(ttl),
+            });
+        }
+    };
+    tracing::info!("Caching captions for {} days in {}", days, cache.backend());
+    Some(cache)
+}
+
+/// The cache key of `image` captioned with `options`, once prepared as `prepared`.
+pub(crate) fn key(
+    image: &[u8],
+    prepared: &PreparedImage,
+    options: &CaptionOptions,
+    context: Option<&CaptureContext>,
+) -> String {
+    let credentials = c2pa::read(image);
+    let ai_signals = c2pa::ai_signals(image, credentials.as_ref());
+    let mut hasher = Sha256::new();
+    for part in [
+        MODEL_ID.to_string(),
+        options.fingerprint(),
+        policy::digest(),
+        serde_json::to_string(&context).unwrap_or_default(),
+        serde_json::to_string(&credentials).unwrap_or_default(),
+        serde_json::to_string(&ai_signals).unwrap_or_default(),
+    ] {
+        // Lengths first, so no two different lists of parts hash alike.
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    for frame in &prepared.frames {
+        hasher.update((frame.data.len() as u64).to_le_bytes());
+        hasher.update(frame.mime_type);
+        hasher.update(&frame.data);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// The caption `tenant` got for `key` while it was fresh, if the cache has it; a cache that
+/// can't be read is logged and missed.
+pub(crate) async fn find(
+    cache: Option<&CaptionCache>,
+    tenant: &str,
+    key: &str,
+) -> Option<CaptionResponse> {
+    let (created_at, json) = match cache?.get(tenant, key).await {
+        Ok(cached) => cached?,
+        Err(e) => {
+            tracing::warn!(tenant, error = %e, "Can't read the caption cache");
+            return None;
+        }
+    };
+    let mut response: CaptionResponse = serde_json::from_str(&json)
+        .inspect_err(|e| tracing::warn!(error = %e, "Ignoring an unreadable cached caption"))
+        .ok()?;
+    response.cached = true;
+    response.cache_age_secs = Some((Utc::now() - created_at).num_seconds().max(0) as u64);
+    Some(response)
+}
+
+/// Keeps `response` as `tenant`'s caption for `key`, without what only this request had.
+pub(crate) async fn store(
+    cache: Option<&CaptionCache>,
+    tenant: &str,
+    key: &str,
+    response: &CaptionResponse,
+) {
+    let Some(cache) = cache else {
+        return;
+    };
+    let response = CaptionResponse {
+        timings: None,
+        input_tokens: None,
+        output_tokens: None,
+        cost_usd: None,
+        request_id: None,
+        ..response.clone()
+    };
+    let json = serde_json::to_string(&response).expect("caption responses always serialize");
+    if let Err(e) = cache.put(tenant, key, json).await {
+        tracing::warn!(tenant, error = %e, "Can't cache a caption");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_least_recently_used_entry_goes_first() {
+        let mut memory = MemoryCache {
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            max_entries: 2,
+            clock: 0,
+        };
+        let (now, since) = (Utc::now(), Utc::now() - Duration::hours(1));
+        memory.put("t", "a", now, "A".into());
+        memory.put("t", "b", now, "B".into());
+        assert!(memory.get("t", "a", since).is_some());
+        memory.put("t", "c", now, "C".into());
+        assert!(memory.get("t", "b", since).is_none());
+        assert!(memory.get("t", "a", since).is_some());
+        memory.put("t", "c", now, "C2".into());
+        assert_eq!(memory.get("t", "c", since).map(|(_, caption)| caption), Some("C2".into()));
+        assert_eq!((memory.entries.len(), memory.by_use.len()), (2, 2));
+    }
+}
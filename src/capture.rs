@@ -0,0 +1,283 @@
+// Capture context from EXIF: when and where a photo was taken and with which camera, given to
+// the model so captions can say "in Lisbon at dusk" rather than "in a city in the evening"
+// (the `exif_context` option). GPS positions are turned into a city name offline (see
+// `places`), so coordinates never leave the server, and `hide_location` leaves the place out
+// altogether.
+//
+// EXIF is read from JPEG, PNG and WebP files, and from TIFF-based RAW files, whose own
+// header is the EXIF block.
+
+use chrono::{Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::modes::CaptionOptions;
+use crate::orientation;
+use crate::places;
+use crate::tiff::{Entry, Tiff};
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const TAG_LENS_MODEL: u16 = 0xA434;
+
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+const TAG_GPS_TIME_STAMP: u16 = 0x0007;
+const TAG_GPS_DATE_STAMP: u16 = 0x001D;
+
+/// Sun elevation in degrees below which it is night rather than dawn or dusk (civil twilight).
+const TWILIGHT_ELEVATION: f64 = -6.0;
+
+/// Sun elevation in degrees below which a late-day photo counts as evening.
+const LOW_SUN_ELEVATION: f64 = 10.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeOfDay {
+    Dawn,
+    Morning,
+    Midday,
+    Afternoon,
+    Evening,
+    Dusk,
+    Night,
+}
+
+impl TimeOfDay {
+    /// From the sun's elevation at `latitude` on day `day` of the year, `solar_hours` after
+    /// solar midnight. Good to a few minutes, which is plenty to tell dusk from night.
+    fn from_sun(day: u32, solar_hours: f64, latitude: f64) -> Self {
+        let declination = (-23.44f64).to_radians()
+            * (2.0 * std::f64::consts::PI / 365.0 * (day as f64 + 10.0)).cos();
+        let hour_angle = (15.0 * (solar_hours - 12.0)).to_radians();
+        let latitude = latitude.to_radians();
+        let elevation = (latitude.sin() * declination.sin()
+            + latitude.cos() * declination.cos() * hour_angle.cos())
+        .asin()
+        .to_degrees();
+
+        if elevation < TWILIGHT_ELEVATION {
+            TimeOfDay::Night
+        } else if elevation < 0.0 {
+            if solar_hours < 12.0 {
+                TimeOfDay::Dawn
+            } else {
+                TimeOfDay::Dusk
+            }
+        } else if solar_hours < 11.0 {
+            TimeOfDay::Morning
+        } else if solar_hours < 14.0 {
+            TimeOfDay::Midday
+        } else if elevation < LOW_SUN_ELEVATION {
+            TimeOfDay::Evening
+        } else {
+            TimeOfDay::Afternoon
+        }
+    }
+
+    /// From the camera's clock alone, when there is no position to place the sun.
+    fn from_clock(hour: u32) -> Self {
+        match hour {
+            5..=10 => TimeOfDay::Morning,
+            11..=13 => TimeOfDay::Midday,
+            14..=17 => TimeOfDay::Afternoon,
+            18..=20 => TimeOfDay::Evening,
+            _ => TimeOfDay::Night,
+        }
+    }
+
+    fn phrase(self) -> &'static str {
+        match self {
+            TimeOfDay::Dawn => "at dawn",
+            TimeOfDay::Morning => "in the morning",
+            TimeOfDay::Midday => "around midday",
+            TimeOfDay::Afternoon => "in the afternoon",
+            TimeOfDay::Evening => "in the evening",
+            TimeOfDay::Dusk => "at dusk",
+            TimeOfDay::Night => "at night",
+        }
+    }
+}
+
+/// What the image's EXIF says about how it was taken, as given to the model.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CaptureContext {
+    /// Capture time as the camera recorded it, with the UTC offset when the file has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taken_at: Option<String>,
+    /// From the sun's position when the file has a GPS position, otherwise from the clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_of_day: Option<TimeOfDay>,
+    /// Nearest known city, e.g. "Lisbon, Portugal" or "near Lisbon, Portugal".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub place: Option<String>,
+    /// Camera and lens, e.g. "Canon EOS R5 with RF24-70mm F2.8 L IS USM".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<String>,
+}
+
+impl CaptureContext {
+    /// The context as a sentence to put in front of the mode's prompt.
+    pub(crate) fn prompt(&self) -> String {
+        let mut taken = Vec::new();
+        if let Some(place) = &self.place {
+            taken.push(if place.starts_with("near ") {
+                place.clone()
+            } else {
+                format!("in {}", place)
+            });
+        }
+        let date = self
+            .taken_at
+            .as_deref()
+            .and_then(|taken_at| NaiveDate::parse_from_str(taken_at.get(..10)?, "%Y-%m-%d").ok());
+        if let Some(date) = date {
+            taken.push(date.format("on %-d %B %Y").to_string());
+        }
+        if let Some(time_of_day) = self.time_of_day {
+            taken.push(time_of_day.phrase().to_string());
+        }
+
+        let mut facts = Vec::new();
+        if !taken.is_empty() {
+            facts.push(format!("taken {}", taken.join(" ")));
+        }
+        if let Some(camera) = &self.camera {
+            facts.push(format!("camera: {}", camera));
+        }
+        format!(
+            "Context from the photo's metadata: {}. Use the place and time where they make the \
+             caption more specific, without contradicting what the image shows, and don't \
+             mention the camera unless it matters.",
+            facts.join("; ")
+        )
+    }
+}
+
+/// The capture context of an image, when `exif_context` asks for it and the image has any.
+pub(crate) fn read(data: &[u8], options: &CaptionOptions) -> Option<CaptureContext> {
+    if !options.exif_context {
+        return None;
+    }
+    from_tiff(orientation::exif(data)?, !options.hide_location)
+}
+
+fn from_tiff(block: &[u8], with_location: bool) -> Option<CaptureContext> {
+    let (tiff, first_ifd) = Tiff::parse(block)?;
+    let (ifd0, _) = tiff.ifd(first_ifd)?;
+    let sub_ifd = |tag| {
+        tiff.first(&ifd0, tag)
+            .and_then(|offset| tiff.ifd(offset as usize))
+            .map(|(entries, _)| entries)
+            .unwrap_or_default()
+    };
+    let exif = sub_ifd(TAG_EXIF_IFD);
+    let gps = sub_ifd(TAG_GPS_IFD);
+
+    let local = tiff
+        .ascii(&exif, TAG_DATE_TIME_ORIGINAL)
+        .or_else(|| tiff.ascii(&ifd0, TAG_DATE_TIME))
+        .and_then(|text| NaiveDateTime::parse_from_str(&text, "%Y:%m:%d %H:%M:%S").ok());
+    let offset = tiff
+        .ascii(&exif, TAG_OFFSET_TIME_ORIGINAL)
+        .and_then(|text| text.parse::<FixedOffset>().ok());
+    let utc = gps_time(&tiff, &gps).or_else(|| {
+        Some(local? - chrono::Duration::seconds(offset?.local_minus_utc().into()))
+    });
+    let position = gps_position(&tiff, &gps);
+
+    let time_of_day = match (position, utc, local) {
+        (Some((latitude, longitude)), Some(utc), _) => {
+            let solar_hours = (hours(utc) + longitude / 15.0).rem_euclid(24.0);
+            Some(TimeOfDay::from_sun(utc.ordinal(), solar_hours, latitude))
+        }
+        // Without a UTC offset, the camera's clock is the best guess at solar time.
+        (Some((latitude, _)), None, Some(local)) => {
+            Some(TimeOfDay::from_sun(local.ordinal(), hours(local), latitude))
+        }
+        (None, _, Some(local)) => Some(TimeOfDay::from_clock(local.hour())),
+        _ => None,
+    };
+
+    let context = CaptureContext {
+        taken_at: local.map(|local| match offset {
+            Some(offset) => format!("{}{}", local.format("%Y-%m-%dT%H:%M:%S"), offset),
+            None => local.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        }),
+        time_of_day,
+        place: position
+            .filter(|_| with_location)
+            .and_then(|(latitude, longitude)| places::describe(latitude, longitude)),
+        camera: camera_name(
+            tiff.ascii(&ifd0, TAG_MAKE),
+            tiff.ascii(&ifd0, TAG_MODEL),
+            tiff.ascii(&exif, TAG_LENS_MODEL),
+        ),
+    };
+    let empty = context.taken_at.is_none() && context.place.is_none() && context.camera.is_none();
+    (!empty).then_some(context)
+}
+
+fn hours(time: NaiveDateTime) -> f64 {
+    f64::from(time.hour()) + f64::from(time.minute()) / 60.0
+}
+
+/// Latitude and longitude in degrees. Cameras without a fix often write 0, 0; that is
+/// treated as no position.
+fn gps_position(tiff: &Tiff, gps: &[Entry]) -> Option<(f64, f64)> {
+    let coordinate = |tag, reference_tag, negative: &str| {
+        let parts = tiff.rationals(gps, tag);
+        let [degrees, minutes, seconds] = parts[..] else {
+            return None;
+        };
+        let value = degrees + minutes / 60.0 + seconds / 3600.0;
+        let reference = tiff.ascii(gps, reference_tag);
+        Some(match reference {
+            Some(reference) if reference.eq_ignore_ascii_case(negative) => -value,
+            _ => value,
+        })
+    };
+    let latitude = coordinate(TAG_GPS_LATITUDE, TAG_GPS_LATITUDE_REF, "S")?;
+    let longitude = coordinate(TAG_GPS_LONGITUDE, TAG_GPS_LONGITUDE_REF, "W")?;
+    let valid = latitude.abs() <= 90.0
+        && longitude.abs() <= 180.0
+        && (latitude != 0.0 || longitude != 0.0);
+    valid.then_some((latitude, longitude))
+}
+
+/// The GPS date and time stamps, which are in UTC.
+fn gps_time(tiff: &Tiff, gps: &[Entry]) -> Option<NaiveDateTime> {
+    let date = NaiveDate::parse_from_str(&tiff.ascii(gps, TAG_GPS_DATE_STAMP)?, "%Y:%m:%d").ok()?;
+    let parts = tiff.rationals(gps, TAG_GPS_TIME_STAMP);
+    let [hour, minute, second] = parts[..] else {
+        return None;
+    };
+    let time = NaiveTime::from_hms_opt(hour as u32, minute as u32, second as u32)?;
+    Some(date.and_time(time))
+}
+
+/// "Canon EOS R5" from make "Canon" and model "Canon EOS R5", "Apple iPhone 15" from "Apple"
+/// and "iPhone 15", plus the lens when it's recorded.
+fn camera_name(make: Option<String>, model: Option<String>, lens: Option<String>) -> Option<String> {
+    let body = match (make, model) {
+        (Some(make), Some(model)) => {
+            let brand = make.split_whitespace().next().unwrap_or_default().to_lowercase();
+            if model.to_lowercase().starts_with(&brand) {
+                model
+            } else {
+                format!("{} {}", make, model)
+            }
+        }
+        (make, model) => model.or(make)?,
+    };
+    Some(match lens {
+        Some(lens) => format!("{} with {}", body, lens),
+        None => body,
+    })
+}
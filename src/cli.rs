@@ -0,0 +1,539 @@
+// Command-line interface: caption local images without starting the web server.
+//
+// Examples:
+//   captioner caption photo.jpg
+//   cat photo.jpg | captioner caption --stdin --format json
+//   find photos -name '*.jpg' | captioner caption --stdin-paths --format ndjson | jq .caption
+//   captioner batch ./photos --output captions.jsonl
+//   captioner batch ./photos --format csv --output captions.csv
+//   captioner batch --retry failures.jsonl
+//   captioner watch ~/Scans --sidecar txt
+//   captioner review ./photos --xmp
+//
+// Exit codes are stable so wrapper scripts can branch on the failure type:
+//   0 success, 2 invalid input, 3 provider error, 4 quota exceeded, 5 configuration error.
+// When several images fail, the most severe (highest) code wins.
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+
+use crate::batch::BatchArgs;
+use crate::listing::ListingArgs;
+use crate::review::ReviewArgs;
+use crate::watch::WatchArgs;
+use crate::modes::{CaptionOptions, Mode};
+use crate::handlers::BatchItem;
+use crate::providers::Usage;
+use crate::{caption_image_bytes, export, BoxError, CaptionError, CaptionResponse};
+
+#[derive(Parser)]
+#[command(version, about = "AI image captioner - web server and command-line tool")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML config file (default: CONFIG_FILE, else ai-image-captioner.toml if there is one)
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Set a config key, e.g. --set provider.max_attempts=5; overrides the file and environment
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = parse_setting)]
+    pub settings: Vec<(String, String)>,
+
+    /// Print every setting with its value and where it came from, then exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+}
+
+impl Cli {
+    /// The settings the command line gives, as config keys and values: `--set` first, so the
+    /// dedicated flags win over it.
+    pub fn settings(&self) -> Vec<(String, String)> {
+        let mut settings = self.settings.clone();
+        if let Some(Command::Serve(args)) = &self.command {
+            settings.extend(args.settings());
+        }
+        settings
+    }
+}
+
+fn parse_setting(text: &str) -> Result<(String, String), String> {
+    let (key, value) = text.split_once('=').ok_or("expected KEY=VALUE")?;
+    Ok((key.trim().to_string(), value.to_string()))
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the web server (default when no subcommand is given)
+    Serve(ServeArgs),
+    /// Caption images from files or stdin
+    Caption(CaptionArgs),
+    /// Caption every image in a directory or manifest, with progress and a run report
+    Batch(Box<BatchArgs>),
+    /// Watch a directory and caption new images as they appear, writing sidecar files
+    Watch(WatchArgs),
+    /// Review captions for a folder in an interactive terminal UI before writing metadata
+    Review(ReviewArgs),
+    /// Write a property listing from photos of it, room by room
+    Listing(ListingArgs),
+}
+
+/// The `serve` flags, each standing for a config key (and the variable behind it).
+#[derive(Args, Default)]
+pub struct ServeArgs {
+    /// Address to listen on (HOST, default 0.0.0.0)
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Port to listen on (PORT, default 3000; 0 picks a free one)
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Gemini API base URL (GEMINI_API_URL)
+    #[arg(long, value_name = "URL")]
+    pub api_url: Option<String>,
+
+    /// Mode for requests that don't send one (DEFAULT_MODE)
+    #[arg(long)]
+    pub mode: Option<Mode>,
+
+    /// Language for requests that don't send one (DEFAULT_LANGUAGE)
+    #[arg(long, value_name = "CODE")]
+    pub language: Option<String>,
+
+    /// Requests per minute per client IP, 0 for no limit (RATE_LIMIT_PER_MINUTE)
+    #[arg(long, value_name = "N")]
+    pub rate_limit_per_minute: Option<u32>,
+
+    /// Provider calls in flight at once (PROVIDER_CONCURRENCY)
+    #[arg(long, value_name = "N")]
+    pub provider_concurrency: Option<usize>,
+
+    /// PostgreSQL database for history, keys and the cache (DATABASE_URL)
+    #[arg(long, value_name = "URL")]
+    pub database_url: Option<String>,
+
+    /// SQLite file for the caption history (HISTORY_DB)
+    #[arg(long, value_name = "FILE")]
+    pub history_db: Option<String>,
+
+    /// Redis for the shared cache and rate limits (REDIS_URL)
+    #[arg(long, value_name = "URL")]
+    pub redis_url: Option<String>,
+
+    /// Where jobs are saved on shutdown, empty to not save them (JOBS_FILE)
+    #[arg(long, value_name = "FILE")]
+    pub jobs_file: Option<String>,
+}
+
+impl ServeArgs {
+    fn settings(&self) -> Vec<(String, String)> {
+        let flags = [
+            ("server.host", self.host.clone()),
+            ("server.port", self.port.map(|port| port.to_string())),
+            ("provider.api_url", self.api_url.clone()),
+            ("prompt.mode", self.mode.map(|mode| mode.to_string())),
+            ("prompt.language", self.language.clone()),
+            ("limits.rate_limit_per_minute", self.rate_limit_per_minute.map(|n| n.to_string())),
+            ("limits.provider_concurrency", self.provider_concurrency.map(|n| n.to_string())),
+            ("storage.database_url", self.database_url.clone()),
+            ("storage.history_db", self.history_db.clone()),
+            ("storage.redis_url", self.redis_url.clone()),
+            ("storage.jobs_file", self.jobs_file.clone()),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?)))
+            .collect()
+    }
+}
+
+/// Captioning options shared by the `caption` and `batch` subcommands.
+#[derive(Args)]
+pub struct OptionArgs {
+    /// Captioning mode (caption, alt_text, hashtags, title_description, accessibility_audit,
+    /// document_layout, comic, fashion, vehicle, real_estate, nature, astronomy, art)
+    #[arg(long, default_value_t = Mode::Caption)]
+    pub mode: Mode,
+
+    /// Number of hashtags and keywords to return in hashtags mode
+    #[arg(long)]
+    pub count: Option<usize>,
+
+    /// Include a 0-1 confidence score and a list of uncertain elements
+    #[arg(long)]
+    pub confidence: bool,
+
+    /// Classify each image's time of day, weather and indoor/outdoor setting
+    #[arg(long)]
+    pub scene: bool,
+
+    /// Estimate whether each image is AI-generated, with a 0-1 score and the reasons
+    #[arg(long)]
+    pub detect_ai: bool,
+
+    /// Give the model the capture time, place and camera from each image's EXIF as context
+    #[arg(long)]
+    pub exif_context: bool,
+
+    /// With --exif-context, leave the GPS position out
+    #[arg(long, requires = "exif_context")]
+    pub hide_location: bool,
+
+    /// In nature mode, add iNaturalist-style observation fields (taxon, count, life stage, sex,
+    /// evidence, alive, captive)
+    #[arg(long)]
+    pub observation: bool,
+
+    /// Treat each image as a contact sheet or film strip and caption every panel too
+    #[arg(long)]
+    pub contact_sheet: bool,
+
+    /// Include the tokens used and their estimated cost (PRICE_TABLE_FILE) in each result
+    #[arg(long)]
+    pub verbose: bool,
+
+    /// Translate the results into this language (e.g. de, pt-BR) with TRANSLATION_PROVIDER
+    #[arg(long, value_name = "CODE")]
+    pub language: Option<String>,
+}
+
+impl OptionArgs {
+    pub fn to_options(&self) -> Result<CaptionOptions, String> {
+        let options = CaptionOptions {
+            mode: self.mode,
+            count: self.count,
+            confidence: self.confidence,
+            scene: self.scene,
+            detect_ai: self.detect_ai,
+            language: self.language.clone(),
+            voice: None,
+            exif_context: self.exif_context,
+            hide_location: self.hide_location,
+            contact_sheet: self.contact_sheet,
+            verbose: self.verbose,
+            screen: false,
+            instructions: None,
+            observation: self.observation,
+        };
+        options.validate()?;
+        Ok(options)
+    }
+}
+
+#[derive(Args)]
+pub struct CaptionArgs {
+    /// Image files to caption
+    pub paths: Vec<PathBuf>,
+
+    /// Read a single image's raw bytes from stdin
+    #[arg(long, conflicts_with = "paths")]
+    pub stdin: bool,
+
+    /// Read image paths from stdin, one per line, either bare or as NDJSON objects with a "path" field
+    #[arg(long, conflicts_with_all = ["paths", "stdin"])]
+    pub stdin_paths: bool,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    #[command(flatten)]
+    pub options: OptionArgs,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Plain caption text
+    Text,
+    /// A JSON object (or an array when several images are given)
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// CSV with one row per image, the same columns as `POST /batch?format=csv`
+    Csv,
+}
+
+impl OutputFormat {
+    /// Whether results (and errors) are written as JSON records.
+    pub(crate) fn is_json(self) -> bool {
+        matches!(self, OutputFormat::Json | OutputFormat::Ndjson)
+    }
+}
+
+enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Input {
+    fn label(&self) -> Option<String> {
+        match self {
+            Input::Stdin => None,
+            Input::File(path) => Some(path.display().to_string()),
+        }
+    }
+
+    async fn read(&self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Input::Stdin => {
+                let mut data = Vec::new();
+                std::io::stdin().lock().read_to_end(&mut data)?;
+                Ok(data)
+            }
+            Input::File(path) => tokio::fs::read(path).await,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorKind {
+    InvalidInput,
+    ProviderError,
+    QuotaExceeded,
+    ConfigError,
+}
+
+impl ErrorKind {
+    pub(crate) fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::InvalidInput => 2,
+            ErrorKind::ProviderError => 3,
+            ErrorKind::QuotaExceeded => 4,
+            ErrorKind::ConfigError => 5,
+        }
+    }
+
+    pub(crate) fn classify(error: &BoxError) -> Self {
+        match error.downcast_ref::<CaptionError>() {
+            Some(CaptionError::RateLimited(_)) => ErrorKind::QuotaExceeded,
+            Some(CaptionError::Provider {
+                status: Some(401 | 403),
+                ..
+            })
+            | Some(CaptionError::Config(_)) => ErrorKind::ConfigError,
+            Some(
+                CaptionError::BadRequest(_)
+                | CaptionError::Unprocessable(_)
+                | CaptionError::Refused(_)
+                | CaptionError::TooLarge(_)
+                | CaptionError::UploadTooLarge { .. }
+                | CaptionError::UnsupportedMedia(_)
+                | CaptionError::NotFound(_),
+            ) => ErrorKind::InvalidInput,
+            Some(_) => ErrorKind::ProviderError,
+            None if error.is::<std::io::Error>() => ErrorKind::InvalidInput,
+            None => ErrorKind::ProviderError,
+        }
+    }
+}
+
+/// Machine-readable error envelope emitted with `--format json` / `--format ndjson`.
+#[derive(Clone, Serialize)]
+pub(crate) struct ErrorBody {
+    kind: ErrorKind,
+    exit_code: i32,
+    message: String,
+}
+
+impl ErrorBody {
+    pub(crate) fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            exit_code: kind.exit_code(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PathLine {
+    path: PathBuf,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CaptionRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) path: Option<String>,
+    #[serde(flatten)]
+    pub(crate) response: CaptionResponse,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) path: Option<String>,
+    pub(crate) error: ErrorBody,
+}
+
+/// Reports a failure that prevents any image from being processed.
+pub(crate) fn fail(format: OutputFormat, kind: ErrorKind, message: &str) -> i32 {
+    if !format.is_json() {
+        eprintln!("{}", message);
+    } else {
+        let record = ErrorRecord {
+            path: None,
+            error: ErrorBody::new(kind, message),
+        };
+        println!("{}", serde_json::to_string(&record).expect("error records always serialize"));
+    }
+    kind.exit_code()
+}
+
+/// Parses a path list: one path per line, either bare or as an NDJSON object with a "path" field.
+pub(crate) fn parse_path_lines(reader: impl BufRead) -> Result<Vec<PathBuf>, BoxError> {
+    let mut paths = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let path = if line.starts_with('{') {
+            serde_json::from_str::<PathLine>(line)?.path
+        } else {
+            PathBuf::from(line)
+        };
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+async fn caption_input(
+    input: &Input,
+    options: &CaptionOptions,
+    api_key: &str,
+) -> Result<(CaptionResponse, Usage), BoxError> {
+    let data = input.read().await?;
+    Ok(caption_image_bytes(&data, options, api_key).await?)
+}
+
+/// Runs the `caption` subcommand and returns the process exit code.
+pub async fn run_caption(args: CaptionArgs) -> i32 {
+    crate::reload::apply_log_settings();
+    let Ok(api_key) = crate::settings::var("GEMINI_API_KEY") else {
+        return fail(
+            args.format,
+            ErrorKind::ConfigError,
+            "GEMINI_API_KEY must be set in the environment or .env file",
+        );
+    };
+
+    let inputs = if args.stdin {
+        vec![Input::Stdin]
+    } else if args.stdin_paths {
+        match parse_path_lines(std::io::stdin().lock()) {
+            Ok(paths) => paths.into_iter().map(Input::File).collect(),
+            Err(e) => {
+                return fail(
+                    args.format,
+                    ErrorKind::InvalidInput,
+                    &format!("Invalid path list on stdin: {}", e),
+                );
+            }
+        }
+    } else {
+        args.paths.into_iter().map(Input::File).collect()
+    };
+
+    if inputs.is_empty() {
+        return fail(
+            args.format,
+            ErrorKind::InvalidInput,
+            "No images given; pass file paths, --stdin or --stdin-paths",
+        );
+    }
+
+    let options = match args.options.to_options() {
+        Ok(options) => options,
+        Err(message) => return fail(args.format, ErrorKind::InvalidInput, &message),
+    };
+
+    let multiple = inputs.len() > 1;
+    let mut json_results = Vec::new();
+    let mut csv_items = Vec::new();
+    let mut worst: Option<ErrorKind> = None;
+
+    for input in &inputs {
+        let path = input.label();
+
+        let value = match caption_input(input, &options, &api_key).await {
+            Ok((response, _usage)) => {
+                if args.format == OutputFormat::Text {
+                    match (&path, multiple) {
+                        (Some(path), true) => println!("{}: {}", path, response.caption),
+                        _ => println!("{}", response.caption),
+                    }
+                    continue;
+                }
+                if args.format == OutputFormat::Csv {
+                    csv_items.push(BatchItem {
+                        file_name: path,
+                        response: Some(response),
+                        xmp: None,
+                        error: None,
+                    });
+                    continue;
+                }
+                serde_json::to_value(CaptionRecord { path, response })
+            }
+            Err(e) => {
+                let kind = ErrorKind::classify(&e);
+                worst = worst.max(Some(kind));
+                if args.format == OutputFormat::Text {
+                    eprintln!("{}: {}", path.as_deref().unwrap_or("stdin"), e);
+                    continue;
+                }
+                if args.format == OutputFormat::Csv {
+                    csv_items.push(BatchItem {
+                        file_name: path,
+                        response: None,
+                        xmp: None,
+                        error: Some(e.to_string()),
+                    });
+                    continue;
+                }
+                serde_json::to_value(ErrorRecord {
+                    path,
+                    error: ErrorBody::new(kind, e.to_string()),
+                })
+            }
+        }
+        .expect("caption records always serialize");
+
+        match args.format {
+            OutputFormat::Ndjson => println!("{}", value),
+            OutputFormat::Json => json_results.push(value),
+            OutputFormat::Text | OutputFormat::Csv => unreachable!(),
+        }
+    }
+
+    if args.format == OutputFormat::Csv {
+        match export::to_csv(&csv_items) {
+            Ok(csv) => {
+                let _ = std::io::stdout().write_all(&csv);
+            }
+            Err(e) => {
+                let message = format!("CSV export error: {}", e);
+                return fail(args.format, ErrorKind::InvalidInput, &message);
+            }
+        }
+    }
+
+    if args.format == OutputFormat::Json {
+        let output = if multiple {
+            serde_json::Value::Array(json_results)
+        } else {
+            json_results.pop().unwrap_or_default()
+        };
+        println!("{}", serde_json::to_string_pretty(&output).expect("JSON values always serialize"));
+    }
+
+    worst.map_or(0, ErrorKind::exit_code)
+}
@@ -0,0 +1,68 @@
+// Fixed settings: the model captions are generated with and request limits.
+
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+pub(crate) const MODEL_LABEL: &str = "Google Gemini 1.5 Flash";
+
+/// Provider and exact model recorded in each caption's provenance.
+pub(crate) const PROVIDER: &str = "google-gemini";
+pub(crate) const MODEL_ID: &str = "gemini-2.5-flash";
+
+/// Upper bound on images per `/batch` request, and how many are captioned at once.
+pub(crate) const MAX_BATCH_IMAGES: usize = 20;
+pub(crate) const BATCH_CONCURRENCY: usize = 4;
+
+/// Provider calls the server makes at once, shared fairly between tenants, and interactive
+/// requests that may wait for one; defaults for PROVIDER_CONCURRENCY and PROVIDER_QUEUE_LIMIT.
+pub(crate) const PROVIDER_CONCURRENCY: usize = 8;
+pub(crate) const PROVIDER_QUEUE_LIMIT: usize = 64;
+
+/// Largest image file an upload form may carry; default for MAX_UPLOAD_MB (see `limits`).
+pub(crate) const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+/// Request body limit for `/batch`, which carries several full-size images.
+pub(crate) const BATCH_BODY_LIMIT: usize = 100 * 1024 * 1024;
+
+/// Upper bound on images per `/jobs` request, and its request body limit.
+pub(crate) const MAX_JOB_IMAGES: usize = 500;
+pub(crate) const JOB_BODY_LIMIT: usize = 500 * 1024 * 1024;
+
+/// Largest file for `/video`.
+pub(crate) const VIDEO_BODY_LIMIT: usize = 200 * 1024 * 1024;
+
+/// Largest file for `/pdf`.
+pub(crate) const PDF_BODY_LIMIT: usize = 100 * 1024 * 1024;
+
+/// Compressed size of a ZIP upload; inflated sizes are limited in `archive`.
+pub(crate) const ZIP_BODY_LIMIT: usize = 200 * 1024 * 1024;
+
+/// Default timeouts for one provider call, one request, and one request to a route that
+/// captions many images; see `timeouts` for the variables that override them.
+pub(crate) const PROVIDER_TIMEOUT: Duration = Duration::from_secs(60);
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+pub(crate) const BULK_REQUEST_TIMEOUT: Duration = Duration::from_secs(900);
+
+/// Where the web server listens unless HOST and PORT say otherwise.
+pub(crate) const DEFAULT_HOST: &str = "0.0.0.0";
+pub(crate) const DEFAULT_PORT: u16 = 3000;
+
+/// How long a shutdown waits for requests and jobs; see `shutdown` for SHUTDOWN_TIMEOUT_SECS.
+pub(crate) const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Requests per minute each client IP may make; see `ratelimit` for RATE_LIMIT_PER_MINUTE.
+pub(crate) const RATE_LIMIT_PER_MINUTE: u32 = 120;
+
+/// Whether to log provider requests and responses. Off by default so programs
+/// embedding the library stay quiet; the server and `caption` read LOG_PROVIDER_TRAFFIC
+/// (default on), and batch runs leave it off so the dump doesn't tear up the progress bar.
+pub(crate) static LOG_PROVIDER_TRAFFIC: AtomicBool = AtomicBool::new(false);
+
+/// Parses a boolean form field ("true"/"false", "1"/"0", "on"/"off").
+pub(crate) fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "on" | "yes" => Some(true),
+        "false" | "0" | "off" | "no" | "" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,149 @@
+// Contact sheets and film strips (the `contact_sheet` option): one image made of many panels.
+// The panels are found by their gutters, the plain bands of even color between them, cut out
+// and sent to the model after the whole sheet, which then captions each panel and the
+// sequence as a whole. Each panel's caption comes back with where it sits on the sheet.
+//
+// Gutters are found in two passes: rows that are even across the whole sheet split it into
+// strips, then columns that are even across a strip split the strip into panels. That covers
+// grids, single film strips and rows of different lengths; irregular comic layouts are left
+// for the model, which is told the panels couldn't be separated.
+
+use image::{DynamicImage, GrayImage};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::modes::ModeOutput;
+
+/// More panels than this is more likely texture than a sheet; the model gets the sheet alone.
+const MAX_PANELS: usize = 24;
+
+/// Largest spread of gray levels (standard deviation) a gutter line may have.
+const GUTTER_SPREAD: f64 = 6.0;
+
+/// Panels narrower or shorter than this fraction of the sheet are taken for noise.
+const MIN_PANEL_FRACTION: f64 = 0.05;
+
+/// Where a panel sits on the sheet, in pixels of the uploaded image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Panel {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+/// Ranges of `0..len` between runs of gutter lines, dropping pieces under `min_len`.
+fn split(len: u32, min_len: u32, is_gutter: impl Fn(u32) -> bool) -> Vec<(u32, u32)> {
+    let mut pieces = Vec::new();
+    let mut start = None;
+    for index in 0..=len {
+        let gutter = index == len || is_gutter(index);
+        match (start, gutter) {
+            (None, false) => start = Some(index),
+            (Some(from), true) => {
+                if index - from >= min_len {
+                    pieces.push((from, index));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    pieces
+}
+
+/// Whether the pixels along a line all have about the same gray level.
+fn even(pixels: impl Iterator<Item = u8>) -> bool {
+    let (mut count, mut sum, mut squares) = (0.0, 0.0, 0.0);
+    for pixel in pixels {
+        let value = f64::from(pixel);
+        count += 1.0;
+        sum += value;
+        squares += value * value;
+    }
+    if count == 0.0 {
+        return true;
+    }
+    let mean = sum / count;
+    (squares / count - mean * mean).max(0.0).sqrt() <= GUTTER_SPREAD
+}
+
+/// The panels of a contact sheet in reading order (left to right, top to bottom), or an
+/// empty list when fewer than two (or more than `MAX_PANELS`) can be told apart.
+pub(crate) fn find_panels(sheet: &DynamicImage) -> Vec<Panel> {
+    let gray: GrayImage = sheet.to_luma8();
+    let (width, height) = gray.dimensions();
+    let min_width = ((f64::from(width) * MIN_PANEL_FRACTION) as u32).max(8);
+    let min_height = ((f64::from(height) * MIN_PANEL_FRACTION) as u32).max(8);
+
+    let mut panels = Vec::new();
+    let strips = split(height, min_height, |y| {
+        even((0..width).map(|x| gray.get_pixel(x, y)[0]))
+    });
+    for (top, bottom) in strips {
+        let columns = split(width, min_width, |x| {
+            even((top..bottom).map(|y| gray.get_pixel(x, y)[0]))
+        });
+        panels.extend(columns.into_iter().map(|(left, right)| Panel {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        }));
+    }
+
+    if panels.len() < 2 || panels.len() > MAX_PANELS {
+        return Vec::new();
+    }
+    panels
+}
+
+/// The panel cut out of the sheet.
+pub(crate) fn crop(sheet: &DynamicImage, panel: Panel) -> DynamicImage {
+    sheet.crop_imm(panel.x, panel.y, panel.width, panel.height)
+}
+
+/// How the images sent along with the prompt relate to each other.
+pub(crate) fn prompt(panel_count: usize) -> String {
+    if panel_count == 0 {
+        return "The image is a contact sheet or film strip whose panels couldn't be separated \
+                automatically; find the panels yourself."
+            .to_string();
+    }
+    format!(
+        "The first image is a contact sheet or film strip. The {} images after it are its \
+         panels, cut out in reading order (left to right, top to bottom); give exactly one \
+         panel description per cut-out, in the same order.",
+        panel_count
+    )
+}
+
+/// Adds each panel's position on the sheet to its description in `output`.
+pub(crate) fn locate(output: &mut ModeOutput, panels: &[Panel]) {
+    let Some(Value::Array(items)) = output.details.get_mut("panels") else {
+        return;
+    };
+    for (item, panel) in items.iter_mut().zip(panels) {
+        if let (Value::Object(item), Value::Object(position)) =
+            (item, serde_json::to_value(panel).unwrap_or_default())
+        {
+            item.extend(position);
+        }
+    }
+}
+
+/// Panel descriptions from the model's `panels` list, before positions are known.
+pub(crate) fn descriptions(reply: &Value) -> Vec<Value> {
+    reply["panels"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(str::trim)
+        .map(|caption| {
+            let mut item = Map::new();
+            item.insert("caption".into(), caption.into());
+            Value::Object(item)
+        })
+        .collect()
+}
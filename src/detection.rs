@@ -0,0 +1,253 @@
+// Estimates whether an image is AI-generated, for moderation queues and newsrooms that need
+// to flag synthetic images. Two kinds of evidence are combined:
+//
+// - The model's own assessment, asked for in the same request as the caption (see
+//   `CaptionOptions::detect_ai`): a likelihood between 0 and 1 plus the visual cues behind it.
+// - Metadata: C2PA and XMP digital source types, generator names in EXIF Software, XMP
+//   CreatorTool or the C2PA claim generator, and the generation settings Stable Diffusion
+//   front ends write into PNG text chunks. Camera details point the other way.
+//
+// Metadata is easy to strip and to forge, so it only moves the score when present: a file
+// that declares itself generated is scored at least `DECLARED_FLOOR` whatever the model
+// thinks, and camera evidence caps the score. Neither proves anything; the score is a
+// triage signal, not a verdict.
+
+use serde::{Deserialize, Serialize};
+
+use crate::c2pa::{self, AiSignal, ContentCredentials};
+use crate::metadata;
+
+/// Lowest score for an image whose metadata says a generator made it.
+const DECLARED_FLOOR: f64 = 0.95;
+
+/// Lowest score for an image that names a generator tool or mixes in generated content.
+const GENERATOR_FLOOR: f64 = 0.85;
+
+/// Highest score for an image whose signed C2PA manifest says a camera captured it.
+const CAPTURE_CEILING: f64 = 0.2;
+
+/// Highest score for an image whose EXIF names the camera that took it.
+const CAMERA_CEILING: f64 = 0.6;
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_SOFTWARE: u16 = 0x0131;
+
+/// Image generators, matched case-insensitively against tool names in the metadata.
+const GENERATORS: &[&str] = &[
+    "midjourney",
+    "dall-e",
+    "dall·e",
+    "stable diffusion",
+    "comfyui",
+    "invokeai",
+    "novelai",
+    "firefly",
+    "imagen",
+    "ideogram",
+    "leonardo.ai",
+];
+
+/// What the model said about the image, parsed from the caption reply.
+#[derive(Clone, Debug)]
+pub struct ModelAssessment {
+    pub likelihood: f64,
+    pub indicators: Vec<String>,
+}
+
+/// The combined estimate returned with a caption.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AiDetection {
+    /// Likelihood that the image is AI-generated, from 0 (not at all) to 1 (certainly).
+    pub score: f64,
+    /// The model's own likelihood, before metadata was taken into account.
+    pub model_score: f64,
+    /// Why: metadata findings first, then the visual cues the model noticed.
+    pub reasons: Vec<String>,
+}
+
+/// One metadata finding and how it bounds the score.
+struct Evidence {
+    reason: String,
+    bound: Bound,
+}
+
+enum Bound {
+    AtLeast(f64),
+    AtMost(f64),
+}
+
+fn generator_in(text: &str) -> bool {
+    let text = text.to_lowercase();
+    GENERATORS.iter().any(|generator| text.contains(generator))
+}
+
+/// The text of a PNG `tEXt` or uncompressed `iTXt` chunk, as (keyword, text).
+fn png_text(kind: &[u8], chunk: &[u8]) -> Option<(String, String)> {
+    let nul = chunk.iter().position(|&b| b == 0)?;
+    let keyword = String::from_utf8_lossy(&chunk[..nul]).into_owned();
+    let rest = &chunk[nul + 1..];
+    let text = match kind {
+        b"tEXt" => rest,
+        b"iTXt" if rest.first() == Some(&0) => {
+            // Compression flag and method, then NUL-terminated language and translated keyword.
+            let rest = rest.get(2..)?;
+            let language = rest.iter().position(|&b| b == 0)?;
+            let rest = &rest[language + 1..];
+            let translated = rest.iter().position(|&b| b == 0)?;
+            &rest[translated + 1..]
+        }
+        _ => return None,
+    };
+    Some((keyword, String::from_utf8_lossy(text).into_owned()))
+}
+
+fn png_text_chunks(data: &[u8]) -> Vec<(String, String)> {
+    let mut chunks = Vec::new();
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return chunks;
+    }
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let Some(chunk) = data.get(offset + 8..offset + 8 + length) else {
+            break;
+        };
+        chunks.extend(png_text(&header[4..], chunk));
+        offset += 12 + length;
+    }
+    chunks
+}
+
+/// Generation settings left in PNG text chunks by Stable Diffusion front ends.
+fn generation_settings(data: &[u8]) -> Option<String> {
+    png_text_chunks(data)
+        .into_iter()
+        .find_map(|(keyword, text)| {
+            let found = match keyword.as_str() {
+                // AUTOMATIC1111 and Forge: the prompt followed by "Steps: 20, Sampler: ..."
+                "parameters" => text.contains("Steps:") && text.contains("Seed:"),
+                // ComfyUI: the node graph as JSON.
+                "prompt" | "workflow" => text.contains("class_type") || text.contains("\"nodes\""),
+                "invokeai_metadata" | "sd-metadata" | "Dream" => true,
+                _ => false,
+            };
+            found.then_some(keyword)
+        })
+}
+
+/// The XMP CreatorTool, written either as an attribute or as an element.
+fn xmp_creator_tool(data: &[u8]) -> Option<String> {
+    let packet = c2pa::xmp_packet(data)?;
+    let field = c2pa::find(packet, b"CreatorTool")?;
+    let after = &packet[field + "CreatorTool".len()..];
+    let after = after
+        .strip_prefix(b"=\"")
+        .or_else(|| after.strip_prefix(b">"))?;
+    let end = after.iter().position(|&b| b == b'"' || b == b'<')?;
+    let tool = String::from_utf8_lossy(&after[..end]).trim().to_string();
+    (!tool.is_empty()).then_some(tool)
+}
+
+fn metadata_evidence(
+    data: &[u8],
+    credentials: Option<&ContentCredentials>,
+    signals: &[AiSignal],
+) -> Vec<Evidence> {
+    let mut evidence = Vec::new();
+
+    for signal in signals {
+        let floor = if signal.value.starts_with("composite") {
+            GENERATOR_FLOOR
+        } else {
+            DECLARED_FLOOR
+        };
+        evidence.push(Evidence {
+            reason: format!(
+                "{} metadata declares the image {}",
+                signal.source.to_uppercase(),
+                signal.value
+            ),
+            bound: Bound::AtLeast(floor),
+        });
+    }
+
+    if let Some(keyword) = generation_settings(data) {
+        evidence.push(Evidence {
+            reason: format!(
+                "PNG text chunk '{}' holds image generation settings",
+                keyword
+            ),
+            bound: Bound::AtLeast(DECLARED_FLOOR),
+        });
+    }
+
+    let [make, model, software] = metadata::exif_text(data, [TAG_MAKE, TAG_MODEL, TAG_SOFTWARE]);
+    let tools = [
+        (
+            "C2PA claim generator",
+            credentials.and_then(|c| c.claim_generator.clone()),
+        ),
+        ("EXIF Software", software),
+        ("XMP CreatorTool", xmp_creator_tool(data)),
+    ];
+    for (field, tool) in tools {
+        if let Some(tool) = tool.filter(|tool| generator_in(tool)) {
+            evidence.push(Evidence {
+                reason: format!("{} names an image generator: {}", field, tool),
+                bound: Bound::AtLeast(GENERATOR_FLOOR),
+            });
+        }
+    }
+
+    let source_type = credentials.and_then(|c| c.digital_source_type.as_deref());
+    if source_type == Some("digitalCapture") {
+        evidence.push(Evidence {
+            reason: "C2PA manifest declares a digital capture from a camera".to_string(),
+            bound: Bound::AtMost(CAPTURE_CEILING),
+        });
+    }
+
+    let camera: Vec<String> = [make, model].into_iter().flatten().collect();
+    if !camera.is_empty() {
+        evidence.push(Evidence {
+            reason: format!("EXIF names the camera: {}", camera.join(" ")),
+            bound: Bound::AtMost(CAMERA_CEILING),
+        });
+    }
+
+    evidence
+}
+
+impl AiDetection {
+    /// Combines the model's assessment with what the image's metadata says. Ceilings are
+    /// applied before floors, so a declared generator wins over camera details.
+    pub(crate) fn new(
+        assessment: ModelAssessment,
+        data: &[u8],
+        credentials: Option<&ContentCredentials>,
+        signals: &[AiSignal],
+    ) -> Self {
+        let evidence = metadata_evidence(data, credentials, signals);
+
+        let mut score = assessment.likelihood;
+        for item in &evidence {
+            if let Bound::AtMost(ceiling) = item.bound {
+                score = score.min(ceiling);
+            }
+        }
+        for item in &evidence {
+            if let Bound::AtLeast(floor) = item.bound {
+                score = score.max(floor);
+            }
+        }
+
+        let mut reasons: Vec<String> = evidence.into_iter().map(|item| item.reason).collect();
+        reasons.extend(assessment.indicators);
+        AiDetection {
+            score: (score * 100.0).round() / 100.0,
+            model_score: assessment.likelihood,
+            reasons,
+        }
+    }
+}
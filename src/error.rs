@@ -0,0 +1,271 @@
+// The crate's error type. Everything from reading an upload to calling the provider and
+// translating the result fails with a `CaptionError`, which knows its HTTP status and is
+// sent to clients as a JSON body:
+//
+//   {"code": "rate_limited", "message": "...", "retryable": true, "request_id": "..."}
+//
+// `retryable` tells clients whether sending the same request again may succeed. While the
+// provider circuit breaker is open, the 503 also carries a `Retry-After` header, and so does
+// the 429 for a full provider queue. An upload over a size limit gets a 413 whose `limit_bytes`
+// is the limit.
+
+use axum::{
+    extract::multipart::MultipartError,
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::request_id;
+
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum CaptionError {
+    /// A malformed request: an unreadable form, header or query parameter, or no image.
+    #[error("{0}")]
+    BadRequest(String),
+    /// A well-formed request that can't be carried out, e.g. an out-of-range option.
+    #[error("{0}")]
+    Unprocessable(String),
+    /// The content policy doesn't allow describing the image (see `policy`).
+    #[error("{0}")]
+    Refused(String),
+    #[error("{0}")]
+    TooLarge(String),
+    /// An upload over one of its size limits (see `limits`), which `limit_bytes` gives.
+    #[error("{message}")]
+    UploadTooLarge { message: String, limit_bytes: u64 },
+    /// The upload isn't in a format that can be read.
+    #[error("{0}")]
+    UnsupportedMedia(String),
+    #[error("{0}")]
+    NotFound(String),
+    /// Missing or wrong admin token or API key.
+    #[error("{0}")]
+    Unauthorized(String),
+    /// A valid API key that isn't allowed to do this, e.g. caption in a mode it doesn't have.
+    #[error("{0}")]
+    Forbidden(String),
+    /// The API key has used up its quota for the day.
+    #[error("{0}")]
+    QuotaExceeded(String),
+    /// The provider is limiting requests or the quota is used up.
+    #[error("{0}")]
+    RateLimited(String),
+    /// The provider couldn't be reached or rejected the request; `status` is its HTTP
+    /// status when it answered.
+    #[error("{message}")]
+    Provider {
+        status: Option<u16>,
+        message: String,
+    },
+    /// The provider has been failing and the circuit breaker is open; calls fail at once
+    /// until `retry_after` has passed.
+    #[error(
+        "The provider is unavailable after repeated failures; try again in {} s",
+        retry_after_secs(*retry_after)
+    )]
+    ProviderUnavailable { retry_after: Duration },
+    /// Too many requests are already waiting for a provider slot.
+    #[error(
+        "Too many requests are waiting for the provider; try again in {} s",
+        retry_after_secs(*retry_after)
+    )]
+    Busy { retry_after: Duration },
+    /// The provider didn't answer in time, or the whole request ran over its time limit.
+    #[error("{0}")]
+    Timeout(String),
+    /// The provider answered, but not with anything usable.
+    #[error("Unusable reply from the provider: {0}")]
+    InvalidReply(String),
+    /// A tool the request needs isn't installed, e.g. ffmpeg for video.
+    #[error("{0}")]
+    Unavailable(String),
+    /// A setting the request needs is missing, e.g. a translation API key.
+    #[error("{0}")]
+    Config(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// Whole seconds for a `Retry-After` header, never 0.
+fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    retryable: bool,
+    /// The request's ID, to quote when reporting the error (see `request_id`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// The size limit an upload went over, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit_bytes: Option<u64>,
+}
+
+/// The message inside a Google API error body, or the body itself, shortened.
+fn provider_message(body: &str) -> String {
+    let message = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|reply| reply["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.trim().to_string());
+    crate::locale::truncate(&message, 300, None)
+}
+
+impl CaptionError {
+    /// An unsuccessful response from `service` (the captioning or a translation provider).
+    pub(crate) fn from_response(service: &str, status: reqwest::StatusCode, body: &str) -> Self {
+        let message = format!(
+            "{} returned {}: {}",
+            service,
+            status,
+            provider_message(body)
+        );
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            CaptionError::RateLimited(message)
+        } else {
+            CaptionError::Provider {
+                status: Some(status.as_u16()),
+                message,
+            }
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            CaptionError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            CaptionError::Unprocessable(_) | CaptionError::Refused(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            CaptionError::TooLarge(_) | CaptionError::UploadTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            CaptionError::UnsupportedMedia(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            CaptionError::NotFound(_) => StatusCode::NOT_FOUND,
+            CaptionError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            CaptionError::Forbidden(_) => StatusCode::FORBIDDEN,
+            CaptionError::RateLimited(_)
+            | CaptionError::QuotaExceeded(_)
+            | CaptionError::Busy { .. } => StatusCode::TOO_MANY_REQUESTS,
+            CaptionError::Provider { .. } | CaptionError::InvalidReply(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            CaptionError::ProviderUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            CaptionError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            CaptionError::Unavailable(_) => StatusCode::NOT_IMPLEMENTED,
+            CaptionError::Config(_) | CaptionError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Stable machine-readable name for the error body's `code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CaptionError::BadRequest(_) => "bad_request",
+            CaptionError::Unprocessable(_) => "unprocessable",
+            CaptionError::Refused(_) => "content_refused",
+            CaptionError::TooLarge(_) | CaptionError::UploadTooLarge { .. } => {
+                "payload_too_large"
+            }
+            CaptionError::UnsupportedMedia(_) => "unsupported_media_type",
+            CaptionError::NotFound(_) => "not_found",
+            CaptionError::Unauthorized(_) => "unauthorized",
+            CaptionError::Forbidden(_) => "forbidden",
+            CaptionError::RateLimited(_) => "rate_limited",
+            CaptionError::QuotaExceeded(_) => "quota_exceeded",
+            CaptionError::Provider { .. } => "provider_error",
+            CaptionError::ProviderUnavailable { .. } => "provider_unavailable",
+            CaptionError::Busy { .. } => "busy",
+            CaptionError::Timeout(_) => "timeout",
+            CaptionError::InvalidReply(_) => "invalid_reply",
+            CaptionError::Unavailable(_) => "not_implemented",
+            CaptionError::Config(_) => "config_error",
+            CaptionError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Whether the same request may succeed if sent again later. Provider failures are
+    /// retryable unless the provider rejected the request itself (a 4xx other than 429).
+    pub fn retryable(&self) -> bool {
+        match self {
+            CaptionError::RateLimited(_)
+            | CaptionError::ProviderUnavailable { .. }
+            | CaptionError::Busy { .. }
+            | CaptionError::Timeout(_)
+            | CaptionError::InvalidReply(_) => true,
+            CaptionError::Provider { status, .. } => status.is_none_or(|status| status >= 500),
+            _ => false,
+        }
+    }
+}
+
+impl IntoResponse for CaptionError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            retryable: self.retryable(),
+            request_id: request_id::current(),
+            limit_bytes: match self {
+                CaptionError::UploadTooLarge { limit_bytes, .. } => Some(limit_bytes),
+                _ => None,
+            },
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let CaptionError::ProviderUnavailable { retry_after }
+        | CaptionError::Busy { retry_after } = self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after_secs(retry_after).into());
+        }
+        response
+    }
+}
+
+impl From<image::ImageError> for CaptionError {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            image::ImageError::Decoding(_) | image::ImageError::Unsupported(_) => {
+                CaptionError::UnsupportedMedia(e.to_string())
+            }
+            // Images are read from memory, so these come from uploads that are cut short or
+            // corrupt rather than from the server.
+            image::ImageError::IoError(_) | image::ImageError::Parameter(_) => {
+                CaptionError::UnsupportedMedia(format!("The image is damaged or incomplete: {}", e))
+            }
+            image::ImageError::Limits(_) => CaptionError::TooLarge(e.to_string()),
+            _ => CaptionError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for CaptionError {
+    fn from(e: reqwest::Error) -> Self {
+        // The URL can carry the API key as a query parameter.
+        CaptionError::Provider {
+            status: e.status().map(|status| status.as_u16()),
+            message: e.without_url().to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for CaptionError {
+    fn from(e: serde_json::Error) -> Self {
+        CaptionError::InvalidReply(e.to_string())
+    }
+}
+
+impl From<MultipartError> for CaptionError {
+    fn from(e: MultipartError) -> Self {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            CaptionError::TooLarge(e.body_text())
+        } else {
+            CaptionError::BadRequest(e.body_text())
+        }
+    }
+}
@@ -0,0 +1,52 @@
+// Conditional requests: whether the copy a client has, by the entity tags it sends in
+// If-None-Match, is still current (RFC 9110, 13.1.2), so it can be answered `304 Not Modified`.
+
+use axum::http::{header, HeaderMap};
+
+/// Whether `If-None-Match` names the entity tag `"tag"`: `*`, or a list of tags compared
+/// weakly, so `W/"tag"` matches too. A header that can't be read matches nothing.
+pub(crate) fn none_match(headers: &HeaderMap, tag: &str) -> bool {
+    let values = headers.get_all(header::IF_NONE_MATCH);
+    values.iter().filter_map(|value| value.to_str().ok()).any(|value| lists(value, tag))
+}
+
+/// Whether the `If-None-Match` value `value` is `*` or lists `tag`.
+fn lists(value: &str, tag: &str) -> bool {
+    if value.trim() == "*" {
+        return true;
+    }
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return false;
+        }
+        // An opaque tag may hold commas, so the list is read tag by tag rather than split.
+        let quoted = rest.strip_prefix("W/").unwrap_or(rest);
+        let Some((opaque, after)) = quoted.strip_prefix('"').and_then(|q| q.split_once('"'))
+        else {
+            return false;
+        };
+        if opaque == tag {
+            return true;
+        }
+        rest = after;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_compared_weakly_across_the_list() {
+        assert!(lists("\"v3\"", "v3"));
+        assert!(lists("W/\"v3\"", "v3"));
+        assert!(lists("\"a,b\", W/\"c\" ,\"v3\"", "v3"));
+        assert!(lists(" * ", "v3"));
+        assert!(!lists("\"v30\"", "v3"));
+        assert!(!lists("\"a,v3\"", "v3"));
+        assert!(!lists("v3", "v3"));
+        assert!(!lists("", "v3"));
+    }
+}
@@ -0,0 +1,172 @@
+// Spreadsheet- and DAM-friendly exports of `/batch` results: CSV or JSON Lines with one
+// row per image (filename, caption, tags, latency, model) plus the caption's provenance.
+
+use serde::Serialize;
+
+use std::time::Duration;
+
+use crate::subtitles::{self, TrackFormat};
+use crate::handlers::BatchItem;
+use crate::Provenance;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Jsonl,
+    Csv,
+    /// Uploads treated as evenly spaced frames of a sequence.
+    Track(TrackFormat),
+}
+
+impl ExportFormat {
+    /// Picks the format from an explicit `format` parameter, falling back to the Accept
+    /// header and then to the regular JSON response.
+    pub fn negotiate(param: Option<&str>, accept: Option<&str>) -> Result<Self, String> {
+        if let Some(param) = param {
+            return match param.trim().to_ascii_lowercase().as_str() {
+                "json" => Ok(ExportFormat::Json),
+                "jsonl" | "ndjson" => Ok(ExportFormat::Jsonl),
+                "csv" => Ok(ExportFormat::Csv),
+                "srt" => Ok(ExportFormat::Track(TrackFormat::Srt)),
+                "vtt" => Ok(ExportFormat::Track(TrackFormat::Vtt)),
+                other => Err(format!(
+                    "unknown format '{}' (expected one of: json, jsonl, csv, srt, vtt)",
+                    other
+                )),
+            };
+        }
+
+        let accept = accept.unwrap_or_default().to_ascii_lowercase();
+        let format = if accept.contains("text/csv") {
+            ExportFormat::Csv
+        } else if accept.contains("text/vtt") {
+            ExportFormat::Track(TrackFormat::Vtt)
+        } else if ["application/x-ndjson", "application/jsonl", "application/x-jsonlines"]
+            .iter()
+            .any(|mime| accept.contains(mime))
+        {
+            ExportFormat::Jsonl
+        } else {
+            ExportFormat::Json
+        };
+        Ok(format)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Jsonl => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Track(track) => track.content_type(),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Track(track) => track.extension(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExportRow<'a> {
+    filename: &'a str,
+    caption: &'a str,
+    tags: Vec<&'a str>,
+    latency_ms: Option<u64>,
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance: Option<&'a Provenance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+impl<'a> From<&'a BatchItem> for ExportRow<'a> {
+    fn from(item: &'a BatchItem) -> Self {
+        let response = item.response.as_ref();
+        ExportRow {
+            filename: item.file_name.as_deref().unwrap_or_default(),
+            caption: response.map_or("", |response| response.caption.as_str()),
+            tags: response.map(|response| response.tags()).unwrap_or_default(),
+            latency_ms: response.map(|response| response.processing_time_ms),
+            model: response.map_or("", |response| response.model.as_str()),
+            provenance: response.and_then(|response| response.provenance.as_ref()),
+            error: item.error.as_deref(),
+        }
+    }
+}
+
+/// One JSON object per line.
+pub fn to_jsonl(items: &[BatchItem]) -> String {
+    items
+        .iter()
+        .map(|item| {
+            let row = serde_json::to_string(&ExportRow::from(item)).expect("rows always serialize");
+            row + "\n"
+        })
+        .collect()
+}
+
+const CSV_HEADER: &[&str] = &[
+    "filename",
+    "caption",
+    "tags",
+    "latency_ms",
+    "model",
+    "error",
+    "content_hash",
+    "provider",
+    "model_id",
+    "mode",
+    "prompt_version",
+    "prompt_hash",
+    "crate_version",
+    "generated_at",
+];
+
+/// A header row plus one row per image; tags are joined with "; " into a single cell and
+/// provenance is spread over its own columns.
+pub fn to_csv(items: &[BatchItem]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(CSV_HEADER)?;
+
+    for item in items {
+        let row = ExportRow::from(item);
+        let mut record = vec![
+            row.filename.to_string(),
+            row.caption.to_string(),
+            row.tags.join("; "),
+            row.latency_ms.map(|ms| ms.to_string()).unwrap_or_default(),
+            row.model.to_string(),
+            row.error.unwrap_or_default().to_string(),
+        ];
+        match row.provenance {
+            Some(provenance) => record.extend([
+                provenance.content_hash.clone(),
+                provenance.provider.clone(),
+                provenance.model.clone(),
+                provenance.mode.to_string(),
+                provenance.prompt_version.to_string(),
+                provenance.prompt_hash.clone(),
+                provenance.crate_version.clone(),
+                provenance.generated_at.clone(),
+            ]),
+            None => record.resize(CSV_HEADER.len(), String::new()),
+        }
+        writer.write_record(&record)?;
+    }
+
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// A caption track with one cue per uploaded frame, `interval` apart.
+pub fn to_track(items: &[BatchItem], format: TrackFormat, interval: Duration) -> String {
+    let captions: Vec<Option<&str>> = items
+        .iter()
+        .map(|item| item.response.as_ref().map(|response| response.caption.as_str()))
+        .collect();
+    subtitles::render(&subtitles::frame_segments(&captions, interval), format)
+}
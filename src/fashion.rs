@@ -0,0 +1,147 @@
+// Garment attributes for catalog enrichment (the `fashion` mode). The model picks each
+// attribute from a fixed vocabulary, so the values can feed catalog facets and filters
+// directly; anything outside it is dropped rather than passed through.
+//
+// The vocabulary is built in and can be replaced per attribute with a JSON file named by
+// FASHION_ATTRIBUTES_FILE, e.g. to match a shop's own taxonomy:
+//
+//   {"garment_type": ["tee", "hoodie", "parka"], "style_tags": ["gorpcore", "quiet_luxury"]}
+//
+// Attributes left out of the file keep the built-in terms. Terms are compared in lowercase
+// with spaces and dashes as underscores. The server checks the file at startup and re-reads it
+// on SIGHUP; the CLI reads it once.
+
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, RwLock};
+
+const GARMENT_TYPES: &[&str] = &[
+    "t_shirt", "shirt", "blouse", "sweater", "hoodie", "cardigan", "jacket", "coat", "blazer",
+    "vest", "dress", "skirt", "jeans", "trousers", "shorts", "leggings", "jumpsuit", "suit",
+    "swimwear", "underwear", "sleepwear", "sneakers", "boots", "sandals", "heels", "flats",
+    "bag", "hat", "scarf", "belt", "jewelry", "eyewear", "other",
+];
+
+const COLORS: &[&str] = &[
+    "black", "white", "grey", "beige", "brown", "red", "pink", "orange", "yellow", "green",
+    "blue", "navy", "purple", "gold", "silver", "multicolor",
+];
+
+const PATTERNS: &[&str] = &[
+    "solid", "striped", "checked", "plaid", "floral", "polka_dot", "animal_print", "camouflage",
+    "geometric", "paisley", "graphic", "abstract", "tie_dye", "colorblock",
+];
+
+const MATERIALS: &[&str] = &[
+    "cotton", "denim", "linen", "wool", "cashmere", "silk", "satin", "polyester", "nylon",
+    "leather", "faux_leather", "suede", "knit", "fleece", "velvet", "corduroy", "lace", "chiffon",
+    "sequin", "rubber", "canvas",
+];
+
+const STYLE_TAGS: &[&str] = &[
+    "casual", "formal", "business", "streetwear", "sporty", "bohemian", "vintage", "minimalist",
+    "preppy", "elegant", "edgy", "romantic", "workwear", "loungewear", "outdoor", "party",
+    "beachwear", "classic", "oversized", "fitted", "cropped",
+];
+
+/// Most style tags returned per garment.
+pub(crate) const MAX_STYLE_TAGS: usize = 5;
+
+static SCHEMA: LazyLock<RwLock<Arc<AttributeSchema>>> = LazyLock::new(|| {
+    let schema = load().unwrap_or_else(|e| {
+        tracing::warn!("{}; using the built-in fashion attributes", e);
+        AttributeSchema::default()
+    });
+    RwLock::new(Arc::new(schema))
+});
+
+/// The terms each attribute may take.
+#[derive(Debug)]
+pub(crate) struct AttributeSchema {
+    pub(crate) garment_types: Vec<String>,
+    pub(crate) colors: Vec<String>,
+    pub(crate) patterns: Vec<String>,
+    pub(crate) materials: Vec<String>,
+    pub(crate) style_tags: Vec<String>,
+}
+
+impl Default for AttributeSchema {
+    fn default() -> Self {
+        let terms = |list: &[&str]| list.iter().map(|term| term.to_string()).collect();
+        AttributeSchema {
+            garment_types: terms(GARMENT_TYPES),
+            colors: terms(COLORS),
+            patterns: terms(PATTERNS),
+            materials: terms(MATERIALS),
+            style_tags: terms(STYLE_TAGS),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SchemaFile {
+    garment_type: Option<Vec<String>>,
+    color: Option<Vec<String>>,
+    pattern: Option<Vec<String>>,
+    material: Option<Vec<String>>,
+    style_tags: Option<Vec<String>>,
+}
+
+fn normalize(term: &str) -> String {
+    term.trim().to_lowercase().replace([' ', '-'], "_")
+}
+
+/// `value` as one of `terms`, if it is one.
+pub(crate) fn pick(terms: &[String], value: &str) -> Option<String> {
+    let value = normalize(value);
+    terms.contains(&value).then_some(value)
+}
+
+/// Reads FASHION_ATTRIBUTES_FILE, or the built-in vocabulary when it isn't set.
+fn load() -> Result<AttributeSchema, String> {
+    let Some(path) = crate::settings::var("FASHION_ATTRIBUTES_FILE")
+        .ok()
+        .filter(|path| !path.trim().is_empty())
+    else {
+        return Ok(AttributeSchema::default());
+    };
+    let path = Path::new(&path);
+    let json = std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let file: SchemaFile = serde_json::from_slice(&json)
+        .map_err(|e| format!("{} is not valid: {}", path.display(), e))?;
+
+    let mut schema = AttributeSchema::default();
+    for (name, terms, target) in [
+        ("garment_type", file.garment_type, &mut schema.garment_types),
+        ("color", file.color, &mut schema.colors),
+        ("pattern", file.pattern, &mut schema.patterns),
+        ("material", file.material, &mut schema.materials),
+        ("style_tags", file.style_tags, &mut schema.style_tags),
+    ] {
+        let Some(terms) = terms else { continue };
+        let mut normalized: Vec<String> = Vec::new();
+        for term in terms.iter().map(|term| normalize(term)) {
+            if !term.is_empty() && !normalized.contains(&term) {
+                normalized.push(term);
+            }
+        }
+        if normalized.is_empty() {
+            return Err(format!("{}: {} lists no terms", path.display(), name));
+        }
+        *target = normalized;
+    }
+    Ok(schema)
+}
+
+/// (Re-)reads FASHION_ATTRIBUTES_FILE; the current vocabulary stays if it can't be read.
+pub fn reload() -> Result<(), String> {
+    let schema = load()?;
+    *SCHEMA.write().expect("fashion schema lock poisoned") = Arc::new(schema);
+    Ok(())
+}
+
+/// The vocabulary in use.
+pub(crate) fn attributes() -> Arc<AttributeSchema> {
+    SCHEMA.read().expect("fashion schema lock poisoned").clone()
+}
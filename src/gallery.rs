@@ -0,0 +1,20 @@
+// The `/gallery` page: the caller's caption history as a grid of thumbnails and captions, newest
+// first, with a button to load older ones, one to delete each and a search box for
+// `GET /search`. It reads `GET /history`, so it
+// needs a history configured and, when the server has API keys, asks for one like the upload
+// page. Thumbnails are fetched with the key and shown from blob URLs, since an <img> can't send
+// it.
+
+use askama::Template;
+use axum::response::Html;
+
+use crate::{assets, CaptionError};
+
+#[derive(Template)]
+#[template(path = "gallery.html")]
+struct GalleryPage;
+
+/// `GET /gallery`
+pub(crate) async fn gallery() -> Result<Html<String>, CaptionError> {
+    assets::render(&GalleryPage)
+}
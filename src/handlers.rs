@@ -0,0 +1,1233 @@
+// HTTP handlers for the web server: single and batch uploads, embedding, XMP sidecars,
+// ZIP archives, background jobs, PDFs, video (uploads and frames from URLs) and server status,
+// plus the upload page.
+
+use askama::Template;
+use axum::{
+    extract::{Multipart, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Json, Response},
+};
+use base64::{engine::general_purpose, Engine as _};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::config::{
+    parse_flag, BATCH_CONCURRENCY, MAX_BATCH_IMAGES, MAX_JOB_IMAGES, MODEL_ID, MODEL_LABEL,
+};
+use crate::limits::{self, Upload};
+use crate::modes::CaptionOptions;
+use crate::providers::generate_text;
+use crate::server::AppState;
+use crate::session::ProviderSession;
+use crate::spool::{self, Spooled, Spooler};
+use crate::{
+    archive, assets, breaker, buffers, export, jobs, listing, metadata, pdf, pipeline, request_id,
+    sanitize, scheduler, subtitles, uploads, usage, video, xmp, CaptionError, CaptionResponse,
+    Timings,
+};
+
+/// An uploaded image file.
+struct UploadedImage {
+    data: Spooled,
+    file_name: Option<String>,
+}
+
+/// Uploaded images plus the captioning options sent alongside them.
+struct UploadForm {
+    images: Vec<UploadedImage>,
+    options: CaptionOptions,
+    /// Also return an XMP sidecar for each image (`/batch` only).
+    xmp: bool,
+    /// How the listing should sound and how long it should be (`/listing` only).
+    tone: listing::Tone,
+    length: listing::Length,
+}
+
+/// The file field an endpoint's form takes, how many files it may hold, what limits their
+/// size and the options that go with them.
+struct FileField {
+    name: &'static str,
+    max: usize,
+    upload: Upload,
+    options: &'static [&'static str],
+}
+
+/// The options of the endpoints that caption each file they get.
+const CAPTION_OPTIONS: &[&str] = &[
+    "mode",
+    "count",
+    "confidence",
+    "detect_ai",
+    "scene",
+    "observation",
+    "contact_sheet",
+    "exif_context",
+    "verbose",
+    "hide_location",
+    "language",
+    "prompt",
+];
+
+/// The caption options, plus an XMP sidecar for each image.
+const BATCH_OPTIONS: &[&str] = &[
+    "mode",
+    "count",
+    "confidence",
+    "detect_ai",
+    "scene",
+    "observation",
+    "contact_sheet",
+    "exif_context",
+    "verbose",
+    "hide_location",
+    "language",
+    "prompt",
+    "xmp",
+];
+
+/// `/listing` captions its photos in the `real_estate` mode; only how the listing reads is up
+/// to the client.
+const LISTING_OPTIONS: &[&str] = &["verbose", "language", "tone", "length"];
+
+/// One image, as `/upload`, `/embed` and `/xmp` take it.
+const IMAGE: FileField = FileField {
+    name: "image",
+    max: 1,
+    upload: Upload::Image,
+    options: CAPTION_OPTIONS,
+};
+
+/// The images of `/batch`.
+const IMAGES: FileField = FileField {
+    name: "images",
+    max: MAX_BATCH_IMAGES,
+    upload: Upload::Batch,
+    options: BATCH_OPTIONS,
+};
+
+/// The photos of `/listing`.
+const LISTING_IMAGES: FileField = FileField {
+    options: LISTING_OPTIONS,
+    ..IMAGES
+};
+
+const JOB_IMAGES: FileField = FileField {
+    name: "images",
+    max: MAX_JOB_IMAGES,
+    upload: Upload::Job,
+    options: BATCH_OPTIONS,
+};
+
+/// The ZIP file of `/zip`.
+const ARCHIVE: FileField = FileField {
+    name: "archive",
+    max: 1,
+    upload: Upload::Zip,
+    options: BATCH_OPTIONS,
+};
+
+const PDF: FileField = FileField {
+    name: "file",
+    max: 1,
+    upload: Upload::Pdf,
+    options: CAPTION_OPTIONS,
+};
+
+const VIDEO: FileField = FileField {
+    name: "video",
+    max: 1,
+    upload: Upload::Video,
+    options: CAPTION_OPTIONS,
+};
+
+/// Longest `prompt` an upload may add, in characters.
+const MAX_PROMPT_CHARS: usize = 2000;
+
+fn not_a_flag(field: &str) -> CaptionError {
+    CaptionError::BadRequest(format!("{} must be true or false", field))
+}
+
+impl UploadForm {
+    /// Sets the option `name` from a form field, or from the `options` JSON as text. Each
+    /// option may be given once, either way, and only if it is one of `allowed`.
+    fn set(
+        &mut self,
+        name: &str,
+        value: &str,
+        allowed: &[&str],
+        seen: &mut Vec<String>,
+    ) -> Result<(), CaptionError> {
+        if !allowed.contains(&name) {
+            return Err(CaptionError::BadRequest(format!(
+                "unknown option '{}'; the options are {}",
+                name,
+                allowed.join(", ")
+            )));
+        }
+        if seen.iter().any(|option| option == name) {
+            return Err(CaptionError::BadRequest(format!("{} is given more than once", name)));
+        }
+        seen.push(name.to_string());
+
+        let flag = || parse_flag(value).ok_or_else(|| not_a_flag(name));
+        let options = &mut self.options;
+        match name {
+            "mode" => options.mode = value.parse().map_err(CaptionError::BadRequest)?,
+            "count" => {
+                let count = value.trim().parse().map_err(|_| {
+                    CaptionError::BadRequest(format!("count must be a number, not '{}'", value))
+                })?;
+                options.count = Some(count);
+            }
+            "confidence" => options.confidence = flag()?,
+            "detect_ai" => options.detect_ai = flag()?,
+            "scene" => options.scene = flag()?,
+            "observation" => options.observation = flag()?,
+            "contact_sheet" => options.contact_sheet = flag()?,
+            "exif_context" => options.exif_context = flag()?,
+            "verbose" => options.verbose = flag()?,
+            "hide_location" => options.hide_location = flag()?,
+            "language" => {
+                let value = value.trim();
+                options.language = (!value.is_empty()).then(|| value.to_string());
+            }
+            "prompt" => {
+                let value = value.trim();
+                if value.chars().count() > MAX_PROMPT_CHARS {
+                    let message = format!("prompt must be at most {} characters", MAX_PROMPT_CHARS);
+                    return Err(CaptionError::BadRequest(message));
+                }
+                options.instructions = (!value.is_empty()).then(|| value.to_string());
+            }
+            "xmp" => self.xmp = flag()?,
+            "tone" => {
+                self.tone = listing::parse_choice(name, value).map_err(CaptionError::BadRequest)?;
+            }
+            "length" => {
+                self.length = listing::parse_choice(name, value).map_err(CaptionError::BadRequest)?;
+            }
+            _ => unreachable!("{} is an option of no endpoint", name),
+        }
+        Ok(())
+    }
+
+    /// Sets the options in `json`, an object of option names and values.
+    fn set_all(
+        &mut self,
+        json: &str,
+        allowed: &[&str],
+        seen: &mut Vec<String>,
+    ) -> Result<(), CaptionError> {
+        let options: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)
+            .map_err(|e| {
+                CaptionError::BadRequest(format!("options must be a JSON object: {}", e))
+            })?;
+        for (name, value) in options {
+            let value = match value {
+                serde_json::Value::Null => continue,
+                serde_json::Value::String(text) => text,
+                serde_json::Value::Bool(flag) => flag.to_string(),
+                serde_json::Value::Number(number) => number.to_string(),
+                _ => {
+                    return Err(CaptionError::BadRequest(format!(
+                        "options.{} must be a string, number or boolean",
+                        name
+                    )))
+                }
+            };
+            self.set(&name, &value, allowed, seen)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the upload form: up to `files.max` files in the `files.name` field, and the options
+/// in `files.options`, as fields of their own or as JSON in `options`. Any other field, and an
+/// option given twice, is turned away.
+async fn read_upload_form(
+    multipart: &mut Multipart,
+    files: FileField,
+) -> Result<UploadForm, CaptionError> {
+    let mut form = UploadForm {
+        images: Vec::new(),
+        options: CaptionOptions::configured().map_err(CaptionError::Internal)?,
+        xmp: false,
+        tone: Default::default(),
+        length: Default::default(),
+    };
+    let mut seen = Vec::new();
+    let mut options_json = false;
+    let mut budget = spool::Budget::configured();
+
+    let form_error = |e| files.upload.form_error(e);
+    while let Some(mut field) = multipart.next_field().await.map_err(form_error)? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == files.name {
+            if form.images.len() == files.max {
+                let message = match files.max {
+                    1 => format!("only one file may be sent in {}", files.name),
+                    max => format!("at most {} files may be sent in {}", max, files.name),
+                };
+                return Err(CaptionError::BadRequest(message));
+            }
+            let file_name = field.file_name().map(str::to_string);
+            let limit = files.upload.file_limit();
+            let mut data = Spooler::new(&mut budget);
+            while let Some(chunk) = field.chunk().await.map_err(form_error)? {
+                if data.len() + chunk.len() > limit {
+                    return Err(files.upload.file_too_large(files.name));
+                }
+                data.push(&chunk).await?;
+            }
+            let data = data.finish().await?;
+            form.images.push(UploadedImage { data, file_name });
+        } else if name == "options" {
+            if std::mem::replace(&mut options_json, true) {
+                return Err(CaptionError::BadRequest("options is given more than once".into()));
+            }
+            let json = field.text().await.map_err(form_error)?;
+            form.set_all(&json, files.options, &mut seen)?;
+        } else if files.options.contains(&name.as_str()) {
+            let value = field.text().await.map_err(form_error)?;
+            form.set(&name, &value, files.options, &mut seen)?;
+        } else {
+            return Err(CaptionError::BadRequest(format!(
+                "unknown field '{}'; the fields are {}, options and {}",
+                name,
+                files.name,
+                files.options.join(", ")
+            )));
+        }
+    }
+
+    if form.images.is_empty() {
+        let message = format!("No file in the upload; send it in the {} field", files.name);
+        return Err(CaptionError::BadRequest(message));
+    }
+    form.options.validate().map_err(CaptionError::Unprocessable)?;
+    Ok(form)
+}
+
+/// Captions an uploaded image through the server's pipeline; as part of an album when it has
+/// the album's `session`.
+async fn caption_upload(
+    image: &[u8],
+    options: &CaptionOptions,
+    state: &AppState,
+    caller: &scheduler::Caller,
+    session: Option<&ProviderSession>,
+    start: std::time::Instant,
+    timings: Timings,
+) -> Result<CaptionResponse, CaptionError> {
+    let api_key = provider_key(state, caller);
+    let mut caption = pipeline::Caption::new(image, options.clone(), api_key, start, timings);
+    caption.session = session;
+    let (response, _usage) = pipeline::server(state, caller).run(caption).await?;
+    Ok(response)
+}
+
+/// The Gemini API key to call with: the caller's own, if its API key has one.
+fn provider_key(state: &AppState, caller: &scheduler::Caller) -> String {
+    caller
+        .policy()
+        .and_then(|policy| policy.provider_key.clone())
+        .unwrap_or_else(|| state.api_key())
+}
+
+/// Strips an uploaded file name down to characters safe inside a Content-Disposition header.
+fn safe_file_name(file_name: Option<&str>) -> String {
+    file_name
+        .unwrap_or("image")
+        .chars()
+        .filter(|c| c.is_ascii_graphic() && !matches!(c, '"' | '\\' | '/'))
+        .collect()
+}
+
+/// With an `Idempotency-Key` header, retries of the same upload share one caption.
+pub(crate) async fn upload_image(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Json<CaptionResponse>, CaptionError> {
+    let start = std::time::Instant::now();
+
+    let token = uploads::token(&headers)?;
+    let form = read_upload_form(&mut multipart, IMAGE).await?;
+    let received = Timings::received(start);
+    let tenant_name = tenant.name.clone();
+    let caller = scheduler::Caller::interactive(tenant);
+
+    let image = form.images[0].data.read().await?;
+    let mut response = match token {
+        Some(token) => {
+            let request = uploads::request_hash(&image, &form.options);
+            let caption_state = state.clone();
+            let caption = async move {
+                let options = &form.options;
+                caption_upload(&image, options, &caption_state, &caller, None, start, received)
+                    .await
+            };
+            state.uploads.run(&tenant_name, token, request, caption).await?
+        }
+        None => {
+            caption_upload(&image, &form.options, &state, &caller, None, start, received).await?
+        }
+    };
+    response.request_id = request_id::current();
+
+    Ok(Json(response))
+}
+
+/// Captions the upload and returns the original file with the caption written into its
+/// EXIF ImageDescription and (for JPEG) IPTC Caption-Abstract fields.
+pub(crate) async fn embed_caption(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, CaptionError> {
+    let start = std::time::Instant::now();
+
+    let form = read_upload_form(&mut multipart, IMAGE).await?;
+    let image = &form.images[0];
+    let data = image.data.read().await?;
+
+    // Check the container up front so we don't pay for a caption we can't embed.
+    match image::guess_format(&data) {
+        Ok(image::ImageFormat::Jpeg | image::ImageFormat::Png | image::ImageFormat::WebP) => {}
+        _ => {
+            let message = "Only JPEG, PNG and WebP images can carry embedded captions";
+            return Err(CaptionError::UnsupportedMedia(message.into()));
+        }
+    }
+
+    let caller = scheduler::Caller::interactive(tenant);
+    let received = Timings::received(start);
+    let response =
+        caption_upload(&data, &form.options, &state, &caller, None, start, received).await?;
+
+    let (bytes, mime) = metadata::embed_caption(&data, &response.caption).map_err(|e| {
+        tracing::warn!(error = %e, "Embed failed");
+        let message = e.to_string();
+        match e {
+            metadata::EmbedError::UnsupportedFormat => CaptionError::UnsupportedMedia(message),
+            metadata::EmbedError::Malformed(_) => CaptionError::BadRequest(message),
+            metadata::EmbedError::TooLarge => CaptionError::TooLarge(message),
+        }
+    })?;
+
+    let disposition = format!(
+        "attachment; filename=\"captioned-{}\"",
+        safe_file_name(image.file_name.as_deref())
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        bytes,
+    ))
+}
+
+/// Captions the upload and returns an XMP sidecar for it, named to sit next to the
+/// original (`IMG_0001.CR2` -> `IMG_0001.xmp`) the way Lightroom expects.
+pub(crate) async fn xmp_sidecar(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, CaptionError> {
+    let start = std::time::Instant::now();
+
+    let form = read_upload_form(&mut multipart, IMAGE).await?;
+    let image = &form.images[0];
+    let data = image.data.read().await?;
+    let caller = scheduler::Caller::interactive(tenant);
+    let received = Timings::received(start);
+    let response =
+        caption_upload(&data, &form.options, &state, &caller, None, start, received).await?;
+
+    let packet = xmp::render(&xmp::XmpFields::from_response(&response));
+
+    let file_name = safe_file_name(image.file_name.as_deref());
+    let stem = file_name.rsplit_once('.').map_or(file_name.as_str(), |(stem, _)| stem);
+    let disposition = format!("attachment; filename=\"{}.xmp\"", stem);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/rdf+xml".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        packet,
+    ))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BatchItem {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file_name: Option<String>,
+    #[serde(flatten)]
+    pub(crate) response: Option<CaptionResponse>,
+    /// XMP sidecar packet, when the request asked for `xmp=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) xmp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BatchResponse {
+    pub(crate) succeeded: usize,
+    pub(crate) failed: usize,
+    pub(crate) results: Vec<BatchItem>,
+}
+
+async fn batch_item(
+    image: &UploadedImage,
+    options: &CaptionOptions,
+    want_xmp: bool,
+    state: &AppState,
+    caller: &scheduler::Caller,
+    session: Option<&ProviderSession>,
+    received: Timings,
+) -> BatchItem {
+    let start = std::time::Instant::now();
+    // Read only now, so a batch on disk is in memory a few images at a time.
+    let caption = match image.data.read().await {
+        Ok(data) => caption_upload(&data, options, state, caller, session, start, received).await,
+        Err(e) => Err(e),
+    };
+    match caption {
+        Ok(response) => {
+            let xmp = want_xmp.then(|| xmp::render(&xmp::XmpFields::from_response(&response)));
+            BatchItem {
+                file_name: image.file_name.clone(),
+                response: Some(response),
+                xmp,
+                error: None,
+            }
+        }
+        Err(e) => BatchItem {
+            file_name: image.file_name.clone(),
+            response: None,
+            xmp: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Parses an `interval` parameter in (fractional) seconds; it must be positive.
+fn parse_interval(
+    seconds: Option<f64>,
+    default: std::time::Duration,
+) -> Result<std::time::Duration, CaptionError> {
+    match seconds {
+        None => Ok(default),
+        Some(seconds) => std::time::Duration::try_from_secs_f64(seconds)
+            .ok()
+            .filter(|interval| !interval.is_zero())
+            .ok_or_else(|| {
+                CaptionError::BadRequest("interval must be a positive number of seconds".into())
+            }),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BatchQuery {
+    format: Option<String>,
+    /// Seconds between frames for `srt`/`vtt` tracks.
+    interval: Option<f64>,
+}
+
+/// Captions several uploaded images in one request. Results keep the upload order; a
+/// failing image is reported in its slot instead of failing the whole batch.
+///
+/// `?format=csv|jsonl` (or an `Accept: text/csv` / `application/x-ndjson` header) returns
+/// a downloadable export instead of the JSON response. `?format=srt|vtt` treats the uploads
+/// as frames `interval` seconds apart (default 1) and returns a descriptive caption track.
+pub(crate) async fn batch_caption(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    Query(query): Query<BatchQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, CaptionError> {
+    let start = std::time::Instant::now();
+    let accept = headers.get(header::ACCEPT).and_then(|value| value.to_str().ok());
+    let format = export::ExportFormat::negotiate(query.format.as_deref(), accept)
+        .map_err(CaptionError::BadRequest)?;
+    let interval = parse_interval(query.interval, std::time::Duration::from_secs(1))?;
+
+    let form = read_upload_form(&mut multipart, IMAGES).await?;
+    let received = Timings::received(start);
+    let caller = scheduler::Caller::interactive(tenant);
+    let session = ProviderSession::open(provider_key(&state, &caller), form.images.len());
+
+    // Collected up front: a lazy `map` here trips the compiler's Send check for handlers.
+    let items: Vec<_> = form
+        .images
+        .iter()
+        .map(|image| {
+            let session = session.as_ref();
+            batch_item(image, &form.options, form.xmp, &state, &caller, session, received)
+        })
+        .collect();
+    let results: Vec<BatchItem> = futures::stream::iter(items)
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    batch_export(results, format, interval)
+}
+
+/// Renders batch results as the JSON response or as a downloadable export.
+fn batch_export(
+    results: Vec<BatchItem>,
+    format: export::ExportFormat,
+    interval: std::time::Duration,
+) -> Result<Response, CaptionError> {
+    let body = match format {
+        export::ExportFormat::Json => {
+            let failed = results.iter().filter(|item| item.error.is_some()).count();
+            return Ok(Json(BatchResponse {
+                succeeded: results.len() - failed,
+                failed,
+                results,
+            })
+            .into_response());
+        }
+        export::ExportFormat::Jsonl => export::to_jsonl(&results).into_bytes(),
+        export::ExportFormat::Track(track) => {
+            export::to_track(&results, track, interval).into_bytes()
+        }
+        export::ExportFormat::Csv => export::to_csv(&results).map_err(|e| {
+            tracing::error!(error = %e, "CSV export failed");
+            CaptionError::Internal(format!("CSV export failed: {}", e))
+        })?,
+    };
+
+    let disposition = format!("attachment; filename=\"batch-results.{}\"", format.extension());
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Captions every image inside an uploaded ZIP archive (up to `archive::MAX_IMAGES`, found by
+/// extension), reported like `/batch` with each entry's path inside the archive as its file
+/// name. Takes the same `format` options as `/batch`, e.g. `?format=csv` for a spreadsheet.
+pub(crate) async fn caption_zip(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    Query(query): Query<BatchQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, CaptionError> {
+    let start = std::time::Instant::now();
+    let accept = headers.get(header::ACCEPT).and_then(|value| value.to_str().ok());
+    let format = export::ExportFormat::negotiate(query.format.as_deref(), accept)
+        .map_err(CaptionError::BadRequest)?;
+    let interval = parse_interval(query.interval, std::time::Duration::from_secs(1))?;
+
+    let form = read_upload_form(&mut multipart, ARCHIVE).await?;
+    let zip = form.images[0].data.reader().map_err(|e| CaptionError::Internal(e.to_string()))?;
+    // Inflating can take a while; keep it off the async workers.
+    let entries = tokio::task::spawn_blocking(move || archive::extract_images(zip))
+        .await
+        .map_err(|e| CaptionError::Internal(format!("ZIP extraction failed: {}", e)))?
+        .map_err(|e| {
+            tracing::warn!(error = %e, "ZIP extraction failed");
+            let message = e.to_string();
+            match e {
+                archive::ArchiveError::Invalid(_) => CaptionError::BadRequest(message),
+                archive::ArchiveError::TooLarge(_) => CaptionError::TooLarge(message),
+                archive::ArchiveError::NoImages => CaptionError::Unprocessable(message),
+            }
+        })?;
+    let received = Timings::received(start);
+
+    let images: Vec<UploadedImage> = entries
+        .into_iter()
+        .map(|entry| UploadedImage {
+            data: axum::body::Bytes::from(entry.data).into(),
+            file_name: Some(entry.name),
+        })
+        .collect();
+    let caller = scheduler::Caller::interactive(tenant);
+    let session = ProviderSession::open(provider_key(&state, &caller), images.len());
+
+    // Collected up front for the same reason as in `batch_caption`.
+    let items: Vec<_> = images
+        .iter()
+        .map(|image| {
+            let session = session.as_ref();
+            batch_item(image, &form.options, form.xmp, &state, &caller, session, received)
+        })
+        .collect();
+    let results: Vec<BatchItem> = futures::stream::iter(items)
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    batch_export(results, format, interval)
+}
+
+/// Queues the uploaded images as a background job and returns it straight away with
+/// `202 Accepted`. Poll `GET /jobs/:id` (optionally with `?wait=30s`) for progress; results
+/// appear once the job has completed.
+pub(crate) async fn create_job(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    mut multipart: Multipart,
+) -> Result<Response, CaptionError> {
+    let start = std::time::Instant::now();
+    let form = read_upload_form(&mut multipart, JOB_IMAGES).await?;
+    let received = Timings::received(start);
+    let file_names = form.images.iter().map(|image| image.file_name.clone()).collect();
+    let entry = state.jobs.create(&tenant.name, file_names);
+
+    let caller = scheduler::Caller::job(tenant, entry.clone());
+    let job = run_job(state.clone(), entry.clone(), caller, form, received);
+    tokio::spawn(request_id::carry(job));
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [
+            (header::LOCATION, format!("/jobs/{}", entry.id())),
+            (header::CONTENT_TYPE, "application/json".to_string()),
+        ],
+        entry.to_json(),
+    )
+        .into_response())
+}
+
+/// Captions a job's images; the job turns `running` once the scheduler gives it a slot.
+async fn run_job(
+    state: Arc<AppState>,
+    entry: Arc<jobs::JobEntry>,
+    caller: scheduler::Caller,
+    form: UploadForm,
+    received: Timings,
+) {
+    let session = ProviderSession::open(provider_key(&state, &caller), form.images.len());
+    let items: Vec<_> = form
+        .images
+        .iter()
+        .map(|image| {
+            let session = session.as_ref();
+            batch_item(image, &form.options, form.xmp, &state, &caller, session, received)
+        })
+        .collect();
+    let mut items = futures::stream::iter(items).buffered(BATCH_CONCURRENCY);
+
+    while let Some(item) = items.next().await {
+        entry.record(item);
+    }
+    entry.finish();
+}
+
+#[derive(Serialize)]
+pub(crate) struct PageResult {
+    pub(crate) page: usize,
+    #[serde(flatten)]
+    pub(crate) response: Option<CaptionResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PdfResponse {
+    pub(crate) pages_captioned: usize,
+    pub(crate) failed: usize,
+    pub(crate) processing_time_ms: u128,
+    pub(crate) pages: Vec<PageResult>,
+}
+
+/// Rasterizes an uploaded PDF and captions each page (up to `pdf::MAX_PAGES`). A page that
+/// fails is reported in its slot instead of failing the whole document.
+pub(crate) async fn caption_pdf(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    mut multipart: Multipart,
+) -> Result<Json<PdfResponse>, CaptionError> {
+    let start = std::time::Instant::now();
+
+    let form = read_upload_form(&mut multipart, PDF).await?;
+    if !pdf::is_pdf(&form.images[0].data.head(5).await?) {
+        return Err(CaptionError::UnsupportedMedia("The upload is not a PDF".into()));
+    }
+
+    let caller = scheduler::Caller::interactive(tenant);
+    let pages = pdf::render_pages(&form.images[0].data, pdf::MAX_PAGES)
+        .await
+        .map_err(|e| {
+            tracing::warn!(error = %e, "PDF rendering failed");
+            let message = e.to_string();
+            match e {
+                pdf::PdfError::RendererMissing(_) => CaptionError::Unavailable(message),
+                pdf::PdfError::Render(_) => CaptionError::Unprocessable(message),
+                pdf::PdfError::Io(_) => CaptionError::Internal(message),
+            }
+        })?;
+
+    let session = ProviderSession::open(provider_key(&state, &caller), pages.len());
+
+    // Collected up front for the same reason as in `batch_caption`.
+    let captions: Vec<_> = pages
+        .iter()
+        .map(|page| {
+            let (start, session) = (std::time::Instant::now(), session.as_ref());
+            let options = &form.options;
+            caption_upload(&page.jpeg, options, &state, &caller, session, start, Timings::default())
+        })
+        .collect();
+    let captions: Vec<Result<CaptionResponse, CaptionError>> = futures::stream::iter(captions)
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    let pages: Vec<PageResult> = pages
+        .iter()
+        .zip(captions)
+        .map(|(page, caption)| match caption {
+            Ok(response) => PageResult {
+                page: page.number,
+                response: Some(response),
+                error: None,
+            },
+            Err(e) => PageResult {
+                page: page.number,
+                response: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+
+    let failed = pages.iter().filter(|page| page.error.is_some()).count();
+    Ok(Json(PdfResponse {
+        pages_captioned: pages.len() - failed,
+        failed,
+        processing_time_ms: start.elapsed().as_millis(),
+        pages,
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct VideoQuery {
+    /// Seconds between sampled frames.
+    interval: Option<f64>,
+    /// `json` (default), `srt` or `vtt`.
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct FrameCaption {
+    pub(crate) timestamp_ms: u128,
+    #[serde(flatten)]
+    pub(crate) response: CaptionResponse,
+}
+
+/// Consecutive frames with the same caption, merged.
+#[derive(Serialize)]
+pub(crate) struct Scene {
+    pub(crate) start_ms: u128,
+    pub(crate) end_ms: u128,
+    pub(crate) caption: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct VideoResponse {
+    pub(crate) summary: String,
+    pub(crate) model: String,
+    pub(crate) processing_time_ms: u128,
+    pub(crate) frames_analyzed: usize,
+    pub(crate) interval_ms: u128,
+    pub(crate) timeline: Vec<FrameCaption>,
+    pub(crate) scenes: Vec<Scene>,
+}
+
+/// Captions a short video: samples a frame every `interval` seconds (default 2) with
+/// ffmpeg, captions each one and summarizes the result. `?format=srt|vtt` returns the
+/// scenes as a descriptive caption track instead.
+pub(crate) async fn caption_video(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    Query(query): Query<VideoQuery>,
+    mut multipart: Multipart,
+) -> Result<Response, CaptionError> {
+    let start = std::time::Instant::now();
+
+    let track = match query.format.as_deref() {
+        None | Some("json") => None,
+        Some("srt") => Some(subtitles::TrackFormat::Srt),
+        Some("vtt") => Some(subtitles::TrackFormat::Vtt),
+        Some(other) => {
+            let message = format!("format must be json, srt or vtt, not '{}'", other);
+            return Err(CaptionError::BadRequest(message));
+        }
+    };
+    let interval = parse_interval(query.interval, video::DEFAULT_FRAME_INTERVAL)?;
+
+    let form = read_upload_form(&mut multipart, VIDEO).await?;
+    let caller = scheduler::Caller::interactive(tenant);
+    let frames = video::sample_frames(&form.images[0].data, interval, video::MAX_FRAMES)
+        .await
+        .map_err(video_error)?;
+
+    let session = ProviderSession::open(provider_key(&state, &caller), frames.len());
+
+    // Collected up front for the same reason as in `batch_caption`.
+    let captions: Vec<_> = frames
+        .iter()
+        .map(|frame| {
+            let (start, session) = (std::time::Instant::now(), session.as_ref());
+            let (options, timings) = (&form.options, Timings::default());
+            caption_upload(&frame.jpeg, options, &state, &caller, session, start, timings)
+        })
+        .collect();
+    let captions: Vec<Result<CaptionResponse, CaptionError>> = futures::stream::iter(captions)
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    let frames_analyzed = frames.len();
+    // With every frame failed there is nothing to summarize; report the first failure.
+    if let [Err(first), ..] = captions.as_slice() {
+        if captions.iter().all(Result::is_err) {
+            return Err(first.clone());
+        }
+    }
+    let captions: Vec<Option<CaptionResponse>> = captions.into_iter().map(Result::ok).collect();
+
+    // Failed frames leave a gap in the scenes rather than stretching their neighbours.
+    let per_frame: Vec<Option<&str>> = captions
+        .iter()
+        .map(|caption| caption.as_ref().map(|response| response.caption.as_str()))
+        .collect();
+    let segments = subtitles::frame_segments(&per_frame, interval);
+
+    if let Some(track) = track {
+        return Ok((
+            [(header::CONTENT_TYPE, track.content_type())],
+            subtitles::render(&segments, track),
+        )
+            .into_response());
+    }
+
+    let summary_input: Vec<(std::time::Duration, &str)> = frames
+        .iter()
+        .zip(&per_frame)
+        .filter_map(|(frame, caption)| Some((frame.timestamp, (*caption)?)))
+        .collect();
+    let slot = state.scheduler.acquire(&caller).await?;
+    let summary_prompt = video::summary_prompt(&summary_input);
+    let voice = state.voices.get(caller.tenant());
+    let (summary, tokens) = generate_text(&summary_prompt, voice.as_deref(), &provider_key(&state, &caller))
+        .await
+        .inspect_err(|e| tracing::warn!(error = %e, "Summary failed"))?;
+    usage::record(&caller, 0, &tokens).await;
+    drop(slot);
+
+    let timeline = frames
+        .iter()
+        .zip(captions)
+        .filter_map(|(frame, caption)| {
+            Some(FrameCaption {
+                timestamp_ms: frame.timestamp.as_millis(),
+                response: caption?,
+            })
+        })
+        .collect();
+
+    let scenes = segments
+        .into_iter()
+        .map(|segment| Scene {
+            start_ms: segment.start.as_millis(),
+            end_ms: segment.end.as_millis(),
+            caption: segment.text,
+        })
+        .collect();
+
+    Ok(Json(VideoResponse {
+        summary: sanitize::clean(&summary),
+        model: MODEL_LABEL.to_string(),
+        processing_time_ms: start.elapsed().as_millis(),
+        frames_analyzed,
+        interval_ms: interval.as_millis(),
+        timeline,
+        scenes,
+    })
+    .into_response())
+}
+
+fn video_error(e: video::VideoError) -> CaptionError {
+    tracing::warn!(error = %e, "Video failed");
+    let message = e.to_string();
+    match e {
+        video::VideoError::FfmpegMissing(_) => CaptionError::Unavailable(message),
+        video::VideoError::Decode(_) => CaptionError::UnsupportedMedia(message),
+        video::VideoError::Source(_) => CaptionError::BadRequest(message),
+        video::VideoError::Io(_) => CaptionError::Internal(message),
+    }
+}
+
+/// A timestamp in seconds, or as `HH:MM:SS.mmm` text.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Timestamp {
+    Seconds(f64),
+    Clock(String),
+}
+
+#[derive(Deserialize)]
+pub(crate) struct VideoFramesRequest {
+    url: String,
+    timestamps: Vec<Timestamp>,
+    #[serde(default)]
+    mode: crate::modes::Mode,
+    #[serde(default)]
+    confidence: bool,
+    #[serde(default)]
+    scene: bool,
+    language: Option<String>,
+}
+
+/// One requested frame: the JPEG and its caption, or why either is missing.
+#[derive(Serialize)]
+pub(crate) struct StillFrame {
+    pub(crate) timestamp_ms: u128,
+    /// The frame as base64-encoded JPEG; absent when it couldn't be extracted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) jpeg: Option<String>,
+    #[serde(flatten)]
+    pub(crate) response: Option<CaptionResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct VideoFramesResponse {
+    pub(crate) model: String,
+    pub(crate) processing_time_ms: u128,
+    pub(crate) captioned: usize,
+    pub(crate) failed: usize,
+    pub(crate) frames: Vec<StillFrame>,
+}
+
+/// Extracts the frames at the given timestamps from a video URL and captions each one,
+/// returning the frames with their captions, e.g. as chapter thumbnails.
+pub(crate) async fn caption_video_frames(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    Json(request): Json<VideoFramesRequest>,
+) -> Result<Json<VideoFramesResponse>, CaptionError> {
+    let start = std::time::Instant::now();
+
+    if request.timestamps.is_empty() || request.timestamps.len() > video::MAX_FRAMES {
+        let message = format!("timestamps must list 1 to {} times", video::MAX_FRAMES);
+        return Err(CaptionError::BadRequest(message));
+    }
+    let timestamps = request
+        .timestamps
+        .iter()
+        .map(|timestamp| {
+            let parsed = match timestamp {
+                Timestamp::Seconds(seconds) => std::time::Duration::try_from_secs_f64(*seconds).ok(),
+                Timestamp::Clock(text) => video::parse_timestamp(text),
+            };
+            parsed.ok_or_else(|| {
+                CaptionError::BadRequest(
+                    "timestamps must be seconds or HH:MM:SS.mmm, not negative".into(),
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let options = CaptionOptions {
+        mode: request.mode,
+        confidence: request.confidence,
+        scene: request.scene,
+        language: request
+            .language
+            .map(|language| language.trim().to_string())
+            .filter(|language| !language.is_empty()),
+        ..Default::default()
+    };
+    options.validate().map_err(CaptionError::Unprocessable)?;
+    let limit = Upload::Video.file_limit();
+    let video = video::fetch(&request.url, limit).await.map_err(video_error)?;
+
+    let caller = scheduler::Caller::interactive(tenant);
+    let session = ProviderSession::open(provider_key(&state, &caller), timestamps.len());
+    let frames: Vec<_> = timestamps
+        .into_iter()
+        .map(|timestamp| {
+            let (video, options, state, caller) = (&video, &options, &state, &caller);
+            let session = session.as_ref();
+            async move {
+                let timestamp_ms = timestamp.as_millis();
+                let frame = match video::extract_frame(video, timestamp).await {
+                    Ok(frame) => frame,
+                    Err(video::VideoError::FfmpegMissing(e)) => {
+                        return Err(video_error(video::VideoError::FfmpegMissing(e)))
+                    }
+                    Err(e) => {
+                        return Ok(StillFrame {
+                            timestamp_ms,
+                            jpeg: None,
+                            response: None,
+                            error: Some(video_error(e).to_string()),
+                        })
+                    }
+                };
+                let start = std::time::Instant::now();
+                let timings = Timings::default();
+                let caption =
+                    caption_upload(&frame.jpeg, options, state, caller, session, start, timings)
+                        .await;
+                Ok(StillFrame {
+                    timestamp_ms,
+                    jpeg: Some(general_purpose::STANDARD.encode(&frame.jpeg)),
+                    error: caption.as_ref().err().map(ToString::to_string),
+                    response: caption.ok(),
+                })
+            }
+        })
+        .collect();
+    let frames = futures::stream::iter(frames)
+        .buffered(BATCH_CONCURRENCY)
+        .collect::<Vec<Result<StillFrame, CaptionError>>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let failed = frames.iter().filter(|frame| frame.error.is_some()).count();
+    Ok(Json(VideoFramesResponse {
+        model: MODEL_LABEL.to_string(),
+        processing_time_ms: start.elapsed().as_millis(),
+        captioned: frames.len() - failed,
+        failed,
+        frames,
+    }))
+}
+
+/// Writes a property listing from its photos: captions each one in the `real_estate` mode,
+/// groups them by room and writes one listing paragraph in the form's `tone` and `length`.
+pub(crate) async fn caption_listing(
+    State(state): State<Arc<AppState>>,
+    tenant: scheduler::Tenant,
+    mut multipart: Multipart,
+) -> Result<Json<listing::ListingResponse>, CaptionError> {
+    let start = std::time::Instant::now();
+    let form = read_upload_form(&mut multipart, LISTING_IMAGES).await?;
+    let received = Timings::received(start);
+    let caller = scheduler::Caller::interactive(tenant);
+    let options = listing::photo_options(&form.options);
+    let session = ProviderSession::open(provider_key(&state, &caller), form.images.len());
+
+    // Collected up front for the same reason as in `batch_caption`.
+    let captions: Vec<_> = form
+        .images
+        .iter()
+        .map(|image| {
+            let (options, state, caller) = (&options, &state, &caller);
+            let session = session.as_ref();
+            async move {
+                let data = image.data.read().await?;
+                let start = std::time::Instant::now();
+                caption_upload(&data, options, state, caller, session, start, received).await
+            }
+        })
+        .collect();
+    let captions: Vec<Result<CaptionResponse, CaptionError>> = futures::stream::iter(captions)
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    // With every photo failed there is nothing to write from; report the first failure.
+    if let [Err(first), ..] = captions.as_slice() {
+        if captions.iter().all(Result::is_err) {
+            return Err(first.clone());
+        }
+    }
+    let photos: Vec<BatchItem> = form
+        .images
+        .iter()
+        .zip(captions)
+        .map(|(image, caption)| BatchItem {
+            file_name: image.file_name.clone(),
+            error: caption.as_ref().err().map(ToString::to_string),
+            response: caption.ok(),
+            xmp: None,
+        })
+        .collect();
+    let rooms = listing::rooms(&photos);
+
+    let slot = state.scheduler.acquire(&caller).await?;
+    let prompt = listing::prompt(&rooms, form.tone, form.length, options.language.as_deref());
+    let voice = state.voices.get(caller.tenant());
+    let (text, tokens) = generate_text(&prompt, voice.as_deref(), &provider_key(&state, &caller))
+        .await
+        .inspect_err(|e| tracing::warn!(error = %e, "Listing failed"))?;
+    usage::record(&caller, 0, &tokens).await;
+    drop(slot);
+
+    Ok(Json(listing::ListingResponse {
+        listing: sanitize::clean(&text),
+        tone: form.tone,
+        length: form.length,
+        model: MODEL_LABEL.to_string(),
+        processing_time_ms: start.elapsed().as_millis(),
+        rooms,
+        photos,
+    }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct StatusResponse {
+    pub(crate) status: &'static str,
+    pub(crate) model: &'static str,
+    /// The provider circuit breaker: `closed` while calls go through.
+    pub(crate) provider_circuit: &'static str,
+    #[serde(flatten)]
+    pub(crate) queue: scheduler::QueueStatus,
+    /// Reuse of image buffers between requests.
+    pub(crate) buffer_pool: buffers::PoolStats,
+}
+
+/// Cheap load report (`GET /status`) so clients can set expectations, e.g. "usually ~6s",
+/// before uploading.
+pub(crate) async fn server_status(State(state): State<Arc<AppState>>) -> Json<StatusResponse> {
+    Json(StatusResponse {
+        status: "ok",
+        model: MODEL_ID,
+        provider_circuit: breaker::PROVIDER.state_name(),
+        queue: state.scheduler.status(),
+        buffer_pool: buffers::POOL.stats(),
+    })
+}
+
+/// The upload page.
+#[derive(Template)]
+#[template(path = "index.html")]
+struct IndexPage {
+    model: &'static str,
+    /// The largest image it may send, in bytes and as the page says it.
+    max_upload_bytes: usize,
+    max_upload: String,
+}
+
+/// `GET /`
+pub(crate) async fn index() -> Result<Html<String>, CaptionError> {
+    let max_upload_bytes = Upload::Image.file_limit();
+    assets::render(&IndexPage {
+        model: MODEL_ID,
+        max_upload_bytes,
+        max_upload: limits::megabytes(max_upload_bytes),
+    })
+}
@@ -0,0 +1,62 @@
+// HEIC/HEIF input (the default iPhone photo format), decoded with libheif behind the
+// `heic` feature. Without the feature, HEIC uploads fail with a clear "unsupported" error
+// instead of the generic "format could not be determined".
+
+use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{DynamicImage, ImageError};
+
+/// Brands in the ISO-BMFF `ftyp` box that identify HEIF still images.
+const HEIF_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+];
+
+/// Whether `data` starts with an ISO-BMFF `ftyp` box naming a HEIF brand.
+pub fn is_heif(data: &[u8]) -> bool {
+    data.len() >= 12
+        && &data[4..8] == b"ftyp"
+        && HEIF_BRANDS.iter().any(|brand| &data[8..12] == *brand)
+}
+
+fn heif_error(message: String) -> ImageError {
+    ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+        ImageFormatHint::Name("HEIF".into()),
+        UnsupportedErrorKind::GenericFeature(message),
+    ))
+}
+
+/// Decodes the primary image (with its rotation and mirroring applied) as RGB.
+#[cfg(feature = "heic")]
+pub fn decode(data: &[u8]) -> Result<DynamicImage, ImageError> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let context = HeifContext::read_from_bytes(data).map_err(|e| heif_error(e.to_string()))?;
+    let handle = context
+        .primary_image_handle()
+        .map_err(|e| heif_error(e.to_string()))?;
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(|e| heif_error(e.to_string()))?;
+
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or_else(|| heif_error("decoder returned no interleaved RGB plane".into()))?;
+
+    // Rows may be padded past width * 3 bytes.
+    let row_len = plane.width as usize * 3;
+    let mut rgb = Vec::with_capacity(row_len * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        rgb.extend_from_slice(&row[..row_len]);
+    }
+
+    image::RgbImage::from_raw(plane.width, plane.height, rgb)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| heif_error("decoded image has unexpected dimensions".into()))
+}
+
+#[cfg(not(feature = "heic"))]
+pub fn decode(_data: &[u8]) -> Result<DynamicImage, ImageError> {
+    Err(heif_error(
+        "HEIC support is not enabled; build with `--features heic`".into(),
+    ))
+}
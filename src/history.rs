@@ -0,0 +1,490 @@
+// Caption history, so results outlive the response that carried them. With a storage configured
+// (DATABASE_URL or HISTORY_DB, see `storage`), every caption the server makes is kept: who
+// asked, the image's SHA-256, a thumbnail, the mode, caption and model, and how long it took.
+// Thumbnails are JPEGs at most 256 pixels on a side in HISTORY_THUMBNAILS_DIR (default
+// `thumbnails` next to a SQLite database), named by the image hash so repeated images share one.
+// A PostgreSQL history is shared by replicas, so its thumbnails have to be too: the server won't
+// start with one unless HISTORY_THUMBNAILS_DIR is set, to a volume every replica mounts.
+//
+// `GET /history` pages through the caller's own captions, newest first: `limit` entries (default
+// 50, at most 200) per page, and `before` set to the previous page's `next_before` for the one
+// after it. `GET /search?q=dog+beach` pages the same way through those whose caption or tags
+// (hashtags, keywords, style tags and features) have every word of `q`, stemmed so "dogs"
+// finds "dog". `GET /thumb/:id` (or `/history/:id/thumbnail`) serves an entry's thumbnail, which
+// the gallery shows in place of the upload the server doesn't keep; thumbnails are only made
+// for, and kept with, history entries. `DELETE /history/:id` removes the entry, and its
+// thumbnail once no entry shows it. A caption that can't be recorded is logged and still
+// returned.
+//
+// For data deletion requests, `DELETE /tenant/:id/data` (with the admin token) removes all of a
+// tenant's entries, their thumbnails, its cached captions, and this process's jobs and upload
+// retries of the tenant's; the response lists what it keeps (audit entries and usage) and why.
+// With HISTORY_RETENTION_DAYS set
+// (default 0, to keep everything), entries older than that are removed at startup and then
+// hourly; it is read each time, so a reload applies it.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::imageproc::thumbnail_jpeg;
+use crate::scheduler::{Caller, Tenant};
+use crate::server::AppState;
+use crate::storage::{self, NewCaption, Storage};
+use crate::voices::authorize;
+use crate::{auth, cache, etag, CaptionError, CaptionResponse};
+
+/// Longer side of the stored thumbnails, in pixels.
+const THUMBNAIL_SIZE: u32 = 256;
+
+const DEFAULT_PAGE: usize = 50;
+const MAX_PAGE: usize = 200;
+
+/// Most words in a search.
+const MAX_SEARCH_TERMS: usize = 10;
+
+/// How often entries past HISTORY_RETENTION_DAYS are looked for.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Result fields kept as the caption's tags.
+const TAG_FIELDS: &[&str] = &["hashtags", "keywords", "style_tags", "features"];
+
+/// Where captions and their thumbnails are kept.
+pub(crate) struct History {
+    storage: Arc<dyn Storage>,
+    thumbnails: PathBuf,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    limit: Option<usize>,
+    /// Only entries older than this id: the previous page's `next_before`.
+    before: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+    before: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct HistoryEntry {
+    pub(crate) id: i64,
+    pub(crate) created_at: String,
+    pub(crate) image_sha256: String,
+    /// Where the replica that made it stored the thumbnail; null when the image couldn't be
+    /// decoded for one.
+    pub(crate) thumbnail_path: Option<String>,
+    pub(crate) mode: String,
+    pub(crate) caption: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) model: String,
+    pub(crate) latency_ms: u64,
+}
+
+/// What `DELETE /tenant/:id/data` removed.
+#[derive(Serialize)]
+pub(crate) struct TenantDataRemoved {
+    pub(crate) tenant: String,
+    pub(crate) captions: u64,
+    pub(crate) thumbnails: u64,
+    pub(crate) cached_captions: u64,
+    /// Jobs, with their results, in the memory of the process that answered.
+    pub(crate) jobs: u64,
+    /// Retry-safe uploads (see `uploads`) with their captions, in the same memory.
+    pub(crate) upload_retries: u64,
+    /// What is kept of the tenant's data, and on what grounds.
+    pub(crate) kept: Vec<KeptData>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct KeptData {
+    pub(crate) data: &'static str,
+    pub(crate) reason: &'static str,
+}
+
+const KEPT_AUDIT_ENTRIES: KeptData = KeptData {
+    data: "audit_log",
+    reason: "Append-only record of who asked for which caption (time, request ID, IP address, \
+             image hash, mode, prompt changes, outcome; no captions), kept as evidence of how \
+             images were processed: a legal obligation or legitimate interest (GDPR Art. 6(1)(c) \
+             and (f), Art. 17(3)(b) and (e)). Export it with GET /admin/audit?tenant=.",
+};
+
+const KEPT_USAGE: KeptData = KeptData {
+    data: "key_usage",
+    reason: "Captions, provider tokens and their cost per day for the API key, kept as billing \
+             and accounting records (GDPR Art. 6(1)(b) and (c)). Revoking the key with \
+             DELETE /admin/keys/:name removes them too.",
+};
+
+#[derive(Serialize)]
+pub(crate) struct HistoryPage {
+    pub(crate) entries: Vec<HistoryEntry>,
+    /// `before` for the next page; null on the last one.
+    pub(crate) next_before: Option<i64>,
+}
+
+/// The history kept in `storage`; `None` keeps no history. Fails for a shared (PostgreSQL)
+/// history without HISTORY_THUMBNAILS_DIR, as its thumbnails would each be on one replica only.
+pub(crate) fn open(storage: Option<Arc<dyn Storage>>) -> Result<Option<History>, String> {
+    let Some(storage) = storage else {
+        return Ok(None);
+    };
+    let thumbnails = match crate::settings::var("HISTORY_THUMBNAILS_DIR") {
+        Ok(dir) if !dir.trim().is_empty() => PathBuf::from(dir),
+        _ => match storage::sqlite_path() {
+            Some(database) => std::path::Path::new(&database).with_file_name("thumbnails"),
+            None => {
+                return Err(format!(
+                    "A {} history needs HISTORY_THUMBNAILS_DIR, on storage every replica \
+                     shares, for its thumbnails",
+                    storage.backend()
+                ))
+            }
+        },
+    };
+    tracing::info!("Keeping caption history in {}", storage.backend());
+    Ok(Some(History {
+        storage,
+        thumbnails,
+    }))
+}
+
+impl History {
+    /// The thumbnail file an entry's `thumbnail_path` names, in this process's
+    /// HISTORY_THUMBNAILS_DIR: the replica that stored it may mount the shared directory
+    /// elsewhere.
+    fn thumbnail_file(&self, stored: &str) -> Option<PathBuf> {
+        let name = std::path::Path::new(stored).file_name()?;
+        Some(self.thumbnails.join(name))
+    }
+}
+
+/// Writes the thumbnail of `image`, turned upright, unless one with its hash is already there.
+fn store_thumbnail(
+    image: &[u8],
+    turn: u32,
+    hash: &str,
+    dir: &std::path::Path,
+) -> Option<String> {
+    let path = dir.join(format!("{}.jpg", hash));
+    if !path.exists() {
+        let written = thumbnail_jpeg(image, turn, THUMBNAIL_SIZE)
+            .map_err(|e| e.to_string())
+            .and_then(|jpeg| {
+                std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+                std::fs::write(&path, jpeg).map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            tracing::warn!(dir = %dir.display(), error = %e, "Can't store a thumbnail");
+            return None;
+        }
+    }
+    Some(path.display().to_string())
+}
+
+/// The result's tags, without hashtags' `#` and repeats.
+fn tags(response: &CaptionResponse) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for field in TAG_FIELDS {
+        let values = response.details.get(*field).and_then(|value| value.as_array());
+        for tag in values.into_iter().flatten().filter_map(|value| value.as_str()) {
+            let tag = tag.trim_start_matches('#').trim();
+            if !tag.is_empty() && !tags.iter().any(|kept| kept.eq_ignore_ascii_case(tag)) {
+                tags.push(tag.to_string());
+            }
+        }
+    }
+    tags
+}
+
+/// Adds a caption of `image`, whose EXIF orientation is `turn`, to the history, when there is
+/// one.
+pub(crate) async fn record(
+    history: Option<&History>,
+    caller: &Caller,
+    image: &[u8],
+    turn: u32,
+    response: &CaptionResponse,
+) {
+    let Some(history) = history else {
+        return;
+    };
+    let hash = match &response.provenance {
+        Some(provenance) => provenance.content_hash.clone(),
+        None => crate::imageproc::content_hash(image),
+    };
+    let hash = hash.trim_start_matches("sha256:").to_string();
+    let (image, dir) = (image.to_vec(), history.thumbnails.clone());
+    let thumbnail_hash = hash.clone();
+    let thumbnail_path =
+        tokio::task::spawn_blocking(move || store_thumbnail(&image, turn, &thumbnail_hash, &dir))
+            .await
+            .ok()
+            .flatten();
+
+    let caption = NewCaption {
+        created_at: chrono::Utc::now(),
+        tenant: caller.tenant().to_string(),
+        image_sha256: hash,
+        thumbnail_path,
+        mode: response.provenance.as_ref().map_or("caption", |p| p.mode.name()),
+        caption: response.caption.clone(),
+        tags: tags(response),
+        model: response.model.clone(),
+        latency_ms: response.processing_time_ms,
+    };
+    if let Err(e) = history.storage.add_caption(caption).await {
+        tracing::error!(tenant = caller.tenant(), error = %e, "Can't record caption history");
+    }
+}
+
+fn configured(state: &AppState) -> Result<&History, CaptionError> {
+    state.history.as_ref().ok_or_else(|| {
+        CaptionError::Unavailable(
+            "Caption history is kept only with DATABASE_URL or HISTORY_DB".into(),
+        )
+    })
+}
+
+fn no_entry(id: i64) -> CaptionError {
+    CaptionError::NotFound(format!("No history entry {}", id))
+}
+
+fn page_size(limit: Option<usize>) -> Result<usize, CaptionError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE);
+    if !(1..=MAX_PAGE).contains(&limit) {
+        return Err(CaptionError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE
+        )));
+    }
+    Ok(limit)
+}
+
+/// A page of `limit` entries from `entries`, which were fetched with one more than that to
+/// tell whether there is a next page.
+fn page(mut entries: Vec<HistoryEntry>, limit: usize) -> Json<HistoryPage> {
+    let next_before = (entries.len() > limit).then(|| {
+        entries.truncate(limit);
+        entries[limit - 1].id
+    });
+    Json(HistoryPage {
+        entries,
+        next_before,
+    })
+}
+
+/// The distinct lowercase words of a search.
+fn search_terms(q: &str) -> Result<Vec<String>, CaptionError> {
+    let mut terms: Vec<String> = Vec::new();
+    for word in q.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+        let word = word.to_lowercase();
+        if !terms.contains(&word) {
+            terms.push(word);
+        }
+    }
+    match terms.len() {
+        0 => Err(CaptionError::BadRequest("q must have at least one word".into())),
+        count if count > MAX_SEARCH_TERMS => Err(CaptionError::BadRequest(format!(
+            "q can have at most {} words",
+            MAX_SEARCH_TERMS
+        ))),
+        _ => Ok(terms),
+    }
+}
+
+/// `GET /history`
+pub async fn get_history(
+    State(state): State<Arc<AppState>>,
+    tenant: Tenant,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryPage>, CaptionError> {
+    let history = configured(&state)?;
+    let limit = page_size(query.limit)?;
+    let before = query.before.unwrap_or(i64::MAX);
+    let entries = history.storage.captions(&tenant.name, before, limit + 1).await?;
+    Ok(page(entries, limit))
+}
+
+/// `GET /search`
+pub async fn search_history(
+    State(state): State<Arc<AppState>>,
+    tenant: Tenant,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<HistoryPage>, CaptionError> {
+    let history = configured(&state)?;
+    let terms = search_terms(&query.q)?;
+    let limit = page_size(query.limit)?;
+    let before = query.before.unwrap_or(i64::MAX);
+    let entries = history.storage.search(&tenant.name, &terms, before, limit + 1).await?;
+    Ok(page(entries, limit))
+}
+
+/// `GET /thumb/:id` and `GET /history/:id/thumbnail`, revalidated by the image hash the
+/// thumbnail is named after.
+pub async fn get_thumbnail(
+    State(state): State<Arc<AppState>>,
+    tenant: Tenant,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<Response, CaptionError> {
+    let history = configured(&state)?;
+    let entry = history.storage.caption(&tenant.name, id).await?.ok_or_else(|| no_entry(id))?;
+    let path = entry
+        .thumbnail_path
+        .as_deref()
+        .and_then(|stored| history.thumbnail_file(stored))
+        .ok_or_else(|| CaptionError::NotFound(format!("History entry {} has no thumbnail", id)))?;
+    let gone = |e: std::io::Error| {
+        tracing::warn!(path = %path.display(), error = %e, "Can't read a thumbnail");
+        CaptionError::NotFound(format!("The thumbnail of history entry {} is gone", id))
+    };
+    // A copy the client has is only current while the file is still there.
+    tokio::fs::metadata(&path).await.map_err(gone)?;
+    let cache = [
+        (header::ETAG, format!("\"{}\"", entry.image_sha256)),
+        (header::CACHE_CONTROL, "private, max-age=86400".into()),
+    ];
+    if etag::none_match(&headers, &entry.image_sha256) {
+        return Ok((StatusCode::NOT_MODIFIED, cache).into_response());
+    }
+    let jpeg = tokio::fs::read(&path).await.map_err(gone)?;
+    Ok((cache, [(header::CONTENT_TYPE, "image/jpeg")], jpeg).into_response())
+}
+
+/// Removes the thumbnails at `paths` that no entry shows any more, since repeated images share
+/// one; returns how many it removed.
+async fn remove_thumbnails(
+    history: &History,
+    paths: impl IntoIterator<Item = String>,
+) -> Result<u64, CaptionError> {
+    let mut removed = 0;
+    let files = paths.into_iter().filter_map(|stored| history.thumbnail_file(&stored));
+    for path in files.collect::<HashSet<_>>() {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if history.storage.thumbnail_in_use(&name).await? {
+            continue;
+        }
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => removed += 1,
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "Can't remove a thumbnail")
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// `DELETE /history/:id`
+pub async fn delete_entry(
+    State(state): State<Arc<AppState>>,
+    tenant: Tenant,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, CaptionError> {
+    let history = configured(&state)?;
+    let entry = history.storage.caption(&tenant.name, id).await?.ok_or_else(|| no_entry(id))?;
+    if !history.storage.delete_caption(&tenant.name, id).await? {
+        return Err(no_entry(id));
+    }
+    remove_thumbnails(history, entry.thumbnail_path).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `DELETE /tenant/:id/data`
+pub async fn delete_tenant_data(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(tenant): Path<String>,
+) -> Result<Json<TenantDataRemoved>, CaptionError> {
+    authorize(&headers)?;
+    let (mut captions, mut thumbnails) = (0, 0);
+    if let Some(history) = &state.history {
+        let paths = history.storage.delete_tenant_captions(&tenant).await?;
+        captions = paths.len() as u64;
+        thumbnails = remove_thumbnails(history, paths.into_iter().flatten()).await?;
+    }
+    let cached_captions = cache::forget(state.cache.as_ref(), &tenant).await?;
+    let jobs = state.jobs.forget_tenant(&tenant);
+    let upload_retries = state.uploads.forget(&tenant);
+    let mut kept = Vec::new();
+    if state.audit.is_some() {
+        kept.push(KEPT_AUDIT_ENTRIES);
+    }
+    if auth::database().is_some() {
+        kept.push(KEPT_USAGE);
+    }
+    tracing::info!(
+        tenant,
+        captions,
+        thumbnails,
+        cached_captions,
+        jobs,
+        upload_retries,
+        "Removed a tenant's data"
+    );
+    Ok(Json(TenantDataRemoved {
+        tenant,
+        captions,
+        thumbnails,
+        cached_captions,
+        jobs,
+        upload_retries,
+        kept,
+    }))
+}
+
+/// HISTORY_RETENTION_DAYS; 0 keeps entries for good.
+fn retention_days() -> i64 {
+    crate::settings::var("HISTORY_RETENTION_DAYS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Removes the entries older than HISTORY_RETENTION_DAYS, now and every `PURGE_INTERVAL`, on
+/// whichever replica holds the purge lease of a database they share.
+pub(crate) async fn purge_expired(state: Arc<AppState>) {
+    let Some(history) = &state.history else {
+        return;
+    };
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let days = retention_days();
+        if days <= 0 {
+            continue;
+        }
+        // Another replica sharing the database purges it (a failure is logged by the
+        // storage); this one asks again next round, in case that one has gone away.
+        if !matches!(history.storage.hold_lease("history-purge").await, Ok(true)) {
+            continue;
+        }
+        let before = chrono::Utc::now() - chrono::Duration::days(days);
+        let purged = match history.storage.prune_captions(before).await {
+            Ok(paths) => paths,
+            // Logged by the storage; the next round tries again.
+            Err(_) => continue,
+        };
+        if purged.is_empty() {
+            continue;
+        }
+        let thumbnails = remove_thumbnails(history, purged.iter().flatten().cloned()).await;
+        tracing::info!(
+            captions = purged.len(),
+            thumbnails = thumbnails.unwrap_or_default(),
+            days,
+            "Removed history past its retention"
+        );
+    }
+}
@@ -0,0 +1,598 @@
+// Getting uploads ready for the provider: format detection, decoding, downscaling and
+// encoding, plus the content hash that identifies an image's exact bytes.
+//
+// The provider bills an image by its size and takes longer to read a big one, while a caption
+// rarely gains from more than about 1500 pixels, so images are sent with their longest side at
+// most MAX_IMAGE_DIMENSION (default 1536, read per call so a SIGHUP reload applies it). The
+// response reports the upload's size and the size sent as `dimensions`.
+//
+// Uploads are hostile until decoded: a file of a few bytes can claim to be gigapixels, so
+// every decode is preceded by `check_dimensions` on the header, and malformed files come back
+// as errors rather than panics (the tests below feed damaged and random bytes through all of
+// it).
+
+use bytes::Bytes;
+use image::codecs::{bmp::BmpDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+use image::error::{LimitError, LimitErrorKind};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+use crate::contact_sheet::{self, Panel};
+use crate::modes::CaptionOptions;
+use crate::{
+    animation, buffers, elapsed_ms, heic, jpeg, orientation, raw, Dimensions, Size, Timings,
+};
+
+/// Largest upload sent to the provider unchanged unless PASSTHROUGH_MAX_BYTES says otherwise;
+/// bigger files are re-encoded.
+pub(crate) const PASSTHROUGH_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// Longest side sent to the provider unless MAX_IMAGE_DIMENSION says otherwise; larger images
+/// are downscaled first.
+pub(crate) const MAX_IMAGE_DIMENSION: u32 = 1536;
+
+/// Most pixels an upload (or a frame of one) may have, about 8K by 8K; it takes 256 MiB decoded.
+pub(crate) const MAX_DECODED_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// One image as sent in the provider's `inline_data` part.
+pub(crate) struct EncodedImage {
+    pub(crate) mime_type: &'static str,
+    /// The image file, base64-encoded only as the request is sent (see `payload`).
+    pub(crate) data: Bytes,
+    pub(crate) size: Size,
+}
+
+/// An upload as sent to the provider.
+pub(crate) struct PreparedImage {
+    pub(crate) frames: Vec<EncodedImage>,
+    /// For a contact sheet, where each panel after the first frame (the whole sheet) sits.
+    pub(crate) panels: Vec<Panel>,
+    /// The upload's size: its preview's for a camera RAW file, its frames' for an animation.
+    pub(crate) original: Size,
+}
+
+impl PreparedImage {
+    /// The upload's size and the first frame's as sent.
+    pub(crate) fn dimensions(&self) -> Option<Dimensions> {
+        let sent = self.frames.first()?.size;
+        Some(Dimensions {
+            original: self.original,
+            sent,
+        })
+    }
+}
+
+/// Gets an upload ready for the API: as a contact sheet and its panels when the options say
+/// it is one, otherwise as `prepare_frames` does. `turn` is the upload's EXIF orientation (see
+/// `orientation::read`).
+pub(crate) fn prepare_image(
+    data: &[u8],
+    turn: u32,
+    options: &CaptionOptions,
+    timings: &mut Timings,
+) -> Result<PreparedImage, image::ImageError> {
+    if !options.contact_sheet {
+        return prepare_frames(data, turn, timings);
+    }
+
+    let decode = std::time::Instant::now();
+    let span = tracing::info_span!("decode", bytes = data.len()).entered();
+    let sheet = if heic::is_heif(data) {
+        heic::decode(data)?
+    } else {
+        decode_upright(data, None, turn)?
+    };
+    let panels = contact_sheet::find_panels(&sheet);
+    drop(span);
+    timings.decode_ms = elapsed_ms(decode);
+
+    let mut frames = vec![encode_frame(&sheet, timings)?];
+    for panel in &panels {
+        frames.push(encode_frame(&contact_sheet::crop(&sheet, *panel), timings)?);
+    }
+    let original = size_of(&sheet);
+    buffers::POOL.recycle(sheet);
+    Ok(PreparedImage {
+        frames,
+        panels,
+        original,
+    })
+}
+
+/// Gets an upload ready for the API. JPEG, PNG and WebP files the provider accepts as they
+/// are go through untouched; anything else is decoded and re-encoded as JPEG (downscaled if
+/// needed). Animated GIFs and WebPs yield several frames sampled across the animation;
+/// camera RAW files are captioned from their embedded preview.
+fn prepare_frames(
+    data: &[u8],
+    turn: u32,
+    timings: &mut Timings,
+) -> Result<PreparedImage, image::ImageError> {
+    let decode = std::time::Instant::now();
+    let span = tracing::info_span!("decode", bytes = data.len()).entered();
+    let mut original = None;
+    let decoded = if heic::is_heif(data) {
+        vec![heic::decode(data)?]
+    } else if let Some(preview) = raw::decode(data)? {
+        vec![preview]
+    } else if let Some(frames) = animation::sample_frames(data, animation::MAX_SAMPLED_FRAMES)? {
+        frames
+    } else if let Some((mime_type, size)) = passthrough(data, turn) {
+        drop(span);
+        timings.decode_ms = elapsed_ms(decode);
+        let encode = std::time::Instant::now();
+        let data = Bytes::copy_from_slice(data);
+        timings.encode_ms = elapsed_ms(encode);
+        return Ok(PreparedImage {
+            frames: vec![EncodedImage {
+                mime_type,
+                data,
+                size,
+            }],
+            panels: Vec::new(),
+            original: size,
+        });
+    } else {
+        // A JPEG may be decoded at a fraction of its size; its header says how big it is.
+        original = header_size(data).map(|size| upright(size, turn));
+        vec![decode_upright(data, Some(max_dimension()), turn)?]
+    };
+    drop(span);
+    timings.decode_ms = elapsed_ms(decode);
+
+    let original = original.or_else(|| decoded.first().map(size_of)).unwrap_or_default();
+    let frames = decoded
+        .into_iter()
+        .map(|image| {
+            let frame = encode_frame(&image, timings);
+            buffers::POOL.recycle(image);
+            frame
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(PreparedImage {
+        frames,
+        panels: Vec::new(),
+        original,
+    })
+}
+
+/// Decodes a still image, after `check_dimensions`, and turns it upright as its EXIF orientation
+/// says. `target` is the longest side it is going to be downscaled to, if any, which lets a JPEG
+/// be decoded at a fraction of its size.
+pub(crate) fn decode_still(
+    data: &[u8],
+    target: Option<u32>,
+) -> Result<image::DynamicImage, image::ImageError> {
+    decode_upright(data, target, orientation::read(data))
+}
+
+fn decode_upright(
+    data: &[u8],
+    target: Option<u32>,
+    turn: u32,
+) -> Result<image::DynamicImage, image::ImageError> {
+    check_dimensions(data)?;
+    let cursor = std::io::Cursor::new(data);
+    let image = match image::guess_format(data) {
+        Ok(image::ImageFormat::Jpeg) => jpeg::decode(data, target),
+        Ok(image::ImageFormat::Png) => buffers::decode(PngDecoder::new(cursor)?),
+        Ok(image::ImageFormat::WebP) => buffers::decode(WebPDecoder::new(cursor)?),
+        Ok(image::ImageFormat::Tiff) => buffers::decode(TiffDecoder::new(cursor)?),
+        Ok(image::ImageFormat::Bmp) => buffers::decode(BmpDecoder::new(cursor)?),
+        _ => image::load_from_memory(data),
+    }?;
+    Ok(orientation::apply(image, turn))
+}
+
+/// Refuses an image whose header claims more than `MAX_DECODED_PIXELS`, before decoding
+/// allocates for them. Files whose header can't be read are left for the decoder to reject.
+pub(crate) fn check_dimensions(data: &[u8]) -> Result<(), image::ImageError> {
+    match header_size(data) {
+        Some(size) => check_pixels(size.width, size.height),
+        None => Ok(()),
+    }
+}
+
+/// The size an image's header gives, if it can be read.
+fn header_size(data: &[u8]) -> Option<Size> {
+    let reader = image::io::Reader::new(std::io::Cursor::new(data)).with_guessed_format().ok()?;
+    let (width, height) = reader.into_dimensions().ok()?;
+    Some(Size { width, height })
+}
+
+/// `size` once turned as `turn` says.
+fn upright(size: Size, turn: u32) -> Size {
+    if orientation::swaps_sides(turn) {
+        Size {
+            width: size.height,
+            height: size.width,
+        }
+    } else {
+        size
+    }
+}
+
+fn size_of(image: &image::DynamicImage) -> Size {
+    Size {
+        width: image.width(),
+        height: image.height(),
+    }
+}
+
+/// The longest side an image is sent with, MAX_IMAGE_DIMENSION.
+pub(crate) fn max_dimension() -> u32 {
+    crate::settings::var("MAX_IMAGE_DIMENSION")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|&dimension| dimension > 0)
+        .unwrap_or(MAX_IMAGE_DIMENSION)
+}
+
+/// Decoder limits that keep any one frame within `MAX_DECODED_PIXELS`, for decoders that
+/// allocate as the file says.
+pub(crate) fn frame_limits() -> image::io::Limits {
+    let mut limits = image::io::Limits::default();
+    limits.max_alloc = Some(MAX_DECODED_PIXELS * 4);
+    limits
+}
+
+/// Refuses `width` by `height` pixels when that is more than `MAX_DECODED_PIXELS`.
+pub(crate) fn check_pixels(width: u32, height: u32) -> Result<(), image::ImageError> {
+    if u64::from(width) * u64::from(height) > MAX_DECODED_PIXELS {
+        let error = LimitError::from_kind(LimitErrorKind::DimensionError);
+        return Err(image::ImageError::Limits(error));
+    }
+    Ok(())
+}
+
+/// When an upload goes to the provider as it is, read from the environment on every call so a
+/// SIGHUP reload applies it: PASSTHROUGH_MAX_BYTES (default 4 MiB; 0 turns passthrough off),
+/// PASSTHROUGH_MAX_DIMENSION (default and at most MAX_IMAGE_DIMENSION) and
+/// PASSTHROUGH_FORMATS (default `jpeg,png,webp`, the formats the provider takes).
+struct Passthrough {
+    max_bytes: usize,
+    max_dimension: u32,
+    formats: Vec<image::ImageFormat>,
+}
+
+impl Passthrough {
+    fn from_env() -> Self {
+        let read = |name: &str, default: u64| {
+            crate::settings::var(name)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(default)
+        };
+        let formats = match crate::settings::var("PASSTHROUGH_FORMATS") {
+            Ok(list) => list.split(',').filter_map(passthrough_format).collect(),
+            Err(_) => vec![
+                image::ImageFormat::Jpeg,
+                image::ImageFormat::Png,
+                image::ImageFormat::WebP,
+            ],
+        };
+        let max_dimension = u64::from(max_dimension());
+        Passthrough {
+            max_bytes: read("PASSTHROUGH_MAX_BYTES", PASSTHROUGH_MAX_BYTES as u64) as usize,
+            max_dimension: read("PASSTHROUGH_MAX_DIMENSION", max_dimension).min(max_dimension)
+                as u32,
+            formats,
+        }
+    }
+}
+
+/// A format named in PASSTHROUGH_FORMATS; only those the provider takes count.
+fn passthrough_format(name: &str) -> Option<image::ImageFormat> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "jpeg" | "jpg" => Some(image::ImageFormat::Jpeg),
+        "png" => Some(image::ImageFormat::Png),
+        "webp" => Some(image::ImageFormat::WebP),
+        "" => None,
+        other => {
+            tracing::warn!(
+                "Ignoring {:?} in PASSTHROUGH_FORMATS; expected jpeg, png or webp",
+                other
+            );
+            None
+        }
+    }
+}
+
+/// The MIME type and size of an upload that can be sent as is: an upright JPEG, PNG or WebP
+/// within the size and dimension limits (see `Passthrough`). One whose EXIF orientation says to
+/// turn it is re-encoded upright instead, as the provider may not honour the tag. Only the
+/// header is read, so this is cheap for big files.
+fn passthrough(data: &[u8], turn: u32) -> Option<(&'static str, Size)> {
+    let limits = Passthrough::from_env();
+    if data.len() > limits.max_bytes {
+        return None;
+    }
+    let format = image::guess_format(data).ok()?;
+    if !limits.formats.contains(&format) {
+        return None;
+    }
+    let mime_type = match format {
+        image::ImageFormat::Jpeg => "image/jpeg",
+        image::ImageFormat::Png => "image/png",
+        image::ImageFormat::WebP => "image/webp",
+        _ => return None,
+    };
+    if turn != 1 {
+        return None;
+    }
+    let (width, height) = image::io::Reader::with_format(std::io::Cursor::new(data), format)
+        .into_dimensions()
+        .ok()?;
+    (width.max(height) <= limits.max_dimension).then_some((mime_type, Size { width, height }))
+}
+
+/// Re-encodes a decoded image as JPEG, adding the resize and encode time to `timings`.
+fn encode_frame(
+    img: &image::DynamicImage,
+    timings: &mut Timings,
+) -> Result<EncodedImage, image::ImageError> {
+    let resize = std::time::Instant::now();
+    let span = tracing::info_span!("resize", width = img.width(), height = img.height());
+    let img = span.in_scope(|| downscale(img));
+    timings.resize_ms += elapsed_ms(resize);
+
+    let encode = std::time::Instant::now();
+    let data = tracing::info_span!("encode").in_scope(|| encode_jpeg(&img))?;
+    timings.encode_ms += elapsed_ms(encode);
+    let size = size_of(&img);
+    if let Cow::Owned(img) = img {
+        buffers::POOL.recycle(img);
+    }
+
+    Ok(EncodedImage {
+        mime_type: "image/jpeg",
+        data: data.into(),
+        size,
+    })
+}
+
+/// The image with its longest side at most MAX_IMAGE_DIMENSION.
+pub(crate) fn downscale(img: &image::DynamicImage) -> Cow<'_, image::DynamicImage> {
+    let max = max_dimension();
+    if img.width().max(img.height()) <= max {
+        return Cow::Borrowed(img);
+    }
+    Cow::Owned(img.resize(max, max, image::imageops::FilterType::Lanczos3))
+}
+
+/// The image as the JPEG sent to the provider.
+pub(crate) fn encode_jpeg(img: &image::DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+    // JPEG has no alpha channel; animation frames always come back as RGBA.
+    jpeg::encode(img, 85)
+}
+
+/// A JPEG of the image in `data`, turned upright from its EXIF orientation `turn`, at most
+/// `size` pixels on its longer side, from the first frame of an animation or the preview of a
+/// camera RAW file.
+pub(crate) fn thumbnail_jpeg(
+    data: &[u8],
+    turn: u32,
+    size: u32,
+) -> Result<Vec<u8>, image::ImageError> {
+    let decoded = if heic::is_heif(data) {
+        heic::decode(data)?
+    } else if let Some(preview) = raw::decode(data)? {
+        preview
+    } else {
+        decode_upright(data, Some(size), turn)?
+    };
+    let thumbnail = decoded.thumbnail(size, size);
+    buffers::POOL.recycle(decoded);
+    jpeg::encode(&thumbnail, 80)
+}
+
+/// Hex SHA-256 of `data`, prefixed with the algorithm name.
+pub(crate) fn content_hash(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Frame, ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage};
+    use img_parts::{jpeg::Jpeg, ImageEXIF};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let image = RgbImage::from_fn(24, 16, |x, y| Rgb([x as u8 * 10, y as u8 * 15, 90]));
+        let mut data = Vec::new();
+        image.write_to(&mut std::io::Cursor::new(&mut data), format).unwrap();
+        data
+    }
+
+    fn animated_gif() -> Vec<u8> {
+        let mut data = Vec::new();
+        let frames = (0..3u8)
+            .map(|i| Frame::new(RgbaImage::from_pixel(12, 8, Rgba([i * 80, 0, 0, 255]))));
+        GifEncoder::new(&mut data).encode_frames(frames).unwrap();
+        data
+    }
+
+    /// A little-endian TIFF whose only IFD points at `jpeg` as its preview, the way camera RAW
+    /// files carry theirs.
+    fn raw_with_preview(jpeg: &[u8]) -> Vec<u8> {
+        let mut data = b"II*\0".to_vec();
+        data.extend(8u32.to_le_bytes());
+        // Tag, type (LONG), count and value; the JPEG starts after the 38-byte header and IFD.
+        let entries = [(0x201u16, 4u16, 1u32, 38u32), (0x202, 4, 1, jpeg.len() as u32)];
+        data.extend((entries.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(kind.to_le_bytes());
+            data.extend(count.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        data.extend(0u32.to_le_bytes());
+        data.extend(jpeg);
+        data
+    }
+
+    /// Well-formed files of every format the decode path tells apart, for damaging.
+    fn seeds() -> Vec<Vec<u8>> {
+        let jpeg = encode(ImageOutputFormat::Jpeg(80));
+        vec![
+            encode(ImageOutputFormat::Png),
+            encode(ImageOutputFormat::Bmp),
+            encode(ImageOutputFormat::Tiff),
+            animated_gif(),
+            raw_with_preview(&jpeg),
+            jpeg,
+        ]
+    }
+
+    /// The magic numbers that send bytes down each decoder.
+    const HEADERS: &[&[u8]] = &[
+        b"\x89PNG\r\n\x1a\n",
+        b"\xff\xd8\xff",
+        b"GIF89a",
+        b"RIFF\0\0\0\0WEBPVP8L",
+        b"RIFF\0\0\0\0WEBPVP8X",
+        b"II*\0",
+        b"MM\0*",
+        b"BM",
+        b"\0\0\0\x18ftypheic",
+    ];
+
+    /// Runs `data` through everything that decodes an upload; an error is fine, a panic isn't.
+    fn decode_everything(data: &[u8]) {
+        let mut options = CaptionOptions::default();
+        let turn = orientation::read(data);
+        let _ = prepare_image(data, turn, &options, &mut Timings::default());
+        options.contact_sheet = true;
+        let _ = prepare_image(data, turn, &options, &mut Timings::default());
+        let _ = thumbnail_jpeg(data, turn, 64);
+        let _ = crate::capture::read(data, &options);
+    }
+
+    #[test]
+    fn seeds_decode() {
+        for seed in seeds() {
+            let options = CaptionOptions::default();
+            let prepared = prepare_image(&seed, 1, &options, &mut Timings::default());
+            assert!(prepared.is_ok_and(|prepared| !prepared.frames.is_empty()));
+        }
+    }
+
+    #[test]
+    fn big_images_are_sent_downscaled() {
+        let big = RgbImage::from_fn(2000, 1000, |x, y| Rgb([x as u8, y as u8, 90]));
+        let mut data = Vec::new();
+        big.write_to(&mut std::io::Cursor::new(&mut data), ImageOutputFormat::Png).unwrap();
+
+        let options = CaptionOptions::default();
+        let prepared = prepare_image(&data, 1, &options, &mut Timings::default()).unwrap();
+        let dimensions = prepared.dimensions().unwrap();
+        assert_eq!((dimensions.original.width, dimensions.original.height), (2000, 1000));
+        assert_eq!((dimensions.sent.width, dimensions.sent.height), (1536, 768));
+        assert_eq!(prepared.frames[0].mime_type, "image/jpeg");
+
+        // Small enough to go as it is.
+        let small = encode(ImageOutputFormat::Png);
+        let prepared = prepare_image(&small, 1, &options, &mut Timings::default()).unwrap();
+        let dimensions = prepared.dimensions().unwrap();
+        assert_eq!(dimensions.original, dimensions.sent);
+        assert_eq!(prepared.frames[0].data, small);
+    }
+
+    #[test]
+    fn photos_are_turned_upright() {
+        // Stored on its side, red on the left and blue on the right, with an EXIF orientation
+        // of 6 (turn 90° clockwise): red is the top of the photo.
+        let stored = RgbImage::from_fn(48, 32, |x, _| match x < 24 {
+            true => Rgb([220, 0, 0]),
+            false => Rgb([0, 0, 220]),
+        });
+        let mut data = Vec::new();
+        stored.write_to(&mut std::io::Cursor::new(&mut data), ImageOutputFormat::Jpeg(90)).unwrap();
+        let mut exif = b"II*\0".to_vec();
+        exif.extend(8u32.to_le_bytes());
+        exif.extend(1u16.to_le_bytes());
+        // Orientation, SHORT, one value.
+        exif.extend([0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        exif.extend(0u32.to_le_bytes());
+        let mut jpeg = Jpeg::from_bytes(data.into()).unwrap();
+        jpeg.set_exif(Some(exif.into()));
+        let data = jpeg.encoder().bytes();
+
+        let turn = orientation::read(&data);
+        assert_eq!(turn, 6);
+        let prepared =
+            prepare_image(&data, turn, &CaptionOptions::default(), &mut Timings::default())
+                .unwrap();
+        let dimensions = prepared.dimensions().unwrap();
+        assert_eq!((dimensions.original.width, dimensions.original.height), (32, 48));
+        assert_eq!((dimensions.sent.width, dimensions.sent.height), (32, 48));
+        assert_ne!(prepared.frames[0].data, data, "sent as it was, on its side");
+        let sent = image::load_from_memory(&prepared.frames[0].data).unwrap().to_rgb8();
+        assert!(sent.get_pixel(16, 4)[0] > 150 && sent.get_pixel(16, 44)[2] > 150);
+
+        let thumbnail = image::load_from_memory(&thumbnail_jpeg(&data, turn, 24).unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (16, 24));
+    }
+
+    #[test]
+    fn images_claiming_to_be_huge_are_refused() {
+        // A BMP header for 60000 by 60000 pixels, a GIF canvas of 65535 by 65535 around small
+        // frames, and a small canvas around a frame of 65535 by 65535: a few hundred bytes that
+        // would decode to gigabytes.
+        let mut bmp = encode(ImageOutputFormat::Bmp);
+        bmp[18..26].copy_from_slice(&[0x60, 0xea, 0, 0, 0x60, 0xea, 0, 0]);
+        let mut canvas = animated_gif();
+        canvas[6..10].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        let mut frame = animated_gif();
+        let descriptor = frame.iter().position(|&byte| byte == 0x2c).unwrap();
+        frame[descriptor + 5..descriptor + 9].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+
+        for data in [bmp, canvas, frame] {
+            let options = CaptionOptions::default();
+            let prepared = prepare_image(&data, 1, &options, &mut Timings::default());
+            assert!(matches!(prepared, Err(image::ImageError::Limits(_))));
+            assert!(matches!(thumbnail_jpeg(&data, 1, 64), Err(image::ImageError::Limits(_))));
+        }
+    }
+
+    // 256 cases each; PROPTEST_CASES=100000 for a longer hunt.
+    proptest! {
+        #[test]
+        fn random_bytes_never_panic(
+            header in proptest::option::of(proptest::sample::select(HEADERS)),
+            body in vec(any::<u8>(), 0..512),
+        ) {
+            let mut data = header.unwrap_or_default().to_vec();
+            data.extend(body);
+            decode_everything(&data);
+        }
+
+        #[test]
+        fn damaged_images_never_panic(
+            seed in any::<Index>(),
+            flips in vec((any::<Index>(), 1..=255u8), 0..8),
+            splice in proptest::option::of((any::<Index>(), vec(any::<u8>(), 1..16))),
+            truncate in proptest::option::of(any::<Index>()),
+        ) {
+            let seeds = seeds();
+            let mut data = seeds[seed.index(seeds.len())].clone();
+            for (at, mask) in flips {
+                let at = at.index(data.len());
+                data[at] ^= mask;
+            }
+            if let Some((at, bytes)) = splice {
+                let at = at.index(data.len());
+                let end = (at + bytes.len()).min(data.len());
+                data.splice(at..end, bytes);
+            }
+            if let Some(at) = truncate {
+                data.truncate(at.index(data.len()));
+            }
+            decode_everything(&data);
+        }
+    }
+}
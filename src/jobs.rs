@@ -0,0 +1,477 @@
+// Asynchronous caption jobs: `POST /jobs` queues a batch and returns immediately, and
+// `GET /jobs/:id` reports progress and, once finished, the results, to the tenant that sent it.
+//
+// Clients waiting for big batches don't need to poll every second: `?wait=30s` holds the
+// request open until the job changes (or finishes), and `If-None-Match` / `If-Modified-Since`
+// turn an unchanged job into a `304 Not Modified`.
+//
+// Jobs share the provider with interactive requests through the fair scheduler, so a job
+// stays `queued` until its first image gets a slot; meanwhile it reports its place in line.
+//
+// Jobs live in memory, and are saved to JOBS_FILE (default `jobs.json`; empty to turn it off)
+// when the server shuts down and loaded again when it starts, so a restart doesn't lose them.
+// Shutting down ends long polls, gives running jobs until the shutdown deadline to finish, and
+// saves the ones still running as completed, with the images they didn't get to as failed.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path as FilePath, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::etag::none_match;
+use crate::handlers::BatchItem;
+use crate::scheduler::Tenant;
+use crate::server::AppState;
+use crate::CaptionError;
+
+/// Where jobs are saved unless JOBS_FILE says otherwise.
+pub(crate) const DEFAULT_FILE: &str = "jobs.json";
+
+/// Longest a single `GET /jobs/:id?wait=...` may be held open.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Finished jobs are forgotten after this long.
+const JOB_TTL: chrono::TimeDelta = chrono::TimeDelta::hours(1);
+
+/// The error of an image a shutdown left uncaptioned.
+const INTERRUPTED: &str = "The server shut down before captioning this image";
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// While queued: where the job's next image stands in the provider queue (1 = next).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    /// While queued: rough seconds until the job starts, once the server has timings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_seconds: Option<u64>,
+    /// Filled in, in upload order, once the job has finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<BatchItem>>,
+    /// Captioned so far, in upload order, until they become `results`.
+    #[serde(skip)]
+    done: Vec<BatchItem>,
+    /// The uploads' file names, for the images a shutdown leaves uncaptioned.
+    #[serde(skip)]
+    file_names: Vec<Option<String>>,
+}
+
+/// A job as JOBS_FILE keeps it, with its version so ETags stay valid across a restart.
+#[derive(Deserialize)]
+struct SavedJob {
+    version: u64,
+    #[serde(default)]
+    tenant: String,
+    job: Job,
+}
+
+/// A job plus a version counter that long-polling requests wait on.
+pub struct JobEntry {
+    job: Mutex<Job>,
+    version: watch::Sender<u64>,
+    /// Who sent the job, so their data can be removed (see `JobStore::forget_tenant`).
+    tenant: String,
+}
+
+impl JobEntry {
+    /// Applies a change and wakes anyone waiting on the job.
+    pub fn update(&self, change: impl FnOnce(&mut Job)) {
+        let mut job = self.job.lock().expect("job lock poisoned");
+        change(&mut job);
+        job.updated_at = Utc::now();
+        // Bumped under the job lock so a version always matches the state readers see.
+        self.version.send_modify(|version| *version += 1);
+    }
+
+    /// Moves a queued job to running; called when its first image gets a provider slot.
+    pub fn mark_started(&self) {
+        if self.status() == JobStatus::Queued {
+            self.update(|job| {
+                job.status = JobStatus::Running;
+                job.queue_position = None;
+                job.eta_seconds = None;
+            });
+        }
+    }
+
+    /// Records a queued job's place in line, bumping the version only if it changed.
+    pub fn set_queue_position(&self, position: usize, eta: Option<Duration>) {
+        // Round up so a job that is about to start never claims zero seconds.
+        let eta_seconds = eta.map(|eta| eta.as_secs_f64().ceil() as u64);
+        let changed = {
+            let job = self.job.lock().expect("job lock poisoned");
+            job.status == JobStatus::Queued
+                && (job.queue_position != Some(position) || job.eta_seconds != eta_seconds)
+        };
+        if changed {
+            self.update(|job| {
+                job.queue_position = Some(position);
+                job.eta_seconds = eta_seconds;
+            });
+        }
+    }
+
+    /// Counts an image's result; they become the job's results once it finishes.
+    pub fn record(&self, item: BatchItem) {
+        self.update(|job| {
+            // Too late, when a shutdown has finished the job.
+            if job.status == JobStatus::Completed {
+                return;
+            }
+            if item.error.is_some() {
+                job.failed += 1;
+            } else {
+                job.succeeded += 1;
+            }
+            job.done.push(item);
+        });
+    }
+
+    /// Marks the job completed with the results recorded so far.
+    pub fn finish(&self) {
+        self.update(|job| {
+            job.status = JobStatus::Completed;
+            job.queue_position = None;
+            job.eta_seconds = None;
+            job.results = Some(std::mem::take(&mut job.done));
+        });
+    }
+
+    /// Finishes a job the server is shutting down on, failing the images it didn't get to.
+    fn interrupt(&self) {
+        if self.is_finished() {
+            return;
+        }
+        self.update(|job| {
+            let remaining = job.file_names.split_off(job.done.len().min(job.file_names.len()));
+            job.failed += remaining.len();
+            job.done.extend(remaining.into_iter().map(|file_name| BatchItem {
+                file_name,
+                response: None,
+                xmp: None,
+                error: Some(INTERRUPTED.to_string()),
+            }));
+        });
+        self.finish();
+    }
+
+    pub fn id(&self) -> String {
+        self.job.lock().expect("job lock poisoned").id.clone()
+    }
+
+    /// The job as JSON, for responses.
+    pub fn to_json(&self) -> String {
+        let job = self.job.lock().expect("job lock poisoned");
+        serde_json::to_string(&*job).expect("jobs always serialize")
+    }
+
+    fn status(&self) -> JobStatus {
+        self.job.lock().expect("job lock poisoned").status
+    }
+
+    fn is_finished(&self) -> bool {
+        self.status() == JobStatus::Completed
+    }
+
+    fn updated_at(&self) -> DateTime<Utc> {
+        self.job.lock().expect("job lock poisoned").updated_at
+    }
+}
+
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Arc<JobEntry>>>,
+    /// Set when the server starts shutting down, ending long polls.
+    closing: watch::Sender<bool>,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        JobStore {
+            jobs: Mutex::default(),
+            closing: watch::channel(false).0,
+        }
+    }
+}
+
+impl JobStore {
+    /// Registers a new queued job of `tenant`'s for the uploads named `file_names`.
+    pub fn create(&self, tenant: &str, file_names: Vec<Option<String>>) -> Arc<JobEntry> {
+        let now = Utc::now();
+        let id = format!("job_{:016x}", rand::random::<u64>());
+        let entry = Arc::new(JobEntry {
+            job: Mutex::new(Job {
+                id: id.clone(),
+                status: JobStatus::Queued,
+                created_at: now,
+                updated_at: now,
+                total: file_names.len(),
+                succeeded: 0,
+                failed: 0,
+                queue_position: None,
+                eta_seconds: None,
+                results: None,
+                done: Vec::new(),
+                file_names,
+            }),
+            version: watch::channel(0).0,
+            tenant: tenant.to_string(),
+        });
+
+        let mut jobs = self.jobs.lock().expect("job store lock poisoned");
+        jobs.retain(|_, entry| !entry.is_finished() || now - entry.updated_at() < JOB_TTL);
+        jobs.insert(id, entry.clone());
+        entry
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<JobEntry>> {
+        self.jobs.lock().expect("job store lock poisoned").get(id).cloned()
+    }
+
+    /// Forgets `tenant`'s jobs and their results; returns how many there were. A running one
+    /// finishes, but nobody can fetch it any more.
+    pub fn forget_tenant(&self, tenant: &str) -> u64 {
+        let mut jobs = self.jobs.lock().expect("job store lock poisoned");
+        let before = jobs.len();
+        jobs.retain(|_, entry| entry.tenant != tenant);
+        (before - jobs.len()) as u64
+    }
+
+    /// The jobs JOBS_FILE kept from the last run; a file that can't be read is logged and
+    /// ignored.
+    pub fn load() -> JobStore {
+        let store = JobStore::default();
+        let Some(path) = jobs_file() else {
+            return store;
+        };
+        let saved: Vec<SavedJob> = match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(saved) => saved,
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "Ignoring saved jobs");
+                    return store;
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return store,
+            Err(e) => {
+                tracing::error!(path = %path.display(), error = %e, "Could not read saved jobs");
+                return store;
+            }
+        };
+
+        let now = Utc::now();
+        let mut jobs = store.jobs.lock().expect("job store lock poisoned");
+        for SavedJob { version, tenant, job } in saved {
+            if job.status == JobStatus::Completed && now - job.updated_at >= JOB_TTL {
+                continue;
+            }
+            let entry = Arc::new(JobEntry {
+                job: Mutex::new(job),
+                version: watch::channel(version).0,
+                tenant,
+            });
+            // Only finished jobs are saved, but one that wasn't could never finish now.
+            entry.interrupt();
+            jobs.insert(entry.id(), entry);
+        }
+        drop(jobs);
+        store
+    }
+
+    /// Ends long polls, so they don't hold up a shutdown.
+    pub fn close(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// Waits until every job has finished or `deadline` passes; returns how many haven't.
+    pub async fn drain(&self, deadline: tokio::time::Instant) -> usize {
+        let running: Vec<_> = {
+            let jobs = self.jobs.lock().expect("job store lock poisoned");
+            jobs.values().filter(|entry| !entry.is_finished()).cloned().collect()
+        };
+        let mut unfinished = 0;
+        for entry in running {
+            let mut versions = entry.version.subscribe();
+            let finished = async {
+                while !entry.is_finished() && versions.changed().await.is_ok() {}
+            };
+            if tokio::time::timeout_at(deadline, finished).await.is_err() {
+                unfinished += 1;
+            }
+        }
+        unfinished
+    }
+
+    /// Finishes the jobs still running as interrupted and writes all of them to JOBS_FILE.
+    pub fn save(&self) {
+        let Some(path) = jobs_file() else {
+            return;
+        };
+        let entries: Vec<_> = {
+            let jobs = self.jobs.lock().expect("job store lock poisoned");
+            jobs.values().cloned().collect()
+        };
+        for entry in &entries {
+            entry.interrupt();
+        }
+        let guards: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                let job = entry.job.lock().expect("job lock poisoned");
+                (*entry.version.borrow(), &entry.tenant, job)
+            })
+            .collect();
+        let saved: Vec<_> = guards
+            .iter()
+            .map(|(version, tenant, job)| {
+                serde_json::json!({ "version": version, "tenant": tenant, "job": &**job })
+            })
+            .collect();
+        match write_file(&path, &serde_json::to_vec(&saved).expect("jobs always serialize")) {
+            Ok(()) => tracing::info!("Saved {} jobs to {}", saved.len(), path.display()),
+            Err(e) => tracing::error!(path = %path.display(), error = %e, "Could not save jobs"),
+        }
+    }
+}
+
+/// JOBS_FILE, or `jobs.json`; `None` when it is set empty.
+fn jobs_file() -> Option<PathBuf> {
+    match crate::settings::var("JOBS_FILE") {
+        Ok(path) if path.trim().is_empty() => None,
+        Ok(path) => Some(PathBuf::from(path.trim())),
+        Err(_) => Some(PathBuf::from(DEFAULT_FILE)),
+    }
+}
+
+/// Writes `data` to `path` through a temporary file, so a crash never leaves half of it.
+fn write_file(path: &FilePath, data: &[u8]) -> std::io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, data)?;
+    std::fs::rename(&temporary, path)
+}
+
+#[derive(Deserialize)]
+pub struct JobQuery {
+    /// How long to wait for a change, e.g. `30s`, `500ms` or `2m` (bare numbers are seconds).
+    wait: Option<String>,
+}
+
+fn parse_wait(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .map_or((value, ""), |split| value.split_at(split));
+    let number: f64 = number.parse().ok()?;
+    let seconds = match unit {
+        "" | "s" => number,
+        "ms" => number / 1000.0,
+        "m" => number * 60.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(seconds).ok().map(|wait| wait.min(MAX_WAIT))
+}
+
+fn etag(version: u64) -> String {
+    format!("\"v{}\"", version)
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether the client's cached copy (per `If-None-Match`, else `If-Modified-Since`) is
+/// still current.
+fn not_modified(headers: &HeaderMap, version: u64, updated_at: DateTime<Utc>) -> Option<bool> {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return Some(none_match(headers, etag(version).trim_matches('"')));
+    }
+
+    let since = headers.get(header::IF_MODIFIED_SINCE)?.to_str().ok()?;
+    let since = DateTime::parse_from_rfc2822(since).ok()?;
+    // HTTP dates have whole-second precision.
+    Some(updated_at.timestamp() <= since.timestamp())
+}
+
+/// `GET /jobs/:id`, for the tenant that sent the job only.
+pub async fn get_job(
+    State(state): State<Arc<AppState>>,
+    tenant: Tenant,
+    Path(id): Path<String>,
+    Query(query): Query<JobQuery>,
+    headers: HeaderMap,
+) -> Result<Response, CaptionError> {
+    let entry = state
+        .jobs
+        .get(&id)
+        // Another tenant's job is one this caller has no way to tell from a missing one.
+        .filter(|entry| entry.tenant == tenant.name)
+        .ok_or_else(|| CaptionError::NotFound(format!("No job {}", id)))?;
+    let wait = match query.wait.as_deref() {
+        Some(wait) => parse_wait(wait).ok_or_else(|| {
+            CaptionError::BadRequest(format!("wait must be a number of seconds, not '{}'", wait))
+        })?,
+        None => Duration::ZERO,
+    };
+    let conditional =
+        headers.contains_key(header::IF_NONE_MATCH) || headers.contains_key(header::IF_MODIFIED_SINCE);
+
+    let mut versions = entry.version.subscribe();
+    let mut closing = state.jobs.closing.subscribe();
+    let deadline = tokio::time::Instant::now() + wait;
+
+    // With a cached copy, wait until it goes stale; without one, wait for the job to finish.
+    loop {
+        let version = *versions.borrow_and_update();
+        let ready = if conditional {
+            not_modified(&headers, version, entry.updated_at()) != Some(true)
+        } else {
+            entry.is_finished()
+        };
+        if ready {
+            break;
+        }
+        tokio::select! {
+            changed = tokio::time::timeout_at(deadline, versions.changed()) => match changed {
+                Ok(Ok(())) => continue,
+                // Timed out, or the job went away.
+                _ => break,
+            },
+            _ = closing.wait_for(|closing| *closing) => break,
+        }
+    }
+
+    let job = entry.job.lock().expect("job lock poisoned");
+    let version = *entry.version.borrow();
+    let cache_headers = [
+        (header::ETAG, etag(version)),
+        (header::LAST_MODIFIED, http_date(job.updated_at)),
+        (header::CACHE_CONTROL, "no-cache".to_string()),
+    ];
+
+    if not_modified(&headers, version, job.updated_at) == Some(true) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+    let body = serde_json::to_string(&*job).expect("jobs always serialize");
+    Ok((cache_headers, [(header::CONTENT_TYPE, "application/json")], body).into_response())
+}
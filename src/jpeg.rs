@@ -0,0 +1,145 @@
+// JPEG decoding and encoding, with libjpeg-turbo's SIMD codec (as built into mozjpeg) behind the
+// `mozjpeg` feature: much faster than the pure-Rust codec on big photos, and able to decode a
+// photo straight at a fraction of its size when it is only going to be downscaled.
+//
+// JPEG_BACKEND picks the codec, read per call: `mozjpeg` (the default when built with the
+// feature) or `image`, the pure-Rust codec, which is always there. A file the fast codec can't
+// read (CMYK, or damaged) goes to the pure-Rust one, which then reports the error if there is
+// one.
+
+use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
+use image::{DynamicImage, ImageError};
+
+use crate::buffers;
+
+/// Which codec JPEG_BACKEND picks.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backend {
+    Image,
+    Mozjpeg,
+}
+
+impl Backend {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Backend::Image => "image",
+            Backend::Mozjpeg => "mozjpeg",
+        }
+    }
+}
+
+/// The codec to use now, as JPEG_BACKEND says; the pure-Rust one when the fast one isn't
+/// built in.
+pub(crate) fn backend() -> Backend {
+    let default = if cfg!(feature = "mozjpeg") {
+        Backend::Mozjpeg
+    } else {
+        Backend::Image
+    };
+    let Ok(value) = crate::settings::var("JPEG_BACKEND") else {
+        return default;
+    };
+    match value.trim() {
+        "" => default,
+        "image" => Backend::Image,
+        "mozjpeg" if cfg!(feature = "mozjpeg") => Backend::Mozjpeg,
+        "mozjpeg" => {
+            tracing::warn!("Ignoring JPEG_BACKEND=mozjpeg; built without the `mozjpeg` feature");
+            Backend::Image
+        }
+        other => {
+            tracing::warn!("Ignoring JPEG_BACKEND={:?}; expected image or mozjpeg", other);
+            default
+        }
+    }
+}
+
+/// Decodes a JPEG. With `target`, the longest side the caller is going to downscale it to, the
+/// fast codec decodes at the smallest scale that still covers it.
+pub(crate) fn decode(data: &[u8], target: Option<u32>) -> Result<DynamicImage, ImageError> {
+    if backend() == Backend::Mozjpeg {
+        if let Some(image) = fast::decode(data, target) {
+            return Ok(image);
+        }
+    }
+    buffers::decode(JpegDecoder::new(std::io::Cursor::new(data))?)
+}
+
+/// Encodes an image as a JPEG of `quality` (1-100), without its alpha channel.
+pub(crate) fn encode(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, ImageError> {
+    // Only images that aren't RGB already are copied to convert them.
+    let converted;
+    let rgb = match image.as_rgb8() {
+        Some(rgb) => rgb,
+        None => {
+            converted = image.to_rgb8();
+            &converted
+        }
+    };
+    if backend() == Backend::Mozjpeg {
+        if let Some(jpeg) = fast::encode(rgb, quality) {
+            return Ok(jpeg);
+        }
+    }
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality).encode_image(rgb)?;
+    Ok(jpeg)
+}
+
+/// The fast codec. libjpeg reports errors by unwinding, so every call is behind
+/// `catch_unwind`; `None` means it failed and the pure-Rust codec should try.
+#[cfg(feature = "mozjpeg")]
+mod fast {
+    use image::{DynamicImage, RgbImage};
+    use std::panic::catch_unwind;
+
+    pub(super) fn decode(data: &[u8], target: Option<u32>) -> Option<DynamicImage> {
+        let decoded = catch_unwind(|| -> std::io::Result<Option<RgbImage>> {
+            let mut decompress = mozjpeg::Decompress::new_mem(data)?;
+            if let Some(target) = target {
+                let (width, height) = decompress.size();
+                decompress.scale(scale(width.max(height), target));
+            }
+            let mut started = decompress.rgb()?;
+            let (width, height) = (started.width() as u32, started.height() as u32);
+            let pixels: Vec<u8> = started.read_scanlines()?;
+            started.finish()?;
+            Ok(RgbImage::from_raw(width, height, pixels))
+        });
+        decoded.ok()?.ok()?.map(DynamicImage::ImageRgb8)
+    }
+
+    /// The smallest scale, in eighths, at which `longest` pixels still cover `target`.
+    fn scale(longest: usize, target: u32) -> u8 {
+        (1..8u8)
+            .find(|eighths| longest * usize::from(*eighths) / 8 >= target as usize)
+            .unwrap_or(8)
+    }
+
+    pub(super) fn encode(image: &RgbImage, quality: u8) -> Option<Vec<u8>> {
+        let encoded = catch_unwind(|| -> std::io::Result<Vec<u8>> {
+            let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+            // Baseline libjpeg-turbo settings: mozjpeg's defaults trade much speed for size.
+            compress.set_fastest_defaults();
+            compress.set_size(image.width() as usize, image.height() as usize);
+            compress.set_quality(f32::from(quality));
+            let mut started = compress.start_compress(Vec::new())?;
+            started.write_scanlines(image.as_raw())?;
+            started.finish()
+        });
+        encoded.ok()?.ok()
+    }
+}
+
+#[cfg(not(feature = "mozjpeg"))]
+mod fast {
+    use image::{DynamicImage, RgbImage};
+
+    pub(super) fn decode(_data: &[u8], _target: Option<u32>) -> Option<DynamicImage> {
+        None
+    }
+
+    pub(super) fn encode(_image: &RgbImage, _quality: u8) -> Option<Vec<u8>> {
+        None
+    }
+}
@@ -0,0 +1,212 @@
+// Managed API keys, so one deployment can serve several teams. Keys live in the API_KEYS_DB
+// database (see `auth`) and are created and revoked through the admin API:
+//
+//   GET    /admin/keys         every key, without the keys themselves
+//   POST   /admin/keys         create one: {"name": "...", "provider_key": "...",
+//                              "daily_quota": 500, "monthly_quota": 10000,
+//                              "allowed_modes": ["caption", "alt_text"]}
+//   DELETE /admin/keys/:name   revoke it
+//
+// Everything but the name is optional. The new key is returned once, in the creation
+// response; only its digest is stored. A revoked key's name can't be used again, since the
+// tenant's history, cached captions and brand voice are kept under it. `provider_key` is a
+// Gemini API key the tenant's captions are billed to instead of GEMINI_API_KEY, and is stored
+// as given since it has to be sent on. The quotas cap captions per UTC day and month (see
+// `usage`). Outside `allowed_modes`, captions fail with 403 `forbidden`.
+
+use axum::{
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{self, KeyPolicy, POLICY_COLUMNS};
+use crate::modes::Mode;
+use crate::usage::{self, with_database};
+use crate::voices::authorize;
+use crate::CaptionError;
+
+const MAX_NAME_CHARS: usize = 64;
+
+#[derive(Deserialize)]
+pub struct NewKey {
+    name: String,
+    provider_key: Option<String>,
+    daily_quota: Option<u64>,
+    monthly_quota: Option<u64>,
+    allowed_modes: Option<Vec<Mode>>,
+}
+
+#[derive(Serialize)]
+pub struct ManagedKey {
+    name: String,
+    /// The key itself, only in the response that created it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    created_at: String,
+    has_provider_key: bool,
+    daily_quota: Option<u64>,
+    monthly_quota: Option<u64>,
+    allowed_modes: Option<Vec<Mode>>,
+    captions_today: u64,
+}
+
+impl ManagedKey {
+    fn new(name: String, created_at: String, policy: KeyPolicy, captions_today: u64) -> Self {
+        ManagedKey {
+            name,
+            key: None,
+            created_at,
+            has_provider_key: policy.provider_key.is_some(),
+            daily_quota: policy.daily_quota,
+            monthly_quota: policy.monthly_quota,
+            allowed_modes: policy.allowed_modes,
+            captions_today,
+        }
+    }
+}
+
+fn key_database() -> Result<String, CaptionError> {
+    auth::database()
+        .ok_or_else(|| CaptionError::Unavailable("Set API_KEYS_DB to manage API keys".into()))
+}
+
+fn check(key: &NewKey) -> Result<(), CaptionError> {
+    let name_ok = (1..=MAX_NAME_CHARS).contains(&key.name.chars().count())
+        && key
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !name_ok {
+        return Err(CaptionError::Unprocessable(format!(
+            "name must be 1 to {} letters, digits, '-', '_' or '.'",
+            MAX_NAME_CHARS
+        )));
+    }
+    if key.daily_quota == Some(0) || key.monthly_quota == Some(0) {
+        return Err(CaptionError::Unprocessable("quotas must be at least 1".into()));
+    }
+    if key.allowed_modes.as_ref().is_some_and(Vec::is_empty) {
+        return Err(CaptionError::Unprocessable(
+            "allowed_modes must name at least one mode".into(),
+        ));
+    }
+    if key.provider_key.as_ref().is_some_and(|k| k.trim().is_empty()) {
+        return Err(CaptionError::Unprocessable("provider_key must not be empty".into()));
+    }
+    Ok(())
+}
+
+/// `GET /admin/keys`
+pub async fn list_keys(headers: HeaderMap) -> Result<Json<Vec<ManagedKey>>, CaptionError> {
+    authorize(&headers)?;
+    let path = key_database()?;
+    let keys = with_database(path, |db| {
+        let today = usage::today();
+        let mut query = db.prepare(&format!(
+            "SELECT name, created_at, {} FROM api_keys ORDER BY name",
+            POLICY_COLUMNS
+        ))?;
+        let rows = query.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, KeyPolicy::from_row(row, 2)?))
+        })?;
+        rows.map(|row| {
+            let (name, created_at, policy): (String, String, KeyPolicy) = row?;
+            let used = usage::totals(db, &name, &today)?.captions;
+            Ok(ManagedKey::new(name, created_at, policy, used))
+        })
+        .collect()
+    })
+    .await?;
+    Ok(Json(keys))
+}
+
+/// `POST /admin/keys`
+pub async fn create_key(
+    headers: HeaderMap,
+    Json(new): Json<NewKey>,
+) -> Result<(StatusCode, Json<ManagedKey>), CaptionError> {
+    authorize(&headers)?;
+    let path = key_database()?;
+    check(&new)?;
+
+    let key = format!("cap_{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>());
+    let policy = KeyPolicy {
+        provider_key: new.provider_key.map(|k| k.trim().to_string()),
+        daily_quota: new.daily_quota,
+        monthly_quota: new.monthly_quota,
+        allowed_modes: new.allowed_modes,
+    };
+    let modes = policy.allowed_modes.as_ref().map(|modes| {
+        modes.iter().map(|mode| mode.name()).collect::<Vec<_>>().join(",")
+    });
+    let (name, digest, row) = (new.name.clone(), hex::encode(Sha256::digest(&key)), policy.clone());
+    let created = with_database(path, move |db| {
+        let revoked: Option<bool> = db
+            .query_row(
+                "SELECT 0 FROM api_keys WHERE name = ?1
+                 UNION ALL SELECT 1 FROM revoked_keys WHERE name = ?1",
+                [&name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(revoked) = revoked {
+            return Ok(Err(revoked));
+        }
+        db.execute(
+            "INSERT INTO api_keys
+                 (name, key_sha256, provider_key, daily_quota, monthly_quota, allowed_modes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![name, digest, row.provider_key, row.daily_quota, row.monthly_quota, modes],
+        )?;
+        db.query_row("SELECT created_at FROM api_keys WHERE name = ?1", [&name], |row| row.get(0))
+            .map(Ok)
+    })
+    .await?;
+    let created_at = match created {
+        Ok(created_at) => created_at,
+        Err(true) => {
+            return Err(CaptionError::Unprocessable(format!(
+                "{} was a revoked key's name; the data kept under it isn't handed to a new key, \
+                 so pick another",
+                new.name
+            )))
+        }
+        Err(false) => {
+            return Err(CaptionError::Unprocessable(format!(
+                "There already is a key named {}",
+                new.name
+            )))
+        }
+    };
+
+    let mut managed = ManagedKey::new(new.name, created_at, policy, 0);
+    managed.key = Some(key);
+    Ok((StatusCode::CREATED, Json(managed)))
+}
+
+/// `DELETE /admin/keys/:name`
+pub async fn revoke_key(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, CaptionError> {
+    authorize(&headers)?;
+    let path = key_database()?;
+    let key = name.clone();
+    let removed = with_database(path, move |db| {
+        db.execute("DELETE FROM key_usage WHERE name = ?1", [&key])?;
+        let removed = db.execute("DELETE FROM api_keys WHERE name = ?1", [&key])?;
+        if removed > 0 {
+            db.execute("INSERT OR IGNORE INTO revoked_keys (name) VALUES (?1)", [&key])?;
+        }
+        Ok(removed)
+    })
+    .await?;
+    if removed == 0 {
+        return Err(CaptionError::NotFound(format!("No API key named {}", name)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
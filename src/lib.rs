@@ -0,0 +1,380 @@
+// AI Image Captioner: captions images with Google Gemini, as a web server, a CLI and a
+// library. Other Rust projects can embed the captioner without the web server by calling
+// `caption_image()`; the binary in main.rs only parses the command line and dispatches to
+// `server::serve()` or one of the CLI subcommands.
+//
+// Cargo.toml:
+// [dependencies]
+// axum = { version = "0.7", features = ["multipart"] }
+// tokio = { version = "1", features = ["full"] }
+// tower = "0.4"
+// tower-http = { version = "0.5", features = ["fs", "cors"] }
+// serde = { version = "1.0", features = ["derive"] }
+// serde_json = { version = "1.0", features = ["preserve_order"] }
+// reqwest = { version = "0.11", features = ["json", "multipart", "stream"] }
+// base64 = "0.22"
+// bytes = "1"
+// image = "0.24"
+// anyhow = "1.0"
+// dotenvy = "0.15"
+// clap = { version = "4", features = ["derive"] }
+// indicatif = "0.17"
+// walkdir = "2"
+// globset = "0.4"
+// futures = "0.3"
+// rand = "0.9"
+// chrono = { version = "0.4", features = ["serde"] }
+// sha2 = "0.10"
+// hex = "0.4"
+// img-parts = "0.3"
+// csv = "1"
+// zip = { version = "2", default-features = false, features = ["deflate"] }
+// libheif-rs = { version = "1", optional = true }  # `heic` feature
+// notify = "8"
+// unicode-segmentation = "1"
+// ratatui = "0.29"
+// unicode-normalization = "0.1"
+// ciborium = "0.2"
+// thiserror = "2"
+// schemars = "1"
+// toml = "0.9"
+// tracing = "0.1"
+// tracing-subscriber = { version = "0.3", features = ["env-filter", "json"] }
+// aws-config = { version = "1", features = ["behavior-version-latest"], optional = true }  # `s3` feature
+// aws-sdk-s3 = { version = "1", optional = true }  # `s3` feature
+// redis = { version = "0.32", default-features = false, features = ["tokio-comp", "connection-manager", "script"], optional = true }  # `redis` feature
+// mozjpeg = { version = "0.10", optional = true }  # `mozjpeg` feature
+// opentelemetry = { version = "0.31", optional = true }  # `otel` feature
+// opentelemetry_sdk = { version = "0.31", optional = true }  # `otel` feature
+// opentelemetry-otlp = { version = "0.31", default-features = false, features = ["trace", "http-proto", "reqwest-blocking-client"], optional = true }  # `otel` feature
+// tracing-opentelemetry = { version = "0.32", optional = true }  # `otel` feature
+
+mod animation;
+mod archive;
+mod art;
+mod assets;
+mod audit;
+mod auth;
+pub mod batch;
+#[doc(hidden)]
+pub mod bench;
+mod breaker;
+mod buffers;
+mod c2pa;
+mod cache;
+mod capture;
+pub mod cli;
+mod config;
+mod contact_sheet;
+mod detection;
+mod error;
+mod etag;
+mod export;
+mod fashion;
+mod gallery;
+mod handlers;
+mod heic;
+mod history;
+mod imageproc;
+mod jobs;
+mod jpeg;
+mod keys;
+mod limits;
+pub mod listing;
+mod locale;
+pub mod logging;
+mod metadata;
+mod modes;
+mod nature;
+mod oidc;
+mod orientation;
+mod payload;
+mod pdf;
+mod pipeline;
+mod places;
+mod policy;
+mod pricing;
+mod providers;
+mod ratelimit;
+mod raw;
+mod reload;
+mod request_id;
+pub mod results;
+pub mod review;
+mod sanitize;
+mod scheduler;
+mod schemas;
+mod scratch;
+mod shared;
+pub mod settings;
+mod shutdown;
+mod storage;
+pub mod server;
+mod session;
+mod spool;
+mod subtitles;
+mod telemetry;
+mod tiff;
+mod timeouts;
+mod translate;
+mod uploads;
+mod usage;
+mod vehicle;
+mod video;
+mod voices;
+pub mod watch;
+mod xmp;
+
+use serde::{Deserialize, Serialize};
+
+use config::{MODEL_ID, MODEL_LABEL, PROVIDER};
+use imageproc::content_hash;
+use modes::ModeOutput;
+use providers::Usage;
+
+pub use c2pa::{AiSignal, ContentCredentials};
+pub use capture::{CaptureContext, TimeOfDay};
+pub use detection::AiDetection;
+pub use error::CaptionError;
+pub use modes::{CaptionOptions, Mode};
+pub use translate::Translation;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where the time for one caption went, in milliseconds per stage, so slowness can be
+/// pinned on the network, the queue, the model or image processing.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Timings {
+    /// Reading the upload (the whole request body for multi-image requests).
+    pub receive_ms: u64,
+    pub decode_ms: u64,
+    pub resize_ms: u64,
+    pub encode_ms: u64,
+    /// Waiting for a provider slot behind other requests.
+    pub queue_ms: u64,
+    pub provider_ms: u64,
+    /// Requests sent to the provider, counting retries; absent from older sidecars.
+    #[serde(default)]
+    pub provider_attempts: u32,
+    pub parse_ms: u64,
+    pub post_process_ms: u64,
+}
+
+impl Timings {
+    /// Starts a breakdown for an upload that began arriving at `start`.
+    pub(crate) fn received(start: std::time::Instant) -> Self {
+        Timings {
+            receive_ms: elapsed_ms(start),
+            ..Timings::default()
+        }
+    }
+}
+
+pub(crate) fn elapsed_ms(since: std::time::Instant) -> u64 {
+    since.elapsed().as_millis() as u64
+}
+
+/// An image's size in pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The upload's size and the size the provider got it at, smaller when it was downscaled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dimensions {
+    pub original: Size,
+    pub sent: Size,
+}
+
+/// Everything needed to reproduce a caption later: the exact input bytes, provider, model
+/// and prompt that produced it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Provenance {
+    pub content_hash: String,
+    pub provider: String,
+    pub model: String,
+    pub mode: modes::Mode,
+    pub prompt_version: u32,
+    pub prompt_hash: String,
+    pub crate_version: String,
+    pub generated_at: String,
+}
+
+impl Provenance {
+    pub(crate) fn new(image: &[u8], options: &CaptionOptions) -> Self {
+        Provenance {
+            content_hash: content_hash(image),
+            provider: PROVIDER.to_string(),
+            model: MODEL_ID.to_string(),
+            mode: options.mode,
+            prompt_version: modes::PROMPT_VERSION,
+            prompt_hash: content_hash(options.fingerprint().as_bytes()),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            generated_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CaptionResponse {
+    pub caption: String,
+    pub model: String,
+    pub processing_time_ms: u64,
+    /// Missing from sidecars written before timings were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    /// Missing only from sidecars written before provenance was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// Set when the text fields were translated from English.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translation: Option<translate::Translation>,
+    /// The image's own Content Credentials (C2PA manifest), when it carries them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_credentials: Option<ContentCredentials>,
+    /// Embedded metadata declaring the image AI-generated; omitted when there is none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ai_signals: Vec<AiSignal>,
+    /// Whether the image looks AI-generated; only when `detect_ai` was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ai_detection: Option<AiDetection>,
+    /// Missing from sidecars and cached captions made before sizes were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<Dimensions>,
+    /// The EXIF capture context given to the model; only when `exif_context` was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capture_context: Option<CaptureContext>,
+    /// Provider tokens the caption took (prompt and reply); only when `verbose` was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_tokens: Option<u64>,
+    /// Estimated USD cost of those tokens at the configured prices; only with `verbose`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+    /// Set when this is the caption made earlier for the same image and options (see `cache`).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+    /// How old that caption is, in seconds; only with `cached`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_age_secs: Option<u64>,
+    /// The ID of the request that returned it, in web API responses (see `request_id`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl CaptionResponse {
+    pub(crate) fn new(
+        output: ModeOutput,
+        image: &[u8],
+        options: &CaptionOptions,
+        start: std::time::Instant,
+        mut timings: Timings,
+    ) -> Self {
+        let post_process = std::time::Instant::now();
+        let provenance = Provenance::new(image, options);
+        let content_credentials = c2pa::read(image);
+        let ai_signals = c2pa::ai_signals(image, content_credentials.as_ref());
+        let ai_detection = output.assessment.map(|assessment| {
+            AiDetection::new(assessment, image, content_credentials.as_ref(), &ai_signals)
+        });
+        timings.post_process_ms = elapsed_ms(post_process);
+        CaptionResponse {
+            caption: output.caption,
+            model: MODEL_LABEL.to_string(),
+            processing_time_ms: elapsed_ms(start),
+            timings: Some(timings),
+            provenance: Some(provenance),
+            translation: None,
+            content_credentials,
+            ai_signals,
+            ai_detection,
+            dimensions: None,
+            capture_context: None,
+            input_tokens: None,
+            output_tokens: None,
+            cost_usd: None,
+            cached: false,
+            cache_age_secs: None,
+            request_id: None,
+            details: output.details,
+        }
+    }
+
+    /// Adds the tokens in `usage` and their estimated cost, for verbose responses.
+    pub(crate) fn report_usage(&mut self, usage: &Usage) {
+        self.input_tokens = Some(usage.prompt_tokens);
+        self.output_tokens = Some(usage.output_tokens);
+        self.cost_usd = Some(usage.cost_usd());
+    }
+
+    /// The fields the mode added, as its type in `results`, e.g. `results::ArtFields` for an
+    /// `art` result.
+    pub fn fields<T: serde::de::DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(serde_json::Value::Object(self.details.clone()))
+    }
+
+    /// Keywords when the mode produced them, otherwise hashtags; empty for plain captions.
+    pub(crate) fn tags(&self) -> Vec<&str> {
+        let list = |key: &str| -> Vec<&str> {
+            self.details
+                .get(key)
+                .and_then(|value| value.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|item| item.as_str())
+                .collect()
+        };
+
+        let keywords = list("keywords");
+        if keywords.is_empty() {
+            list("hashtags")
+        } else {
+            keywords
+        }
+    }
+}
+
+/// Captions one image, given as the bytes of any supported file (JPEG, PNG, WebP, GIF, BMP,
+/// TIFF, camera RAW, and HEIC with the `heic` feature), using the Gemini `api_key`.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use ai_image_captioner::{caption_image, CaptionOptions, Mode};
+///
+/// let image = std::fs::read("photo.jpg")?;
+/// let options = CaptionOptions {
+///     mode: Mode::AltText,
+///     ..CaptionOptions::default()
+/// };
+/// let response = caption_image(&image, &options, "your-gemini-api-key").await?;
+/// println!("{}", response.caption);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn caption_image(
+    data: &[u8],
+    options: &CaptionOptions,
+    api_key: &str,
+) -> Result<CaptionResponse, CaptionError> {
+    options.validate().map_err(CaptionError::Unprocessable)?;
+    let (response, _usage) = caption_image_bytes(data, options, api_key).await?;
+    Ok(response)
+}
+
+/// Captions raw image bytes end to end through the library's pipeline: prepare for the API,
+/// call the provider, translate.
+pub(crate) async fn caption_image_bytes(
+    data: &[u8],
+    options: &CaptionOptions,
+    api_key: &str,
+) -> Result<(CaptionResponse, Usage), CaptionError> {
+    let start = std::time::Instant::now();
+    let api_key = api_key.to_string();
+    let caption = pipeline::Caption::new(data, options.clone(), api_key, start, Timings::default());
+    pipeline::library(options).run(caption).await
+}
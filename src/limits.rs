@@ -0,0 +1,134 @@
+// Upload size limits. Every route that takes a form has two: one on the request body as a whole,
+// which axum enforces (`DefaultBodyLimit`) as the body is read, and one on each file in it,
+// checked while the file arrives so an oversized one is turned away without being read in full.
+// Going over either fails with 413 and a `payload_too_large` error whose `limit_bytes` is the
+// limit that was hit, so a client can tell the user or send less.
+//
+// - MAX_UPLOAD_MB (default 10) is the largest image `/upload`, `/embed`, `/xmp`, `/batch`,
+//   `/listing` and `/jobs` take. A single-image route's body may hold that much plus room for
+//   the form's other fields.
+// - MAX_BATCH_MB (default 100) limits the body of `/batch` and `/listing`, and MAX_JOB_MB
+//   (default 500) that of `/jobs`.
+// - MAX_VIDEO_MB (200), MAX_PDF_MB (100) and MAX_ZIP_MB (200, compressed) limit the file of
+//   `/video`, `/pdf` and `/zip`. MAX_VIDEO_MB also limits the video `/video/frames` downloads.
+//
+// They are read once, when the routes are built; a change needs a restart.
+
+use axum::extract::{multipart::MultipartError, DefaultBodyLimit};
+use axum::http::StatusCode;
+use std::sync::LazyLock;
+
+use crate::config::{
+    BATCH_BODY_LIMIT, JOB_BODY_LIMIT, MAX_UPLOAD_BYTES, PDF_BODY_LIMIT, VIDEO_BODY_LIMIT,
+    ZIP_BODY_LIMIT,
+};
+use crate::CaptionError;
+
+const MB: usize = 1024 * 1024;
+
+/// Room a body limit leaves for the form's fields besides its one file.
+const FORM_ALLOWANCE: usize = 64 * 1024;
+
+static LIMITS: LazyLock<Limits> = LazyLock::new(Limits::from_env);
+
+/// The limits in bytes, as configured.
+struct Limits {
+    image: usize,
+    batch: usize,
+    job: usize,
+    video: usize,
+    pdf: usize,
+    zip: usize,
+}
+
+impl Limits {
+    fn from_env() -> Self {
+        let read = |name: &str, default: usize| match crate::settings::var(name) {
+            Ok(value) => match value.trim().parse::<usize>() {
+                Ok(mb) if mb > 0 => mb.saturating_mul(MB),
+                _ => {
+                    tracing::warn!("Ignoring {}={:?}; expected a number of megabytes", name, value);
+                    default
+                }
+            },
+            Err(_) => default,
+        };
+        Limits {
+            image: read("MAX_UPLOAD_MB", MAX_UPLOAD_BYTES),
+            batch: read("MAX_BATCH_MB", BATCH_BODY_LIMIT),
+            job: read("MAX_JOB_MB", JOB_BODY_LIMIT),
+            video: read("MAX_VIDEO_MB", VIDEO_BODY_LIMIT),
+            pdf: read("MAX_PDF_MB", PDF_BODY_LIMIT),
+            zip: read("MAX_ZIP_MB", ZIP_BODY_LIMIT),
+        }
+    }
+}
+
+/// What a route's form carries, which decides its limits.
+#[derive(Clone, Copy)]
+pub(crate) enum Upload {
+    /// One image: `/upload`, `/embed` and `/xmp`.
+    Image,
+    /// The images of `/batch` and `/listing`.
+    Batch,
+    Job,
+    Video,
+    Pdf,
+    Zip,
+}
+
+impl Upload {
+    /// The most one file in the form may take.
+    pub(crate) fn file_limit(self) -> usize {
+        match self {
+            Upload::Image | Upload::Batch | Upload::Job => LIMITS.image,
+            Upload::Video => LIMITS.video,
+            Upload::Pdf => LIMITS.pdf,
+            Upload::Zip => LIMITS.zip,
+        }
+    }
+
+    /// The most the whole request body may take.
+    pub(crate) fn body_limit(self) -> usize {
+        match self {
+            Upload::Batch => LIMITS.batch,
+            Upload::Job => LIMITS.job,
+            _ => self.file_limit().saturating_add(FORM_ALLOWANCE),
+        }
+    }
+
+    /// The layer that holds a route's body to `body_limit`.
+    pub(crate) fn body_layer(self) -> DefaultBodyLimit {
+        DefaultBodyLimit::max(self.body_limit())
+    }
+
+    /// The error for a file in `field` over `file_limit`.
+    pub(crate) fn file_too_large(self, field: &str) -> CaptionError {
+        too_large(&format!("A file in {}", field), self.file_limit())
+    }
+
+    /// `error` from reading the form, with `body_limit` when the body went over it.
+    pub(crate) fn form_error(self, error: MultipartError) -> CaptionError {
+        if error.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            too_large("The request", self.body_limit())
+        } else {
+            error.into()
+        }
+    }
+}
+
+fn too_large(what: &str, limit: usize) -> CaptionError {
+    CaptionError::UploadTooLarge {
+        message: format!("{} is larger than the {} limit", what, megabytes(limit)),
+        limit_bytes: limit as u64,
+    }
+}
+
+/// `bytes` in megabytes, for messages: `10 MB`, or `10.1 MB` when it isn't a whole number.
+pub(crate) fn megabytes(bytes: usize) -> String {
+    if bytes.is_multiple_of(MB) {
+        format!("{} MB", bytes / MB)
+    } else {
+        format!("{:.1} MB", bytes as f64 / MB as f64)
+    }
+}
@@ -0,0 +1,313 @@
+// Property listings from a set of photos (`POST /listing` and the `listing` subcommand). Each
+// photo is captioned in the `real_estate` mode, which names the room it shows and describes
+// it; photos of the same kind of room are grouped into a room-by-room description, and the
+// model then writes one listing paragraph from those in the requested tone and length.
+//
+// The paragraph is written only from what the photos show. The prompt keeps it to the property
+// rather than who it would suit, in the spirit of fair housing rules, and away from facts a
+// photo can't tell (size, price, location).
+
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::cli::{fail, ErrorKind, OutputFormat};
+use crate::config::MODEL_LABEL;
+use crate::handlers::BatchItem;
+use crate::modes::{CaptionOptions, Mode};
+use crate::providers::generate_text;
+use crate::{caption_image_bytes, sanitize, BoxError};
+
+/// What a listing photo can show, for `room_type`.
+pub(crate) const ROOM_TYPES: &[&str] = &[
+    "living_room", "kitchen", "dining_room", "bedroom", "bathroom", "office", "hallway",
+    "staircase", "laundry", "storage", "garage", "basement", "attic", "balcony", "terrace",
+    "garden", "pool", "exterior", "view", "floor_plan", "other",
+];
+
+/// Most features listed per photo.
+pub(crate) const MAX_FEATURES: usize = 6;
+
+/// Most features listed per room, over all its photos.
+const MAX_ROOM_FEATURES: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Tone {
+    /// Factual and polished
+    #[default]
+    Professional,
+    /// Inviting, helping buyers picture living there
+    Warm,
+    /// Upscale, dwelling on finishes and craftsmanship
+    Luxury,
+    /// Friendly and conversational
+    Casual,
+}
+
+impl Tone {
+    fn instruction(self) -> &'static str {
+        match self {
+            Tone::Professional => "Keep the tone professional and factual.",
+            Tone::Warm => "Use a warm, inviting tone that helps buyers picture themselves there.",
+            Tone::Luxury => {
+                "Use an elegant, upscale tone that dwells on finishes, materials and \
+                 craftsmanship, without exaggerating."
+            }
+            Tone::Casual => "Use a friendly, conversational tone.",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Length {
+    /// About 60 words
+    Short,
+    /// About 120 words
+    #[default]
+    Medium,
+    /// About 200 words
+    Long,
+}
+
+impl Length {
+    fn words(self) -> usize {
+        match self {
+            Length::Short => 60,
+            Length::Medium => 120,
+            Length::Long => 200,
+        }
+    }
+}
+
+/// Parses a `tone` or `length` form field.
+pub(crate) fn parse_choice<T: ValueEnum>(field: &str, value: &str) -> Result<T, String> {
+    T::from_str(value.trim(), true).map_err(|_| {
+        let names: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|choice| choice.to_possible_value())
+            .map(|choice| choice.get_name().to_string())
+            .collect();
+        format!("{} must be one of: {}", field, names.join(", "))
+    })
+}
+
+/// The photos of one kind of room, described together.
+#[derive(Serialize)]
+pub(crate) struct Room {
+    pub(crate) room_type: String,
+    /// The descriptions of its photos, in upload order.
+    pub(crate) description: String,
+    pub(crate) features: Vec<String>,
+    /// Indexes into `photos`.
+    pub(crate) photos: Vec<usize>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ListingResponse {
+    pub(crate) listing: String,
+    pub(crate) tone: Tone,
+    pub(crate) length: Length,
+    pub(crate) model: String,
+    pub(crate) processing_time_ms: u128,
+    pub(crate) rooms: Vec<Room>,
+    pub(crate) photos: Vec<BatchItem>,
+}
+
+/// The captioning options for listing photos: the `real_estate` mode, plus the output language
+/// and brand voice of `options`.
+pub(crate) fn photo_options(options: &CaptionOptions) -> CaptionOptions {
+    CaptionOptions {
+        mode: Mode::RealEstate,
+        language: options.language.clone(),
+        voice: options.voice.clone(),
+        verbose: options.verbose,
+        ..CaptionOptions::default()
+    }
+}
+
+/// Groups captioned photos by room type, in the order each type first appears.
+pub(crate) fn rooms(photos: &[BatchItem]) -> Vec<Room> {
+    let mut rooms: Vec<Room> = Vec::new();
+    for (index, photo) in photos.iter().enumerate() {
+        let Some(response) = &photo.response else {
+            continue;
+        };
+        let room_type = response.details.get("room_type").and_then(Value::as_str);
+        let room_type = room_type.unwrap_or("other");
+        let features = response
+            .details
+            .get("features")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|feature| feature.as_str());
+
+        let position = match rooms.iter().position(|room| room.room_type == room_type) {
+            Some(position) => position,
+            None => {
+                rooms.push(Room {
+                    room_type: room_type.to_string(),
+                    description: String::new(),
+                    features: Vec::new(),
+                    photos: Vec::new(),
+                });
+                rooms.len() - 1
+            }
+        };
+        let room = &mut rooms[position];
+        if !room.description.is_empty() {
+            room.description.push(' ');
+        }
+        room.description.push_str(&response.caption);
+        for feature in features {
+            let known = room.features.iter().any(|known| known.eq_ignore_ascii_case(feature));
+            if !known && room.features.len() < MAX_ROOM_FEATURES {
+                room.features.push(feature.to_string());
+            }
+        }
+        room.photos.push(index);
+    }
+    rooms
+}
+
+/// The request for the listing paragraph, written from the room-by-room descriptions.
+pub(crate) fn prompt(
+    rooms: &[Room],
+    tone: Tone,
+    length: Length,
+    language: Option<&str>,
+) -> String {
+    let mut prompt = format!(
+        "These are descriptions of the photos of a property for sale or rent, grouped by room. \
+         Write the listing description: one paragraph of about {} words that walks a reader \
+         through the property, leading with its most appealing features. {} Use only what the \
+         descriptions say; don't make up sizes, prices, locations or amenities. Describe the \
+         property, not who it would suit: don't mention family status, age, religion, \
+         ethnicity, gender or disability. Reply with the paragraph only, without a heading.",
+        length.words(),
+        tone.instruction()
+    );
+    if let Some(language) = language {
+        prompt.push_str(&format!(" Write it in the language with the code {}.", language));
+    }
+    prompt.push('\n');
+    for room in rooms {
+        prompt.push_str(&format!("\n[{}] {}", room.room_type.replace('_', " "), room.description));
+        if !room.features.is_empty() {
+            prompt.push_str(&format!(" Features: {}.", room.features.join(", ")));
+        }
+    }
+    prompt
+}
+
+#[derive(Args)]
+pub struct ListingArgs {
+    /// Photos of the property, in the order a viewing would go
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// How the listing should sound
+    #[arg(long, value_enum, default_value_t = Tone::Professional)]
+    pub tone: Tone,
+
+    /// How long the listing paragraph should be
+    #[arg(long, value_enum, default_value_t = Length::Medium)]
+    pub length: Length,
+
+    /// Write the listing and room descriptions in this language (e.g. de, pt-BR)
+    #[arg(long, value_name = "CODE")]
+    pub language: Option<String>,
+
+    /// Print the full result as JSON instead of the room descriptions and listing as text
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Runs the `listing` subcommand and returns the process exit code.
+pub async fn run_listing(args: ListingArgs) -> i32 {
+    let format = match args.json {
+        true => OutputFormat::Json,
+        false => OutputFormat::Text,
+    };
+    crate::reload::apply_log_settings();
+    let Ok(api_key) = crate::settings::var("GEMINI_API_KEY") else {
+        return fail(
+            format,
+            ErrorKind::ConfigError,
+            "GEMINI_API_KEY must be set in the environment or .env file",
+        );
+    };
+    let options = photo_options(&CaptionOptions {
+        language: args.language.clone(),
+        ..CaptionOptions::default()
+    });
+    if let Err(message) = options.validate() {
+        return fail(format, ErrorKind::InvalidInput, &message);
+    }
+
+    let start = Instant::now();
+    let mut photos = Vec::new();
+    let mut worst: Option<ErrorKind> = None;
+    for path in &args.paths {
+        let captioned: Result<_, BoxError> = match tokio::fs::read(path).await {
+            Ok(data) => caption_image_bytes(&data, &options, &api_key)
+                .await
+                .map(|(response, _usage)| response)
+                .map_err(BoxError::from),
+            Err(e) => Err(e.into()),
+        };
+        let (response, error) = match captioned {
+            Ok(response) => (Some(response), None),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                worst = worst.max(Some(ErrorKind::classify(&e)));
+                (None, Some(e.to_string()))
+            }
+        };
+        photos.push(BatchItem {
+            file_name: Some(path.display().to_string()),
+            response,
+            xmp: None,
+            error,
+        });
+    }
+
+    let rooms = rooms(&photos);
+    if rooms.is_empty() {
+        let kind = worst.unwrap_or(ErrorKind::ProviderError);
+        return fail(format, kind, "None of the photos could be described");
+    }
+    let prompt = prompt(&rooms, args.tone, args.length, options.language.as_deref());
+    let listing = match generate_text(&prompt, None, &api_key).await {
+        Ok((listing, _usage)) => sanitize::clean(&listing),
+        Err(e) => {
+            let message = format!("Could not write the listing: {}", e);
+            return fail(format, ErrorKind::classify(&e.into()), &message);
+        }
+    };
+
+    if args.json {
+        let response = ListingResponse {
+            listing,
+            tone: args.tone,
+            length: args.length,
+            model: MODEL_LABEL.to_string(),
+            processing_time_ms: start.elapsed().as_millis(),
+            rooms,
+            photos,
+        };
+        let json = serde_json::to_string_pretty(&response).expect("listings always serialize");
+        println!("{}", json);
+    } else {
+        for room in &rooms {
+            println!("{}: {}\n", room.room_type.replace('_', " "), room.description);
+        }
+        println!("{}", listing);
+    }
+
+    worst.map_or(0, ErrorKind::exit_code)
+}
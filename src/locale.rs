@@ -0,0 +1,202 @@
+// Locale-aware finishing for captions in languages other than English. Translations come
+// back with whatever punctuation the provider chose, so this normalizes it for the script,
+// marks right-to-left text so it displays correctly next to left-to-right content, and
+// truncates without cutting into words in scripts that don't separate them with spaces.
+//
+// Length limits everywhere are counted in graphemes (what a reader sees as one character),
+// so combining accents, emoji sequences and Hangul syllables aren't split or over-counted.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// RIGHT-TO-LEFT MARK, which sets the base direction of text that starts with a
+/// left-to-right run (a brand name, a number).
+const RLM: char = '\u{200F}';
+
+/// Chinese and Japanese punctuation that ends a clause or sentence.
+const FULL_WIDTH_PUNCTUATION: &str = "。、，；：！？）」』";
+
+/// How a language's script affects punctuation, line direction and truncation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Script {
+    /// Space-separated words, Western punctuation.
+    Spaced,
+    /// Chinese and Japanese: no spaces between words, full-width punctuation.
+    Cjk,
+    /// Thai, Lao, Khmer, Burmese: no spaces between words, Western punctuation.
+    Unspaced,
+    /// Arabic-script languages: right-to-left with Arabic punctuation.
+    Arabic,
+    /// Hebrew and Yiddish: right-to-left with Western punctuation.
+    Hebrew,
+}
+
+impl Script {
+    fn of(language: Option<&str>) -> Script {
+        let Some(language) = language else {
+            return Script::Spaced;
+        };
+        let primary = language
+            .split('-')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match primary.as_str() {
+            "zh" | "ja" | "yue" => Script::Cjk,
+            "th" | "lo" | "km" | "my" => Script::Unspaced,
+            "ar" | "fa" | "ur" | "ps" | "sd" | "ug" | "ckb" => Script::Arabic,
+            "he" | "iw" | "yi" => Script::Hebrew,
+            _ => Script::Spaced,
+        }
+    }
+
+    fn spaced(self) -> bool {
+        !matches!(self, Script::Cjk | Script::Unspaced)
+    }
+
+    fn right_to_left(self) -> bool {
+        matches!(self, Script::Arabic | Script::Hebrew)
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'     // Hiragana, Katakana
+        | '\u{3400}'..='\u{4DBF}'   // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}'   // CJK Unified Ideographs
+        | '\u{F900}'..='\u{FAFF}'   // CJK Compatibility Ideographs
+        | '\u{FF66}'..='\u{FF9F}'   // Half-width Katakana
+        | '\u{20000}'..='\u{2FA1F}' // Supplementary ideographs
+    )
+}
+
+fn is_right_to_left(c: char) -> bool {
+    matches!(c,
+        '\u{0590}'..='\u{08FF}'     // Hebrew, Arabic, Syriac, Thaana, NKo, Arabic supplements
+        | '\u{FB1D}'..='\u{FDFF}'   // Hebrew and Arabic presentation forms A
+        | '\u{FE70}'..='\u{FEFF}'   // Arabic presentation forms B
+    )
+}
+
+fn is_arabic(c: char) -> bool {
+    matches!(c, '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' | '\u{08A0}'..='\u{08FF}')
+}
+
+/// The script's own form of an ASCII punctuation mark, if it has one.
+fn native_punctuation(c: char, script: Script, language: Option<&str>) -> Option<char> {
+    match script {
+        Script::Cjk => {
+            let japanese = language.is_some_and(|language| language.starts_with("ja"));
+            Some(match c {
+                ',' if japanese => '、',
+                ',' => '，',
+                '.' => '。',
+                '?' => '？',
+                '!' => '！',
+                ':' => '：',
+                ';' => '；',
+                _ => return None,
+            })
+        }
+        Script::Arabic => Some(match c {
+            ',' => '،',
+            '?' => '؟',
+            ';' => '؛',
+            _ => return None,
+        }),
+        _ => None,
+    }
+}
+
+/// Whether the character before an ASCII mark belongs to the script, so `3.5` or an
+/// embedded English name keep their punctuation.
+fn follows_script(previous: Option<char>, script: Script) -> bool {
+    previous.is_some_and(|c| match script {
+        Script::Cjk => is_cjk(c),
+        Script::Arabic => is_arabic(c),
+        _ => false,
+    })
+}
+
+/// Normalizes punctuation and spacing for `language` and marks right-to-left text.
+pub fn normalize(text: &str, language: Option<&str>) -> String {
+    let script = Script::of(language);
+    let mut out = String::with_capacity(text.len());
+
+    for c in text.trim().chars() {
+        let previous = out.trim_end().chars().last();
+        let c = match native_punctuation(c, script, language) {
+            Some(native) if follows_script(previous, script) => native,
+            _ => c,
+        };
+        // Full-width punctuation carries its own spacing; drop spaces providers put around it.
+        if script == Script::Cjk {
+            if FULL_WIDTH_PUNCTUATION.contains(c) {
+                out.truncate(out.trim_end().len());
+            } else if c.is_whitespace()
+                && previous.is_some_and(|p| FULL_WIDTH_PUNCTUATION.contains(p))
+            {
+                continue;
+            }
+        }
+        // Collapse runs of whitespace left over from the translation.
+        if c.is_whitespace() && out.ends_with(char::is_whitespace) {
+            continue;
+        }
+        out.push(c);
+    }
+
+    if script.right_to_left() {
+        let starts_right_to_left = out
+            .chars()
+            .find(|c| c.is_alphanumeric())
+            .is_some_and(is_right_to_left);
+        if !starts_right_to_left && !out.is_empty() && !out.starts_with(RLM) {
+            out.insert(0, RLM);
+        }
+    }
+    out
+}
+
+/// Cuts `text` to at most `max` graphemes without splitting a word. Spaced scripts are cut
+/// at the last space; Chinese, Japanese and Thai-like scripts at the last clause or
+/// sentence break if there is one in the second half, otherwise at a word boundary.
+pub fn truncate(text: &str, max: usize, language: Option<&str>) -> String {
+    let Some((cut, _)) = text.grapheme_indices(true).nth(max) else {
+        return text.to_string();
+    };
+    let script = Script::of(language);
+    let head = &text[..cut];
+
+    let head = if script.spaced() {
+        head.rfind(char::is_whitespace)
+            .map_or(head, |space| &head[..space])
+    } else {
+        let clause = head
+            .char_indices()
+            .filter(|(_, c)| FULL_WIDTH_PUNCTUATION.contains(*c) || ",;:!?".contains(*c))
+            .map(|(index, c)| index + c.len_utf8())
+            .next_back()
+            .filter(|&end| end >= head.len() / 2);
+        match clause {
+            Some(end) => &head[..end],
+            // The word the cut falls in starts at the last boundary inside the head.
+            None => {
+                let start = text
+                    .split_word_bound_indices()
+                    .map(|(index, _)| index)
+                    .take_while(|&index| index <= cut)
+                    .last()
+                    .unwrap_or(0);
+                if start == 0 {
+                    head
+                } else {
+                    &head[..start]
+                }
+            }
+        }
+    };
+
+    head.trim_end_matches([',', ';', ':', ' ', '，', '、', '；', '：', '،', '؛'])
+        .to_string()
+}
+
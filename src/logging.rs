@@ -0,0 +1,103 @@
+// Logging with `tracing`. The server and the CLI log to stderr (stdout is for the CLI's
+// results), as text or, with LOG_FORMAT=json, one JSON object per line for log collectors.
+// RUST_LOG picks what is logged: `info` by default, or e.g. `warn` or
+// `ai_image_captioner=debug,tower_http=warn`.
+//
+// The server runs each request in a `request` span with its ID (see `request_id`), method,
+// path, upload size, provider and (behind API keys) caller, so everything logged while handling
+// it carries them, and ends it with an event giving its status and latency. A program embedding the library
+// logs nothing unless it sets up a `tracing` subscriber of its own. The same spans can be
+// exported as OpenTelemetry traces (see `telemetry`).
+
+use axum::extract::Request;
+use axum::http::{header::CONTENT_LENGTH, HeaderValue};
+use axum::{middleware::Next, response::Response};
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+use tracing::Instrument;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, Layer, Registry};
+
+use crate::config::PROVIDER;
+use crate::{elapsed_ms, request_id, telemetry};
+
+/// Swaps in a new RUST_LOG filter on reload.
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// RUST_LOG, with unreadable directives left out.
+fn filter() -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse_lossy(crate::settings::var("RUST_LOG").unwrap_or_default())
+}
+
+/// Sets up logging as RUST_LOG and LOG_FORMAT say, and trace export when an OTLP endpoint is
+/// set. Call once, after the configuration is loaded.
+pub fn init() {
+    let (filter, handle) = reload::Layer::new(filter());
+    let format = crate::settings::var("LOG_FORMAT").unwrap_or_default();
+    let logger = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let logger = match format.trim() {
+        "json" => logger.json().flatten_event(true).with_span_list(false).boxed(),
+        _ => logger.boxed(),
+    };
+    let (traces, traces_error) = match telemetry::layer() {
+        Ok(layer) => (layer, None),
+        Err(e) => (None, Some(e)),
+    };
+    tracing_subscriber::registry().with(filter).with(logger).with(traces).init();
+    let _ = FILTER.set(handle);
+    if !matches!(format.trim(), "" | "text" | "json") {
+        tracing::warn!("Ignoring LOG_FORMAT={:?}; expected text or json", format);
+    }
+    if let Some(e) = traces_error {
+        tracing::warn!("{}", e);
+    }
+}
+
+/// Exports the spans not sent yet (see `telemetry`). Call before exiting.
+pub fn finish() {
+    telemetry::shutdown();
+}
+
+/// Applies a changed RUST_LOG; LOG_FORMAT takes a restart.
+pub(crate) fn reload() {
+    if let Some(Err(e)) = FILTER.get().map(|handle| handle.reload(filter())) {
+        tracing::warn!("Could not apply RUST_LOG: {}", e);
+    }
+}
+
+/// Runs the request in a span of its own, under its request ID, and logs how it ended: server
+/// errors as errors, client errors as warnings. The response carries the ID in `X-Request-Id`.
+pub(crate) async fn trace_request(request: Request, next: Next) -> Response {
+    let id = request_id::from_headers(request.headers());
+    let header = HeaderValue::from_str(&id).expect("request IDs are visible ASCII");
+    let upload_bytes = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let span = tracing::info_span!(
+        "request",
+        request_id = %id,
+        method = %request.method(),
+        path = request.uri().path(),
+        upload_bytes,
+        provider = PROVIDER,
+        caller = tracing::field::Empty,
+    );
+
+    let start = std::time::Instant::now();
+    let mut response = request_id::scope(id, next.run(request).instrument(span.clone())).await;
+    response.headers_mut().insert(request_id::HEADER, header);
+    let latency_ms = elapsed_ms(start);
+    let status = response.status().as_u16();
+    span.in_scope(|| match status {
+        500.. => tracing::error!(status, latency_ms, "Request failed"),
+        400.. => tracing::warn!(status, latency_ms, "Request refused"),
+        _ => tracing::info!(status, latency_ms, "Request finished"),
+    });
+    response
+}
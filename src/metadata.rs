@@ -0,0 +1,444 @@
+// Writes captions back into image files: EXIF ImageDescription (JPEG, PNG, WebP) and
+// IPTC Caption-Abstract (JPEG only, inside the Photoshop APP13 segment).
+//
+// Existing metadata is preserved. For EXIF, a new IFD0 that includes the description is
+// appended to the TIFF block and the header is pointed at it, so no existing offsets move.
+// The same TIFF reader also pulls text tags such as Software back out, for the
+// AI-generation heuristics.
+
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+use img_parts::{Bytes, DynImage, ImageEXIF};
+use unicode_segmentation::UnicodeSegmentation;
+
+const TAG_IMAGE_DESCRIPTION: u16 = 0x010E;
+const TIFF_TYPE_ASCII: u16 = 2;
+
+/// APP1/APP13 payloads are limited by the 16-bit segment length.
+const MAX_SEGMENT_CONTENTS: usize = 65533;
+const EXIF_PREFIX_LEN: usize = 6;
+
+const PHOTOSHOP_SIGNATURE: &[u8] = b"Photoshop 3.0\0";
+const RESOURCE_IPTC: u16 = 0x0404;
+const RESOURCE_IPTC_DIGEST: u16 = 0x0425;
+/// IPTC IIM limits Caption-Abstract (2:120) to 2000 bytes.
+const IPTC_CAPTION_MAX_BYTES: usize = 2000;
+/// Dataset 1:90 value declaring UTF-8 text.
+const IPTC_UTF8: &[u8] = b"\x1b%G";
+
+#[derive(Debug)]
+pub enum EmbedError {
+    UnsupportedFormat,
+    Malformed(String),
+    TooLarge,
+}
+
+impl std::fmt::Display for EmbedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmbedError::UnsupportedFormat => {
+                write!(f, "Only JPEG, PNG and WebP images can carry embedded captions")
+            }
+            EmbedError::Malformed(reason) => write!(f, "Can't parse image container: {}", reason),
+            EmbedError::TooLarge => write!(f, "Metadata doesn't fit in a single segment"),
+        }
+    }
+}
+
+impl std::error::Error for EmbedError {}
+
+/// Returns `original` with `caption` written into its metadata, plus the MIME type.
+pub fn embed_caption(original: &[u8], caption: &str) -> Result<(Vec<u8>, &'static str), EmbedError> {
+    let image = DynImage::from_bytes(Bytes::copy_from_slice(original))
+        .map_err(|e| EmbedError::Malformed(e.to_string()))?
+        .ok_or(EmbedError::UnsupportedFormat)?;
+
+    match image {
+        DynImage::Jpeg(mut jpeg) => {
+            set_exif_description(&mut jpeg, caption)?;
+            set_iptc_caption(&mut jpeg, caption)?;
+            Ok((jpeg.encoder().bytes().to_vec(), "image/jpeg"))
+        }
+        DynImage::Png(mut png) => {
+            set_exif_description(&mut png, caption)?;
+            Ok((png.encoder().bytes().to_vec(), "image/png"))
+        }
+        DynImage::WebP(mut webp) => {
+            set_exif_description(&mut webp, caption)?;
+            Ok((webp.encoder().bytes().to_vec(), "image/webp"))
+        }
+    }
+}
+
+/// The text of ASCII tags in the EXIF IFD0 of a JPEG, PNG or WebP file, in `tags` order.
+pub(crate) fn exif_text<const N: usize>(data: &[u8], tags: [u16; N]) -> [Option<String>; N] {
+    let exif = DynImage::from_bytes(Bytes::copy_from_slice(data))
+        .ok()
+        .flatten()
+        .and_then(|image| image.exif());
+    let tiff = exif.as_deref().and_then(|exif| Tiff::parse(exif).ok());
+    tags.map(|tag| tiff.as_ref().and_then(|tiff| tiff.ascii(tag)))
+}
+
+fn set_exif_description(image: &mut impl ImageEXIF, caption: &str) -> Result<(), EmbedError> {
+    let existing = image.exif();
+    let tiff = existing
+        .as_deref()
+        .and_then(|tiff| Tiff::parse(tiff).ok())
+        .map_or_else(|| new_tiff(caption), |tiff| tiff.with_description(caption));
+
+    if tiff.len() + EXIF_PREFIX_LEN > MAX_SEGMENT_CONTENTS {
+        return Err(EmbedError::TooLarge);
+    }
+    image.set_exif(Some(tiff.into()));
+    Ok(())
+}
+
+/// ASCII-typed tag value: the text plus a NUL terminator. UTF-8 is written as-is, which
+/// is what exiftool, Lightroom and darktable expect in practice.
+fn ascii_value(text: &str) -> Vec<u8> {
+    let mut value: Vec<u8> = text.bytes().filter(|&b| b != 0).collect();
+    value.push(0);
+    value
+}
+
+fn new_tiff(caption: &str) -> Vec<u8> {
+    let value = ascii_value(caption);
+
+    let mut tiff = Vec::with_capacity(26 + value.len());
+    tiff.extend_from_slice(b"II*\0");
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+    tiff.extend_from_slice(&1u16.to_le_bytes());
+    write_entry(&mut tiff, false, TAG_IMAGE_DESCRIPTION, &value, 26);
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    if value.len() > 4 {
+        tiff.extend_from_slice(&value);
+    }
+    tiff
+}
+
+/// Appends a 12-byte ASCII IFD entry; `offset` is used when the value doesn't fit inline.
+fn write_entry(out: &mut Vec<u8>, big_endian: bool, tag: u16, value: &[u8], offset: u32) {
+    let u16_bytes = |v: u16| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+    let u32_bytes = |v: u32| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+
+    out.extend_from_slice(&u16_bytes(tag));
+    out.extend_from_slice(&u16_bytes(TIFF_TYPE_ASCII));
+    out.extend_from_slice(&u32_bytes(value.len() as u32));
+    if value.len() <= 4 {
+        let mut inline = [0u8; 4];
+        inline[..value.len()].copy_from_slice(value);
+        out.extend_from_slice(&inline);
+    } else {
+        out.extend_from_slice(&u32_bytes(offset));
+    }
+}
+
+/// Minimal view of a TIFF block: enough to rewrite IFD0.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+    entries: Vec<[u8; 12]>,
+    next_ifd: [u8; 4],
+}
+
+impl<'a> Tiff<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        let big_endian = match data.get(..2) {
+            Some(b"II") => false,
+            Some(b"MM") => true,
+            _ => return Err("bad byte order"),
+        };
+        let read_u16 = |at: usize| -> Result<u16, &'static str> {
+            let bytes: [u8; 2] = data.get(at..at + 2).ok_or("truncated")?.try_into().unwrap();
+            Ok(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+        };
+        let read_u32 = |at: usize| -> Result<u32, &'static str> {
+            let bytes: [u8; 4] = data.get(at..at + 4).ok_or("truncated")?.try_into().unwrap();
+            Ok(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+        };
+
+        if read_u16(2)? != 42 {
+            return Err("bad magic");
+        }
+
+        let ifd0 = read_u32(4)? as usize;
+        let count = read_u16(ifd0)? as usize;
+        let mut entries = Vec::with_capacity(count + 1);
+        for i in 0..count {
+            let at = ifd0 + 2 + i * 12;
+            let entry: [u8; 12] = data.get(at..at + 12).ok_or("truncated")?.try_into().unwrap();
+            entries.push(entry);
+        }
+        let next_at = ifd0 + 2 + count * 12;
+        let next_ifd: [u8; 4] = data
+            .get(next_at..next_at + 4)
+            .ok_or("truncated")?
+            .try_into()
+            .unwrap();
+
+        Ok(Tiff {
+            data,
+            big_endian,
+            entries,
+            next_ifd,
+        })
+    }
+
+    fn tag(&self, entry: &[u8; 12]) -> u16 {
+        let bytes = [entry[0], entry[1]];
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn read_u32(&self, bytes: &[u8]) -> u32 {
+        let bytes: [u8; 4] = bytes.try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// The value of an ASCII-typed IFD0 entry, without its NUL terminator.
+    fn ascii(&self, tag: u16) -> Option<String> {
+        let entry = self.entries.iter().find(|entry| self.tag(entry) == tag)?;
+        let kind = if self.big_endian {
+            u16::from_be_bytes([entry[2], entry[3]])
+        } else {
+            u16::from_le_bytes([entry[2], entry[3]])
+        };
+        if kind != TIFF_TYPE_ASCII {
+            return None;
+        }
+        let count = self.read_u32(&entry[4..8]) as usize;
+        let value = if count <= 4 {
+            &entry[8..8 + count]
+        } else {
+            let offset = self.read_u32(&entry[8..12]) as usize;
+            self.data.get(offset..offset.checked_add(count)?)?
+        };
+        let text = String::from_utf8_lossy(value);
+        let text = text.trim_end_matches('\0').trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+
+    /// Rebuilds the block with a new IFD0 (old entries + description) appended at the end.
+    fn with_description(&self, caption: &str) -> Vec<u8> {
+        let value = ascii_value(caption);
+
+        let mut out = self.data.to_vec();
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+        let value_offset = out.len() as u32;
+        if value.len() > 4 {
+            out.extend_from_slice(&value);
+            if out.len() % 2 == 1 {
+                out.push(0);
+            }
+        }
+
+        let mut new_entry = Vec::with_capacity(12);
+        write_entry(&mut new_entry, self.big_endian, TAG_IMAGE_DESCRIPTION, &value, value_offset);
+        let new_entry: [u8; 12] = new_entry.try_into().unwrap();
+
+        let mut entries: Vec<[u8; 12]> = self
+            .entries
+            .iter()
+            .filter(|entry| self.tag(entry) != TAG_IMAGE_DESCRIPTION)
+            .copied()
+            .collect();
+        entries.push(new_entry);
+        entries.sort_by_key(|entry| self.tag(entry));
+
+        let ifd_offset = out.len() as u32;
+        let count = entries.len() as u16;
+        out.extend_from_slice(&if self.big_endian { count.to_be_bytes() } else { count.to_le_bytes() });
+        for entry in &entries {
+            out.extend_from_slice(entry);
+        }
+        out.extend_from_slice(&self.next_ifd);
+
+        let header = if self.big_endian {
+            ifd_offset.to_be_bytes()
+        } else {
+            ifd_offset.to_le_bytes()
+        };
+        out[4..8].copy_from_slice(&header);
+        out
+    }
+}
+
+/// One IPTC IIM dataset (record:dataset = value).
+struct Dataset {
+    record: u8,
+    number: u8,
+    value: Vec<u8>,
+}
+
+fn parse_iim(mut data: &[u8]) -> Vec<Dataset> {
+    let mut datasets = Vec::new();
+    while data.len() >= 5 && data[0] == 0x1C {
+        let len = u16::from_be_bytes([data[3], data[4]]) as usize;
+        // Extended (>32 KB) datasets never hold captions; stop rather than misparse.
+        if len & 0x8000 != 0 || data.len() < 5 + len {
+            break;
+        }
+        datasets.push(Dataset {
+            record: data[1],
+            number: data[2],
+            value: data[5..5 + len].to_vec(),
+        });
+        data = &data[5 + len..];
+    }
+    datasets
+}
+
+fn encode_iim(datasets: &[Dataset]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for dataset in datasets {
+        out.extend_from_slice(&[0x1C, dataset.record, dataset.number]);
+        out.extend_from_slice(&(dataset.value.len() as u16).to_be_bytes());
+        out.extend_from_slice(&dataset.value);
+    }
+    out
+}
+
+/// A Photoshop image resource block ("8BIM" + id + name + data).
+struct Resource {
+    id: u16,
+    name: Vec<u8>,
+    data: Vec<u8>,
+}
+
+fn parse_resources(mut data: &[u8]) -> Result<Vec<Resource>, &'static str> {
+    let mut resources = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 7 || &data[..4] != b"8BIM" {
+            return Err("bad resource block");
+        }
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        let name_len = data[6] as usize;
+        // The Pascal name (length byte + text) is padded to an even size.
+        let name_end = 6 + ((1 + name_len + 1) & !1);
+        let size_at = name_end;
+        let size_bytes = data.get(size_at..size_at + 4).ok_or("truncated")?;
+        let size = u32::from_be_bytes(size_bytes.try_into().unwrap()) as usize;
+        let data_at = size_at + 4;
+        let block = data.get(data_at..data_at + size).ok_or("truncated")?;
+
+        resources.push(Resource {
+            id,
+            name: data[7..7 + name_len].to_vec(),
+            data: block.to_vec(),
+        });
+
+        let end = (data_at + size + 1) & !1;
+        data = data.get(end..).unwrap_or_default();
+    }
+    Ok(resources)
+}
+
+fn encode_resources(resources: &[Resource]) -> Vec<u8> {
+    let mut out = PHOTOSHOP_SIGNATURE.to_vec();
+    for resource in resources {
+        out.extend_from_slice(b"8BIM");
+        out.extend_from_slice(&resource.id.to_be_bytes());
+        out.push(resource.name.len() as u8);
+        out.extend_from_slice(&resource.name);
+        if (1 + resource.name.len()) % 2 == 1 {
+            out.push(0);
+        }
+        out.extend_from_slice(&(resource.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&resource.data);
+        if resource.data.len() % 2 == 1 {
+            out.push(0);
+        }
+    }
+    out
+}
+
+/// Cuts `text` to at most `max` bytes without splitting a grapheme cluster.
+fn truncate_bytes(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let end = text
+        .grapheme_indices(true)
+        .map(|(index, grapheme)| index + grapheme.len())
+        .take_while(|&end| end <= max)
+        .last()
+        .unwrap_or(0);
+    &text[..end]
+}
+
+fn set_iptc_caption(jpeg: &mut Jpeg, caption: &str) -> Result<(), EmbedError> {
+    let is_photoshop = |segment: &JpegSegment| {
+        segment.marker() == markers::APP13 && segment.contents().starts_with(PHOTOSHOP_SIGNATURE)
+    };
+
+    let mut resources = match jpeg.segments().iter().find(|segment| is_photoshop(segment)) {
+        Some(segment) => parse_resources(&segment.contents()[PHOTOSHOP_SIGNATURE.len()..])
+            .map_err(|e| EmbedError::Malformed(e.to_string()))?,
+        None => Vec::new(),
+    };
+
+    let existing = resources
+        .iter()
+        .find(|resource| resource.id == RESOURCE_IPTC)
+        .map(|resource| parse_iim(&resource.data))
+        .unwrap_or_default();
+
+    let mut datasets = vec![Dataset {
+        record: 1,
+        number: 90,
+        value: IPTC_UTF8.to_vec(),
+    }];
+    datasets.extend(
+        existing
+            .into_iter()
+            .filter(|d| !matches!((d.record, d.number), (1, 90) | (2, 120))),
+    );
+    if !datasets.iter().any(|d| d.record == 2 && d.number == 0) {
+        // Record version 4, required before any other record-2 dataset.
+        datasets.push(Dataset {
+            record: 2,
+            number: 0,
+            value: vec![0, 4],
+        });
+    }
+    datasets.push(Dataset {
+        record: 2,
+        number: 120,
+        value: truncate_bytes(caption, IPTC_CAPTION_MAX_BYTES).as_bytes().to_vec(),
+    });
+    datasets.sort_by_key(|d| (d.record, d.number != 0));
+
+    // The digest would no longer match the rewritten IPTC block, so drop it.
+    resources.retain(|r| r.id != RESOURCE_IPTC && r.id != RESOURCE_IPTC_DIGEST);
+    resources.push(Resource {
+        id: RESOURCE_IPTC,
+        name: Vec::new(),
+        data: encode_iim(&datasets),
+    });
+
+    let contents = encode_resources(&resources);
+    if contents.len() > MAX_SEGMENT_CONTENTS {
+        return Err(EmbedError::TooLarge);
+    }
+
+    let segments = jpeg.segments_mut();
+    segments.retain(|segment| !is_photoshop(segment));
+    let insert_at = segments
+        .iter()
+        .rposition(|segment| matches!(segment.marker(), markers::APP0 | markers::APP1))
+        .map_or(0, |index| index + 1);
+    segments.insert(
+        insert_at,
+        JpegSegment::new_with_contents(markers::APP13, contents.into()),
+    );
+    Ok(())
+}
@@ -0,0 +1,1383 @@
+// Captioning modes: each mode owns its prompt and knows how to turn the model's
+// reply into the caption text plus any structured fields for the JSON response.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::detection::ModelAssessment;
+use crate::results::{
+    self, AccessibilityAuditFields, AltTextFields, ArtFields, AstronomyFields, AuditFinding,
+    CaptionFields, ComicFields, ComicPanel, DescribedVehicle, DocumentLayoutFields, FashionFields,
+    HashtagsFields, LayoutRegion, Lettering, NatureFields, Observation, PageBox, RealEstateFields,
+    SkyObject, Species, TitleDescriptionFields, VehicleFields,
+};
+use crate::{
+    art, contact_sheet, fashion, listing, locale, nature, policy, sanitize, vehicle, CaptionError,
+};
+
+/// Version of the prompt templates below, recorded in every caption's provenance. Bump it
+/// whenever prompt wording changes so old and new captions can be told apart.
+pub const PROMPT_VERSION: u32 = 1;
+
+/// WCAG guidance suggests keeping alt text to roughly 125 characters. Both limits are
+/// counted in graphemes.
+const ALT_TEXT_MAX_CHARS: usize = 125;
+
+/// Photo managers and CMSs typically cap title fields around 60 characters.
+const TITLE_MAX_CHARS: usize = 60;
+
+/// Default and maximum number of hashtags/keywords per response.
+const DEFAULT_TAG_COUNT: usize = 10;
+const MAX_TAG_COUNT: usize = 50;
+
+/// Issue types an accessibility audit reports, with the WCAG 2.2 success criterion each one
+/// falls under.
+pub(crate) const AUDIT_ISSUES: &[(&str, &str)] = &[
+    ("low_contrast", "1.4.3"),
+    ("small_target", "2.5.8"),
+    ("missing_label", "3.3.2"),
+    ("color_only", "1.4.1"),
+    ("small_text", "1.4.4"),
+    ("other", ""),
+];
+
+/// Audit finding severities, most severe first.
+pub(crate) const SEVERITIES: &[&str] = &["high", "medium", "low"];
+
+/// Kinds of region a document layout describes.
+pub(crate) const REGION_TYPES: &[&str] = &[
+    "heading",
+    "paragraph",
+    "list",
+    "table",
+    "figure",
+    "caption",
+    "header",
+    "footer",
+    "page_number",
+    "form_field",
+    "signature",
+    "other",
+];
+
+/// The scale the model gives region and panel boxes on, per side of the page.
+const BOX_SCALE: f64 = 1000.0;
+
+/// Directions a comic page is read in: Western comics, manga, and vertical-scroll webtoons.
+pub(crate) const READING_DIRECTIONS: &[&str] = &["left_to_right", "right_to_left", "top_to_bottom"];
+
+/// Kinds of lettering transcribed from comic panels.
+pub(crate) const DIALOGUE_KINDS: &[&str] = &["speech", "thought", "narration", "sound_effect"];
+
+/// Kinds of object an astrophotograph can show.
+pub(crate) const SKY_OBJECT_KINDS: &[&str] = &[
+    "moon",
+    "sun",
+    "planet",
+    "star",
+    "constellation",
+    "asterism",
+    "galaxy",
+    "nebula",
+    "star_cluster",
+    "milky_way",
+    "comet",
+    "meteor",
+    "satellite",
+    "aurora",
+    "other",
+];
+
+/// Phases of the Moon, in order from new to new.
+pub(crate) const MOON_PHASES: &[&str] = &[
+    "new_moon",
+    "waxing_crescent",
+    "first_quarter",
+    "waxing_gibbous",
+    "full_moon",
+    "waning_gibbous",
+    "last_quarter",
+    "waning_crescent",
+];
+
+const PLANETS: &[&str] = &["Mercury", "Venus", "Mars", "Jupiter", "Saturn", "Uranus", "Neptune"];
+
+/// Most objects annotated per astrophotograph.
+pub(crate) const MAX_SKY_OBJECTS: usize = 20;
+
+/// Values of the `scene` fields. Times of day match `capture_context.time_of_day`.
+const TIMES_OF_DAY: &[&str] = &["dawn", "morning", "midday", "afternoon", "evening", "dusk", "night"];
+const WEATHER: &[&str] = &["clear", "partly_cloudy", "overcast", "rain", "snow", "fog", "storm"];
+const SETTINGS: &[&str] = &["indoor", "outdoor"];
+
+/// Redundant lead-ins screen readers already announce.
+const ALT_TEXT_PREFIXES: &[&str] = &[
+    "image of ",
+    "an image of ",
+    "a picture of ",
+    "picture of ",
+    "a photo of ",
+    "photo of ",
+    "a photograph of ",
+    "photograph of ",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    #[default]
+    Caption,
+    AltText,
+    Hashtags,
+    TitleDescription,
+    AccessibilityAudit,
+    DocumentLayout,
+    Comic,
+    Fashion,
+    Vehicle,
+    RealEstate,
+    Nature,
+    Astronomy,
+    Art,
+}
+
+impl Mode {
+    pub const ALL: &'static [Mode] = &[
+        Mode::Caption,
+        Mode::AltText,
+        Mode::Hashtags,
+        Mode::TitleDescription,
+        Mode::AccessibilityAudit,
+        Mode::DocumentLayout,
+        Mode::Comic,
+        Mode::Fashion,
+        Mode::Vehicle,
+        Mode::RealEstate,
+        Mode::Nature,
+        Mode::Astronomy,
+        Mode::Art,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Caption => "caption",
+            Mode::AltText => "alt_text",
+            Mode::Hashtags => "hashtags",
+            Mode::TitleDescription => "title_description",
+            Mode::AccessibilityAudit => "accessibility_audit",
+            Mode::DocumentLayout => "document_layout",
+            Mode::Comic => "comic",
+            Mode::Fashion => "fashion",
+            Mode::Vehicle => "vehicle",
+            Mode::RealEstate => "real_estate",
+            Mode::Nature => "nature",
+            Mode::Astronomy => "astronomy",
+            Mode::Art => "art",
+        }
+    }
+
+    /// Whether the mode always needs a JSON reply from the model.
+    fn structured(self) -> bool {
+        !matches!(self, Mode::Caption)
+    }
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Mode::ALL
+            .iter()
+            .copied()
+            .find(|mode| mode.name() == s.trim())
+            .ok_or_else(|| {
+                let names: Vec<_> = Mode::ALL.iter().map(|mode| mode.name()).collect();
+                format!("unknown mode '{}' (expected one of: {})", s, names.join(", "))
+            })
+    }
+}
+
+/// `de`, `eng`, `pt-BR`, `zh-Hans`: a language with an optional region or script.
+fn is_language_code(code: &str) -> bool {
+    let (language, suffix) = code.split_once('-').unwrap_or((code, ""));
+    (2..=3).contains(&language.len())
+        && language.bytes().all(|b| b.is_ascii_alphabetic())
+        && (code.len() == language.len()
+            || (2..=4).contains(&suffix.len()) && suffix.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
+
+/// Per-request captioning options shared by the web API and the CLI.
+#[derive(Clone, Debug, Default)]
+pub struct CaptionOptions {
+    pub mode: Mode,
+    /// Number of hashtags and keywords to return in `hashtags` mode.
+    pub count: Option<usize>,
+    /// Ask the model to self-assess with `confidence` and `uncertainties` fields.
+    pub confidence: bool,
+    /// Estimate whether the image is AI-generated, returned as `ai_detection`.
+    pub detect_ai: bool,
+    /// Classify the scene into `time_of_day`, `weather` and `indoor_outdoor` fields.
+    pub scene: bool,
+    /// Translate the result into this language (e.g. `de`, `pt-BR`); English otherwise.
+    pub language: Option<String>,
+    /// The tenant's brand voice, sent to the model as a system instruction.
+    pub voice: Option<String>,
+    /// Give the model the capture time, place and camera from the image's EXIF as context.
+    pub exif_context: bool,
+    /// Leave the GPS position out of the EXIF context (privacy).
+    pub hide_location: bool,
+    /// The image is a contact sheet or film strip: caption each panel and the sequence.
+    pub contact_sheet: bool,
+    /// Report the provider tokens used and their estimated cost with the result.
+    pub verbose: bool,
+    /// Flag sensitive content (medical images and the like) for the content policy, returned
+    /// as `sensitive_content`.
+    pub screen: bool,
+    /// Instructions added to the prompt, e.g. by a content policy rule.
+    pub instructions: Option<String>,
+    /// Add iNaturalist-style observation fields to `nature` results, returned as `observation`.
+    pub observation: bool,
+}
+
+/// The caption text plus any mode-specific fields, flattened into the response.
+pub struct ModeOutput {
+    pub caption: String,
+    pub details: Map<String, Value>,
+    /// The model's AI-generation assessment, when `detect_ai` asked for one.
+    pub assessment: Option<ModelAssessment>,
+}
+
+impl CaptionOptions {
+    /// The options a web request starts from: DEFAULT_MODE and DEFAULT_LANGUAGE when they're
+    /// set, read per request so a reload applies them.
+    pub(crate) fn configured() -> Result<Self, String> {
+        let variable = |name: &str| {
+            let value = crate::settings::var(name).ok()?;
+            Some(value.trim().to_string()).filter(|value| !value.is_empty())
+        };
+        let mode = match variable("DEFAULT_MODE") {
+            Some(mode) => mode.parse().map_err(|e| format!("DEFAULT_MODE: {}", e))?,
+            None => Mode::default(),
+        };
+        let language = variable("DEFAULT_LANGUAGE");
+        if let Some(language) = language.as_deref().filter(|code| !is_language_code(code)) {
+            return Err(format!(
+                "DEFAULT_LANGUAGE must be a code such as de or pt-BR, not '{}'",
+                language
+            ));
+        }
+        Ok(CaptionOptions {
+            mode,
+            language,
+            ..Default::default()
+        })
+    }
+
+    /// Checks option values that can't be expressed in the types alone.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.count {
+            Some(count) if count == 0 || count > MAX_TAG_COUNT => {
+                return Err(format!("count must be between 1 and {}", MAX_TAG_COUNT))
+            }
+            _ => {}
+        }
+        if self.observation && self.mode != Mode::Nature {
+            return Err("observation needs the nature mode".into());
+        }
+        match &self.language {
+            Some(language) if !is_language_code(language) => Err(format!(
+                "language must be a code such as de or pt-BR, not '{}'",
+                language
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// What a stored result must match to still be current: the prompt, plus the output
+    /// language when translating, the brand voice when there is one and whether EXIF
+    /// context was given.
+    pub fn fingerprint(&self) -> String {
+        let mut fingerprint = self.prompt();
+        if self.exif_context {
+            fingerprint.push_str(if self.hide_location {
+                "\nexif context: without location"
+            } else {
+                "\nexif context: with location"
+            });
+        }
+        if let Some(language) = &self.language {
+            fingerprint.push_str(&format!("\nlanguage: {}", language));
+        }
+        if let Some(voice) = &self.voice {
+            fingerprint.push_str(&format!("\nvoice: {}", voice));
+        }
+        fingerprint
+    }
+
+    fn tag_count(&self) -> usize {
+        self.count.unwrap_or(DEFAULT_TAG_COUNT)
+    }
+
+    pub fn prompt(&self) -> String {
+        let mut prompt = self.task();
+
+        if self.contact_sheet {
+            prompt.push_str(
+                " Describe each panel in one short sentence in \"panels\", in reading order, and \
+                 let the rest of the reply describe the sheet as a whole: what the sequence \
+                 shows or how it unfolds from panel to panel.",
+            );
+        }
+
+        if self.confidence {
+            prompt.push_str(
+                " Also assess how sure you are: give an overall confidence between 0 and 1 \
+                 (1 = certain) and list any elements you are unsure about (objects you may have \
+                 misidentified, unreadable text, ambiguous actions). Use an empty list when \
+                 nothing is uncertain.",
+            );
+        }
+
+        if self.scene {
+            prompt.push_str(&format!(
+                " Also classify the scene: \"time_of_day\" as one of {}; \"weather\" as one of \
+                 {}; and \"indoor_outdoor\" as one of {}. Judge from what the image shows (light, \
+                 sky, shadows, windows) and use null for anything it doesn't show, such as the \
+                 weather in a windowless room.",
+                TIMES_OF_DAY.join(", "),
+                WEATHER.join(", "),
+                SETTINGS.join(", ")
+            ));
+        }
+
+        if self.detect_ai {
+            prompt.push_str(
+                " Separately, estimate how likely it is that the image was made or substantially \
+                 altered by an AI image generator rather than photographed or drawn by a person, \
+                 between 0 and 1, and list the visual cues behind your estimate (malformed hands \
+                 or text, inconsistent lighting, shadows or reflections, implausibly smooth \
+                 textures, warped backgrounds). Use an empty list when nothing stands out.",
+            );
+        }
+
+        if self.screen {
+            let categories: Vec<String> = policy::CATEGORIES
+                .iter()
+                .map(|(name, description)| format!("{} ({})", name, description))
+                .collect();
+            prompt.push_str(&format!(
+                " Also list in \"sensitive_content\" which of these the image shows, if any: {}. \
+                 Use an empty list when none apply.",
+                categories.join(", ")
+            ));
+        }
+
+        if let Some(instructions) = &self.instructions {
+            prompt.push(' ');
+            prompt.push_str(instructions.trim());
+        }
+
+        if self.expects_json() {
+            let fields: Vec<String> = self
+                .json_fields()
+                .iter()
+                .map(|(name, kind)| format!("\"{}\": {}", name, kind))
+                .collect();
+            prompt.push_str(&format!(
+                " Respond with JSON of the form {{{}}}.",
+                fields.join(", ")
+            ));
+        }
+
+        prompt
+    }
+
+    /// The mode-specific instructions, without the reply format.
+    fn task(&self) -> String {
+        match self.mode {
+            Mode::Caption => {
+                "Describe this image in detail. Provide a clear, descriptive caption.".to_string()
+            }
+            Mode::AltText => format!(
+                "Write alt text for this image for screen reader users, following WCAG guidance. \
+                 Be concise and aim for at most {} characters. Do not start with \"image of\", \
+                 \"picture of\" or \"photo of\". Describe the content and function, not the \
+                 styling. If the image is purely decorative (a background texture, divider, \
+                 spacer or ornament that conveys no information), set \"decorative\" to true and \
+                 \"alt_text\" to an empty string.",
+                ALT_TEXT_MAX_CHARS
+            ),
+            Mode::Hashtags => format!(
+                "Suggest social media hashtags and plain search keywords for this image. Return \
+                 exactly {count} hashtags and {count} keywords, each list ranked from most to \
+                 least relevant. Hashtags are single tokens without spaces; keywords are short \
+                 lowercase words or phrases. Also write a one-sentence caption.",
+                count = self.tag_count()
+            ),
+            Mode::TitleDescription => format!(
+                "Write metadata for this image: a short title of at most {} characters (no \
+                 trailing period) and a longer description of two to four sentences.",
+                TITLE_MAX_CHARS
+            ),
+            Mode::AccessibilityAudit => format!(
+                "Audit this screenshot of an app or web page for accessibility issues you can see \
+                 in it: text with too little contrast against its background, tap or click \
+                 targets smaller than about 24 by 24 CSS pixels, form fields or icon-only buttons \
+                 without a visible label, information conveyed by color alone, and text too small \
+                 to read comfortably. For each issue give its type (one of {}), its severity \
+                 (high, medium or low), the element it affects as a user would name it, and one \
+                 sentence explaining the problem and how to fix it. Report only what is visible. \
+                 Also write a one-sentence summary of the screen and how accessible it looks. If \
+                 the image is not a screenshot of a user interface, say so in the summary and \
+                 return no findings.",
+                AUDIT_ISSUES
+                    .iter()
+                    .map(|(issue, _)| *issue)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Mode::DocumentLayout => format!(
+                "Describe the layout of this document page for a document-processing pipeline. \
+                 Split the page into regions in reading order: each region's type (one of {}), \
+                 its bounding box as [x0, y0, x1, y1] on a 0-{scale} scale from the top-left \
+                 corner, and its text exactly as printed. For a heading, give its level (1 for \
+                 the main title). For a table, also give its cells as rows of strings. Give the \
+                 number of text columns in the body, all of the page's text in reading order, \
+                 and a one-sentence summary saying what kind of document this is. If the image \
+                 is not a document, say so in the summary and return no regions.",
+                REGION_TYPES.join(", "),
+                scale = BOX_SCALE
+            ),
+            Mode::Comic => format!(
+                "This is a comic or manga page. Say which way it reads (one of {}; manga usually \
+                 reads right to left). List its panels in reading order: each panel's bounding \
+                 box as [x0, y0, x1, y1] on a 0-{scale} scale from the top-left corner, one \
+                 sentence describing what happens in it, and its lettering in reading order, \
+                 each with its kind (one of {}), the character speaking or thinking if you can \
+                 tell (null for narration and sound effects or when unclear), and the text \
+                 exactly as lettered, in its original language. Then summarize the page's story \
+                 in two to four sentences. If the image is not a comic, say so in the summary \
+                 and return no panels.",
+                READING_DIRECTIONS.join(", "),
+                DIALOGUE_KINDS.join(", "),
+                scale = BOX_SCALE
+            ),
+            Mode::Fashion => {
+                let schema = fashion::attributes();
+                format!(
+                    "Describe the main garment or accessory in this product photo for a fashion \
+                     catalog: its garment type (one of {}); its main color and any secondary \
+                     colors (each one of {}); its pattern (one of {}); your best guess at its \
+                     material from how it looks (one of {}); and up to {} style tags (from {}). \
+                     Use only these terms, and null for anything you can't tell. Also write a \
+                     one-sentence product description as the summary. If the image shows no \
+                     clothing or accessories, say so in the summary and use null for every \
+                     attribute.",
+                    schema.garment_types.join(", "),
+                    schema.colors.join(", "),
+                    schema.patterns.join(", "),
+                    schema.materials.join(", "),
+                    fashion::MAX_STYLE_TAGS,
+                    schema.style_tags.join(", ")
+                )
+            }
+            Mode::Vehicle => format!(
+                "Describe the vehicles in this photo for a fleet or insurance record, most \
+                 prominent first and at most {}: each one's make and model as badged or as best \
+                 you can tell (null when you can't), its color (one of {}), its body type (one \
+                 of {}), and any visible damage: how severe it is (one of {}) and one or two \
+                 sentences on what is damaged and where, such as \"dented rear bumper on the \
+                 driver's side\" (null when there is none). Never write out a license plate, \
+                 in any field; where it matters, say only that a plate is visible. Also write a \
+                 one-sentence summary of the scene. If the photo shows no vehicle, say so in \
+                 the summary and return no vehicles.",
+                vehicle::MAX_VEHICLES,
+                vehicle::COLORS.join(", "),
+                vehicle::BODY_TYPES.join(", "),
+                vehicle::DAMAGE_SEVERITIES.join(", ")
+            ),
+            Mode::RealEstate => format!(
+                "This is a photo from a property listing. Say which room or part of the property \
+                 it shows (one of {}), and describe it for the listing in two or three sentences: \
+                 the impression of space and light, the finishes, and anything a buyer would \
+                 notice, such as a view, a fireplace or built-in storage. Stick to the property \
+                 itself rather than furniture or belongings that would leave with the seller, and \
+                 to what the photo shows. Also list up to {} of its notable features as short \
+                 phrases, such as \"hardwood floors\" or \"kitchen island\". If the photo \
+                 isn't of a property, say so in the description, use other and list no features.",
+                listing::ROOM_TYPES.join(", "),
+                listing::MAX_FEATURES
+            ),
+            Mode::Nature => {
+                let mut task = format!(
+                    "Identify the wild animals, plants or fungi in this photo, which may come from \
+                     a camera trap: up to {} candidate species, most likely first, each with its \
+                     common name in English (null if it has none), its scientific name, the rank \
+                     you can identify it to (one of {}; give the genus or family rather than guess \
+                     a species) and your confidence between 0 and 1. Name what could be confused \
+                     with your top candidate, or why the photo can't settle it, in a short caution \
+                     note (null if nothing). Also write a one-sentence summary of what the photo \
+                     shows. If it shows no organism, as camera-trap frames often don't, say so in \
+                     the summary and return no species.",
+                    nature::MAX_SPECIES,
+                    nature::RANKS.join(", ")
+                );
+                if self.observation {
+                    task.push_str(&format!(
+                        " Also describe the observation for iNaturalist: the iconic taxon of \
+                         your top candidate (one of {}), how many individuals are visible, and \
+                         their life stage (one of {}), sex (one of {}), evidence of presence \
+                         (one of {}; organism when the organism itself is seen), whether it is \
+                         alive, and whether it is captive or cultivated, such as in a zoo or a \
+                         garden. Use null for anything the photo doesn't show.",
+                        nature::ICONIC_TAXA.join(", "),
+                        nature::LIFE_STAGES.join(", "),
+                        nature::SEXES.join(", "),
+                        nature::EVIDENCE.join(", ")
+                    ));
+                }
+                task
+            }
+            Mode::Astronomy => format!(
+                "This is an astrophotograph or a photo of the night sky. Annotate the objects in \
+                 it that you can recognize, at most {}, most prominent first: each one's kind \
+                 (one of {}), its name (\"Jupiter\", \"Orion\", \"Andromeda Galaxy\"; null for \
+                 an unnamed star or meteor), its catalog designation such as M31 or NGC 7000 if \
+                 it has one, and its bounding box as [x0, y0, x1, y1] on a 0-{scale} scale from \
+                 the top-left corner (null for something spread across the frame, like the Milky \
+                 Way or an aurora). Name only what you can recognize from its shape, color and \
+                 the stars around it, not guesses. If the Moon is visible, give its phase (one \
+                 of {}). Also write a one- or two-sentence summary of the image. If it doesn't \
+                 show the sky, say so in the summary and annotate nothing.",
+                MAX_SKY_OBJECTS,
+                SKY_OBJECT_KINDS.join(", "),
+                MOON_PHASES.join(", "),
+                scale = BOX_SCALE
+            ),
+            Mode::Art => format!(
+                "Analyze this artwork for a gallery archive: its medium (one of {}); up to {} \
+                 styles or movements it belongs to, most fitting first (from {}); up to {} short \
+                 notes on its composition, such as the focal point, the lines leading to it, the \
+                 balance, the palette and the light; and when it was probably made, as a short \
+                 description such as \"late 19th century\" with the earliest and latest likely \
+                 years (negative for BCE). Also write a two- or three-sentence description of \
+                 what it depicts and how, without naming an artist. Separately, if the work \
+                 reminds you of a particular artist, give that artist and what in the work \
+                 suggests them; this is a guess from a photo, not an attribution, so use null \
+                 unless the resemblance is strong. If the image isn't an artwork, say so in the \
+                 description and use null and empty lists for the rest.",
+                art::MEDIA.join(", "),
+                art::MAX_STYLES,
+                art::STYLES.join(", "),
+                art::MAX_COMPOSITION_NOTES
+            ),
+        }
+    }
+
+    /// Fields of the JSON reply, as (name, type) pairs shown to the model.
+    fn json_fields(&self) -> Vec<(&'static str, &'static str)> {
+        let mut fields = match self.mode {
+            Mode::Caption => vec![("caption", "string")],
+            Mode::AltText => vec![("alt_text", "string"), ("decorative", "boolean")],
+            Mode::Hashtags => vec![
+                ("caption", "string"),
+                ("hashtags", "[string]"),
+                ("keywords", "[string]"),
+            ],
+            Mode::TitleDescription => vec![("title", "string"), ("description", "string")],
+            Mode::AccessibilityAudit => vec![
+                ("summary", "string"),
+                (
+                    "findings",
+                    "[{\"issue\": string, \"severity\": string, \"element\": string, \"detail\": string}]",
+                ),
+            ],
+            Mode::DocumentLayout => vec![
+                ("summary", "string"),
+                ("columns", "number"),
+                (
+                    "regions",
+                    "[{\"type\": string, \"box\": [number], \"text\": string, \"level\": number, \"cells\": [[string]]}]",
+                ),
+                ("ocr_text", "string"),
+            ],
+            Mode::Comic => vec![
+                ("summary", "string"),
+                ("reading_direction", "string"),
+                (
+                    "comic_panels",
+                    "[{\"box\": [number], \"description\": string, \"dialogue\": [{\"kind\": string, \"speaker\": string or null, \"text\": string}]}]",
+                ),
+            ],
+            Mode::Fashion => vec![
+                ("summary", "string"),
+                ("garment_type", "string or null"),
+                ("color", "string or null"),
+                ("secondary_colors", "[string]"),
+                ("pattern", "string or null"),
+                ("material", "string or null"),
+                ("style_tags", "[string]"),
+            ],
+            Mode::RealEstate => vec![
+                ("room_type", "string"),
+                ("description", "string"),
+                ("features", "[string]"),
+            ],
+            Mode::Nature => vec![
+                ("summary", "string"),
+                (
+                    "species",
+                    "[{\"common_name\": string or null, \"scientific_name\": string, \"rank\": string, \"confidence\": number}]",
+                ),
+                ("caution", "string or null"),
+            ],
+            Mode::Astronomy => vec![
+                ("summary", "string"),
+                ("moon_phase", "string or null"),
+                (
+                    "sky_objects",
+                    "[{\"kind\": string, \"name\": string or null, \"catalog_id\": string or null, \"box\": [number] or null}]",
+                ),
+            ],
+            Mode::Art => vec![
+                ("summary", "string"),
+                ("medium", "string or null"),
+                ("styles", "[string]"),
+                ("composition", "[string]"),
+                (
+                    "era",
+                    "{\"description\": string, \"from_year\": number or null, \"to_year\": number or null} or null",
+                ),
+                ("attribution", "{\"artist\": string, \"basis\": string} or null"),
+            ],
+            Mode::Vehicle => vec![
+                ("summary", "string"),
+                (
+                    "vehicles",
+                    "[{\"make\": string or null, \"model\": string or null, \"color\": string or null, \"body_type\": string or null, \"damage_severity\": string, \"damage\": string or null}]",
+                ),
+            ],
+        };
+
+        if self.observation {
+            fields.push((
+                "observation",
+                "{\"iconic_taxon\": string or null, \"individual_count\": number or null, \"life_stage\": string or null, \"sex\": string or null, \"evidence\": string or null, \"alive\": boolean or null, \"captive\": boolean or null}",
+            ));
+        }
+
+        if self.contact_sheet {
+            fields.push(("panels", "[string]"));
+        }
+
+        if self.confidence {
+            fields.push(("confidence", "number"));
+            fields.push(("uncertainties", "[string]"));
+        }
+
+        if self.scene {
+            fields.push(("time_of_day", "string or null"));
+            fields.push(("weather", "string or null"));
+            fields.push(("indoor_outdoor", "string or null"));
+        }
+
+        if self.detect_ai {
+            fields.push(("ai_generated_likelihood", "number"));
+            fields.push(("ai_generated_indicators", "[string]"));
+        }
+
+        if self.screen {
+            fields.push(("sensitive_content", "[string]"));
+        }
+
+        fields
+    }
+
+    /// Whether the provider should be asked for a JSON reply.
+    pub fn expects_json(&self) -> bool {
+        self.mode.structured()
+            || self.contact_sheet
+            || self.confidence
+            || self.scene
+            || self.detect_ai
+            || self.screen
+    }
+
+    /// Re-applies the mode's length limits in the output language, after translation has
+    /// changed the text.
+    pub fn apply_limits(&self, output: &mut ModeOutput) {
+        let language = self.language.as_deref();
+        for (field, max) in [("alt_text", ALT_TEXT_MAX_CHARS), ("title", TITLE_MAX_CHARS)] {
+            if let Some(Value::String(text)) = output.details.get_mut(field) {
+                *text = locale::truncate(text, max, language);
+            }
+        }
+        if self.mode == Mode::AltText {
+            output.caption = locale::truncate(&output.caption, ALT_TEXT_MAX_CHARS, language);
+        }
+    }
+
+    pub fn parse_output(&self, text: &str) -> Result<ModeOutput, CaptionError> {
+        if !self.expects_json() {
+            return Ok(ModeOutput {
+                caption: sanitize::clean(text),
+                details: Map::new(),
+                assessment: None,
+            });
+        }
+
+        let mut reply = parse_json_reply(text)?;
+        sanitize::clean_value(&mut reply);
+        let mut output = self.parse_reply(&reply)?;
+
+        if self.contact_sheet {
+            let panels = contact_sheet::descriptions(&reply);
+            output.details.insert("panels".into(), panels.into());
+        }
+
+        if self.confidence {
+            let confidence = reply["confidence"].as_f64().map(|value| value.clamp(0.0, 1.0));
+            let uncertainties: Vec<String> = string_list(&reply["uncertainties"])
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect();
+
+            output.details.insert("confidence".into(), confidence.into());
+            output
+                .details
+                .insert("uncertainties".into(), uncertainties.into());
+        }
+
+        if self.scene {
+            for (field, allowed) in [
+                ("time_of_day", TIMES_OF_DAY),
+                ("weather", WEATHER),
+                ("indoor_outdoor", SETTINGS),
+            ] {
+                let value = reply[field].as_str().and_then(|value| vocabulary_term(value, allowed));
+                output.details.insert(field.into(), value.into());
+            }
+        }
+
+        if self.detect_ai {
+            let likelihood = reply["ai_generated_likelihood"]
+                .as_f64()
+                .ok_or_else(|| missing("ai_generated_likelihood"))?;
+            output.assessment = Some(ModelAssessment {
+                likelihood: likelihood.clamp(0.0, 1.0),
+                indicators: string_list(&reply["ai_generated_indicators"])
+                    .map(|item| item.trim().to_string())
+                    .filter(|item| !item.is_empty())
+                    .collect(),
+            });
+        }
+
+        if self.screen {
+            let names: Vec<&str> = policy::CATEGORIES.iter().map(|(name, _)| *name).collect();
+            let flagged = string_list(&reply["sensitive_content"])
+                .filter_map(|category| vocabulary_term(&category, &names));
+            let flagged = ranked_unique(flagged, names.len());
+            output.details.insert("sensitive_content".into(), flagged.into());
+        }
+
+        if self.mode == Mode::Vehicle {
+            vehicle::redact(&mut output);
+        }
+
+        Ok(output)
+    }
+
+    fn parse_reply(&self, reply: &Value) -> Result<ModeOutput, CaptionError> {
+        let summary = || {
+            reply["summary"]
+                .as_str()
+                .map(|summary| summary.trim().to_string())
+                .ok_or_else(|| missing("summary"))
+        };
+
+        match self.mode {
+            Mode::Caption => {
+                let caption = reply["caption"].as_str().ok_or_else(|| missing("caption"))?;
+                Ok(mode_output(caption.trim().to_string(), &CaptionFields {}))
+            }
+            Mode::AltText => {
+                let decorative = reply["decorative"].as_bool().unwrap_or(false);
+                let alt_text = if decorative {
+                    String::new()
+                } else {
+                    clean_alt_text(reply["alt_text"].as_str().ok_or_else(|| missing("alt_text"))?)
+                };
+
+                let fields = AltTextFields {
+                    alt_text: alt_text.clone(),
+                    decorative,
+                };
+                Ok(mode_output(alt_text, &fields))
+            }
+            Mode::Hashtags => {
+                let count = self.tag_count();
+
+                let hashtags = string_list(&reply["hashtags"])
+                    .filter_map(|tag| normalize_hashtag(&tag));
+                let keywords = string_list(&reply["keywords"])
+                    .map(|keyword| keyword.trim().to_lowercase())
+                    .filter(|keyword| !keyword.is_empty());
+
+                let fields = HashtagsFields {
+                    hashtags: ranked_unique(hashtags, count),
+                    keywords: ranked_unique(keywords, count),
+                };
+                let caption = reply["caption"].as_str().unwrap_or_default().trim().to_string();
+                Ok(mode_output(caption, &fields))
+            }
+            Mode::TitleDescription => {
+                let title = reply["title"].as_str().ok_or_else(|| missing("title"))?;
+                let title = locale::truncate(title.trim().trim_end_matches('.'), TITLE_MAX_CHARS, None);
+                let description = reply["description"]
+                    .as_str()
+                    .ok_or_else(|| missing("description"))?
+                    .trim()
+                    .to_string();
+
+                let fields = TitleDescriptionFields {
+                    title,
+                    description: description.clone(),
+                };
+                Ok(mode_output(description, &fields))
+            }
+            Mode::AccessibilityAudit => {
+                let summary = summary()?;
+                let mut findings: Vec<AuditFinding> = reply["findings"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(audit_finding)
+                    .collect();
+                findings.sort_by_key(|finding| {
+                    SEVERITIES.iter().position(|severity| finding.severity == *severity)
+                });
+
+                Ok(mode_output(summary, &AccessibilityAuditFields { findings }))
+            }
+            Mode::DocumentLayout => {
+                let summary = summary()?;
+                let regions: Vec<LayoutRegion> = reply["regions"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(layout_region)
+                    .collect();
+                let columns = reply["columns"]
+                    .as_u64()
+                    .filter(|columns| *columns > 0)
+                    .map(|columns| columns.min(12));
+
+                let fields = DocumentLayoutFields {
+                    columns,
+                    regions,
+                    ocr_text: reply["ocr_text"].as_str().unwrap_or_default().trim().to_string(),
+                };
+                Ok(mode_output(summary, &fields))
+            }
+            Mode::Comic => {
+                let summary = summary()?;
+                let direction = reply["reading_direction"]
+                    .as_str()
+                    .and_then(|direction| vocabulary_term(direction, READING_DIRECTIONS));
+                let panels: Vec<ComicPanel> = reply["comic_panels"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(comic_panel)
+                    .enumerate()
+                    .map(|(index, panel)| ComicPanel {
+                        order: index + 1,
+                        ..panel
+                    })
+                    .collect();
+
+                let fields = ComicFields {
+                    reading_direction: direction,
+                    comic_panels: panels,
+                };
+                Ok(mode_output(summary, &fields))
+            }
+            Mode::RealEstate => {
+                let description = reply["description"]
+                    .as_str()
+                    .ok_or_else(|| missing("description"))?;
+                let room_type = reply["room_type"]
+                    .as_str()
+                    .and_then(|room| vocabulary_term(room, listing::ROOM_TYPES))
+                    .unwrap_or_else(|| "other".to_string());
+                let features = string_list(&reply["features"])
+                    .map(|feature| feature.trim().to_string())
+                    .filter(|feature| !feature.is_empty());
+
+                let fields = RealEstateFields {
+                    room_type,
+                    features: ranked_unique(features, listing::MAX_FEATURES),
+                };
+                Ok(mode_output(description.trim().to_string(), &fields))
+            }
+            Mode::Nature => {
+                let summary = summary()?;
+                let mut species: Vec<Species> = reply["species"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(candidate_species)
+                    .collect();
+                // Most confident first; the model's order breaks ties.
+                species.sort_by(|a, b| {
+                    let confidence = |candidate: &Species| candidate.confidence.unwrap_or(0.0);
+                    confidence(b).total_cmp(&confidence(a))
+                });
+                species.truncate(nature::MAX_SPECIES);
+
+                let fields = NatureFields {
+                    species,
+                    caution: nature::caution(reply["caution"].as_str()),
+                    observation: self.observation.then(|| observation(&reply["observation"])),
+                };
+                Ok(mode_output(summary, &fields))
+            }
+            Mode::Astronomy => {
+                let summary = summary()?;
+                let objects: Vec<SkyObject> = reply["sky_objects"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(sky_object)
+                    .take(MAX_SKY_OBJECTS)
+                    .collect();
+                // A phase only means something when the Moon is in the picture.
+                let moon_phase = reply["moon_phase"]
+                    .as_str()
+                    .map(|phase| phase.trim().to_lowercase().replace(' ', "_"))
+                    .map(|phase| match phase.as_str() {
+                        "new" | "full" => format!("{}_moon", phase),
+                        "third_quarter" => "last_quarter".to_string(),
+                        _ => phase,
+                    })
+                    .and_then(|phase| vocabulary_term(&phase, MOON_PHASES))
+                    .filter(|_| objects.iter().any(|object| object.kind == "moon"));
+
+                let fields = AstronomyFields {
+                    moon_phase,
+                    sky_objects: objects,
+                };
+                Ok(mode_output(summary, &fields))
+            }
+            Mode::Art => {
+                let summary = summary()?;
+                let medium = reply["medium"]
+                    .as_str()
+                    .map(|medium| match medium.trim().to_lowercase().as_str() {
+                        "oil on canvas" | "oil painting" => "oil",
+                        "watercolour" => "watercolor",
+                        _ => medium,
+                    })
+                    .and_then(|medium| vocabulary_term(medium, art::MEDIA));
+                let styles = string_list(&reply["styles"])
+                    .filter_map(|style| vocabulary_term(&style, art::STYLES));
+                let composition = string_list(&reply["composition"])
+                    .map(|note| note.trim().to_string())
+                    .filter(|note| !note.is_empty());
+
+                let fields = ArtFields {
+                    medium,
+                    styles: ranked_unique(styles, art::MAX_STYLES),
+                    composition: ranked_unique(composition, art::MAX_COMPOSITION_NOTES),
+                    era: art::era(&reply["era"]),
+                    attribution_guess: art::attribution_guess(&reply["attribution"]),
+                };
+                Ok(mode_output(summary, &fields))
+            }
+            Mode::Vehicle => {
+                let summary = summary()?;
+                let vehicles: Vec<DescribedVehicle> = reply["vehicles"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(described_vehicle)
+                    .take(vehicle::MAX_VEHICLES)
+                    .collect();
+
+                Ok(mode_output(summary, &VehicleFields { vehicles }))
+            }
+            Mode::Fashion => {
+                let summary = summary()?;
+                let schema = fashion::attributes();
+                let term = |field: &str, terms: &[String]| {
+                    reply[field].as_str().and_then(|value| fashion::pick(terms, value))
+                };
+                let terms = |field: &str, terms: &[String], max: usize| {
+                    let picked = string_list(&reply[field])
+                        .filter_map(|value| fashion::pick(terms, &value));
+                    ranked_unique(picked, max)
+                };
+                let color = term("color", &schema.colors);
+                let secondary_colors: Vec<String> =
+                    terms("secondary_colors", &schema.colors, schema.colors.len())
+                        .into_iter()
+                        .filter(|secondary| Some(secondary) != color.as_ref())
+                        .collect();
+
+                let fields = FashionFields {
+                    garment_type: term("garment_type", &schema.garment_types),
+                    color,
+                    secondary_colors,
+                    pattern: term("pattern", &schema.patterns),
+                    material: term("material", &schema.materials),
+                    style_tags: terms("style_tags", &schema.style_tags, fashion::MAX_STYLE_TAGS),
+                };
+                Ok(mode_output(summary, &fields))
+            }
+        }
+    }
+}
+
+/// The caption plus `fields` as its details.
+fn mode_output(caption: String, fields: &impl Serialize) -> ModeOutput {
+    ModeOutput {
+        caption,
+        details: results::details(fields),
+        assessment: None,
+    }
+}
+
+/// A `[x0, y0, x1, y1]` box on the `BOX_SCALE` scale as fractions of the page, or `None` when
+/// it isn't four numbers or has no area.
+fn page_box(value: &Value) -> Option<PageBox> {
+    let corners: Vec<f64> = value
+        .as_array()?
+        .iter()
+        .filter_map(Value::as_f64)
+        .map(|value| (value / BOX_SCALE).clamp(0.0, 1.0))
+        .collect();
+    let [x0, y0, x1, y1] = corners[..] else {
+        return None;
+    };
+    let (left, right) = (x0.min(x1), x0.max(x1));
+    let (top, bottom) = (y0.min(y1), y0.max(y1));
+    if right - left <= 0.0 || bottom - top <= 0.0 {
+        return None;
+    }
+    let fraction = |value: f64| (value * 1000.0).round() / 1000.0;
+
+    Some(PageBox {
+        x: fraction(left),
+        y: fraction(top),
+        width: fraction(right - left),
+        height: fraction(bottom - top),
+    })
+}
+
+/// One annotated sky object with its kind held to the known kinds and its box, if it has a
+/// usable one, turned into fractions of the image. Planets must be one of the seven; objects
+/// with neither a name nor a box say nothing and are dropped.
+fn sky_object(item: &Value) -> Option<SkyObject> {
+    let text = |field: &str| {
+        item[field]
+            .as_str()
+            .map(|text| text.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|text| !text.is_empty() && !text.eq_ignore_ascii_case("unknown"))
+    };
+    let kind = item["kind"]
+        .as_str()
+        .and_then(|kind| vocabulary_term(kind, SKY_OBJECT_KINDS))
+        .unwrap_or_else(|| "other".to_string());
+    let mut name = text("name");
+    match kind.as_str() {
+        "moon" => name = Some("Moon".to_string()),
+        "sun" => name = Some("Sun".to_string()),
+        "planet" => {
+            let named = name.as_deref().unwrap_or_default();
+            let planet = PLANETS.iter().find(|planet| planet.eq_ignore_ascii_case(named))?;
+            name = Some(planet.to_string());
+        }
+        _ => {}
+    }
+    let position = page_box(&item["box"]);
+    if name.is_none() && position.is_none() {
+        return None;
+    }
+
+    Some(SkyObject {
+        kind,
+        name,
+        catalog_id: text("catalog_id"),
+        position,
+    })
+}
+
+/// One candidate species with its scientific name capitalized the usual way ("Vulpes
+/// vulpes"), its rank held to the known ranks and its confidence between 0 and 1. Candidates
+/// without a scientific name are dropped.
+fn candidate_species(item: &Value) -> Option<Species> {
+    let name = item["scientific_name"].as_str()?;
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    let mut letters = name.chars();
+    let scientific_name: String = letters.next()?.to_uppercase().chain(letters).collect();
+    let common_name = item["common_name"]
+        .as_str()
+        .map(str::trim)
+        .filter(|name| !name.is_empty() && !name.eq_ignore_ascii_case("unknown"));
+    let rank = item["rank"]
+        .as_str()
+        .and_then(|rank| vocabulary_term(rank, nature::RANKS))
+        .unwrap_or_else(|| "species".to_string());
+
+    Some(Species {
+        common_name: common_name.map(str::to_string),
+        scientific_name,
+        rank,
+        confidence: item["confidence"].as_f64().map(|value| value.clamp(0.0, 1.0)),
+    })
+}
+
+/// The iNaturalist-style observation fields, each held to its known terms or null.
+fn observation(reply: &Value) -> Observation {
+    let term = |field: &str, allowed: &[&str]| {
+        reply[field].as_str().and_then(|value| vocabulary_term(value, allowed))
+    };
+
+    Observation {
+        iconic_taxon: term("iconic_taxon", nature::ICONIC_TAXA),
+        individual_count: reply["individual_count"].as_u64().filter(|count| *count > 0),
+        life_stage: term("life_stage", nature::LIFE_STAGES),
+        sex: term("sex", nature::SEXES),
+        evidence: term("evidence", nature::EVIDENCE),
+        alive: reply["alive"].as_bool(),
+        captive: reply["captive"].as_bool(),
+    }
+}
+
+/// One vehicle with its color, body type and damage severity held to the known terms. Damage
+/// described without a severity counts as minor; a severity of none drops the description.
+fn described_vehicle(item: &Value) -> DescribedVehicle {
+    let text = |field: &str| {
+        item[field]
+            .as_str()
+            .map(str::trim)
+            .filter(|text| !text.is_empty() && !text.eq_ignore_ascii_case("unknown"))
+            .map(str::to_string)
+    };
+    let term = |field: &str, allowed: &[&str]| {
+        item[field].as_str().and_then(|value| vocabulary_term(value, allowed))
+    };
+    let mut damage = text("damage");
+    let severity = match term("damage_severity", vehicle::DAMAGE_SEVERITIES) {
+        Some(severity) if severity == "none" => {
+            damage = None;
+            severity
+        }
+        Some(severity) => severity,
+        None if damage.is_some() => "minor".to_string(),
+        None => "none".to_string(),
+    };
+
+    DescribedVehicle {
+        make: text("make"),
+        model: text("model"),
+        color: term("color", vehicle::COLORS),
+        body_type: term("body_type", vehicle::BODY_TYPES),
+        damage_severity: severity,
+        damage,
+    }
+}
+
+/// One comic panel with its box turned into fractions of the page and its lettering held to
+/// the known kinds, numbered later. Panels without a usable box are dropped, and so is empty
+/// lettering.
+fn comic_panel(item: &Value) -> Option<ComicPanel> {
+    let position = page_box(&item["box"])?;
+    let dialogue: Vec<Lettering> = item["dialogue"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|line| {
+            let text = line["text"].as_str().map(str::trim).filter(|text| !text.is_empty())?;
+            let kind = line["kind"]
+                .as_str()
+                .map(|kind| match kind.trim().eq_ignore_ascii_case("sfx") {
+                    true => "sound_effect",
+                    false => kind,
+                })
+                .and_then(|kind| vocabulary_term(kind, DIALOGUE_KINDS))
+                .unwrap_or_else(|| "speech".to_string());
+            let voiced = matches!(kind.as_str(), "speech" | "thought");
+            let speaker = line["speaker"]
+                .as_str()
+                .map(str::trim)
+                .filter(|speaker| voiced && !speaker.is_empty());
+
+            Some(Lettering {
+                kind,
+                speaker: speaker.map(str::to_string),
+                text: text.to_string(),
+            })
+        })
+        .collect();
+
+    Some(ComicPanel {
+        order: 0,
+        position,
+        description: item["description"].as_str().unwrap_or_default().trim().to_string(),
+        dialogue,
+    })
+}
+
+/// One layout region with its type held to the known values and its box turned into
+/// fractions of the page. Regions without a usable box are dropped.
+fn layout_region(item: &Value) -> Option<LayoutRegion> {
+    let position = page_box(&item["box"])?;
+    let kind = item["type"]
+        .as_str()
+        .and_then(|kind| vocabulary_term(kind, REGION_TYPES))
+        .unwrap_or_else(|| "other".to_string());
+    let level = item["level"]
+        .as_u64()
+        .filter(|level| kind == "heading" && (1..=6).contains(level));
+    let cells = (kind == "table").then(|| {
+        item["cells"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|row| string_list(row).map(|cell| cell.trim().to_string()).collect())
+            .collect()
+    });
+
+    Some(LayoutRegion {
+        kind,
+        position,
+        text: item["text"].as_str().unwrap_or_default().trim().to_string(),
+        level,
+        cells,
+    })
+}
+
+/// One audit finding with its type and severity held to the known values and the WCAG
+/// criterion filled in. Findings without a description are dropped.
+fn audit_finding(item: &Value) -> Option<AuditFinding> {
+    let detail = item["detail"].as_str().map(str::trim).filter(|detail| !detail.is_empty())?;
+    let term = item["issue"].as_str().map(|issue| issue.trim().to_lowercase().replace([' ', '-'], "_"));
+    let (issue, wcag) = AUDIT_ISSUES
+        .iter()
+        .copied()
+        .find(|(known, _)| term.as_deref() == Some(*known))
+        .unwrap_or(("other", ""));
+    let severity = item["severity"]
+        .as_str()
+        .and_then(|severity| vocabulary_term(severity, SEVERITIES))
+        .unwrap_or_else(|| "medium".to_string());
+
+    Some(AuditFinding {
+        issue: issue.to_string(),
+        severity,
+        element: item["element"].as_str().unwrap_or_default().trim().to_string(),
+        detail: detail.to_string(),
+        wcag: (!wcag.is_empty()).then(|| wcag.to_string()),
+    })
+}
+
+fn string_list(value: &Value) -> impl Iterator<Item = String> + '_ {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.as_str().map(str::to_string))
+}
+
+/// Keeps the first `count` distinct entries, preserving the model's ranking.
+fn ranked_unique(items: impl Iterator<Item = String>, count: usize) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    items
+        .filter(|item| seen.insert(item.to_lowercase()))
+        .take(count)
+        .collect()
+}
+
+fn normalize_hashtag(raw: &str) -> Option<String> {
+    let body: String = raw
+        .trim()
+        .trim_start_matches('#')
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '_')
+        .collect();
+
+    (!body.is_empty()).then(|| format!("#{}", body))
+}
+
+/// The term in `allowed` the model meant, tolerating case and spaces ("Partly cloudy"), or
+/// `None` for anything outside the vocabulary.
+fn vocabulary_term(value: &str, allowed: &[&str]) -> Option<String> {
+    let term = value.trim().to_lowercase().replace([' ', '-'], "_");
+    allowed.contains(&term.as_str()).then_some(term)
+}
+
+fn missing(field: &str) -> CaptionError {
+    CaptionError::InvalidReply(format!("No {} in response", field))
+}
+
+/// Parses a JSON reply, tolerating the Markdown code fences models sometimes add.
+fn parse_json_reply(text: &str) -> Result<Value, CaptionError> {
+    let trimmed = text.trim();
+    let unfenced = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .unwrap_or(trimmed);
+
+    Ok(serde_json::from_str(unfenced.trim())?)
+}
+
+fn clean_alt_text(raw: &str) -> String {
+    let mut text = raw.trim();
+
+    for prefix in ALT_TEXT_PREFIXES {
+        if text.get(..prefix.len()).is_some_and(|head| head.eq_ignore_ascii_case(prefix)) {
+            text = &text[prefix.len()..];
+            break;
+        }
+    }
+
+    let mut alt_text = locale::truncate(text, ALT_TEXT_MAX_CHARS, None);
+    if let Some(first) = alt_text.chars().next() {
+        alt_text.replace_range(..first.len_utf8(), &first.to_uppercase().to_string());
+    }
+    alt_text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alt_text_cleanup_handles_any_script() {
+        assert_eq!(clean_alt_text("image of a red bicycle"), "A red bicycle");
+        // Prefixes end mid-character in these, which must not be sliced through.
+        assert_eq!(clean_alt_text("Красный велосипед у стены"), "Красный велосипед у стены");
+        assert_eq!(clean_alt_text("دراجة حمراء بجانب الجدار"), "دراجة حمراء بجانب الجدار");
+        assert_eq!(clean_alt_text("赤い自転車が壁に立てかけてある"), "赤い自転車が壁に立てかけてある");
+    }
+
+    #[test]
+    fn prompts_have_no_runs_of_spaces() {
+        let options = CaptionOptions {
+            confidence: true,
+            detect_ai: true,
+            scene: true,
+            screen: true,
+            ..CaptionOptions::default()
+        };
+        assert!(!options.prompt().contains("  "), "{}", options.prompt());
+    }
+}
@@ -0,0 +1,46 @@
+// Wildlife and plant identification (the `nature` mode), e.g. for camera-trap batches: the
+// probable species in the photo, each with its common and scientific name and a 0-1
+// confidence, most likely first, plus a caution note. The caution always ends with `CAUTION`,
+// since the model can't tell look-alikes apart reliably and people act on identifications
+// (handling animals, eating plants and fungi).
+//
+// With the `observation` option the result also carries iNaturalist-style observation fields:
+// the iconic taxon, how many individuals are visible, and the life stage, sex, evidence of
+// presence and whether the organism is alive, each held to iNaturalist's annotation terms and
+// null when the photo doesn't show it.
+
+/// Most candidate species returned per photo.
+pub(crate) const MAX_SPECIES: usize = 5;
+
+/// Taxonomic ranks an identification can stop at, finest first.
+pub(crate) const RANKS: &[&str] = &["subspecies", "species", "genus", "family", "order", "class"];
+
+/// iNaturalist's iconic taxa.
+pub(crate) const ICONIC_TAXA: &[&str] = &[
+    "aves", "mammalia", "reptilia", "amphibia", "actinopterygii", "mollusca", "arachnida",
+    "insecta", "animalia", "plantae", "fungi", "chromista", "protozoa",
+];
+
+pub(crate) const LIFE_STAGES: &[&str] =
+    &["adult", "juvenile", "subimago", "teneral", "nymph", "pupa", "larva", "egg"];
+
+pub(crate) const SEXES: &[&str] = &["female", "male"];
+
+pub(crate) const EVIDENCE: &[&str] = &[
+    "organism", "track", "scat", "feather", "hair", "bone", "molt", "egg", "gall", "leafmine",
+    "construction",
+];
+
+/// Appended to every caution note.
+pub(crate) const CAUTION: &str = "Identifications from a photo can be wrong; have an expert \
+    confirm them before relying on them, and never eat or handle anything on their strength.";
+
+/// The caution note for a result: the model's note on look-alikes or doubts, if it gave one,
+/// then `CAUTION`.
+pub(crate) fn caution(note: Option<&str>) -> String {
+    match note.map(str::trim).filter(|note| !note.is_empty()) {
+        Some(note) if note.ends_with(['.', '!', '?']) => format!("{} {}", note, CAUTION),
+        Some(note) => format!("{}. {}", note, CAUTION),
+        None => CAUTION.to_string(),
+    }
+}
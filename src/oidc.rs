@@ -0,0 +1,190 @@
+// JWT authentication against an external identity provider, so the server can sit behind
+// corporate SSO. With OIDC_ISSUER set, a bearer token that is a JWT is accepted when it is
+// signed by one of the issuer's keys, names the issuer, hasn't expired and (with
+// OIDC_AUDIENCE set) is meant for this service. The user it names, the `sub` claim or the one
+// in OIDC_IDENTITY_CLAIM (e.g. `email`), is the caller for logging, scheduling and quotas, as
+// tenant `oidc:<user>`: API key names can't have a `:`, so no user can take over a key's data.
+//
+// The signing keys come from OIDC_JWKS_URL, or from the `jwks_uri` in the issuer's discovery
+// document. They are kept for an hour and fetched again sooner when a token names a key that
+// isn't among them, so the provider can rotate keys. Only asymmetric algorithms (RS*, PS*,
+// ES*, EdDSA) are accepted. The settings are read per request, so a SIGHUP reload applies them.
+
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::CaptionError;
+
+/// How long fetched keys are used before being fetched again.
+const KEYS_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// How soon keys may be fetched again because a token names an unknown key.
+const KEYS_MIN_AGE: Duration = Duration::from_secs(60);
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+static KEYS: LazyLock<Mutex<Option<FetchedKeys>>> = LazyLock::new(Mutex::default);
+
+#[derive(Clone)]
+struct FetchedKeys {
+    issuer: String,
+    keys: JwkSet,
+    fetched: Instant,
+}
+
+/// The configured issuer, without a trailing slash.
+pub(crate) fn issuer() -> Option<String> {
+    crate::settings::var("OIDC_ISSUER")
+        .ok()
+        .map(|issuer| issuer.trim().trim_end_matches('/').to_string())
+        .filter(|issuer| !issuer.is_empty())
+}
+
+fn setting(name: &str) -> Option<String> {
+    crate::settings::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Whether `token` is shaped like a JWT (three dot-separated parts) rather than an API key.
+pub(crate) fn looks_like_jwt(token: &str) -> bool {
+    token.split('.').count() == 3 && token.starts_with("eyJ")
+}
+
+fn unreachable(e: impl std::fmt::Display) -> CaptionError {
+    CaptionError::Provider {
+        status: None,
+        message: format!("Can't fetch the identity provider's signing keys: {}", e),
+    }
+}
+
+fn invalid(reason: impl std::fmt::Display) -> CaptionError {
+    CaptionError::Unauthorized(format!("Invalid token: {}", reason))
+}
+
+fn rejected(e: jsonwebtoken::errors::Error) -> CaptionError {
+    use jsonwebtoken::errors::ErrorKind;
+    match e.kind() {
+        ErrorKind::ExpiredSignature => invalid("it has expired"),
+        ErrorKind::ImmatureSignature => invalid("it isn't valid yet"),
+        ErrorKind::InvalidIssuer => invalid("it is from another issuer"),
+        ErrorKind::InvalidAudience => invalid("it is meant for another service"),
+        ErrorKind::InvalidSignature => invalid("the signature doesn't match"),
+        ErrorKind::MissingRequiredClaim(claim) => invalid(format!("no `{}` claim", claim)),
+        _ => invalid(e),
+    }
+}
+
+async fn fetch_keys(issuer: &str) -> Result<JwkSet, CaptionError> {
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(unreachable)?;
+    let url = match setting("OIDC_JWKS_URL") {
+        Some(url) => url,
+        None => {
+            let discovery = format!("{}/.well-known/openid-configuration", issuer);
+            let document: Value = client
+                .get(&discovery)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(unreachable)?
+                .json()
+                .await
+                .map_err(unreachable)?;
+            document["jwks_uri"]
+                .as_str()
+                .ok_or_else(|| unreachable(format!("no jwks_uri in {}", discovery)))?
+                .to_string()
+        }
+    };
+    client
+        .get(&url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(unreachable)?
+        .json()
+        .await
+        .map_err(unreachable)
+}
+
+/// The issuer's signing keys, fetched again when they are old or, if `refresh` is set and
+/// they are not brand new, because a token names a key they don't have.
+async fn signing_keys(issuer: &str, refresh: bool) -> Result<JwkSet, CaptionError> {
+    let cached = KEYS.lock().expect("OIDC key cache lock poisoned").clone();
+    if let Some(cached) = cached.filter(|cached| cached.issuer == issuer) {
+        let age = cached.fetched.elapsed();
+        if age < KEYS_MAX_AGE && !(refresh && age >= KEYS_MIN_AGE) {
+            return Ok(cached.keys);
+        }
+    }
+    let keys = fetch_keys(issuer).await?;
+    *KEYS.lock().expect("OIDC key cache lock poisoned") = Some(FetchedKeys {
+        issuer: issuer.to_string(),
+        keys: keys.clone(),
+        fetched: Instant::now(),
+    });
+    Ok(keys)
+}
+
+/// The tenant of the user `token` was issued to, once its signature and claims check out.
+pub(crate) async fn identity(issuer: &str, token: &str) -> Result<String, CaptionError> {
+    let header = jsonwebtoken::decode_header(token).map_err(invalid)?;
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Err(invalid("symmetric signatures aren't accepted"));
+    }
+
+    let find = |keys: &JwkSet| match &header.kid {
+        Some(kid) => keys.find(kid).cloned(),
+        None if keys.keys.len() == 1 => keys.keys.first().cloned(),
+        None => None,
+    };
+    let jwk = match find(&signing_keys(issuer, false).await?) {
+        Some(jwk) => jwk,
+        None => find(&signing_keys(issuer, true).await?)
+            .ok_or_else(|| invalid("signed with an unknown key"))?,
+    };
+    let key = DecodingKey::from_jwk(&jwk).map_err(invalid)?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[issuer, &format!("{}/", issuer)]);
+    validation.set_required_spec_claims(&["exp", "iss"]);
+    match setting("OIDC_AUDIENCE") {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => validation.validate_aud = false,
+    }
+    let claims = jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation)
+        .map_err(rejected)?
+        .claims;
+
+    let claim = setting("OIDC_IDENTITY_CLAIM").unwrap_or_else(|| "sub".to_string());
+    claims
+        .get(&claim)
+        .and_then(Value::as_str)
+        .filter(|user| !user.is_empty())
+        .map(tenant)
+        .ok_or_else(|| invalid(format!("no `{}` claim", claim)))
+}
+
+/// The tenant name for SSO user `user`, apart from every API key's.
+fn tenant(user: &str) -> String {
+    format!("oidc:{}", user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_are_not_tenants_of_keys_with_their_name() {
+        // Key names, in API_KEYS (`name:key`) or from the admin API, never have a `:`.
+        assert_eq!(tenant("design-team"), "oidc:design-team");
+        assert_ne!(tenant("design-team"), "design-team");
+        assert_ne!(tenant("oidc:design-team"), tenant("design-team"));
+    }
+}
@@ -0,0 +1,159 @@
+// EXIF orientation. Cameras and phones store a photo as the sensor read it and say in an EXIF
+// tag which way up it goes; a portrait phone photo is usually a landscape image tagged "turn
+// 90° clockwise". Viewers honour the tag, but re-encoding drops it, so every decoded image is
+// turned upright here before it is downscaled, encoded or thumbnailed, and an upload whose tag
+// says to turn it isn't sent to the provider as it is (see `imageproc::passthrough`).
+//
+// The tag is found by walking the file's segments (JPEG markers, PNG and WebP chunks) up to the
+// EXIF block, which is borrowed from the upload rather than copied, and read once per caption.
+
+use image::DynamicImage;
+
+use crate::buffers;
+use crate::tiff::Tiff;
+
+pub(crate) const TAG_ORIENTATION: u16 = 0x112;
+
+/// What some writers put before the TIFF header of an EXIF block.
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+
+/// The EXIF block of a JPEG, PNG or WebP file, or a TIFF file's own header, which is one.
+pub(crate) fn exif(data: &[u8]) -> Option<&[u8]> {
+    let block = if Tiff::parse(data).is_some() {
+        return Some(data);
+    } else if data.starts_with(&[0xff, 0xd8]) {
+        jpeg_exif(data)?
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_exif(data)?
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        webp_exif(data)?
+    } else {
+        return None;
+    };
+    Some(block.strip_prefix(EXIF_PREFIX).unwrap_or(block))
+}
+
+/// The first APP1 segment holding EXIF, among the markers before the image data.
+fn jpeg_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut at = 2;
+    loop {
+        let marker = *data.get(at..at + 2)?.get(1)?;
+        // Start of scan and end of image: no metadata after them.
+        if data[at] != 0xff || marker == 0xda || marker == 0xd9 {
+            return None;
+        }
+        let length = u16::from_be_bytes(data.get(at + 2..at + 4)?.try_into().ok()?) as usize;
+        let segment = data.get(at + 4..at + 2 + length.max(2))?;
+        if marker == 0xe1 && segment.starts_with(EXIF_PREFIX) {
+            return Some(segment);
+        }
+        at += 2 + length.max(2);
+    }
+}
+
+/// The `eXIf` chunk.
+fn png_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut at = 8;
+    loop {
+        let length = u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?) as usize;
+        let kind = data.get(at + 4..at + 8)?;
+        if kind == b"IEND" {
+            return None;
+        }
+        let chunk = data.get(at + 8..(at + 8).checked_add(length)?)?;
+        if kind == b"eXIf" {
+            return Some(chunk);
+        }
+        // Past the chunk and its CRC.
+        at += 12 + length;
+    }
+}
+
+/// The `EXIF` chunk.
+fn webp_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut at = 12;
+    loop {
+        let kind = data.get(at..at + 4)?;
+        let length = u32::from_le_bytes(data.get(at + 4..at + 8)?.try_into().ok()?) as usize;
+        let chunk = data.get(at + 8..(at + 8).checked_add(length)?)?;
+        if kind == b"EXIF" {
+            return Some(chunk);
+        }
+        // Chunks are padded to an even length.
+        at += 8 + length + length % 2;
+    }
+}
+
+/// The orientation tag of the image in `data`: 1 (upright) when it has none.
+pub(crate) fn read(data: &[u8]) -> u32 {
+    let from_exif = |block: &[u8]| {
+        let (tiff, first_ifd) = Tiff::parse(block)?;
+        let (ifd0, _) = tiff.ifd(first_ifd)?;
+        tiff.first(&ifd0, TAG_ORIENTATION)
+    };
+    exif(data).and_then(from_exif).filter(|o| (1..=8).contains(o)).unwrap_or(1)
+}
+
+/// `image` turned upright from `orientation`, giving its old pixels back to the buffer pool.
+pub(crate) fn apply(image: DynamicImage, orientation: u32) -> DynamicImage {
+    let upright = match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => return image,
+    };
+    buffers::POOL.recycle(image);
+    upright
+}
+
+/// Whether `orientation` turns the image on its side, swapping its width and height.
+pub(crate) fn swaps_sides(orientation: u32) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgb, RgbImage};
+    use img_parts::{jpeg::Jpeg, png::Png, webp::WebP, ImageEXIF};
+
+    /// An EXIF block with only an orientation tag of `orientation`.
+    fn exif_block(orientation: u8) -> Vec<u8> {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend(8u32.to_le_bytes());
+        exif.extend(1u16.to_le_bytes());
+        exif.extend([0x12, 0x01, 3, 0, 1, 0, 0, 0, orientation, 0, 0, 0]);
+        exif.extend(0u32.to_le_bytes());
+        exif
+    }
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        let image = RgbImage::from_pixel(8, 8, Rgb([40, 80, 120]));
+        image.write_to(&mut std::io::Cursor::new(&mut data), format).unwrap();
+        data
+    }
+
+    #[test]
+    fn orientation_is_read_from_every_container() {
+        let mut jpeg = Jpeg::from_bytes(encode(ImageOutputFormat::Jpeg(90)).into()).unwrap();
+        jpeg.set_exif(Some(exif_block(6).into()));
+        assert_eq!(read(&jpeg.encoder().bytes()), 6);
+
+        let mut png = Png::from_bytes(encode(ImageOutputFormat::Png).into()).unwrap();
+        png.set_exif(Some(exif_block(3).into()));
+        assert_eq!(read(&png.encoder().bytes()), 3);
+
+        let mut webp = WebP::from_bytes(encode(ImageOutputFormat::WebP).into()).unwrap();
+        webp.set_exif(Some(exif_block(8).into()));
+        assert_eq!(read(&webp.encoder().bytes()), 8);
+
+        assert_eq!(read(&exif_block(5)), 5);
+        assert_eq!(read(&encode(ImageOutputFormat::Png)), 1);
+        assert_eq!(read(&[0xff, 0xd8, 0xff, 0xe1, 0xff]), 1);
+    }
+}
@@ -0,0 +1,144 @@
+// Provider request bodies with the images streamed into them. Gemini takes images inline, as
+// base64 inside the JSON, so a request built the plain way holds each image three times: as
+// bytes, as base64 text and inside the serialized body. Here the JSON is serialized with a
+// marker where each image goes, and the body is sent as the JSON around the markers with the
+// images base64-encoded a chunk at a time in between, so only the bytes stay in memory.
+//
+// A body can be sent any number of times (for retries); each send encodes the images again.
+
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+
+/// Bytes of image encoded per chunk of the body; a multiple of 3, so the chunks' base64 joins
+/// up without padding in between.
+const CHUNK_BYTES: usize = 48 * 1024;
+
+/// Images to send inline, each standing in the JSON as a marker until the body is made.
+pub(crate) struct InlineImages {
+    /// Random, so no text in the prompt can pass for a marker.
+    marker: String,
+    images: Vec<Bytes>,
+}
+
+impl InlineImages {
+    pub(crate) fn new() -> Self {
+        InlineImages {
+            marker: format!("\u{1}image-{:016x}-", rand::random::<u64>()),
+            images: Vec::new(),
+        }
+    }
+
+    /// The `inline_data` part for an image of `mime_type`.
+    pub(crate) fn part(&mut self, mime_type: &str, data: Bytes) -> serde_json::Value {
+        let placeholder = self.placeholder(self.images.len());
+        self.images.push(data);
+        serde_json::json!({
+            "inline_data": {
+                "mime_type": mime_type,
+                "data": placeholder
+            }
+        })
+    }
+
+    fn placeholder(&self, index: usize) -> String {
+        format!("{}{}\u{1}", self.marker, index)
+    }
+
+    /// `payload` as a body to send, with each image's base64 where its marker is.
+    pub(crate) fn body(self, payload: &serde_json::Value) -> Body {
+        let mut json = serde_json::to_string(payload).expect("JSON values always serialize");
+        let mut segments = Vec::with_capacity(self.images.len() + 1);
+        for index in 0..self.images.len() {
+            // The placeholder as it comes out of serialization, without its quotes.
+            let placeholder = serde_json::to_string(&self.placeholder(index))
+                .expect("strings always serialize");
+            let placeholder = &placeholder[1..placeholder.len() - 1];
+            let start = json.find(placeholder).expect("every image has a part in the payload");
+            let rest = json.split_off(start + placeholder.len());
+            json.truncate(start);
+            segments.push(Bytes::from(std::mem::replace(&mut json, rest)));
+        }
+        segments.push(Bytes::from(json));
+        Body {
+            segments,
+            images: self.images,
+        }
+    }
+}
+
+/// A request body: JSON segments with an image between each pair.
+pub(crate) struct Body {
+    segments: Vec<Bytes>,
+    images: Vec<Bytes>,
+}
+
+impl Body {
+    /// The body's length in bytes, images in base64.
+    pub(crate) fn content_length(&self) -> u64 {
+        let json: usize = self.segments.iter().map(Bytes::len).sum();
+        let base64: usize = self.images.iter().map(|image| image.len().div_ceil(3) * 4).sum();
+        (json + base64) as u64
+    }
+
+    /// The body as a stream for reqwest, encoding the images as it is read.
+    pub(crate) fn stream(&self) -> reqwest::Body {
+        reqwest::Body::wrap_stream(futures::stream::iter(self.chunks()))
+    }
+
+    fn chunks(&self) -> impl Iterator<Item = Result<Bytes, std::io::Error>> + Send + 'static {
+        let mut chunks = Vec::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            chunks.push(Chunk::Json(segment.clone()));
+            if let Some(image) = self.images.get(index) {
+                let mut offset = 0;
+                while offset < image.len() {
+                    let end = (offset + CHUNK_BYTES).min(image.len());
+                    chunks.push(Chunk::Image(image.slice(offset..end)));
+                    offset = end;
+                }
+            }
+        }
+        chunks.into_iter().map(|chunk| {
+            Ok(match chunk {
+                Chunk::Json(json) => json,
+                Chunk::Image(bytes) => Bytes::from(general_purpose::STANDARD.encode(bytes)),
+            })
+        })
+    }
+}
+
+/// A piece of the body, before encoding.
+enum Chunk {
+    Json(Bytes),
+    Image(Bytes),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_is_the_json_with_the_images_in_base64() {
+        // Big enough to take several chunks, and not a multiple of 3.
+        let photo: Vec<u8> = (0..CHUNK_BYTES * 2 + 1000).map(|i| (i * 7 % 251) as u8).collect();
+        let icon = vec![1, 2];
+        let mut images = InlineImages::new();
+        let parts = serde_json::json!([
+            { "text": "Describe these" },
+            images.part("image/jpeg", Bytes::from(photo.clone())),
+            images.part("image/png", Bytes::from(icon.clone())),
+        ]);
+        let body = images.body(&serde_json::json!({ "contents": [{ "parts": parts }] }));
+
+        let sent: Vec<u8> = body.chunks().flat_map(|chunk| chunk.unwrap().to_vec()).collect();
+        assert_eq!(sent.len() as u64, body.content_length());
+        let sent: serde_json::Value = serde_json::from_slice(&sent).unwrap();
+        let parts = &sent["contents"][0]["parts"];
+        assert_eq!(parts[0]["text"], "Describe these");
+        for (part, image) in [(&parts[1], &photo), (&parts[2], &icon)] {
+            let data = part["inline_data"]["data"].as_str().unwrap();
+            assert_eq!(&general_purpose::STANDARD.decode(data).unwrap(), image);
+        }
+        assert_eq!(parts[2]["inline_data"]["mime_type"], "image/png");
+    }
+}
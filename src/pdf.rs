@@ -0,0 +1,98 @@
+// PDF ingestion: rasterize each page with poppler's pdftoppm so pages can be captioned
+// like images (scanned documents, slide decks).
+//
+// pdftoppm must be on PATH (or PDFTOPPM must point at it).
+
+use crate::scratch::ScratchDir;
+use crate::spool::Spooled;
+
+/// Upper bound on pages rendered per document, which also bounds provider calls.
+pub const MAX_PAGES: usize = 50;
+
+/// Enough resolution for slide text to stay legible without huge uploads.
+const RENDER_DPI: u32 = 110;
+
+#[derive(Debug)]
+pub enum PdfError {
+    /// pdftoppm isn't installed or couldn't be started.
+    RendererMissing(std::io::Error),
+    /// The upload isn't a PDF, or pdftoppm couldn't render it.
+    Render(String),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for PdfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PdfError::RendererMissing(e) => write!(f, "pdftoppm is not available: {}", e),
+            PdfError::Render(message) => write!(f, "Could not render PDF: {}", message),
+            PdfError::Io(e) => write!(f, "I/O error while rendering pages: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PdfError {}
+
+impl From<std::io::Error> for PdfError {
+    fn from(e: std::io::Error) -> Self {
+        PdfError::Io(e)
+    }
+}
+
+pub struct Page {
+    /// 1-based page number.
+    pub number: usize,
+    pub jpeg: Vec<u8>,
+}
+
+pub fn is_pdf(data: &[u8]) -> bool {
+    data.starts_with(b"%PDF-")
+}
+
+/// Renders the first `max_pages` pages of an uploaded PDF (checked with `is_pdf`) as JPEG, in
+/// page order.
+pub(crate) async fn render_pages(pdf: &Spooled, max_pages: usize) -> Result<Vec<Page>, PdfError> {
+    let scratch = ScratchDir::new()?;
+    let input = scratch.path().join("input.pdf");
+    pdf.write_to(&input).await?;
+
+    let program = crate::settings::var("PDFTOPPM").unwrap_or_else(|_| "pdftoppm".into());
+    let output = tokio::process::Command::new(program)
+        .kill_on_drop(true)
+        .args(["-jpeg", "-r", &RENDER_DPI.to_string()])
+        .args(["-f", "1", "-l", &max_pages.to_string()])
+        .arg(&input)
+        .arg(scratch.path().join("page"))
+        .output()
+        .await
+        .map_err(PdfError::RendererMissing)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(PdfError::Render(stderr.trim().to_string()));
+    }
+
+    // pdftoppm zero-pads page numbers to the width of the page count (page-1.jpg,
+    // page-01.jpg, ...), so parse the numbers back out rather than guessing names.
+    let mut pages = Vec::new();
+    for entry in std::fs::read_dir(scratch.path())? {
+        let path = entry?.path();
+        let number = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix("page-"))
+            .and_then(|number| number.parse().ok());
+        if let Some(number) = number {
+            pages.push(Page {
+                number,
+                jpeg: tokio::fs::read(&path).await?,
+            });
+        }
+    }
+    pages.sort_by_key(|page| page.number);
+
+    if pages.is_empty() {
+        return Err(PdfError::Render("the document has no pages".into()));
+    }
+    Ok(pages)
+}
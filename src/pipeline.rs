@@ -0,0 +1,429 @@
+// Captioning as a pipeline of stages: audit (the attempt and how it ended), validate (may the
+// caller have this caption?), preprocess (the capture context and the image as the provider
+// gets it), provider (the model call), postprocess (the content policy, then translation),
+// persist (the cache, usage and history) and respond (the result). Each stage is middleware
+// around the ones after it: it does its part and calls `next`, can answer without calling it,
+// and sees what the later stages made once it returns. That is how a cache hit skips the
+// provider, and how the persisting stages, which come before the provider so a hit is recorded
+// too, get the result built after them.
+//
+// `server()` and `library()` put together the stages each needs; a new step is a new `Stage`
+// in the right place in them.
+
+use axum::async_trait;
+use std::time::Instant;
+use tracing::Instrument;
+
+use crate::cache::{self, CaptionCache};
+use crate::imageproc::{content_hash, prepare_image, PreparedImage};
+use crate::modes::ModeOutput;
+use crate::providers::{generate_caption, Usage};
+use crate::scheduler::{Caller, Scheduler};
+use crate::server::AppState;
+use crate::session::ProviderSession;
+use crate::translate::{self, Translation};
+use crate::{
+    audit, capture, elapsed_ms, history, orientation, policy, usage, vehicle, CaptionError,
+    CaptionOptions,
+    CaptionResponse, CaptureContext, Dimensions, Timings,
+};
+
+/// One image on its way through the pipeline, gathering what each stage makes.
+pub(crate) struct Caption<'a> {
+    pub(crate) image: &'a [u8],
+    pub(crate) options: CaptionOptions,
+    /// The Gemini API key to call with.
+    pub(crate) api_key: String,
+    /// The album the image is part of, whose images share the provider context.
+    pub(crate) session: Option<&'a ProviderSession>,
+    pub(crate) start: Instant,
+    pub(crate) timings: Timings,
+    pub(crate) context: Option<CaptureContext>,
+    /// The image's EXIF orientation, read once for everything that decodes it.
+    pub(crate) orientation: u32,
+    /// Until the provider takes it.
+    pub(crate) prepared: Option<PreparedImage>,
+    /// The image's size, and its size as the provider gets it.
+    pub(crate) dimensions: Option<Dimensions>,
+    /// From the provider until the response is made of it.
+    pub(crate) output: Option<ModeOutput>,
+    /// Tokens spent on this caption so far.
+    pub(crate) usage: Usage,
+    pub(crate) translation: Option<Translation>,
+    /// Instructions the content policy had added to the prompt, for the audit log.
+    pub(crate) policy_prompt: Option<String>,
+    pub(crate) response: Option<CaptionResponse>,
+}
+
+impl<'a> Caption<'a> {
+    pub(crate) fn new(
+        image: &'a [u8],
+        options: CaptionOptions,
+        api_key: String,
+        start: Instant,
+        timings: Timings,
+    ) -> Self {
+        Caption {
+            image,
+            options,
+            api_key,
+            session: None,
+            start,
+            timings,
+            context: None,
+            orientation: 1,
+            prepared: None,
+            dimensions: None,
+            output: None,
+            usage: Usage::default(),
+            translation: None,
+            policy_prompt: None,
+            response: None,
+        }
+    }
+}
+
+#[async_trait]
+pub(crate) trait Stage: Send + Sync {
+    /// Does this stage's part of `caption`, calling `next` for the stages after it unless
+    /// this one answers by itself.
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError>;
+}
+
+/// The stages after the running one.
+pub(crate) struct Next<'s> {
+    stages: &'s [Box<dyn Stage + 's>],
+}
+
+impl Next<'_> {
+    pub(crate) async fn run(self, caption: &mut Caption<'_>) -> Result<(), CaptionError> {
+        match self.stages.split_first() {
+            Some((stage, stages)) => stage.run(caption, Next { stages }).await,
+            None => Ok(()),
+        }
+    }
+}
+
+pub(crate) struct Pipeline<'s> {
+    stages: Vec<Box<dyn Stage + 's>>,
+}
+
+impl Pipeline<'_> {
+    /// Runs `caption` through the stages; returns the result and the tokens it took.
+    pub(crate) async fn run(
+        &self,
+        mut caption: Caption<'_>,
+    ) -> Result<(CaptionResponse, Usage), CaptionError> {
+        Next {
+            stages: &self.stages,
+        }
+        .run(&mut caption)
+        .await?;
+        let response = caption
+            .response
+            .ok_or_else(|| CaptionError::Internal("The caption pipeline made no result".into()))?;
+        Ok((response, caption.usage))
+    }
+}
+
+/// The server's pipeline for `caller`: with the audit log, its quota, brand voice, content
+/// policy, the cache, the fair-share scheduler, usage and history.
+pub(crate) fn server<'s>(state: &'s AppState, caller: &'s Caller) -> Pipeline<'s> {
+    Pipeline {
+        stages: vec![
+            Box::new(Audit {
+                audit: state.audit.as_ref(),
+                caller,
+            }),
+            Box::new(Validate { state, caller }),
+            Box::new(Preprocess),
+            Box::new(Record { state, caller }),
+            Box::new(Cache {
+                cache: state.cache.as_ref(),
+                tenant: caller.tenant(),
+            }),
+            Box::new(Schedule {
+                scheduler: &state.scheduler,
+                caller,
+            }),
+            Box::new(Provider { log_errors: true }),
+            Box::new(Screen { caller }),
+            Box::new(Translate {
+                provider: state.translators.for_tenant(caller.tenant()),
+                log_errors: true,
+            }),
+            Box::new(Respond),
+        ],
+    }
+}
+
+/// The library's pipeline for `options`: the image straight to the provider, translated with
+/// the default provider when a language is asked for.
+pub(crate) fn library(options: &CaptionOptions) -> Pipeline<'static> {
+    let mut stages: Vec<Box<dyn Stage>> = vec![
+        Box::new(Preprocess),
+        Box::new(Provider { log_errors: false }),
+    ];
+    if options.language.is_some() {
+        stages.push(Box::new(Translate {
+            provider: translate::default_provider(),
+            log_errors: false,
+        }));
+    }
+    stages.push(Box::new(Respond));
+    Pipeline { stages }
+}
+
+/// Audit: adds the caption to the audit log once the later stages are done with it, whether
+/// they made it or not.
+struct Audit<'s> {
+    audit: Option<&'s audit::AuditLog>,
+    caller: &'s Caller,
+}
+
+#[async_trait]
+impl Stage for Audit<'_> {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        let Some(audit) = self.audit else {
+            return next.run(caption).await;
+        };
+        let result = next.run(caption).await;
+        audit::record(audit, self.caller, caption, result.as_ref().err()).await;
+        result
+    }
+}
+
+/// Validate: turns away modes and callers that can't have the caption, and applies the
+/// caller's brand voice and the content policy to the options.
+struct Validate<'s> {
+    state: &'s AppState,
+    caller: &'s Caller,
+}
+
+#[async_trait]
+impl Stage for Validate<'_> {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        vehicle::check_enabled(caption.options.mode)?;
+        if let Some(policy) = self.caller.policy() {
+            policy.check_mode(caption.options.mode)?;
+        }
+        usage::check_quota(self.caller).await?;
+        caption.options.voice = self.state.voices.get(self.caller.tenant());
+        caption.options.screen = policy::active();
+        next.run(caption).await
+    }
+}
+
+/// Preprocess: reads the capture context and orientation and prepares the image for the
+/// provider.
+struct Preprocess;
+
+#[async_trait]
+impl Stage for Preprocess {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        caption.context = capture::read(caption.image, &caption.options);
+        caption.orientation = orientation::read(caption.image);
+        let (image, turn) = (caption.image, caption.orientation);
+        let prepared = prepare_image(image, turn, &caption.options, &mut caption.timings)?;
+        caption.dimensions = prepared.dimensions();
+        caption.prepared = Some(prepared);
+        next.run(caption).await
+    }
+}
+
+/// Persist: counts the result in the caller's usage and adds it to the history, whether it
+/// came from the provider or the cache.
+struct Record<'s> {
+    state: &'s AppState,
+    caller: &'s Caller,
+}
+
+#[async_trait]
+impl Stage for Record<'_> {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        next.run(caption).await?;
+        if let Some(response) = &caption.response {
+            usage::record(self.caller, 1, &caption.usage).await;
+            let (history, turn) = (self.state.history.as_ref(), caption.orientation);
+            history::record(history, self.caller, caption.image, turn, response).await;
+        }
+        Ok(())
+    }
+}
+
+/// Persist: answers from the caption cache when it has the image, and keeps the result
+/// otherwise.
+struct Cache<'s> {
+    cache: Option<&'s CaptionCache>,
+    tenant: &'s str,
+}
+
+#[async_trait]
+impl Stage for Cache<'_> {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        if self.cache.is_none() {
+            return next.run(caption).await;
+        }
+        let prepared = caption.prepared.as_ref().expect("the image is prepared before the cache");
+        let key = cache::key(caption.image, prepared, &caption.options, caption.context.as_ref());
+        if let Some(mut response) = cache::find(self.cache, self.tenant, &key).await {
+            if let Some(provenance) = &mut response.provenance {
+                provenance.content_hash = content_hash(caption.image);
+            }
+            response.processing_time_ms = elapsed_ms(caption.start);
+            response.timings = Some(caption.timings);
+            if caption.options.verbose {
+                response.report_usage(&Usage::default());
+            }
+            caption.response = Some(response);
+            return Ok(());
+        }
+
+        next.run(caption).await?;
+        if let Some(response) = &caption.response {
+            cache::store(self.cache, self.tenant, &key, response).await;
+        }
+        Ok(())
+    }
+}
+
+/// Waits for a provider slot from the fair-share scheduler, held until the result is made.
+struct Schedule<'s> {
+    scheduler: &'s Scheduler,
+    caller: &'s Caller,
+}
+
+#[async_trait]
+impl Stage for Schedule<'_> {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        let queue = Instant::now();
+        let queued = self.scheduler.acquire(self.caller);
+        let _slot = queued.instrument(tracing::info_span!("queue")).await?;
+        caption.timings.queue_ms = elapsed_ms(queue);
+        next.run(caption).await
+    }
+}
+
+/// Provider: asks the model for the caption.
+struct Provider {
+    log_errors: bool,
+}
+
+#[async_trait]
+impl Stage for Provider {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        let prepared = caption.prepared.take().expect("the image is prepared before the provider");
+        let (output, usage) = generate_caption(
+            prepared,
+            &caption.options,
+            caption.context.as_ref(),
+            caption.session,
+            &caption.api_key,
+            &mut caption.timings,
+        )
+        .await
+        .inspect_err(|e| {
+            if self.log_errors {
+                tracing::warn!(error = %e, "Caption failed");
+            }
+        })?;
+        caption.output = Some(output);
+        caption.usage += usage;
+        next.run(caption).await
+    }
+}
+
+/// Postprocess: applies the content policy to the reply, refusing it or asking again with the
+/// policy's prompt when a rule says so.
+struct Screen<'s> {
+    caller: &'s Caller,
+}
+
+#[async_trait]
+impl Stage for Screen<'_> {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        let output = caption.output.as_mut().expect("the provider replies before the policy");
+        let decision = policy::decide(output);
+        decision.log(output, self.caller.tenant(), &content_hash(caption.image));
+        match decision.action {
+            Some(policy::Action::Refuse) => {
+                usage::record(self.caller, 0, &caption.usage).await;
+                return Err(decision.refusal());
+            }
+            Some(policy::Action::Prompt) => {
+                let routed = CaptionOptions {
+                    instructions: decision.prompt.clone(),
+                    ..caption.options.clone()
+                };
+                let (image, turn) = (caption.image, caption.orientation);
+                let prepared = prepare_image(image, turn, &routed, &mut caption.timings)?;
+                let (routed_output, usage) = generate_caption(
+                    prepared,
+                    &routed,
+                    caption.context.as_ref(),
+                    caption.session,
+                    &caption.api_key,
+                    &mut caption.timings,
+                )
+                .await
+                .inspect_err(|e| tracing::warn!(error = %e, "Caption failed"))?;
+                // The first reply's flags are the ones the decision was made on.
+                let flagged = output.details.remove("sensitive_content");
+                *output = routed_output;
+                output.details.extend(flagged.map(|flagged| ("sensitive_content".into(), flagged)));
+                caption.usage += usage;
+                caption.policy_prompt = routed.instructions;
+            }
+            _ => {}
+        }
+        decision.annotate(output);
+        next.run(caption).await
+    }
+}
+
+/// Postprocess: translates the reply when the options ask for a language.
+struct Translate {
+    provider: translate::Provider,
+    log_errors: bool,
+}
+
+#[async_trait]
+impl Stage for Translate {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        let output = caption.output.as_mut().expect("the provider replies before translation");
+        caption.translation =
+            translate::apply(output, &caption.options, self.provider, &caption.api_key)
+                .await
+                .inspect_err(|e| {
+                    if self.log_errors {
+                        tracing::warn!(error = %e, "Translation failed");
+                    }
+                })?;
+        next.run(caption).await
+    }
+}
+
+/// Respond: makes the result of the reply.
+struct Respond;
+
+#[async_trait]
+impl Stage for Respond {
+    async fn run(&self, caption: &mut Caption<'_>, next: Next<'_>) -> Result<(), CaptionError> {
+        let output = caption.output.take().expect("the provider replies before the response");
+        let mut response = CaptionResponse::new(
+            output,
+            caption.image,
+            &caption.options,
+            caption.start,
+            caption.timings,
+        );
+        response.translation = caption.translation.take();
+        response.dimensions = caption.dimensions;
+        response.capture_context = caption.context.take();
+        if caption.options.verbose {
+            response.report_usage(&caption.usage);
+        }
+        caption.response = Some(response);
+        next.run(caption).await
+    }
+}
@@ -0,0 +1,221 @@
+// Offline reverse geocoding for capture context: the nearest of a few hundred cities
+// worldwide. Coarse on purpose; it only has to turn GPS coordinates into "Lisbon, Portugal"
+// or "near Kyoto, Japan", and it keeps photo locations from being sent to a geocoding service.
+
+/// Within this distance a photo counts as taken in the city, beyond it as near it.
+const IN_CITY_KM: f64 = 25.0;
+
+/// Beyond this, no city is named at all.
+const NEAR_CITY_KM: f64 = 100.0;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// (city, country, latitude, longitude)
+const CITIES: &[(&str, &str, f64, f64)] = &[
+    // Europe
+    ("Amsterdam", "Netherlands", 52.370, 4.895),
+    ("Athens", "Greece", 37.984, 23.728),
+    ("Barcelona", "Spain", 41.385, 2.173),
+    ("Belgrade", "Serbia", 44.787, 20.457),
+    ("Berlin", "Germany", 52.520, 13.405),
+    ("Bern", "Switzerland", 46.948, 7.447),
+    ("Bordeaux", "France", 44.838, -0.579),
+    ("Bratislava", "Slovakia", 48.149, 17.107),
+    ("Brussels", "Belgium", 50.850, 4.352),
+    ("Bucharest", "Romania", 44.427, 26.103),
+    ("Budapest", "Hungary", 47.498, 19.040),
+    ("Copenhagen", "Denmark", 55.676, 12.568),
+    ("Dublin", "Ireland", 53.350, -6.260),
+    ("Dubrovnik", "Croatia", 42.650, 18.094),
+    ("Edinburgh", "United Kingdom", 55.953, -3.188),
+    ("Florence", "Italy", 43.770, 11.255),
+    ("Frankfurt", "Germany", 50.110, 8.682),
+    ("Geneva", "Switzerland", 46.204, 6.143),
+    ("Hamburg", "Germany", 53.551, 9.994),
+    ("Helsinki", "Finland", 60.170, 24.938),
+    ("Istanbul", "Turkey", 41.008, 28.978),
+    ("Kraków", "Poland", 50.065, 19.945),
+    ("Kyiv", "Ukraine", 50.450, 30.523),
+    ("Lisbon", "Portugal", 38.722, -9.139),
+    ("Ljubljana", "Slovenia", 46.057, 14.506),
+    ("London", "United Kingdom", 51.507, -0.128),
+    ("Lyon", "France", 45.764, 4.836),
+    ("Madrid", "Spain", 40.417, -3.704),
+    ("Manchester", "United Kingdom", 53.481, -2.243),
+    ("Marseille", "France", 43.297, 5.370),
+    ("Milan", "Italy", 45.464, 9.190),
+    ("Munich", "Germany", 48.135, 11.582),
+    ("Naples", "Italy", 40.852, 14.268),
+    ("Nice", "France", 43.710, 7.262),
+    ("Oslo", "Norway", 59.914, 10.752),
+    ("Palermo", "Italy", 38.116, 13.361),
+    ("Paris", "France", 48.857, 2.352),
+    ("Porto", "Portugal", 41.158, -8.629),
+    ("Prague", "Czechia", 50.076, 14.438),
+    ("Reykjavík", "Iceland", 64.147, -21.942),
+    ("Riga", "Latvia", 56.950, 24.106),
+    ("Rome", "Italy", 41.903, 12.496),
+    ("Santorini", "Greece", 36.393, 25.461),
+    ("Seville", "Spain", 37.389, -5.984),
+    ("Sofia", "Bulgaria", 42.698, 23.322),
+    ("Split", "Croatia", 43.508, 16.440),
+    ("Stockholm", "Sweden", 59.329, 18.069),
+    ("Tallinn", "Estonia", 59.437, 24.754),
+    ("Tbilisi", "Georgia", 41.716, 44.783),
+    ("Valencia", "Spain", 39.470, -0.376),
+    ("Venice", "Italy", 45.441, 12.316),
+    ("Vienna", "Austria", 48.208, 16.374),
+    ("Vilnius", "Lithuania", 54.687, 25.280),
+    ("Warsaw", "Poland", 52.230, 21.012),
+    ("Zagreb", "Croatia", 45.815, 15.982),
+    ("Zürich", "Switzerland", 47.377, 8.540),
+    // Africa and the Middle East
+    ("Abu Dhabi", "United Arab Emirates", 24.454, 54.377),
+    ("Accra", "Ghana", 5.604, -0.187),
+    ("Addis Ababa", "Ethiopia", 9.030, 38.740),
+    ("Amman", "Jordan", 31.954, 35.911),
+    ("Cairo", "Egypt", 30.044, 31.236),
+    ("Cape Town", "South Africa", -33.925, 18.424),
+    ("Casablanca", "Morocco", 33.573, -7.590),
+    ("Dakar", "Senegal", 14.716, -17.467),
+    ("Dar es Salaam", "Tanzania", -6.792, 39.208),
+    ("Doha", "Qatar", 25.286, 51.531),
+    ("Dubai", "United Arab Emirates", 25.205, 55.271),
+    ("Jerusalem", "Israel", 31.769, 35.216),
+    ("Johannesburg", "South Africa", -26.204, 28.047),
+    ("Lagos", "Nigeria", 6.524, 3.379),
+    ("Luxor", "Egypt", 25.687, 32.640),
+    ("Marrakesh", "Morocco", 31.629, -7.981),
+    ("Nairobi", "Kenya", -1.292, 36.822),
+    ("Petra", "Jordan", 30.329, 35.444),
+    ("Riyadh", "Saudi Arabia", 24.713, 46.675),
+    ("Tehran", "Iran", 35.689, 51.389),
+    ("Tel Aviv", "Israel", 32.085, 34.782),
+    ("Tunis", "Tunisia", 36.806, 10.181),
+    ("Zanzibar City", "Tanzania", -6.165, 39.199),
+    // Asia
+    ("Bangkok", "Thailand", 13.756, 100.502),
+    ("Beijing", "China", 39.904, 116.407),
+    ("Bengaluru", "India", 12.972, 77.595),
+    ("Busan", "South Korea", 35.180, 129.076),
+    ("Chengdu", "China", 30.573, 104.066),
+    ("Chiang Mai", "Thailand", 18.788, 98.986),
+    ("Colombo", "Sri Lanka", 6.927, 79.861),
+    ("Delhi", "India", 28.704, 77.102),
+    ("Denpasar", "Indonesia", -8.650, 115.217),
+    ("Dhaka", "Bangladesh", 23.810, 90.413),
+    ("Hanoi", "Vietnam", 21.028, 105.834),
+    ("Ho Chi Minh City", "Vietnam", 10.823, 106.630),
+    ("Hong Kong", "China", 22.320, 114.169),
+    ("Hiroshima", "Japan", 34.385, 132.455),
+    ("Jaipur", "India", 26.912, 75.787),
+    ("Jakarta", "Indonesia", -6.209, 106.846),
+    ("Kathmandu", "Nepal", 27.717, 85.324),
+    ("Kolkata", "India", 22.573, 88.364),
+    ("Kuala Lumpur", "Malaysia", 3.139, 101.687),
+    ("Kyoto", "Japan", 35.012, 135.768),
+    ("Manila", "Philippines", 14.600, 120.984),
+    ("Mumbai", "India", 19.076, 72.878),
+    ("Osaka", "Japan", 34.694, 135.502),
+    ("Phnom Penh", "Cambodia", 11.556, 104.928),
+    ("Sapporo", "Japan", 43.062, 141.354),
+    ("Seoul", "South Korea", 37.567, 126.978),
+    ("Shanghai", "China", 31.230, 121.474),
+    ("Siem Reap", "Cambodia", 13.362, 103.860),
+    ("Singapore", "Singapore", 1.352, 103.820),
+    ("Taipei", "Taiwan", 25.033, 121.565),
+    ("Tashkent", "Uzbekistan", 41.299, 69.240),
+    ("Tokyo", "Japan", 35.676, 139.650),
+    ("Ulaanbaatar", "Mongolia", 47.886, 106.906),
+    ("Xi'an", "China", 34.342, 108.940),
+    // Oceania
+    ("Adelaide", "Australia", -34.929, 138.601),
+    ("Auckland", "New Zealand", -36.848, 174.763),
+    ("Brisbane", "Australia", -27.470, 153.026),
+    ("Cairns", "Australia", -16.919, 145.771),
+    ("Christchurch", "New Zealand", -43.532, 172.637),
+    ("Hobart", "Australia", -42.882, 147.327),
+    ("Melbourne", "Australia", -37.814, 144.963),
+    ("Nadi", "Fiji", -17.777, 177.436),
+    ("Perth", "Australia", -31.951, 115.861),
+    ("Queenstown", "New Zealand", -45.031, 168.663),
+    ("Sydney", "Australia", -33.869, 151.209),
+    ("Wellington", "New Zealand", -41.286, 174.776),
+    // North America
+    ("Anchorage", "United States", 61.218, -149.900),
+    ("Atlanta", "United States", 33.749, -84.388),
+    ("Austin", "United States", 30.267, -97.743),
+    ("Boston", "United States", 42.360, -71.059),
+    ("Calgary", "Canada", 51.045, -114.072),
+    ("Cancún", "Mexico", 21.162, -86.851),
+    ("Chicago", "United States", 41.878, -87.630),
+    ("Dallas", "United States", 32.777, -96.797),
+    ("Denver", "United States", 39.739, -104.990),
+    ("Havana", "Cuba", 23.114, -82.366),
+    ("Honolulu", "United States", 21.307, -157.858),
+    ("Houston", "United States", 29.760, -95.370),
+    ("Las Vegas", "United States", 36.170, -115.140),
+    ("Los Angeles", "United States", 34.052, -118.244),
+    ("Mexico City", "Mexico", 19.433, -99.133),
+    ("Miami", "United States", 25.762, -80.192),
+    ("Montreal", "Canada", 45.502, -73.567),
+    ("Nashville", "United States", 36.163, -86.781),
+    ("New Orleans", "United States", 29.951, -90.072),
+    ("New York", "United States", 40.713, -74.006),
+    ("Oaxaca", "Mexico", 17.073, -96.726),
+    ("Panama City", "Panama", 8.983, -79.517),
+    ("Philadelphia", "United States", 39.953, -75.165),
+    ("Phoenix", "United States", 33.448, -112.074),
+    ("Portland", "United States", 45.515, -122.679),
+    ("Quebec City", "Canada", 46.814, -71.208),
+    ("Salt Lake City", "United States", 40.761, -111.891),
+    ("San Diego", "United States", 32.716, -117.161),
+    ("San Francisco", "United States", 37.775, -122.419),
+    ("San José", "Costa Rica", 9.928, -84.091),
+    ("San Juan", "Puerto Rico", 18.466, -66.106),
+    ("Seattle", "United States", 47.606, -122.332),
+    ("Toronto", "Canada", 43.653, -79.383),
+    ("Vancouver", "Canada", 49.283, -123.121),
+    ("Washington, D.C.", "United States", 38.907, -77.037),
+    // South America
+    ("Bogotá", "Colombia", 4.711, -74.072),
+    ("Buenos Aires", "Argentina", -34.604, -58.382),
+    ("Cartagena", "Colombia", 10.391, -75.479),
+    ("Cusco", "Peru", -13.532, -71.967),
+    ("La Paz", "Bolivia", -16.490, -68.119),
+    ("Lima", "Peru", -12.046, -77.043),
+    ("Medellín", "Colombia", 6.244, -75.581),
+    ("Montevideo", "Uruguay", -34.901, -56.165),
+    ("Quito", "Ecuador", -0.180, -78.468),
+    ("Rio de Janeiro", "Brazil", -22.907, -43.173),
+    ("Santiago", "Chile", -33.449, -70.669),
+    ("São Paulo", "Brazil", -23.551, -46.633),
+    ("Ushuaia", "Argentina", -54.801, -68.303),
+];
+
+/// Great-circle distance in kilometres.
+fn distance_km((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let half_dlat = (lat2 - lat1) / 2.0;
+    let half_dlon = (lon2 - lon1).to_radians() / 2.0;
+    let a = half_dlat.sin().powi(2) + lat1.cos() * lat2.cos() * half_dlon.sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// "Lisbon, Portugal" or "near Lisbon, Portugal" for a position, or `None` when no city in
+/// the table is close enough to be worth naming.
+pub(crate) fn describe(latitude: f64, longitude: f64) -> Option<String> {
+    let (city, country, distance) = CITIES
+        .iter()
+        .map(|&(city, country, lat, lon)| {
+            (city, country, distance_km((latitude, longitude), (lat, lon)))
+        })
+        .min_by(|a, b| a.2.total_cmp(&b.2))?;
+    if distance <= IN_CITY_KM {
+        Some(format!("{}, {}", city, country))
+    } else if distance <= NEAR_CITY_KM {
+        Some(format!("near {}, {}", city, country))
+    } else {
+        None
+    }
+}
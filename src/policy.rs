@@ -0,0 +1,225 @@
+// Content policy for deployments that must treat some images with care, e.g. on a hospital
+// intranet. With POLICY_FILE naming a JSON file of rules, the server asks the model to flag
+// each image with the sensitive categories it shows (`sensitive_content`), and each rule says
+// what happens to images of its category:
+//
+//   {"medical_scan": {"action": "disclaimer",
+//                     "disclaimer": "AI-generated description, not a diagnosis."},
+//    "clinical_photo": {"action": "prompt",
+//                       "prompt": "Describe it neutrally and don't suggest a diagnosis."},
+//    "graphic_injury": {"action": "refuse"}}
+//
+// - `refuse`: the caption is withheld and the request fails with 422 `content_refused` (the
+//   rule's `message`, if given, is the error message).
+// - `prompt`: the image is captioned again with the rule's `prompt` added to the instructions.
+// - `disclaimer`: the rule's `disclaimer` is returned with the caption.
+//
+// A `prompt` rule can carry a disclaimer too. When an image matches several rules, the strictest
+// action wins (refuse, then prompt, then disclaimer), and the prompts and disclaimers of the
+// matching rules are combined. Every decision on a flagged image is logged with the caller and
+// the image hash. The flags come from the model and can miss, so the policy narrows what the
+// server says about such images rather than guaranteeing it. The server checks the file at
+// startup and re-reads it on SIGHUP; the CLI, run by the operator, doesn't apply it.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use crate::modes::ModeOutput;
+use crate::CaptionError;
+
+/// The categories the model flags, with how the prompt explains them.
+pub(crate) const CATEGORIES: &[(&str, &str)] = &[
+    ("medical_scan", "an X-ray, CT, MRI or ultrasound scan, or a pathology slide"),
+    ("clinical_photo", "a photo of a patient, wound, skin condition or surgery"),
+    ("medical_diagram", "an anatomical or medical diagram or chart"),
+    ("graphic_injury", "gore or a severe injury outside a clinical setting"),
+    ("nudity", "nudity"),
+    ("self_harm", "self-harm"),
+];
+
+static POLICY: LazyLock<RwLock<Arc<Policy>>> = LazyLock::new(|| {
+    let policy = load().unwrap_or_else(|e| {
+        tracing::warn!("{}; applying no content policy", e);
+        Policy::default()
+    });
+    RwLock::new(Arc::new(policy))
+});
+
+/// What happens to an image of a category, strictest last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Action {
+    Disclaimer,
+    Prompt,
+    Refuse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    action: Action,
+    disclaimer: Option<String>,
+    prompt: Option<String>,
+    message: Option<String>,
+}
+
+/// The rules by category.
+#[derive(Debug, Default)]
+struct Policy {
+    rules: HashMap<String, Rule>,
+    /// The SHA-256 of the file the rules came from; empty without one.
+    digest: String,
+}
+
+/// What the policy does with one image.
+#[derive(Debug, Default)]
+pub(crate) struct Decision {
+    pub(crate) action: Option<Action>,
+    /// The categories whose rules apply.
+    pub(crate) categories: Vec<String>,
+    pub(crate) prompt: Option<String>,
+    pub(crate) disclaimer: Option<String>,
+    pub(crate) message: Option<String>,
+}
+
+/// Reads POLICY_FILE, or no rules when it isn't set.
+fn load() -> Result<Policy, String> {
+    let Some(path) = crate::settings::var("POLICY_FILE")
+        .ok()
+        .filter(|path| !path.trim().is_empty())
+    else {
+        return Ok(Policy::default());
+    };
+    let path = Path::new(&path);
+    let json = std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let rules: HashMap<String, Rule> = serde_json::from_slice(&json)
+        .map_err(|e| format!("{} is not valid: {}", path.display(), e))?;
+    for (category, rule) in &rules {
+        let invalid = |problem: &str| format!("{}: {} {}", path.display(), category, problem);
+        if !CATEGORIES.iter().any(|(name, _)| name == category) {
+            let names: Vec<&str> = CATEGORIES.iter().map(|(name, _)| *name).collect();
+            return Err(invalid(&format!("is not one of {}", names.join(", "))));
+        }
+        let given = |text: &Option<String>| text.as_ref().is_some_and(|text| !text.trim().is_empty());
+        match rule.action {
+            Action::Disclaimer if !given(&rule.disclaimer) => {
+                return Err(invalid("needs a disclaimer"))
+            }
+            Action::Prompt if !given(&rule.prompt) => return Err(invalid("needs a prompt")),
+            _ => {}
+        }
+    }
+    Ok(Policy {
+        rules,
+        digest: crate::imageproc::content_hash(&json),
+    })
+}
+
+/// (Re-)reads POLICY_FILE; the current rules stay if it can't be read.
+pub fn reload() -> Result<(), String> {
+    let policy = load()?;
+    *POLICY.write().expect("content policy lock poisoned") = Arc::new(policy);
+    Ok(())
+}
+
+fn current() -> Arc<Policy> {
+    POLICY.read().expect("content policy lock poisoned").clone()
+}
+
+/// Tells policy files apart, so captions made under one aren't reused under another.
+pub(crate) fn digest() -> String {
+    current().digest.clone()
+}
+
+/// Whether images need flagging: there are rules to apply.
+pub(crate) fn active() -> bool {
+    !current().rules.is_empty()
+}
+
+/// The `sensitive_content` the model flagged in `output`.
+fn flagged(output: &ModeOutput) -> Vec<&str> {
+    output
+        .details
+        .get("sensitive_content")
+        .and_then(|value| value.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|category| category.as_str())
+        .collect()
+}
+
+/// What the rules say about an image flagged as in `output`.
+pub(crate) fn decide(output: &ModeOutput) -> Decision {
+    let policy = current();
+    let mut decision = Decision::default();
+    let (mut prompts, mut disclaimers) = (Vec::new(), Vec::new());
+    for category in flagged(output) {
+        let Some(rule) = policy.rules.get(category) else {
+            continue;
+        };
+        decision.categories.push(category.to_string());
+        decision.action = decision.action.max(Some(rule.action));
+        if rule.action == Action::Refuse && decision.message.is_none() {
+            decision.message = rule.message.clone();
+        }
+        if rule.action == Action::Prompt {
+            prompts.extend(rule.prompt.iter().map(|prompt| prompt.trim()));
+        }
+        if let Some(disclaimer) = rule.disclaimer.as_deref().map(str::trim) {
+            if !disclaimers.contains(&disclaimer) {
+                disclaimers.push(disclaimer);
+            }
+        }
+    }
+    decision.prompt = (!prompts.is_empty()).then(|| prompts.join(" "));
+    decision.disclaimer = (!disclaimers.is_empty()).then(|| disclaimers.join(" "));
+    decision
+}
+
+impl Decision {
+    /// Logs the decision for a flagged image.
+    pub(crate) fn log(&self, output: &ModeOutput, tenant: &str, image_hash: &str) {
+        let flagged = flagged(output);
+        if flagged.is_empty() {
+            return;
+        }
+        let action = match self.action {
+            Some(Action::Refuse) => "refused",
+            Some(Action::Prompt) => "captioned with the policy prompt",
+            Some(Action::Disclaimer) => "captioned with a disclaimer",
+            None => "captioned (no rule)",
+        };
+        tracing::info!(
+            image = image_hash,
+            tenant,
+            flagged = %flagged.join(", "),
+            action,
+            "Content policy flagged an image"
+        );
+    }
+
+    /// The error a refused image fails with.
+    pub(crate) fn refusal(&self) -> CaptionError {
+        let message = self.message.clone().unwrap_or_else(|| {
+            format!(
+                "This server doesn't describe images of this kind ({})",
+                self.categories.join(", ")
+            )
+        });
+        CaptionError::Refused(message)
+    }
+
+    /// Adds what the client should know of the decision to `output`: `policy_action` and the
+    /// `disclaimer`, when a rule applied.
+    pub(crate) fn annotate(&self, output: &mut ModeOutput) {
+        if let Some(action) = self.action {
+            let action = serde_json::to_value(action).expect("actions always serialize");
+            output.details.insert("policy_action".into(), action);
+        }
+        if let Some(disclaimer) = &self.disclaimer {
+            output.details.insert("disclaimer".into(), disclaimer.clone().into());
+        }
+    }
+}
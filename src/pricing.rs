@@ -0,0 +1,100 @@
+// Provider prices, for the estimated cost of captions in verbose responses, the batch summary
+// and the usage totals. The built-in table has Gemini's list prices in USD per million tokens;
+// PRICE_TABLE_FILE can name a JSON file that adds models or overrides their prices, e.g. for a
+// negotiated rate or a price change:
+//
+//   {"gemini-2.5-flash": {"input_per_mtok": 0.25, "output_per_mtok": 2.0}}
+//
+// Output prices apply to thinking tokens too. Costs are worked out when a caption is made, so
+// totals already recorded keep the prices they were made at. The server checks the file at
+// startup and re-reads it on SIGHUP; the CLI reads it once.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use crate::config::MODEL_ID;
+use crate::providers::Usage;
+
+/// Gemini list prices: model, input and output USD per million tokens.
+const LIST_PRICES: &[(&str, f64, f64)] = &[
+    ("gemini-2.5-flash", 0.30, 2.50),
+    ("gemini-2.5-flash-lite", 0.10, 0.40),
+    ("gemini-2.5-pro", 1.25, 10.00),
+];
+
+static TABLE: LazyLock<RwLock<Arc<PriceTable>>> = LazyLock::new(|| {
+    let table = load().unwrap_or_else(|e| {
+        tracing::warn!("{}; using the built-in prices", e);
+        built_in()
+    });
+    RwLock::new(Arc::new(table))
+});
+
+type PriceTable = HashMap<String, Price>;
+
+/// What one model costs, in USD per million tokens.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Price {
+    pub(crate) input_per_mtok: f64,
+    pub(crate) output_per_mtok: f64,
+}
+
+fn built_in() -> PriceTable {
+    LIST_PRICES
+        .iter()
+        .map(|&(model, input_per_mtok, output_per_mtok)| {
+            let price = Price {
+                input_per_mtok,
+                output_per_mtok,
+            };
+            (model.to_string(), price)
+        })
+        .collect()
+}
+
+/// Reads PRICE_TABLE_FILE over the built-in prices, or just those when it isn't set.
+fn load() -> Result<PriceTable, String> {
+    let mut table = built_in();
+    let Some(path) = crate::settings::var("PRICE_TABLE_FILE")
+        .ok()
+        .filter(|path| !path.trim().is_empty())
+    else {
+        return Ok(table);
+    };
+    let path = Path::new(&path);
+    let json = std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let prices: PriceTable = serde_json::from_slice(&json)
+        .map_err(|e| format!("{} is not valid: {}", path.display(), e))?;
+    for (model, price) in prices {
+        let valid = |value: f64| value.is_finite() && value >= 0.0;
+        if !valid(price.input_per_mtok) || !valid(price.output_per_mtok) {
+            return Err(format!("{}: {} has a negative price", path.display(), model));
+        }
+        table.insert(model, price);
+    }
+    Ok(table)
+}
+
+/// (Re-)reads PRICE_TABLE_FILE; the current prices stay if it can't be read.
+pub fn reload() -> Result<(), String> {
+    let table = load()?;
+    *TABLE.write().expect("price table lock poisoned") = Arc::new(table);
+    Ok(())
+}
+
+/// The price of the model captions are made with.
+pub(crate) fn current() -> Price {
+    let table = TABLE.read().expect("price table lock poisoned").clone();
+    table.get(MODEL_ID).copied().unwrap_or_default()
+}
+
+/// Estimated USD cost of `usage` at the current prices.
+pub(crate) fn cost_usd(usage: &Usage) -> f64 {
+    let price = current();
+    (usage.prompt_tokens as f64 * price.input_per_mtok
+        + usage.output_tokens as f64 * price.output_per_mtok)
+        / 1_000_000.0
+}
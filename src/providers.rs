@@ -0,0 +1,365 @@
+// Calls to the captioning provider (Google Gemini): request building, error handling and
+// token accounting. GEMINI_API_URL points the calls at another server speaking the Gemini API
+// (a proxy, or a mock in tests); it defaults to Google's.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::Instrument;
+
+use crate::breaker;
+use crate::contact_sheet;
+use crate::capture::CaptureContext;
+use crate::config::{LOG_PROVIDER_TRAFFIC, MODEL_ID};
+use crate::imageproc::PreparedImage;
+use crate::pricing;
+use crate::modes::{CaptionOptions, ModeOutput};
+use crate::payload::{Body, InlineImages};
+use crate::session::ProviderSession;
+use crate::{elapsed_ms, request_id, timeouts, CaptionError, Timings};
+
+pub(crate) const DEFAULT_API_URL: &str = "https://generativelanguage.googleapis.com";
+
+/// Attempts per call, and the backoff before the first retry, unless PROVIDER_MAX_ATTEMPTS and
+/// PROVIDER_RETRY_BASE_MS say otherwise.
+pub(crate) const MAX_ATTEMPTS: u64 = 3;
+pub(crate) const RETRY_BASE_MS: u64 = 500;
+
+/// Longest wait before a retry, whether from backoff or the provider's `Retry-After`.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Token counts reported by the provider for a single call.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Usage {
+    pub(crate) prompt_tokens: u64,
+    pub(crate) output_tokens: u64,
+}
+
+impl Usage {
+    fn from_gemini(metadata: &serde_json::Value) -> Self {
+        let count = |key: &str| metadata[key].as_u64().unwrap_or(0);
+        Usage {
+            prompt_tokens: count("promptTokenCount"),
+            output_tokens: count("candidatesTokenCount") + count("thoughtsTokenCount"),
+        }
+    }
+
+    pub(crate) fn cost_usd(&self) -> f64 {
+        pricing::cost_usd(self)
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+}
+
+/// Captions one image, given as a single encoded image, several frames of an animation or a
+/// contact sheet and its panels. `context` is what the image's EXIF says about when and where
+/// it was taken, if wanted. With a `session`, the prompt and brand voice the album's images
+/// share come from its cached context when the provider has one.
+pub(crate) async fn generate_caption(
+    image: PreparedImage,
+    options: &CaptionOptions,
+    context: Option<&CaptureContext>,
+    session: Option<&ProviderSession>,
+    api_key: &str,
+    timings: &mut Timings,
+) -> Result<(ModeOutput, Usage), CaptionError> {
+    let PreparedImage { frames, panels, .. } = image;
+    let frame_count = frames.len();
+    let prompt = options.prompt();
+    // What this image's prompt says before the shared part.
+    let mut lead = context.map(CaptureContext::prompt);
+    if options.contact_sheet {
+        lead = Some(join(contact_sheet::prompt(panels.len()), lead));
+    } else if frame_count > 1 {
+        let frames = format!(
+            "The following {} images are frames sampled in order from one animated image. \
+             Treat them as a single animation and describe the motion or what changes over \
+             time (for example \"a cat repeatedly pawing at a laser dot\") rather than \
+             describing the frames separately.",
+            frame_count
+        );
+        lead = Some(join(frames, lead));
+    }
+    let request = |text: Option<String>| {
+        let mut images = InlineImages::new();
+        let text = text.map(|text| serde_json::json!({ "text": text }));
+        let mut parts: Vec<_> = text.into_iter().collect();
+        parts.extend(frames.iter().map(|frame| images.part(frame.mime_type, frame.data.clone())));
+        (serde_json::Value::from(parts), images)
+    };
+
+    let provider = std::time::Instant::now();
+    let mut reply = None;
+    if let Some(session) = session {
+        if let Some(name) = session.context(options.voice.as_deref(), &prompt).await {
+            let (parts, images) = request(lead.clone());
+            let json_reply = options.expects_json();
+            match call_gemini(parts, images, Some(&name), None, json_reply, api_key).await {
+                Err(e) if lost_context(&e) => {
+                    tracing::info!(error = %e, "The provider context is gone; sending it again");
+                    session.forget(&name);
+                }
+                result => reply = Some(result?),
+            }
+        }
+    }
+    let reply = match reply {
+        Some(reply) => reply,
+        None => {
+            let (parts, images) = request(Some(join(prompt, lead)));
+            let system = options.voice.as_deref();
+            call_gemini(parts, images, None, system, options.expects_json(), api_key).await?
+        }
+    };
+    timings.provider_ms = elapsed_ms(provider);
+    timings.provider_attempts = reply.attempts;
+
+    let parse = std::time::Instant::now();
+    let mut output = options.parse_output(&reply.text)?;
+    timings.parse_ms = elapsed_ms(parse);
+    if options.contact_sheet {
+        contact_sheet::locate(&mut output, &panels);
+    } else if frame_count > 1 {
+        output.details.insert("frames_analyzed".into(), frame_count.into());
+    }
+
+    if LOG_PROVIDER_TRAFFIC.load(Ordering::Relaxed) {
+        tracing::info!(caption = %output.caption, "Provider replied");
+    }
+
+    Ok((output, reply.usage))
+}
+
+/// `text` after `lead`, if there is one.
+fn join(text: String, lead: Option<String>) -> String {
+    match lead {
+        Some(lead) => format!("{} {}", lead, text),
+        None => text,
+    }
+}
+
+/// Whether a call naming a cached context failed because the provider no longer has it.
+fn lost_context(error: &CaptionError) -> bool {
+    matches!(error, CaptionError::Provider { status: Some(400 | 403 | 404), .. })
+}
+
+/// Text-only request, e.g. summarizing captions that were already generated.
+pub(crate) async fn generate_text(
+    prompt: &str,
+    system: Option<&str>,
+    api_key: &str,
+) -> Result<(String, Usage), CaptionError> {
+    let parts = serde_json::json!([{ "text": prompt }]);
+    let reply = call_gemini(parts, InlineImages::new(), None, system, false, api_key).await?;
+    Ok((reply.text.trim().to_string(), reply.usage))
+}
+
+/// Provider retries, read from the environment on every call so a SIGHUP reload applies
+/// them: PROVIDER_MAX_ATTEMPTS (default 3; 1 turns retries off) and PROVIDER_RETRY_BASE_MS
+/// (default 500), the first backoff before jitter.
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    fn from_env() -> Self {
+        let read = |name: &str, default: u64| {
+            crate::settings::var(name)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(default)
+        };
+        RetryPolicy {
+            max_attempts: read("PROVIDER_MAX_ATTEMPTS", MAX_ATTEMPTS).clamp(1, 10) as u32,
+            base_delay: Duration::from_millis(read("PROVIDER_RETRY_BASE_MS", RETRY_BASE_MS)),
+        }
+    }
+
+    /// Full jitter: anywhere up to base × 2^(attempt - 1), so clients that failed together
+    /// don't retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_RETRY_DELAY);
+        ceiling.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Failures that may pass on their own: rate limits, provider 5xx, network errors and
+/// timeouts. A reply without a candidate (e.g. a safety block) would come back the same.
+fn transient(error: &CaptionError) -> bool {
+    match error {
+        CaptionError::RateLimited(_) | CaptionError::Timeout(_) => true,
+        CaptionError::Provider { status, .. } => status.is_none_or(|status| status >= 500),
+        _ => false,
+    }
+}
+
+/// `Retry-After` as either delay-seconds or an HTTP date.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+/// GEMINI_API_URL, or Google's API when it isn't set; without a trailing slash.
+pub(crate) fn api_base() -> String {
+    let base = crate::settings::var("GEMINI_API_URL")
+        .ok()
+        .filter(|url| !url.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_API_URL.to_string());
+    base.trim().trim_end_matches('/').to_string()
+}
+
+/// The text of the first candidate, the tokens it took and how many requests it needed.
+pub(crate) struct GeminiReply {
+    pub(crate) text: String,
+    pub(crate) usage: Usage,
+    pub(crate) attempts: u32,
+}
+
+/// Sends a generateContent request through the circuit breaker, retrying transient failures
+/// with jittered exponential backoff. A `Retry-After` from the provider is waited out when it is longer than the
+/// backoff; one longer than `MAX_RETRY_DELAY` ends the retries.
+/// `cached` names a cached context (see `session`) the request builds on; `system` is an
+/// optional system instruction (a tenant's brand voice), which a cached context holds already.
+pub(crate) async fn call_gemini(
+    parts: serde_json::Value,
+    images: InlineImages,
+    cached: Option<&str>,
+    system: Option<&str>,
+    json_reply: bool,
+    api_key: &str,
+) -> Result<GeminiReply, CaptionError> {
+    let client = reqwest::Client::new();
+    
+    let url = format!(
+        "{}/v1beta/models/{}:generateContent?key={}",
+        api_base(),
+        MODEL_ID,
+        api_key
+    );
+    
+    let mut payload = serde_json::json!({
+        "contents": [{
+            "parts": parts
+        }]
+    });
+
+    if let Some(cached) = cached {
+        payload["cachedContent"] = serde_json::json!(cached);
+    }
+
+    if let Some(system) = system {
+        payload["systemInstruction"] = serde_json::json!({
+            "parts": [{ "text": system }]
+        });
+    }
+
+    if json_reply {
+        payload["generationConfig"] = serde_json::json!({
+            "responseMimeType": "application/json"
+        });
+    }
+    let body = images.body(&payload);
+
+    let policy = RetryPolicy::from_env();
+    let mut attempt = 1;
+    loop {
+        let mut wait = None;
+        breaker::PROVIDER.admit()?;
+        let span = tracing::info_span!("provider_call", attempt, status = tracing::field::Empty);
+        let result = send(&client, &url, &body, &mut wait).instrument(span).await;
+        breaker::PROVIDER.record(result.as_ref().err());
+        let error = match result {
+            Ok((text, usage)) => {
+                return Ok(GeminiReply {
+                    text,
+                    usage,
+                    attempts: attempt,
+                })
+            }
+            Err(error) => error,
+        };
+        if attempt >= policy.max_attempts || !transient(&error) {
+            return Err(error);
+        }
+        let delay = policy.backoff(attempt).max(wait.unwrap_or_default());
+        if delay > MAX_RETRY_DELAY {
+            return Err(error);
+        }
+        if LOG_PROVIDER_TRAFFIC.load(Ordering::Relaxed) {
+            tracing::warn!(
+                error = %error,
+                delay_ms = delay.as_millis() as u64,
+                attempt = attempt + 1,
+                max_attempts = policy.max_attempts,
+                "Retrying the provider"
+            );
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// One attempt at the request. `wait` is set to the provider's `Retry-After`, if it sent one.
+async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    body: &Body,
+    wait: &mut Option<Duration>,
+) -> Result<(String, Usage), CaptionError> {
+    let log_traffic = LOG_PROVIDER_TRAFFIC.load(Ordering::Relaxed);
+    if log_traffic {
+        tracing::info!("Sending request to Google Gemini");
+    }
+
+
+    let mut request = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.content_length())
+        .body(body.stream());
+    if let Some(id) = request_id::current() {
+        request = request.header(request_id::HEADER.as_str(), id);
+    }
+    if let Some(limit) = timeouts::provider_call() {
+        request = request.timeout(limit);
+    }
+    let failed = |e| timeouts::provider_error("Gemini", e);
+    let response = request.send().await.map_err(failed)?;
+
+    let status = response.status();
+    tracing::Span::current().record("status", status.as_u16());
+    *wait = retry_after(response.headers());
+    let response_text = response.text().await.map_err(failed)?;
+    
+    if log_traffic {
+        let body = response_text.chars().take(500).collect::<String>();
+        tracing::info!(status = status.as_u16(), body, "Gemini response");
+    }
+
+    if !status.is_success() {
+        return Err(CaptionError::from_response("Gemini", status, &response_text));
+    }
+
+    let result: serde_json::Value = serde_json::from_str(&response_text)?;
+    
+    let text = result["candidates"][0]["content"]["parts"][0]["text"]
+        .as_str()
+        .ok_or_else(|| CaptionError::InvalidReply("No caption in response".into()))?;
+
+    Ok((text.to_string(), Usage::from_gemini(&result["usageMetadata"])))
+}
@@ -0,0 +1,179 @@
+// Per-client request rate limiting, so one address can't flood the server. Each client IP gets
+// a token bucket of RATE_LIMIT_PER_MINUTE requests (default 120; 0 turns limiting off) that
+// refills evenly over the minute. Over the limit, requests fail with 429 `rate_limited` and a
+// Retry-After header. Every limited response carries the RateLimit-Limit, RateLimit-Remaining
+// and RateLimit-Reset headers.
+//
+// Behind a reverse proxy every request comes from the proxy's address; with TRUST_PROXY=true
+// the client is taken from the last X-Forwarded-For entry instead, the one the proxy added.
+// Only set it when a proxy is actually in front: otherwise clients can pick their own address.
+// The same address identifies tenants without an API key in the fair-share scheduler.
+//
+// Both settings are read per request, so a SIGHUP reload applies them. Buckets are per process,
+// unless REDIS_URL is set (see `shared`): then they're kept in Redis and all replicas take from
+// the same ones. Should Redis fail, the process falls back to its own buckets until it's back.
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, Extensions, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{parse_flag, RATE_LIMIT_PER_MINUTE};
+use crate::{shared, CaptionError};
+
+/// How often buckets that have filled up again are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+static LIMITER: LazyLock<Mutex<Limiter>> = LazyLock::new(Mutex::default);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Limiter {
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned: Option<Instant>,
+}
+
+/// The outcome of one request against its client's bucket.
+struct Decision {
+    allowed: bool,
+    remaining: u32,
+    /// Until the bucket is full again.
+    reset: Duration,
+    /// Until the next request would be allowed.
+    retry_after: Duration,
+}
+
+impl Decision {
+    /// The decision for a bucket of `per_minute` that has `tokens` left after this request.
+    fn new(allowed: bool, tokens: f64, per_minute: u32) -> Decision {
+        let rate = f64::from(per_minute) / 60.0;
+        Decision {
+            allowed,
+            remaining: tokens as u32,
+            reset: Duration::from_secs_f64((f64::from(per_minute) - tokens).max(0.0) / rate),
+            retry_after: Duration::from_secs_f64((1.0 - tokens).max(0.0) / rate),
+        }
+    }
+}
+
+impl Limiter {
+    fn take(&mut self, client: IpAddr, per_minute: u32, now: Instant) -> Decision {
+        let capacity = f64::from(per_minute);
+        let rate = capacity / 60.0;
+
+        if self.pruned.is_none_or(|pruned| now - pruned >= PRUNE_INTERVAL) {
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + (now - bucket.updated).as_secs_f64() * rate < capacity
+            });
+            self.pruned = Some(now);
+        }
+
+        let bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let refilled = (now - bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Decision::new(allowed, bucket.tokens, per_minute)
+    }
+}
+
+/// Takes a token from `client`'s bucket: the shared one when there is one, else this process's.
+async fn take(client: IpAddr, per_minute: u32) -> Decision {
+    if let Some(shared) = shared::get() {
+        match shared.take_token(client, per_minute).await {
+            Ok((allowed, tokens)) => return Decision::new(allowed, tokens, per_minute),
+            Err(e) => tracing::warn!(%client, error = %e, "Rate limiting in this process only"),
+        }
+    }
+    LIMITER
+        .lock()
+        .expect("rate limiter lock poisoned")
+        .take(client, per_minute, Instant::now())
+}
+
+fn per_minute() -> u32 {
+    crate::settings::var("RATE_LIMIT_PER_MINUTE")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(RATE_LIMIT_PER_MINUTE)
+}
+
+fn trust_proxy() -> bool {
+    crate::settings::var("TRUST_PROXY")
+        .ok()
+        .and_then(|value| parse_flag(&value))
+        .unwrap_or(false)
+}
+
+/// The client's address: the peer, or what the trusted proxy says the peer was. Also used
+/// to tell tenants without an API key apart.
+pub(crate) fn client_ip(headers: &HeaderMap, extensions: &Extensions) -> Option<IpAddr> {
+    if trust_proxy() {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .next_back()
+            .and_then(|last| last.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+}
+
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+fn set_headers(headers: &mut HeaderMap, per_minute: u32, decision: &Decision) {
+    headers.insert("ratelimit-limit", per_minute.into());
+    headers.insert("ratelimit-remaining", decision.remaining.into());
+    headers.insert("ratelimit-reset", whole_seconds(decision.reset).into());
+    if !decision.allowed {
+        let retry_after = whole_seconds(decision.retry_after).max(1);
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+}
+
+/// Middleware turning away clients that are over their request rate.
+pub(crate) async fn limit_rate(request: Request, next: Next) -> Response {
+    let per_minute = per_minute();
+    let client = client_ip(request.headers(), request.extensions()).filter(|_| per_minute > 0);
+    let Some(client) = client else {
+        return next.run(request).await;
+    };
+    let decision = take(client, per_minute).await;
+
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        CaptionError::RateLimited(format!(
+            "Too many requests from this address; the limit is {} per minute",
+            per_minute
+        ))
+        .into_response()
+    };
+    set_headers(response.headers_mut(), per_minute, &decision);
+    response
+}
@@ -0,0 +1,137 @@
+// Camera RAW input (CR2, NEF, ARW, DNG). These are TIFF containers that carry a JPEG preview
+// rendered by the camera alongside the sensor data. Captions are made from the largest such
+// preview instead of demosaicing the sensor data: no extra dependencies, and it shows the
+// photo the way the camera (and the photographer, on its screen) saw it.
+
+use image::{DynamicImage, ImageResult};
+
+use crate::orientation::{self, TAG_ORIENTATION};
+use crate::tiff::Tiff;
+
+const TAG_IMAGE_WIDTH: u16 = 0x100;
+const TAG_IMAGE_LENGTH: u16 = 0x101;
+const TAG_COMPRESSION: u16 = 0x103;
+const TAG_PHOTOMETRIC: u16 = 0x106;
+const TAG_STRIP_OFFSETS: u16 = 0x111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x117;
+const TAG_SUB_IFDS: u16 = 0x14a;
+const TAG_JPEG_OFFSET: u16 = 0x201;
+const TAG_JPEG_LENGTH: u16 = 0x202;
+
+/// Compression values for JPEG data stored as a TIFF strip (old-style and new-style).
+const JPEG_COMPRESSION: [u32; 2] = [6, 7];
+
+/// PhotometricInterpretation values for sensor data (DNG CFA and LinearRaw).
+const RAW_PHOTOMETRIC: [u32; 2] = [32803, 34892];
+
+/// Guards against malformed files whose IFDs point at each other.
+const MAX_IFDS: usize = 32;
+
+/// Frame type and size of a JPEG, from its start-of-frame marker.
+fn jpeg_frame(jpeg: &[u8]) -> Option<(u8, u32, u32)> {
+    if !jpeg.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut offset = 2;
+    loop {
+        let marker = *jpeg.get(offset + 1)?;
+        if jpeg[offset] != 0xff {
+            return None;
+        }
+        let length = u16::from_be_bytes(jpeg.get(offset + 2..offset + 4)?.try_into().ok()?);
+        // SOF0..SOF15, except DHT (C4), JPG (C8) and DAC (CC).
+        if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let frame = jpeg.get(offset + 4..offset + 9)?;
+            let height = u16::from_be_bytes([frame[1], frame[2]]);
+            let width = u16::from_be_bytes([frame[3], frame[4]]);
+            return Some((marker, u32::from(width), u32::from(height)));
+        }
+        offset += 2 + length as usize;
+    }
+}
+
+struct Preview<'a> {
+    jpeg: &'a [u8],
+    /// EXIF orientation of the main image (1 = upright).
+    orientation: u32,
+}
+
+/// The largest embedded (non-lossless) JPEG, if it's at least as big as the container's
+/// main image. Plain TIFFs (main image bigger than any thumbnail) and files without
+/// previews yield `None`.
+fn find_preview(data: &[u8]) -> Option<Preview<'_>> {
+    let (tiff, first_ifd) = Tiff::parse(data)?;
+    let (ifd0, _) = tiff.ifd(first_ifd)?;
+    // Some DNGs keep the sensor data itself in IFD0; that doesn't count as a picture.
+    let main_area = if tiff
+        .first(&ifd0, TAG_PHOTOMETRIC)
+        .is_some_and(|photometric| RAW_PHOTOMETRIC.contains(&photometric))
+    {
+        0
+    } else {
+        u64::from(tiff.first(&ifd0, TAG_IMAGE_WIDTH).unwrap_or(0))
+            * u64::from(tiff.first(&ifd0, TAG_IMAGE_LENGTH).unwrap_or(0))
+    };
+    let orientation = tiff.first(&ifd0, TAG_ORIENTATION).unwrap_or(1);
+
+    let mut pending = vec![first_ifd];
+    let mut visited = Vec::new();
+    let mut best: Option<(u64, &[u8])> = None;
+
+    while let Some(offset) = pending.pop() {
+        if offset == 0 || visited.contains(&offset) || visited.len() >= MAX_IFDS {
+            continue;
+        }
+        visited.push(offset);
+        let Some((entries, next)) = tiff.ifd(offset) else {
+            continue;
+        };
+        pending.push(next);
+        pending.extend(tiff.values(&entries, TAG_SUB_IFDS).iter().map(|&o| o as usize));
+
+        let mut candidates = Vec::new();
+        if let (Some(start), Some(length)) = (
+            tiff.first(&entries, TAG_JPEG_OFFSET),
+            tiff.first(&entries, TAG_JPEG_LENGTH),
+        ) {
+            candidates.push((start, length));
+        }
+        let compression = tiff.first(&entries, TAG_COMPRESSION);
+        let strips = tiff.values(&entries, TAG_STRIP_OFFSETS);
+        if compression.is_some_and(|c| JPEG_COMPRESSION.contains(&c)) && strips.len() == 1 {
+            if let Some(length) = tiff.first(&entries, TAG_STRIP_BYTE_COUNTS) {
+                candidates.push((strips[0], length));
+            }
+        }
+
+        for (start, length) in candidates {
+            let end = (start as usize).checked_add(length as usize);
+            let Some(jpeg) = end.and_then(|end| data.get(start as usize..end)) else {
+                continue;
+            };
+            // Baseline, extended and progressive only: lossless frames (C3) are the
+            // sensor data itself, not a picture.
+            let Some((0xc0..=0xc2, width, height)) = jpeg_frame(jpeg) else {
+                continue;
+            };
+            let area = u64::from(width) * u64::from(height);
+            if best.is_none_or(|(best_area, _)| area > best_area) {
+                best = Some((area, jpeg));
+            }
+        }
+    }
+
+    let (area, jpeg) = best?;
+    (area >= main_area).then_some(Preview { jpeg, orientation })
+}
+
+/// Decodes a camera RAW file from its embedded preview, turned upright. Returns `None` for
+/// anything that isn't a RAW file with a usable preview.
+pub fn decode(data: &[u8]) -> ImageResult<Option<DynamicImage>> {
+    let Some(preview) = find_preview(data) else {
+        return Ok(None);
+    };
+    crate::imageproc::check_dimensions(preview.jpeg)?;
+    let image = crate::jpeg::decode(preview.jpeg, None)?;
+    Ok(Some(orientation::apply(image, preview.orientation)))
+}
@@ -0,0 +1,82 @@
+// Runtime reload on SIGHUP, the way daemons usually behave: re-read `.env` (on top of the
+// environment the process started with) and the config file, with the command-line flags on
+// top again (see `settings`), and apply the settings that are safe to change while serving.
+// The process environment is left as it is; the new values replace the ones `settings::var`
+// gives out.
+//
+// Reloaded: GEMINI_API_KEY (key rotation), TENANT_WEIGHTS, RUST_LOG, LOG_PROVIDER_TRAFFIC, the
+// FASHION_ATTRIBUTES_FILE vocabulary, the PRICE_TABLE_FILE prices and the POLICY_FILE rules.
+// ADMIN_TOKEN, the API keys and SSO settings, ENABLE_VEHICLE_MODE, the timeouts, the rate limit,
+// DEFAULT_MODE and DEFAULT_LANGUAGE and the provider retry and circuit breaker settings are read
+// per request, so they follow the new configuration too. Everything else (host and port, limits,
+// concurrency, DATABASE_URL) still needs a restart.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::config::{parse_flag, LOG_PROVIDER_TRAFFIC};
+use crate::{fashion, logging, policy, pricing, scheduler, settings, CaptionOptions};
+use crate::server::AppState;
+
+/// Applies LOG_PROVIDER_TRAFFIC (default on); an unrecognized value leaves it unchanged.
+pub fn apply_log_settings() {
+    let Ok(value) = settings::var("LOG_PROVIDER_TRAFFIC") else {
+        LOG_PROVIDER_TRAFFIC.store(true, Ordering::Relaxed);
+        return;
+    };
+    match parse_flag(&value) {
+        Some(enabled) => LOG_PROVIDER_TRAFFIC.store(enabled, Ordering::Relaxed),
+        None => tracing::warn!("Ignoring LOG_PROVIDER_TRAFFIC={:?}; expected true or false", value),
+    }
+}
+
+fn reload(state: &AppState) {
+    if let Err(e) = settings::reload() {
+        tracing::warn!("{}; keeping the values it gave before", e);
+    }
+    logging::reload();
+    if let Err(e) = CaptionOptions::configured() {
+        tracing::warn!("{}; uploads fail until it is fixed", e);
+    }
+
+    apply_log_settings();
+    state.scheduler.set_weights(scheduler::weights_from_env());
+    if let Err(e) = fashion::reload() {
+        tracing::warn!("{}; keeping the current fashion attributes", e);
+    }
+    if let Err(e) = pricing::reload() {
+        tracing::warn!("{}; keeping the current prices", e);
+    }
+    if let Err(e) = policy::reload() {
+        tracing::warn!("{}; keeping the current content policy", e);
+    }
+    match settings::var("GEMINI_API_KEY") {
+        Ok(key) if !key.trim().is_empty() => {
+            *state.api_key.write().expect("api key lock poisoned") = key;
+        }
+        _ => tracing::warn!("GEMINI_API_KEY is missing after reload; keeping the current key"),
+    }
+
+    tracing::info!("Configuration reloaded");
+}
+
+/// Reloads the configuration every time the process receives SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup(state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::warn!("Could not listen for SIGHUP; configuration reload is disabled: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        reload(&state);
+    }
+}
+
+/// SIGHUP doesn't exist here; settings are read once at startup.
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_state: Arc<AppState>) {}
@@ -0,0 +1,51 @@
+// Request IDs, for matching a failure a client reports with the server's logs and the
+// provider's. Every request gets one: the caller's `X-Request-Id` when it sends a usable one
+// (so an ID set by a proxy in front carries through), otherwise a new random one. The ID is
+// logged with everything the request logs (see `logging`), returned in the `X-Request-Id`
+// response header and in `request_id` of captions and error bodies, and sent to the provider
+// as `X-Request-Id`.
+//
+// The ID is kept in a task-local while the request runs; work the request hands to a task of
+// its own takes it along with `carry`.
+
+use axum::http::{HeaderMap, HeaderName};
+use std::future::Future;
+use tracing::Instrument;
+
+pub(crate) const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest caller ID taken as it is; the same limit as upload tokens.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: Option<String>;
+}
+
+/// The ID of the request being handled, if any.
+pub(crate) fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok().flatten()
+}
+
+/// The caller's ID when it is 1 to `MAX_LEN` visible ASCII characters, otherwise a new one.
+pub(crate) fn from_headers(headers: &HeaderMap) -> String {
+    let sent = headers.get(&HEADER).and_then(|value| value.to_str().ok()).map(str::trim);
+    match sent {
+        Some(id) if is_usable(id) => id.to_string(),
+        _ => format!("{:032x}", rand::random::<u128>()),
+    }
+}
+
+fn is_usable(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Runs `future` as part of request `id`.
+pub(crate) fn scope<F: Future>(id: String, future: F) -> impl Future<Output = F::Output> {
+    REQUEST_ID.scope(Some(id), future)
+}
+
+/// `future` with the current request's ID and logging span, for running in a task of its own.
+pub(crate) fn carry<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let future = future.instrument(tracing::Span::current());
+    REQUEST_ID.scope(current(), future)
+}
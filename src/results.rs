@@ -0,0 +1,341 @@
+// The fields each mode adds to a caption result, as typed structs. `parse_reply` fills them in
+// and flattens them into the response next to `caption`, so the shape of every mode is fixed
+// here at compile time rather than by whatever a parser happened to insert. Library users can
+// read a result's fields back with `CaptionResponse::fields`, and the JSON Schema of each mode
+// is generated from these types and served as `mode-<mode>.v1.json` (see `schemas`).
+//
+// Field doc comments end up as the schema descriptions. Terms from a fixed vocabulary are
+// strings here, with the vocabulary added to the schema as an `enum`.
+
+use schemars::{generate::SchemaSettings, JsonSchema, Schema};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::modes::{
+    Mode, AUDIT_ISSUES, DIALOGUE_KINDS, MOON_PHASES, READING_DIRECTIONS, REGION_TYPES,
+    SEVERITIES, SKY_OBJECT_KINDS,
+};
+use crate::{art, fashion, listing, nature, vehicle};
+
+/// A vocabulary as a schema `enum`, with null when the field may be missing.
+fn terms<T: AsRef<str>>(allowed: &[T], nullable: bool) -> Value {
+    let mut terms: Vec<Value> = allowed.iter().map(|term| term.as_ref().into()).collect();
+    if nullable {
+        terms.push(Value::Null);
+    }
+    terms.into()
+}
+
+fn audit_issues() -> Value {
+    terms(&AUDIT_ISSUES.iter().map(|(issue, _)| *issue).collect::<Vec<_>>(), false)
+}
+
+/// `caption` mode adds no fields.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct CaptionFields {}
+
+/// `alt_text` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AltTextFields {
+    /// At most 125 characters; empty when the image is decorative.
+    pub alt_text: String,
+    /// Whether the image is decorative and should be hidden from screen readers (`alt=""`).
+    pub decorative: bool,
+}
+
+/// `hashtags` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct HashtagsFields {
+    /// Most relevant first, each starting with `#`.
+    #[schemars(extend("items" = {"type": "string", "pattern": "^#\\S+$"}))]
+    pub hashtags: Vec<String>,
+    /// Lowercase search keywords, most relevant first.
+    pub keywords: Vec<String>,
+}
+
+/// `title_description` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct TitleDescriptionFields {
+    /// At most 60 characters, without a closing period.
+    pub title: String,
+    /// The same text as `caption`.
+    pub description: String,
+}
+
+/// `accessibility_audit` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AccessibilityAuditFields {
+    /// Most severe first.
+    pub findings: Vec<AuditFinding>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AuditFinding {
+    #[schemars(extend("enum" = audit_issues()))]
+    pub issue: String,
+    #[schemars(extend("enum" = terms(SEVERITIES, false)))]
+    pub severity: String,
+    /// The element the finding is about; empty when it concerns the whole screen.
+    pub element: String,
+    /// What is wrong and how to fix it.
+    pub detail: String,
+    /// The WCAG 2.2 success criterion, e.g. "1.4.3"; null for `other` issues.
+    pub wcag: Option<String>,
+}
+
+/// `document_layout` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DocumentLayoutFields {
+    /// Text columns on the page, at most 12; null when unclear.
+    #[schemars(range(min = 1, max = 12))]
+    pub columns: Option<u64>,
+    /// In reading order.
+    pub regions: Vec<LayoutRegion>,
+    /// All the text on the page, in reading order.
+    pub ocr_text: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct LayoutRegion {
+    #[serde(rename = "type")]
+    #[schemars(extend("enum" = terms(REGION_TYPES, false)))]
+    pub kind: String,
+    #[serde(rename = "box")]
+    pub position: PageBox,
+    pub text: String,
+    /// Headings only: 1-6.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 1, max = 6))]
+    pub level: Option<u64>,
+    /// Tables only: rows of cell text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cells: Option<Vec<Vec<String>>>,
+}
+
+/// Where something is, as fractions of the page or image from its top left corner.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PageBox {
+    #[schemars(range(min = 0.0, max = 1.0))]
+    pub x: f64,
+    #[schemars(range(min = 0.0, max = 1.0))]
+    pub y: f64,
+    #[schemars(range(min = 0.0, max = 1.0))]
+    pub width: f64,
+    #[schemars(range(min = 0.0, max = 1.0))]
+    pub height: f64,
+}
+
+/// `comic` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ComicFields {
+    /// Null when unclear.
+    #[schemars(extend("enum" = terms(READING_DIRECTIONS, true)))]
+    pub reading_direction: Option<String>,
+    /// In reading order.
+    pub comic_panels: Vec<ComicPanel>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ComicPanel {
+    /// 1 for the first panel read.
+    #[schemars(range(min = 1))]
+    pub order: usize,
+    #[serde(rename = "box")]
+    pub position: PageBox,
+    pub description: String,
+    /// The panel's lettering in reading order.
+    pub dialogue: Vec<Lettering>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Lettering {
+    #[schemars(extend("enum" = terms(DIALOGUE_KINDS, false)))]
+    pub kind: String,
+    /// Who speaks or thinks it; null for narration and sound effects, or when unclear.
+    pub speaker: Option<String>,
+    pub text: String,
+}
+
+/// `fashion` mode. The vocabularies are the server's (FASHION_ATTRIBUTES_FILE).
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct FashionFields {
+    /// Null when unclear.
+    #[schemars(extend("enum" = terms(&fashion::attributes().garment_types, true)))]
+    pub garment_type: Option<String>,
+    /// The main color.
+    #[schemars(extend("enum" = terms(&fashion::attributes().colors, true)))]
+    pub color: Option<String>,
+    pub secondary_colors: Vec<String>,
+    #[schemars(extend("enum" = terms(&fashion::attributes().patterns, true)))]
+    pub pattern: Option<String>,
+    /// As guessed from the photo.
+    #[schemars(extend("enum" = terms(&fashion::attributes().materials, true)))]
+    pub material: Option<String>,
+    /// Most fitting first.
+    #[schemars(length(max = fashion::MAX_STYLE_TAGS))]
+    pub style_tags: Vec<String>,
+}
+
+/// `vehicle` mode. License plates are never included; text that looks like one reads
+/// "[plate redacted]".
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct VehicleFields {
+    /// Most prominent first.
+    #[schemars(length(max = vehicle::MAX_VEHICLES))]
+    pub vehicles: Vec<DescribedVehicle>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DescribedVehicle {
+    /// Null when it can't be told from the photo.
+    pub make: Option<String>,
+    pub model: Option<String>,
+    #[schemars(extend("enum" = terms(vehicle::COLORS, true)))]
+    pub color: Option<String>,
+    #[schemars(extend("enum" = terms(vehicle::BODY_TYPES, true)))]
+    pub body_type: Option<String>,
+    #[schemars(extend("enum" = terms(vehicle::DAMAGE_SEVERITIES, false)))]
+    pub damage_severity: String,
+    /// The visible damage; null when there is none.
+    pub damage: Option<String>,
+}
+
+/// `real_estate` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RealEstateFields {
+    #[schemars(extend("enum" = terms(listing::ROOM_TYPES, false)))]
+    pub room_type: String,
+    /// Selling points, most notable first.
+    #[schemars(length(max = listing::MAX_FEATURES))]
+    pub features: Vec<String>,
+}
+
+/// `nature` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct NatureFields {
+    /// Candidate species, most likely first.
+    #[schemars(length(max = nature::MAX_SPECIES))]
+    pub species: Vec<Species>,
+    /// Look-alikes or doubts, always ending with a warning not to rely on the identification.
+    pub caution: String,
+    /// Only with the `observation` option.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observation: Option<Observation>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Species {
+    pub common_name: Option<String>,
+    /// Capitalized the usual way, e.g. "Vulpes vulpes".
+    pub scientific_name: String,
+    /// The rank the identification stops at.
+    #[schemars(extend("enum" = terms(nature::RANKS, false)))]
+    pub rank: String,
+    #[schemars(range(min = 0.0, max = 1.0))]
+    pub confidence: Option<f64>,
+}
+
+/// iNaturalist-style observation fields, with the `observation` option; each is null when the
+/// photo doesn't show it.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Observation {
+    #[schemars(extend("enum" = terms(nature::ICONIC_TAXA, true)))]
+    pub iconic_taxon: Option<String>,
+    #[schemars(range(min = 1))]
+    pub individual_count: Option<u64>,
+    #[schemars(extend("enum" = terms(nature::LIFE_STAGES, true)))]
+    pub life_stage: Option<String>,
+    #[schemars(extend("enum" = terms(nature::SEXES, true)))]
+    pub sex: Option<String>,
+    #[schemars(extend("enum" = terms(nature::EVIDENCE, true)))]
+    pub evidence: Option<String>,
+    pub alive: Option<bool>,
+    pub captive: Option<bool>,
+}
+
+/// `astronomy` mode.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AstronomyFields {
+    /// Null unless the Moon is among the sky objects.
+    #[schemars(extend("enum" = terms(MOON_PHASES, true)))]
+    pub moon_phase: Option<String>,
+    #[schemars(length(max = crate::modes::MAX_SKY_OBJECTS))]
+    pub sky_objects: Vec<SkyObject>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SkyObject {
+    #[schemars(extend("enum" = terms(SKY_OBJECT_KINDS, false)))]
+    pub kind: String,
+    /// E.g. "Orion Nebula"; planets are always one of the seven by their English name.
+    pub name: Option<String>,
+    /// E.g. "M42".
+    pub catalog_id: Option<String>,
+    /// Null when the object couldn't be placed.
+    #[serde(rename = "box")]
+    pub position: Option<PageBox>,
+}
+
+/// `art` mode. The artist is never named in `caption`; see `attribution_guess`.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ArtFields {
+    #[schemars(extend("enum" = terms(art::MEDIA, true)))]
+    pub medium: Option<String>,
+    /// Styles or movements, most fitting first.
+    #[schemars(length(max = art::MAX_STYLES))]
+    pub styles: Vec<String>,
+    /// Notes on the composition.
+    #[schemars(length(max = art::MAX_COMPOSITION_NOTES))]
+    pub composition: Vec<String>,
+    pub era: Option<Era>,
+    /// Null when the model named no artist.
+    pub attribution_guess: Option<AttributionGuess>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Era {
+    /// E.g. "late 19th century".
+    pub description: String,
+    /// Years before the common era are negative.
+    pub from_year: Option<i64>,
+    pub to_year: Option<i64>,
+}
+
+/// Who may have made the artwork: a guess from a photo, never an attribution.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AttributionGuess {
+    pub artist: String,
+    /// Why the model guessed this artist.
+    pub basis: Option<String>,
+    /// Always true.
+    #[schemars(extend("const" = true))]
+    pub uncertain: bool,
+}
+
+/// The fields as the `details` flattened into a caption response.
+pub(crate) fn details(fields: &impl Serialize) -> Map<String, Value> {
+    match serde_json::to_value(fields) {
+        Ok(Value::Object(details)) => details,
+        _ => unreachable!("result fields always serialize as objects"),
+    }
+}
+
+/// The JSON Schema of `mode`'s fields (draft 2020-12).
+pub fn schema(mode: Mode) -> Schema {
+    let generator = SchemaSettings::draft2020_12().into_generator();
+    match mode {
+        Mode::Caption => generator.into_root_schema_for::<CaptionFields>(),
+        Mode::AltText => generator.into_root_schema_for::<AltTextFields>(),
+        Mode::Hashtags => generator.into_root_schema_for::<HashtagsFields>(),
+        Mode::TitleDescription => generator.into_root_schema_for::<TitleDescriptionFields>(),
+        Mode::AccessibilityAudit => generator.into_root_schema_for::<AccessibilityAuditFields>(),
+        Mode::DocumentLayout => generator.into_root_schema_for::<DocumentLayoutFields>(),
+        Mode::Comic => generator.into_root_schema_for::<ComicFields>(),
+        Mode::Fashion => generator.into_root_schema_for::<FashionFields>(),
+        Mode::Vehicle => generator.into_root_schema_for::<VehicleFields>(),
+        Mode::RealEstate => generator.into_root_schema_for::<RealEstateFields>(),
+        Mode::Nature => generator.into_root_schema_for::<NatureFields>(),
+        Mode::Astronomy => generator.into_root_schema_for::<AstronomyFields>(),
+        Mode::Art => generator.into_root_schema_for::<ArtFields>(),
+    }
+}
@@ -0,0 +1,560 @@
+// `review DIR`: a terminal UI for checking captions before any metadata is written. It walks
+// the folder one image at a time, shows the generated caption (or the one already in the
+// image's sidecar), and lets the reviewer accept it, edit it, regenerate it or skip the
+// image. Only accepted captions are written, as a JSON sidecar and optionally an XMP one.
+//
+// The current and next images are captioned in the background so the reviewer rarely waits.
+
+use clap::Args;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::batch::sidecar::{self, Sidecar};
+use crate::batch::{existing_xmp, is_image, XmpNaming};
+use crate::cli::{fail, ErrorKind, OptionArgs, OutputFormat};
+use crate::modes::CaptionOptions;
+use crate::config::LOG_PROVIDER_TRAFFIC;
+use crate::imageproc::content_hash;
+use crate::{caption_image_bytes, xmp, BoxError, CaptionResponse};
+
+/// How long to wait for a key press before checking for finished captions.
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Args)]
+pub struct ReviewArgs {
+    /// Directory of images to review, including subdirectories
+    pub dir: PathBuf,
+
+    /// Also write an XMP sidecar for each accepted caption. Lightroom naming (photo.xmp) is
+    /// the default; darktable expects photo.jpg.xmp
+    #[arg(long, value_enum, value_name = "NAMING", num_args = 0..=1, require_equals = true,
+          default_missing_value = "lightroom")]
+    pub xmp: Option<XmpNaming>,
+
+    /// Only review images that don't have a JSON sidecar yet
+    #[arg(long)]
+    pub new_only: bool,
+
+    #[command(flatten)]
+    pub options: OptionArgs,
+}
+
+enum Caption {
+    /// Not requested yet.
+    Pending,
+    Generating,
+    Ready {
+        response: Box<CaptionResponse>,
+        content_hash: String,
+        /// Loaded from a current sidecar rather than generated in this session.
+        existing: bool,
+        edited: bool,
+    },
+    Failed(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    Undecided,
+    Accepted,
+    Skipped,
+}
+
+struct Item {
+    path: PathBuf,
+    caption: Caption,
+    decision: Decision,
+}
+
+/// A finished background caption for the item at `index`.
+struct Loaded {
+    index: usize,
+    result: Result<(CaptionResponse, String, bool), String>,
+}
+
+/// Text being edited, with the cursor as a character offset.
+struct Editor {
+    text: String,
+    cursor: usize,
+}
+
+impl Editor {
+    fn new(text: &str) -> Self {
+        Editor {
+            text: text.to_string(),
+            cursor: text.chars().count(),
+        }
+    }
+
+    fn byte_index(&self) -> usize {
+        self.text
+            .char_indices()
+            .nth(self.cursor)
+            .map_or(self.text.len(), |(index, _)| index)
+    }
+
+    /// Applies an editing key; other keys are ignored.
+    fn handle(&mut self, code: KeyCode) {
+        let len = self.text.chars().count();
+        match code {
+            KeyCode::Char(c) => {
+                let at = self.byte_index();
+                self.text.insert(at, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                let at = self.byte_index();
+                self.text.remove(at);
+            }
+            KeyCode::Delete if self.cursor < len => {
+                let at = self.byte_index();
+                self.text.remove(at);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(len),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = len,
+            _ => {}
+        }
+    }
+}
+
+/// Settings shared by the background caption tasks.
+struct Settings {
+    options: CaptionOptions,
+    api_key: String,
+    prompt_hash: String,
+}
+
+struct Review {
+    items: Vec<Item>,
+    current: usize,
+    settings: Arc<Settings>,
+    xmp: Option<XmpNaming>,
+    editor: Option<Editor>,
+    /// Result of the last action, shown above the key help.
+    status: String,
+    loaded_tx: mpsc::UnboundedSender<Loaded>,
+    loaded: mpsc::UnboundedReceiver<Loaded>,
+}
+
+/// Reads the image and returns its current sidecar's result, or a fresh caption.
+async fn load(
+    path: &Path,
+    settings: &Settings,
+    regenerate: bool,
+) -> Result<(CaptionResponse, String, bool), BoxError> {
+    let data = tokio::fs::read(path).await?;
+    let hash = content_hash(&data);
+    if !regenerate && sidecar::is_current(path, &hash, &settings.prompt_hash) {
+        if let Some(existing) = sidecar::existing(path) {
+            let existing: Sidecar = serde_json::from_value(existing)?;
+            return Ok((existing.response, hash, true));
+        }
+    }
+    let (response, _usage) =
+        caption_image_bytes(&data, &settings.options, &settings.api_key).await?;
+    Ok((response, hash, false))
+}
+
+impl Review {
+    fn counts(&self) -> (usize, usize) {
+        let count = |decision| {
+            self.items
+                .iter()
+                .filter(|item| item.decision == decision)
+                .count()
+        };
+        (count(Decision::Accepted), count(Decision::Skipped))
+    }
+
+    /// Starts captioning the item at `index` in the background.
+    fn start(&mut self, index: usize, regenerate: bool) {
+        let item = &mut self.items[index];
+        item.caption = Caption::Generating;
+        let path = item.path.clone();
+        let settings = Arc::clone(&self.settings);
+        let loaded = self.loaded_tx.clone();
+        tokio::spawn(async move {
+            let result = load(&path, &settings, regenerate)
+                .await
+                .map_err(|e| e.to_string());
+            let _ = loaded.send(Loaded { index, result });
+        });
+    }
+
+    /// Makes sure the current and next images are being captioned.
+    fn prefetch(&mut self) {
+        for index in [self.current, self.current + 1] {
+            if self
+                .items
+                .get(index)
+                .is_some_and(|item| matches!(item.caption, Caption::Pending))
+            {
+                self.start(index, false);
+            }
+        }
+    }
+
+    fn finish_load(&mut self, loaded: Loaded) {
+        self.items[loaded.index].caption = match loaded.result {
+            Ok((response, content_hash, existing)) => Caption::Ready {
+                response: Box::new(response),
+                content_hash,
+                existing,
+                edited: false,
+            },
+            Err(message) => Caption::Failed(message),
+        };
+    }
+
+    /// Moves to the next undecided image after the current one, if any.
+    fn advance(&mut self) {
+        let next = (self.current + 1..self.items.len())
+            .find(|&index| self.items[index].decision == Decision::Undecided);
+        if let Some(next) =
+            next.or((self.current + 1 < self.items.len()).then_some(self.current + 1))
+        {
+            self.current = next;
+        }
+    }
+
+    fn accept(&mut self) {
+        let item = &mut self.items[self.current];
+        let Caption::Ready {
+            response,
+            content_hash,
+            ..
+        } = &item.caption
+        else {
+            self.status = "Nothing to accept yet".to_string();
+            return;
+        };
+        let sidecar = Sidecar {
+            content_hash: content_hash.clone(),
+            prompt_hash: self.settings.prompt_hash.clone(),
+            response: response.as_ref().clone(),
+        };
+        if let Err(e) = write(&item.path, &sidecar, self.xmp) {
+            self.status = format!("❌ {}", e);
+            return;
+        }
+        item.decision = Decision::Accepted;
+        self.status = format!("✅ Wrote metadata for {}", item.path.display());
+        self.advance();
+    }
+
+    /// Replaces the caption with the reviewer's text. Mode fields that mirror the caption
+    /// (alt text, description) follow it.
+    fn apply_edit(&mut self, text: String) {
+        if let Caption::Ready {
+            response, edited, ..
+        } = &mut self.items[self.current].caption
+        {
+            let text = text.trim().to_string();
+            for value in response.details.values_mut() {
+                if value.as_str() == Some(response.caption.as_str()) {
+                    *value = Value::String(text.clone());
+                }
+            }
+            response.caption = text;
+            *edited = true;
+        }
+    }
+
+    /// Handles one key press; returns false when the reviewer quits.
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if let Some(editor) = &mut self.editor {
+            match key.code {
+                KeyCode::Enter => {
+                    let text = self
+                        .editor
+                        .take()
+                        .map(|editor| editor.text)
+                        .unwrap_or_default();
+                    self.apply_edit(text);
+                    self.status = "Caption edited; Enter to accept".to_string();
+                }
+                KeyCode::Esc => {
+                    self.editor = None;
+                    self.status = "Edit cancelled".to_string();
+                }
+                code => editor.handle(code),
+            }
+            return true;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Enter | KeyCode::Char('a') => self.accept(),
+            KeyCode::Char('e') => match &self.items[self.current].caption {
+                Caption::Ready { response, .. } => {
+                    self.editor = Some(Editor::new(&response.caption))
+                }
+                _ => self.status = "Nothing to edit yet".to_string(),
+            },
+            KeyCode::Char('r') => {
+                if matches!(self.items[self.current].caption, Caption::Generating) {
+                    self.status = "Already generating".to_string();
+                } else {
+                    self.start(self.current, true);
+                    self.status = "Regenerating…".to_string();
+                }
+            }
+            KeyCode::Char('s') => {
+                self.items[self.current].decision = Decision::Skipped;
+                self.status = format!("Skipped {}", self.items[self.current].path.display());
+                self.advance();
+            }
+            KeyCode::Right | KeyCode::Char('n') => {
+                self.current = (self.current + 1).min(self.items.len() - 1);
+            }
+            KeyCode::Left | KeyCode::Char('p') => self.current = self.current.saturating_sub(1),
+            _ => {}
+        }
+        true
+    }
+
+    async fn run(&mut self, terminal: &mut DefaultTerminal) -> std::io::Result<()> {
+        loop {
+            while let Ok(loaded) = self.loaded.try_recv() {
+                self.finish_load(loaded);
+            }
+            self.prefetch();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            // Polling blocks; keep it off the threads the caption tasks run on.
+            if !tokio::task::block_in_place(|| event::poll(TICK))? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.handle_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, body, details, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(5),
+            Constraint::Length(6),
+            Constraint::Length(2),
+        ])
+        .areas(frame.area());
+
+        let item = &self.items[self.current];
+        let (accepted, skipped) = self.counts();
+        let decision = match item.decision {
+            Decision::Undecided => Span::raw(""),
+            Decision::Accepted => " accepted".green(),
+            Decision::Skipped => " skipped".yellow(),
+        };
+        frame.render_widget(
+            Line::from(vec![
+                format!("{}/{} ", self.current + 1, self.items.len()).bold(),
+                Span::raw(item.path.display().to_string()),
+                decision,
+                format!("   {} accepted, {} skipped", accepted, skipped).dark_gray(),
+            ]),
+            header,
+        );
+
+        let (title, text) = match (&self.editor, &item.caption) {
+            (Some(editor), _) => {
+                let at = editor.byte_index();
+                let line = Line::from(vec![
+                    Span::raw(&editor.text[..at]),
+                    Span::styled("▏", Style::default().fg(Color::Cyan)),
+                    Span::raw(&editor.text[at..]),
+                ]);
+                (
+                    " Editing (Enter to keep, Esc to cancel) ".to_string(),
+                    Text::from(line),
+                )
+            }
+            (None, Caption::Pending | Caption::Generating) => (
+                " Caption ".to_string(),
+                Text::from("Generating…".dark_gray()),
+            ),
+            (None, Caption::Failed(message)) => (
+                " Caption ".to_string(),
+                Text::from(format!("❌ {}", message).red()),
+            ),
+            (
+                None,
+                Caption::Ready {
+                    response,
+                    existing,
+                    edited,
+                    ..
+                },
+            ) => {
+                let source = match (edited, existing) {
+                    (true, _) => " (edited)",
+                    (false, true) => " (from sidecar)",
+                    (false, false) => "",
+                };
+                (
+                    format!(" Caption{} ", source),
+                    Text::from(response.caption.as_str()),
+                )
+            }
+        };
+        frame.render_widget(
+            Paragraph::new(text)
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title(title)),
+            body,
+        );
+
+        let mut lines = Vec::new();
+        if let Caption::Ready { response, .. } = &item.caption {
+            for (key, value) in &response.details {
+                let value = match value {
+                    Value::String(text) if *text != response.caption => text.clone(),
+                    Value::Array(items) => items
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    Value::Number(number) => number.to_string(),
+                    _ => continue,
+                };
+                lines.push(Line::from(vec![
+                    format!("{}: ", key).bold(),
+                    Span::raw(value),
+                ]));
+            }
+        }
+        frame.render_widget(
+            Paragraph::new(lines)
+                .wrap(Wrap { trim: true })
+                .block(Block::bordered().title(" Details ")),
+            details,
+        );
+
+        let help = if self.editor.is_some() {
+            "←/→ move cursor · Enter keep edit · Esc cancel"
+        } else {
+            "Enter accept · e edit · r regenerate · s skip · ←/→ previous/next · q quit"
+        };
+        frame.render_widget(
+            Paragraph::new(vec![
+                Line::from(self.status.as_str()),
+                Line::from(help.dark_gray()),
+            ]),
+            footer,
+        );
+    }
+}
+
+/// Writes the accepted result's sidecars, refusing to replace an XMP another tool wrote.
+fn write(image: &Path, sidecar: &Sidecar, naming: Option<XmpNaming>) -> Result<(), BoxError> {
+    if let Some(target) = naming.map(|naming| naming.path(image)) {
+        existing_xmp(&target)?;
+        std::fs::write(
+            &target,
+            xmp::render(&xmp::XmpFields::from_response(&sidecar.response)),
+        )?;
+    }
+    sidecar::write(image, sidecar)?;
+    Ok(())
+}
+
+fn list_images(dir: &Path, new_only: bool) -> Vec<Item> {
+    walkdir::WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file() && is_image(entry.path()))
+        .map(walkdir::DirEntry::into_path)
+        .filter(|path| !new_only || !sidecar::sidecar_path(path).exists())
+        .map(|path| Item {
+            path,
+            caption: Caption::Pending,
+            decision: Decision::Undecided,
+        })
+        .collect()
+}
+
+/// Runs the `review` subcommand and returns the process exit code.
+pub async fn run_review(args: ReviewArgs) -> i32 {
+    let format = OutputFormat::Text;
+    if !std::io::stdout().is_terminal() {
+        return fail(
+            format,
+            ErrorKind::InvalidInput,
+            "review needs an interactive terminal",
+        );
+    }
+    let Ok(api_key) = crate::settings::var("GEMINI_API_KEY") else {
+        let message = "GEMINI_API_KEY must be set in the environment or .env file";
+        return fail(format, ErrorKind::ConfigError, message);
+    };
+    let options = match args.options.to_options() {
+        Ok(options) => options,
+        Err(message) => return fail(format, ErrorKind::InvalidInput, &message),
+    };
+    if !args.dir.is_dir() {
+        let message = format!("{} is not a directory", args.dir.display());
+        return fail(format, ErrorKind::InvalidInput, &message);
+    }
+
+    let items = list_images(&args.dir, args.new_only);
+    if items.is_empty() {
+        eprintln!("No images to review in {}", args.dir.display());
+        return 0;
+    }
+
+    // Provider logging would scribble over the interface.
+    LOG_PROVIDER_TRAFFIC.store(false, Ordering::Relaxed);
+    let (loaded_tx, loaded) = mpsc::unbounded_channel();
+    let mut review = Review {
+        items,
+        current: 0,
+        settings: Arc::new(Settings {
+            prompt_hash: content_hash(options.fingerprint().as_bytes()),
+            options,
+            api_key,
+        }),
+        xmp: args.xmp,
+        editor: None,
+        status: String::new(),
+        loaded_tx,
+        loaded,
+    };
+
+    let mut terminal = ratatui::init();
+    let result = review.run(&mut terminal).await;
+    ratatui::restore();
+
+    let (accepted, skipped) = review.counts();
+    eprintln!(
+        "📝 {} accepted, {} skipped, {} not reviewed",
+        accepted,
+        skipped,
+        review.items.len() - accepted - skipped
+    );
+    match result {
+        Ok(()) => 0,
+        Err(e) => fail(
+            format,
+            ErrorKind::ConfigError,
+            &format!("Terminal error: {}", e),
+        ),
+    }
+}
@@ -0,0 +1,121 @@
+// Cleanup for text that comes back from a provider, before it reaches a response, a sidecar
+// or embedded metadata. Models occasionally answer in Markdown, and stray control characters
+// or decomposed accents make strict downstream stores reject the string, so every caption
+// field is normalized to NFC plain text here.
+
+use serde_json::Value;
+use unicode_normalization::UnicodeNormalization;
+
+/// Zero-width and bidirectional formatting characters with no place in a caption. ZWJ and
+/// ZWNJ stay (emoji sequences and Persian need them), as do the LRM/RLM marks.
+fn is_invisible(c: char) -> bool {
+    matches!(c,
+        '\u{200B}'                // zero-width space
+        | '\u{202A}'..='\u{202E}' // bidi embeddings and overrides
+        | '\u{2060}'..='\u{2064}' // word joiner, invisible operators
+        | '\u{2066}'..='\u{2069}' // bidi isolates
+        | '\u{FEFF}'              // byte order mark
+    )
+}
+
+/// Drops Markdown block markers (headings, bullets, quotes) at the start of a line.
+fn strip_line_marker(line: &str) -> &str {
+    let trimmed = line.trim_start();
+    let heading = trimmed.trim_start_matches('#');
+    if heading.len() < trimmed.len() && heading.starts_with(' ') {
+        return heading.trim_start();
+    }
+    for marker in ["- ", "* ", "+ ", "• ", "> "] {
+        if let Some(rest) = trimmed.strip_prefix(marker) {
+            return rest;
+        }
+    }
+    line
+}
+
+/// Replaces `[text](url)` links with their text.
+fn strip_links(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find('[') {
+        let link = rest[open + 1..].find("](").and_then(|close| {
+            let label = &rest[open + 1..open + 1 + close];
+            let after = &rest[open + 1 + close + 2..];
+            let end = after.find(')')?;
+            (!label.contains('[')).then(|| (label, &after[end + 1..]))
+        });
+        match link {
+            Some((label, after)) => {
+                out.push_str(&rest[..open]);
+                out.push_str(label);
+                rest = after;
+            }
+            None => {
+                out.push_str(&rest[..=open]);
+                rest = &rest[open + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Removes Markdown emphasis and code markers. Single asterisks only count as emphasis when
+/// they pair up, and underscores only when doubled, so `5 * 3` and `snake_case` survive.
+fn strip_emphasis(text: &str) -> String {
+    let mut text = text.replace("**", "").replace("__", "").replace('`', "");
+    if text.matches('*').count().is_multiple_of(2) {
+        text = text.replace('*', "");
+    }
+    text
+}
+
+/// Turns provider text into NFC plain text: no Markdown, no control or invisible formatting
+/// characters, single spaces and at most one blank line between paragraphs.
+pub fn clean(text: &str) -> String {
+    let text: String = text.replace("\r\n", "\n").nfc().collect();
+
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            let line = strip_emphasis(&strip_links(strip_line_marker(line)));
+            let mut out = String::with_capacity(line.len());
+            for c in line.chars() {
+                let c = if c == '\t' { ' ' } else { c };
+                if c.is_control() || is_invisible(c) {
+                    continue;
+                }
+                if c.is_whitespace() && out.ends_with(char::is_whitespace) {
+                    continue;
+                }
+                out.push(c);
+            }
+            out.trim().to_string()
+        })
+        .collect();
+
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+    for line in lines {
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank { "\n\n" } else { "\n" });
+        }
+        out.push_str(&line);
+        blank = false;
+    }
+    out
+}
+
+/// Cleans every string in a parsed JSON reply, however deeply nested.
+pub fn clean_value(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = clean(text),
+        Value::Array(items) => items.iter_mut().for_each(clean_value),
+        Value::Object(fields) => fields.values_mut().for_each(clean_value),
+        _ => {}
+    }
+}
@@ -0,0 +1,420 @@
+// Fair sharing of provider calls between tenants. Every caption the server makes takes a
+// slot first; when all slots are busy, waiters are served in start-time fair queueing
+// order, so a tenant running a 50k-image job and a tenant uploading one photo take turns
+// instead of the photo waiting behind the whole job.
+//
+// A tenant is the caller's `X-Api-Key` header, or its IP address without one. TENANT_WEIGHTS
+// (`key-or-ip=weight,...`, default weight 1) gives some tenants a bigger share: weight 3 gets
+// three slots for every one a weight-1 tenant gets while both have work waiting. Weights
+// can be changed without a restart (see `reload`).
+//
+// PROVIDER_CONCURRENCY sets the number of slots (default 8) and PROVIDER_QUEUE_LIMIT how many
+// interactive requests may wait for one (default 64; 0 for no limit). Past that, requests are
+// turned away with 429 and a Retry-After instead of piling up in memory. Captions for
+// background jobs always queue, since the job has already been accepted. Both are read at
+// startup.
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::request::Parts,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use crate::config::{PROVIDER_CONCURRENCY, PROVIDER_QUEUE_LIMIT};
+use crate::auth::{Identity, KeyPolicy};
+use crate::jobs::JobEntry;
+use crate::{ratelimit, CaptionError};
+
+/// How strongly the newest call duration moves the average used for ETAs.
+const SERVICE_TIME_SMOOTHING: f64 = 0.2;
+
+/// Who a caption is being made for.
+#[derive(Clone)]
+pub struct Tenant {
+    pub name: String,
+    /// What the tenant may do, when it was authenticated; `None` for callers told apart by
+    /// header or address.
+    pub(crate) policy: Option<Arc<KeyPolicy>>,
+    /// The address the request came from, for the audit log.
+    pub(crate) ip: Option<IpAddr>,
+}
+
+impl Tenant {
+    pub fn new(name: impl Into<String>) -> Self {
+        Tenant {
+            name: name.into(),
+            policy: None,
+            ip: None,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tenant {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ip = ratelimit::client_ip(&parts.headers, &parts.extensions);
+        if let Some(identity) = parts.extensions.get::<Identity>() {
+            return Ok(Tenant {
+                name: identity.name.clone(),
+                policy: Some(identity.policy.clone()),
+                ip,
+            });
+        }
+        let name = match parts.headers.get("x-api-key").and_then(|key| key.to_str().ok()) {
+            Some(key) => key.to_string(),
+            None => ip.map_or_else(|| "anonymous".to_string(), |ip| ip.to_string()),
+        };
+        Ok(Tenant {
+            ip,
+            ..Tenant::new(name)
+        })
+    }
+}
+
+/// A tenant, plus the background job the caption belongs to (if any) so the job's queue
+/// position can be kept up to date.
+#[derive(Clone)]
+pub struct Caller {
+    tenant: Tenant,
+    job: Option<Arc<JobEntry>>,
+}
+
+impl Caller {
+    pub fn tenant(&self) -> &str {
+        &self.tenant.name
+    }
+
+    /// What the caller may do, when it was authenticated.
+    pub(crate) fn policy(&self) -> Option<&KeyPolicy> {
+        self.tenant.policy.as_deref()
+    }
+
+    /// The address of the request the caption was asked for with.
+    pub(crate) fn ip(&self) -> Option<IpAddr> {
+        self.tenant.ip
+    }
+
+    pub fn interactive(tenant: Tenant) -> Self {
+        Caller { tenant, job: None }
+    }
+
+    pub fn job(tenant: Tenant, job: Arc<JobEntry>) -> Self {
+        Caller {
+            tenant,
+            job: Some(job),
+        }
+    }
+}
+
+struct Waiter {
+    /// Virtual start time; the smallest tag is served next.
+    tag: f64,
+    /// Arrival order, to break ties.
+    seq: u64,
+    job: Option<Arc<JobEntry>>,
+    grant: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct Queue {
+    busy: usize,
+    virtual_time: f64,
+    /// Virtual finish time of each tenant's latest request. Tenants at or behind
+    /// `virtual_time` are dropped; they would be tagged the same without an entry.
+    finish: HashMap<String, f64>,
+    waiting: Vec<Waiter>,
+    next_seq: u64,
+    /// Smoothed time a slot is held, once anything has finished.
+    service_time: Option<Duration>,
+}
+
+impl Queue {
+    fn tag(&mut self, tenant: &str, weight: f64) -> f64 {
+        let virtual_time = self.virtual_time;
+        self.finish.retain(|_, finish| *finish > virtual_time);
+        let tag = self.finish.get(tenant).copied().unwrap_or(virtual_time);
+        self.finish.insert(tenant.to_string(), tag + 1.0 / weight);
+        tag
+    }
+
+    fn order(&self) -> Vec<&Waiter> {
+        let mut order: Vec<&Waiter> = self.waiting.iter().collect();
+        order.sort_by(|a, b| a.tag.total_cmp(&b.tag).then(a.seq.cmp(&b.seq)));
+        order
+    }
+
+    fn pop_next(&mut self) -> Option<Waiter> {
+        let seq = self.order().first()?.seq;
+        let index = self.waiting.iter().position(|waiter| waiter.seq == seq)?;
+        Some(self.waiting.swap_remove(index))
+    }
+}
+
+/// How many provider calls may run at once, and how many interactive requests may wait.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub concurrency: usize,
+    /// `None` queues without limit.
+    pub queue: Option<usize>,
+}
+
+impl Limits {
+    /// Reads PROVIDER_CONCURRENCY and PROVIDER_QUEUE_LIMIT, reporting unreadable values.
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: usize| match crate::settings::var(name) {
+            Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+                tracing::warn!("Ignoring {}={:?}; expected a whole number", name, value);
+                default
+            }),
+            Err(_) => default,
+        };
+        let queue = read("PROVIDER_QUEUE_LIMIT", PROVIDER_QUEUE_LIMIT);
+        Limits {
+            concurrency: read("PROVIDER_CONCURRENCY", PROVIDER_CONCURRENCY),
+            queue: (queue > 0).then_some(queue),
+        }
+    }
+}
+
+/// Reads tenant weights from TENANT_WEIGHTS, skipping (and reporting) malformed entries.
+pub fn weights_from_env() -> HashMap<String, f64> {
+    let mut weights = HashMap::new();
+    for entry in crate::settings::var("TENANT_WEIGHTS").unwrap_or_default().split(',') {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let parsed = entry
+            .rsplit_once('=')
+            .and_then(|(tenant, weight)| Some((tenant.trim(), weight.trim().parse().ok()?)))
+            .filter(|(_, weight): &(&str, f64)| *weight > 0.0);
+        match parsed {
+            Some((tenant, weight)) => {
+                weights.insert(tenant.to_string(), weight);
+            }
+            None => tracing::warn!("Ignoring malformed TENANT_WEIGHTS entry: {}", entry),
+        }
+    }
+    weights
+}
+
+/// The scheduler's current load, as reported by `GET /status`.
+#[derive(Serialize)]
+pub struct QueueStatus {
+    /// Provider calls allowed in flight at once.
+    pub capacity: usize,
+    pub in_flight: usize,
+    /// Requests waiting for a slot.
+    pub queued: usize,
+    /// Interactive requests allowed to wait before new ones are turned away.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_limit: Option<usize>,
+    /// Smoothed time one caption holds a slot; absent until something has finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_caption_ms: Option<u64>,
+    /// Rough wait for a slot if a request arrived now.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_wait_ms: Option<u64>,
+}
+
+pub struct Scheduler {
+    capacity: usize,
+    queue_limit: Option<usize>,
+    weights: RwLock<HashMap<String, f64>>,
+    queue: Mutex<Queue>,
+}
+
+impl Scheduler {
+    pub fn new(limits: Limits, weights: HashMap<String, f64>) -> Self {
+        Scheduler {
+            capacity: limits.concurrency.max(1),
+            queue_limit: limits.queue,
+            weights: RwLock::new(weights),
+            queue: Mutex::default(),
+        }
+    }
+
+    /// Replaces the tenant weights; requests already waiting keep their place.
+    pub fn set_weights(&self, weights: HashMap<String, f64>) {
+        *self.weights.write().expect("weights lock poisoned") = weights;
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        let queue = self.lock();
+        let estimated_wait = if queue.busy < self.capacity && queue.waiting.is_empty() {
+            Some(Duration::ZERO)
+        } else {
+            self.wait_behind(&queue, queue.waiting.len())
+        };
+        QueueStatus {
+            capacity: self.capacity,
+            in_flight: queue.busy,
+            queued: queue.waiting.len(),
+            queue_limit: self.queue_limit,
+            average_caption_ms: queue.service_time.map(|time| time.as_millis() as u64),
+            estimated_wait_ms: estimated_wait.map(|wait| wait.as_millis() as u64),
+        }
+    }
+
+    /// Rough wait for a slot with `ahead` requests in line and every slot busy.
+    fn wait_behind(&self, queue: &Queue, ahead: usize) -> Option<Duration> {
+        let rounds = (ahead / self.capacity + 1) as u32;
+        queue.service_time.map(|service_time| service_time * rounds)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().expect("scheduler lock poisoned")
+    }
+
+    /// Waits for a provider slot. The slot is held until the returned permit is dropped.
+    /// Interactive callers are turned away when the queue is full.
+    pub async fn acquire(&self, caller: &Caller) -> Result<Permit<'_>, CaptionError> {
+        let weight = self
+            .weights
+            .read()
+            .expect("weights lock poisoned")
+            .get(&caller.tenant.name)
+            .copied()
+            .unwrap_or(1.0);
+        let (grant, granted) = oneshot::channel();
+        let seq = {
+            let mut queue = self.lock();
+            let free = queue.busy < self.capacity && queue.waiting.is_empty();
+            if !free && caller.job.is_none() {
+                let waiting = queue.waiting.iter().filter(|waiter| waiter.job.is_none()).count();
+                if self.queue_limit.is_some_and(|limit| waiting >= limit) {
+                    let retry_after = self
+                        .wait_behind(&queue, queue.waiting.len())
+                        .unwrap_or(Duration::from_secs(1));
+                    return Err(CaptionError::Busy { retry_after });
+                }
+            }
+            let tag = queue.tag(&caller.tenant.name, weight);
+            if free {
+                queue.busy += 1;
+                queue.virtual_time = tag;
+                if let Some(job) = &caller.job {
+                    job.mark_started();
+                }
+                return Ok(Permit::new(self));
+            }
+            let seq = queue.next_seq;
+            queue.next_seq += 1;
+            queue.waiting.push(Waiter {
+                tag,
+                seq,
+                job: caller.job.clone(),
+                grant,
+            });
+            self.publish_positions(&queue);
+            seq
+        };
+
+        let mut waiting = Waiting {
+            scheduler: self,
+            seq,
+            granted: false,
+        };
+        // The sender is only dropped unsent when `Waiting` removes it, which can't happen
+        // while this future is still running.
+        let _ = granted.await;
+        waiting.granted = true;
+        Ok(Permit::new(self))
+    }
+
+    /// Hands a freed slot to the next waiter, or returns it to the pool.
+    fn release(&self, held: Option<Duration>) {
+        let mut queue = self.lock();
+        if let Some(held) = held {
+            queue.service_time = Some(match queue.service_time {
+                Some(average) => average.mul_f64(1.0 - SERVICE_TIME_SMOOTHING)
+                    + held.mul_f64(SERVICE_TIME_SMOOTHING),
+                None => held,
+            });
+        }
+
+        queue.busy -= 1;
+        while let Some(next) = queue.pop_next() {
+            queue.virtual_time = next.tag;
+            if next.grant.send(()).is_ok() {
+                queue.busy += 1;
+                if let Some(job) = &next.job {
+                    job.mark_started();
+                }
+                break;
+            }
+        }
+        self.publish_positions(&queue);
+    }
+
+    /// Tells each queued job where its next caption stands in line and roughly how long
+    /// until it starts.
+    fn publish_positions(&self, queue: &Queue) {
+        let mut seen: Vec<&Arc<JobEntry>> = Vec::new();
+        for (ahead, waiter) in queue.order().into_iter().enumerate() {
+            let Some(job) = &waiter.job else { continue };
+            if seen.iter().any(|other| Arc::ptr_eq(other, job)) {
+                continue;
+            }
+            seen.push(job);
+            job.set_queue_position(ahead + 1, self.wait_behind(queue, ahead));
+        }
+    }
+}
+
+/// Removes an abandoned waiter (the request was dropped while queued), or frees the slot
+/// if it was granted in the meantime.
+struct Waiting<'a> {
+    scheduler: &'a Scheduler,
+    seq: u64,
+    granted: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        let mut queue = self.scheduler.lock();
+        match queue.waiting.iter().position(|waiter| waiter.seq == self.seq) {
+            Some(index) => {
+                queue.waiting.swap_remove(index);
+                self.scheduler.publish_positions(&queue);
+            }
+            None => {
+                drop(queue);
+                self.scheduler.release(None);
+            }
+        }
+    }
+}
+
+/// A held provider slot.
+pub struct Permit<'a> {
+    scheduler: &'a Scheduler,
+    acquired: Instant,
+}
+
+impl<'a> Permit<'a> {
+    fn new(scheduler: &'a Scheduler) -> Self {
+        Permit {
+            scheduler,
+            acquired: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.scheduler.release(Some(self.acquired.elapsed()));
+    }
+}
@@ -0,0 +1,886 @@
+// Versioned JSON Schemas for the public response shapes, served at /schemas/*.
+//
+// The schema files live in schemas/ at the repository root. Version 1 of a schema may gain
+// new optional fields; removing or retyping a field means publishing a new version next to
+// the old one. The fields each mode adds are also published on their own, as
+// `mode-<mode>.v1.json`, generated from their types in `results`. The tests below keep the
+// schemas and the actual responses in step.
+
+use axum::{
+    extract::Path,
+    http::header,
+    response::{IntoResponse, Json},
+};
+use serde_json::{Map, Value};
+
+use crate::modes::Mode;
+use crate::{results, CaptionError};
+
+/// Where the published schemas' `$id`s point.
+const ID_BASE: &str = "https://github.com/eexanem/ai-image-captioner-rust/schemas";
+
+/// Every published schema, by file name.
+pub const SCHEMAS: &[(&str, &str)] = &[
+    (
+        "caption-result.v1.json",
+        include_str!("../schemas/caption-result.v1.json"),
+    ),
+    (
+        "batch-result.v1.json",
+        include_str!("../schemas/batch-result.v1.json"),
+    ),
+    (
+        "batch-export-row.v1.json",
+        include_str!("../schemas/batch-export-row.v1.json"),
+    ),
+    (
+        "job.v1.json",
+        include_str!("../schemas/job.v1.json"),
+    ),
+    (
+        "pdf-result.v1.json",
+        include_str!("../schemas/pdf-result.v1.json"),
+    ),
+    (
+        "video-result.v1.json",
+        include_str!("../schemas/video-result.v1.json"),
+    ),
+    (
+        "video-frames-result.v1.json",
+        include_str!("../schemas/video-frames-result.v1.json"),
+    ),
+    (
+        "listing-result.v1.json",
+        include_str!("../schemas/listing-result.v1.json"),
+    ),
+    (
+        "status.v1.json",
+        include_str!("../schemas/status.v1.json"),
+    ),
+    (
+        "usage.v1.json",
+        include_str!("../schemas/usage.v1.json"),
+    ),
+    (
+        "history.v1.json",
+        include_str!("../schemas/history.v1.json"),
+    ),
+    (
+        "error.v1.json",
+        include_str!("../schemas/error.v1.json"),
+    ),
+];
+
+fn mode_schema_name(mode: Mode) -> String {
+    format!("mode-{}.v1.json", mode.name())
+}
+
+/// The schema of the fields `mode` adds, with its `$id` and description up front like the
+/// schema files have them.
+fn mode_schema(mode: Mode) -> Value {
+    let Value::Object(mut generated) = Value::from(results::schema(mode)) else {
+        unreachable!("schemas of structs are objects");
+    };
+    let mut schema = Map::new();
+    let id = format!("{}/{}", ID_BASE, mode_schema_name(mode));
+    schema.extend(generated.remove("$schema").map(|draft| ("$schema".into(), draft)));
+    schema.insert("$id".into(), id.into());
+    for key in ["title", "description"] {
+        schema.extend(generated.remove(key).map(|value| (key.into(), value)));
+    }
+    schema.extend(generated);
+    schema.into()
+}
+
+/// `GET /schemas`: the names of all published schemas.
+pub async fn list_schemas() -> Json<Vec<String>> {
+    let files = SCHEMAS.iter().map(|(name, _)| name.to_string());
+    Json(files.chain(Mode::ALL.iter().copied().map(mode_schema_name)).collect())
+}
+
+/// `GET /schemas/:name`
+pub async fn get_schema(Path(name): Path<String>) -> Result<impl IntoResponse, CaptionError> {
+    let schema = match SCHEMAS.iter().find(|(file, _)| *file == name) {
+        Some((_, schema)) => schema.to_string(),
+        None => {
+            let mode = Mode::ALL
+                .iter()
+                .copied()
+                .find(|mode| mode_schema_name(*mode) == name)
+                .ok_or_else(|| CaptionError::NotFound(format!("No schema named {}", name)))?;
+            serde_json::to_string_pretty(&mode_schema(mode))
+                .expect("schemas always serialize")
+        }
+    };
+
+    Ok(([(header::CONTENT_TYPE, "application/schema+json")], schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modes::CaptionOptions;
+    use crate::handlers::{
+        BatchItem, BatchResponse, FrameCaption, PageResult, PdfResponse, Scene, StatusResponse,
+        StillFrame, VideoFramesResponse, VideoResponse,
+    };
+    use crate::{export, CaptionResponse, Timings};
+    use serde_json::json;
+
+    fn schema(name: &str) -> Value {
+        let (_, text) = SCHEMAS.iter().find(|(file, _)| *file == name).unwrap();
+        serde_json::from_str(text).unwrap()
+    }
+
+    /// A validator for `name` that can resolve references to the other published schemas.
+    fn validator(name: &str) -> jsonschema::Validator {
+        let mut options = jsonschema::options().should_validate_formats(true);
+        for (file, _) in SCHEMAS {
+            let contents = schema(file);
+            let id = contents["$id"].as_str().unwrap().to_string();
+            options = options.with_resource(id, jsonschema::Resource::from_contents(contents).unwrap());
+        }
+        options.build(&schema(name)).unwrap()
+    }
+
+    fn assert_valid(validator: &jsonschema::Validator, instance: &Value) {
+        let errors: Vec<String> = validator
+            .iter_errors(instance)
+            .map(|error| format!("{} at {}", error, error.instance_path))
+            .collect();
+        assert!(errors.is_empty(), "{}\n{:#}", errors.join("\n"), instance);
+    }
+
+    fn response(mode: Mode, confidence: bool, reply: &str) -> CaptionResponse {
+        let options = CaptionOptions {
+            mode,
+            confidence,
+            ..Default::default()
+        };
+        let output = options.parse_output(reply).unwrap();
+        let start = std::time::Instant::now();
+        CaptionResponse::new(output, b"image bytes", &options, start, Timings::default())
+    }
+
+    const NATURE_REPLY: &str = r#"{"summary": "A fox crossing a forest clearing at night.",
+        "species": [
+            {"common_name": "Grey fox", "scientific_name": "urocyon  cinereoargenteus",
+             "rank": "Species", "confidence": 0.2},
+            {"common_name": "Red fox", "scientific_name": "Vulpes vulpes", "rank": "species",
+             "confidence": 1.4},
+            {"common_name": "Unknown", "scientific_name": "VULPES", "rank": "genus", "confidence": 0.9},
+            {"common_name": "Fox", "scientific_name": " "}],
+        "caution": "Grey foxes look similar at night",
+        "observation": {"iconic_taxon": "Mammalia", "individual_count": 1, "life_stage": "Adult",
+                        "sex": "unknown", "evidence": "organism", "alive": true, "captive": "no"}}"#;
+
+    const ASTRONOMY_REPLY: &str = r#"{"summary": "A waxing Moon beside Jupiter above the Orion Nebula.",
+        "moon_phase": "Waxing gibbous",
+        "sky_objects": [
+            {"kind": "Moon", "name": "Luna", "box": [100, 80, 260, 240]},
+            {"kind": "planet", "name": "jupiter", "catalog_id": null, "box": [400, 120, 410, 130]},
+            {"kind": "planet", "name": "Pluto", "box": [600, 600, 605, 605]},
+            {"kind": "nebula", "name": "Orion  Nebula", "catalog_id": "M42",
+             "box": [700, 700, 760, 780]},
+            {"kind": "milky way", "name": "Milky Way", "box": null},
+            {"kind": "star", "name": null, "box": null}]}"#;
+
+    const ART_REPLY: &str = r#"{"summary": "Haystacks in a field at sunset, in short broken strokes.",
+        "medium": "Oil on canvas", "styles": ["Impressionism", "Post-Impressionism", "impressionism",
+        "plein air"], "composition": ["Low horizon", " ", "Warm palette against blue shadows"],
+        "era": {"description": "late 19th century", "from_year": 1895, "to_year": 1885},
+        "attribution": {"artist": "Claude Monet", "basis": "The series of haystacks at sunset"}}"#;
+
+    const VEHICLE_REPLY: &str = r#"{"summary": "A white Mercedes GLC300 with plate AB12 CDE, parked behind a red van (KA01AB1234).",
+        "vehicles": [
+            {"make": "Mercedes-Benz", "model": "GLC300", "color": "White", "body_type": "SUV",
+             "damage_severity": "moderate", "damage": "Dented rear bumper below the plate ABC-1234."},
+            {"make": "Ford", "model": null, "color": "red", "body_type": "van",
+             "damage_severity": null, "damage": "Scratched sliding door"},
+            {"make": "unknown", "model": "", "color": "teal", "body_type": "tractor",
+             "damage_severity": "none", "damage": "None visible"}
+        ]}"#;
+
+    fn sample_responses() -> Vec<CaptionResponse> {
+        let mut animated = response(Mode::Caption, false, "A cat repeatedly paws at a laser dot.");
+        animated.details.insert("frames_analyzed".into(), json!(6));
+        animated.report_usage(&crate::providers::Usage {
+            prompt_tokens: 1_806,
+            output_tokens: 412,
+        });
+        let mut translated = response(Mode::Caption, false, "Ein rotes Fahrrad an einer Wand.");
+        translated.translation = Some(crate::translate::Translation {
+            language: "de".into(),
+            provider: crate::translate::Provider::Deepl,
+        });
+
+        let mut credentialed = response(Mode::Caption, false, "A sunset over a beach.");
+        credentialed.content_credentials = Some(crate::c2pa::ContentCredentials {
+            claim_generator: Some("Image Generator 1.2".into()),
+            signed_by: Some("Example News".into()),
+            actions: vec!["c2pa.created".into()],
+            digital_source_type: Some("trainedAlgorithmicMedia".into()),
+            manifests: 1,
+            ..Default::default()
+        });
+        credentialed.ai_signals = vec![crate::c2pa::AiSignal {
+            source: "c2pa".into(),
+            field: "digitalSourceType".into(),
+            value: "trainedAlgorithmicMedia".into(),
+        }];
+
+        let mut detected = response(Mode::Caption, false, "A castle floating above clouds.");
+        detected.ai_detection = Some(crate::detection::AiDetection {
+            score: 0.95,
+            model_score: 0.7,
+            reasons: vec![
+                "PNG text chunk 'parameters' holds image generation settings".into(),
+                "Implausibly smooth stone textures".into(),
+            ],
+        });
+
+        let scene_options = CaptionOptions {
+            scene: true,
+            ..Default::default()
+        };
+        let scene_output = scene_options
+            .parse_output(
+                r#"{"caption": "A foggy harbour.", "time_of_day": "Dawn",
+                    "weather": "partly cloudy", "indoor_outdoor": "somewhere"}"#,
+            )
+            .unwrap();
+        let scene = CaptionResponse::new(
+            scene_output,
+            b"image bytes",
+            &scene_options,
+            std::time::Instant::now(),
+            Timings::default(),
+        );
+
+        let sheet_options = CaptionOptions {
+            contact_sheet: true,
+            ..Default::default()
+        };
+        let mut sheet_output = sheet_options
+            .parse_output(
+                r#"{"caption": "A runner crosses the finish line.",
+                    "panels": ["Runners at the start.", "The leader breaks the tape."]}"#,
+            )
+            .unwrap();
+        let panel = crate::contact_sheet::Panel {
+            x: 12,
+            y: 12,
+            width: 400,
+            height: 300,
+        };
+        crate::contact_sheet::locate(&mut sheet_output, &[panel]);
+        let sheet = CaptionResponse::new(
+            sheet_output,
+            b"image bytes",
+            &sheet_options,
+            std::time::Instant::now(),
+            Timings::default(),
+        );
+
+        let screen_options = CaptionOptions {
+            screen: true,
+            ..Default::default()
+        };
+        let mut screened_output = screen_options
+            .parse_output(
+                r#"{"caption": "A chest X-ray showing the ribs and lungs.",
+                    "sensitive_content": ["Medical scan", "medical_scan", "tattoo"]}"#,
+            )
+            .unwrap();
+        assert_eq!(screened_output.details["sensitive_content"], json!(["medical_scan"]));
+        let decision = crate::policy::Decision {
+            action: Some(crate::policy::Action::Disclaimer),
+            categories: vec!["medical_scan".into()],
+            disclaimer: Some("AI-generated description, not a diagnosis.".into()),
+            ..Default::default()
+        };
+        decision.annotate(&mut screened_output);
+        let screened = CaptionResponse::new(
+            screened_output,
+            b"image bytes",
+            &screen_options,
+            std::time::Instant::now(),
+            Timings::default(),
+        );
+
+        let observation_options = CaptionOptions {
+            mode: Mode::Nature,
+            observation: true,
+            ..Default::default()
+        };
+        let observed = CaptionResponse::new(
+            observation_options.parse_output(NATURE_REPLY).unwrap(),
+            b"image bytes",
+            &observation_options,
+            std::time::Instant::now(),
+            Timings::default(),
+        );
+
+        let mut located = response(Mode::Caption, false, "A yellow tram climbing a narrow street.");
+        located.capture_context = Some(crate::capture::CaptureContext {
+            taken_at: Some("2024-03-14T18:52:10+00:00".into()),
+            time_of_day: Some(crate::capture::TimeOfDay::Dusk),
+            place: Some("Lisbon, Portugal".into()),
+            camera: Some("Canon EOS R5 with RF24-70mm F2.8 L IS USM".into()),
+        });
+
+        // As the cache hands it back: stored as JSON, read again and marked.
+        let reply = r##"{"caption": "A pier at dawn", "hashtags": ["#pier"]}"##;
+        let stored = response(Mode::Hashtags, false, reply);
+        let mut cached: CaptionResponse =
+            serde_json::from_str(&serde_json::to_string(&stored).unwrap()).unwrap();
+        cached.cached = true;
+        cached.cache_age_secs = Some(86_400);
+
+        vec![
+            animated,
+            translated,
+            credentialed,
+            detected,
+            scene,
+            sheet,
+            screened,
+            observed,
+            located,
+            cached,
+            response(Mode::Caption, false, "A red bicycle leaning against a brick wall."),
+            response(
+                Mode::AltText,
+                true,
+                r#"{"alt_text": "Photo of a red bicycle", "decorative": false,
+                    "confidence": 0.8, "uncertainties": ["wall material"]}"#,
+            ),
+            response(Mode::AltText, false, r#"{"alt_text": "", "decorative": true}"#),
+            response(
+                Mode::Hashtags,
+                false,
+                r##"{"caption": "Bike by a wall", "hashtags": ["#bike", "cycling"],
+                    "keywords": ["bicycle", "brick wall"]}"##,
+            ),
+            response(
+                Mode::AccessibilityAudit,
+                false,
+                r#"{"summary": "A sign-in form with faint placeholder text.", "findings": [
+                    {"issue": "small target", "severity": "low", "element": "Help icon",
+                     "detail": "The help icon is about 16 px wide; make it at least 24 px."},
+                    {"issue": "low_contrast", "severity": "High", "element": "Email field",
+                     "detail": "Light grey placeholder on white; darken it to 4.5:1."},
+                    {"issue": "glare", "severity": "urgent",
+                     "detail": "The banner image has text baked into it."}]}"#,
+            ),
+            response(
+                Mode::DocumentLayout,
+                false,
+                r#"{"summary": "An invoice with a line-item table.", "columns": 1, "regions": [
+                    {"type": "Heading", "box": [80, 40, 520, 90], "text": "Invoice 1042", "level": 1},
+                    {"type": "table", "box": [80, 300, 920, 640], "text": "Item Qty\nPaper 2",
+                     "cells": [["Item", "Qty"], ["Paper", "2"]]},
+                    {"type": "stamp", "box": [700, 800, 640, 900], "text": "PAID"},
+                    {"type": "figure", "box": [10, 10, 10, 50], "text": ""}],
+                    "ocr_text": "Invoice 1042\nItem Qty\nPaper 2\nPAID"}"#,
+            ),
+            response(
+                Mode::Comic,
+                false,
+                r#"{"summary": "Aki arrives late to class.", "reading_direction": "Right to left",
+                    "comic_panels": [
+                    {"box": [520, 30, 970, 340], "description": "Aki bursts in.", "dialogue": [
+                        {"kind": "sfx", "speaker": "door", "text": "SLAM"},
+                        {"kind": "speech", "speaker": "Aki", "text": "Made it!"},
+                        {"kind": "thought", "speaker": null, "text": " "}]},
+                    {"box": [30, 30, 30, 340], "description": "Nothing."},
+                    {"box": [30, 30, 500, 340], "description": "The teacher sighs.", "dialogue": [
+                        {"kind": "narration", "speaker": "Narrator", "text": "Again."}]}]}"#,
+            ),
+            response(
+                Mode::Fashion,
+                false,
+                r#"{"summary": "A navy striped cotton shirt with a button-down collar.",
+                    "garment_type": "Shirt", "color": "navy", "secondary_colors": ["white", "navy", "teal"],
+                    "pattern": "striped", "material": "cotton or linen",
+                    "style_tags": ["preppy", "casual", "Business", "classic", "nautical", "fitted", "summer"]}"#,
+            ),
+            response(Mode::Vehicle, false, VEHICLE_REPLY),
+            response(Mode::Nature, false, NATURE_REPLY),
+            response(Mode::Astronomy, true, ASTRONOMY_REPLY),
+            response(Mode::Art, false, ART_REPLY),
+            response(Mode::Art, false, r#"{"summary": "A plain wall.", "medium": null, "styles": [],
+                "composition": [], "era": null, "attribution": {"artist": "Unknown"}}"#),
+            response(
+                Mode::RealEstate,
+                false,
+                r#"{"room_type": "Living Room", "description": "A bright living room with tall windows.",
+                    "features": ["hardwood floors", "Fireplace", "fireplace", "bay window", " "]}"#,
+            ),
+            response(
+                Mode::TitleDescription,
+                false,
+                r#"{"title": "Red bicycle.", "description": "A bicycle rests on a wall."}"#,
+            ),
+        ]
+    }
+
+    #[test]
+    fn schemas_are_valid_and_served_names_match_ids() {
+        for (file, _) in SCHEMAS {
+            let contents = schema(file);
+            assert!(contents["$id"].as_str().unwrap().ends_with(file));
+            jsonschema::meta::validate(&contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn mode_fields_match_their_generated_schemas() {
+        let published = schema("caption-result.v1.json");
+        let published = published["$defs"]["fields"]["properties"].as_object().unwrap();
+        for mode in Mode::ALL {
+            let contents = mode_schema(*mode);
+            assert!(contents["$id"].as_str().unwrap().ends_with(&mode_schema_name(*mode)));
+            jsonschema::meta::validate(&contents).unwrap();
+            // Every mode field is in the schema of the whole result too.
+            for field in contents["properties"].as_object().into_iter().flat_map(Map::keys) {
+                assert!(published.contains_key(field), "{} {}", mode, field);
+            }
+        }
+
+        for response in sample_responses() {
+            let mode = response.provenance.as_ref().map_or(Mode::Caption, |p| p.mode);
+            let validator = jsonschema::validator_for(&mode_schema(mode)).unwrap();
+            assert_valid(&validator, &Value::Object(response.details.clone()));
+        }
+
+        let art = response(Mode::Art, false, ART_REPLY);
+        let fields: results::ArtFields = art.fields().unwrap();
+        assert!(fields.attribution_guess.unwrap().uncertain);
+        assert_eq!(fields.era.unwrap().to_year, Some(1895));
+    }
+
+    #[test]
+    fn caption_results_match_schema() {
+        let validator = validator("caption-result.v1.json");
+        for response in sample_responses() {
+            assert_valid(&validator, &serde_json::to_value(&response).unwrap());
+        }
+    }
+
+    #[test]
+    fn caption_result_schema_rejects_unknown_fields() {
+        let validator = validator("caption-result.v1.json");
+        let mut value = serde_json::to_value(&sample_responses()[0]).unwrap();
+        value["surprise"] = json!(true);
+        assert!(!validator.is_valid(&value));
+    }
+
+    fn sample_batch() -> Vec<BatchItem> {
+        let mut items: Vec<BatchItem> = sample_responses()
+            .into_iter()
+            .map(|response| BatchItem {
+                file_name: Some("photo.jpg".into()),
+                xmp: Some(crate::xmp::render(&crate::xmp::XmpFields::from_response(&response))),
+                response: Some(response),
+                error: None,
+            })
+            .collect();
+        items.push(BatchItem {
+            file_name: Some("broken.jpg".into()),
+            response: None,
+            xmp: None,
+            error: Some("Bad Request".into()),
+        });
+        items
+    }
+
+    #[test]
+    fn batch_results_match_schema() {
+        let results = sample_batch();
+        let batch = BatchResponse {
+            succeeded: results.len() - 1,
+            failed: 1,
+            results,
+        };
+        assert_valid(
+            &validator("batch-result.v1.json"),
+            &serde_json::to_value(&batch).unwrap(),
+        );
+    }
+
+    #[test]
+    fn batch_export_rows_match_schema() {
+        let validator = validator("batch-export-row.v1.json");
+        for line in export::to_jsonl(&sample_batch()).lines() {
+            assert_valid(&validator, &serde_json::from_str(line).unwrap());
+        }
+    }
+
+    #[test]
+    fn jobs_match_schema() {
+        let store = crate::jobs::JobStore::default();
+        let entry = store.create("tenant", vec![None; 6]);
+        let validator = validator("job.v1.json");
+
+        let queued: Value = serde_json::from_str(&entry.to_json()).unwrap();
+        assert_valid(&validator, &queued);
+
+        entry.set_queue_position(3, Some(std::time::Duration::from_millis(4500)));
+        let waiting: Value = serde_json::from_str(&entry.to_json()).unwrap();
+        assert_eq!(waiting["queue_position"], 3);
+        assert_eq!(waiting["eta_seconds"], 5);
+        assert_valid(&validator, &waiting);
+
+        entry.update(|job| {
+            job.status = crate::jobs::JobStatus::Completed;
+            job.succeeded = 5;
+            job.failed = 1;
+            job.results = Some(sample_batch());
+        });
+        let completed: Value = serde_json::from_str(&entry.to_json()).unwrap();
+        assert_valid(&validator, &completed);
+    }
+
+    #[test]
+    fn pdf_results_match_schema() {
+        let mut pages: Vec<PageResult> = sample_responses()
+            .into_iter()
+            .enumerate()
+            .map(|(index, response)| PageResult {
+                page: index + 1,
+                response: Some(response),
+                error: None,
+            })
+            .collect();
+        pages.push(PageResult {
+            page: pages.len() + 1,
+            response: None,
+            error: Some("Bad Request".into()),
+        });
+        let pdf = PdfResponse {
+            pages_captioned: pages.len() - 1,
+            failed: 1,
+            processing_time_ms: 4200,
+            pages,
+        };
+        assert_valid(
+            &validator("pdf-result.v1.json"),
+            &serde_json::to_value(&pdf).unwrap(),
+        );
+    }
+
+    #[test]
+    fn video_results_match_schema() {
+        let video = VideoResponse {
+            summary: "A cyclist rides past a brick wall.".into(),
+            model: "test".into(),
+            processing_time_ms: 1200,
+            frames_analyzed: 3,
+            interval_ms: 2000,
+            timeline: sample_responses()
+                .into_iter()
+                .take(2)
+                .enumerate()
+                .map(|(index, response)| FrameCaption {
+                    timestamp_ms: index as u128 * 2000,
+                    response,
+                })
+                .collect(),
+            scenes: vec![Scene {
+                start_ms: 0,
+                end_ms: 4000,
+                caption: "A red bicycle.".into(),
+            }],
+        };
+        assert_valid(
+            &validator("video-result.v1.json"),
+            &serde_json::to_value(&video).unwrap(),
+        );
+    }
+
+    #[test]
+    fn video_frame_results_match_schema() {
+        let mut frames: Vec<StillFrame> = sample_responses()
+            .into_iter()
+            .take(2)
+            .map(|response| StillFrame {
+                timestamp_ms: 83_250,
+                jpeg: Some("/9j/4AAQSkZJRg==".into()),
+                response: Some(response),
+                error: None,
+            })
+            .collect();
+        frames.push(StillFrame {
+            timestamp_ms: 5_400_000,
+            jpeg: None,
+            response: None,
+            error: Some("no frame at 5400.000 s; the video may be shorter".into()),
+        });
+        frames.push(StillFrame {
+            timestamp_ms: 12_000,
+            jpeg: Some("/9j/4AAQSkZJRg==".into()),
+            response: None,
+            error: Some("Gemini didn't answer within 60 s".into()),
+        });
+        let result = VideoFramesResponse {
+            model: "test".into(),
+            processing_time_ms: 3100,
+            captioned: 2,
+            failed: 2,
+            frames,
+        };
+        assert_valid(
+            &validator("video-frames-result.v1.json"),
+            &serde_json::to_value(&result).unwrap(),
+        );
+    }
+
+    #[test]
+    fn listing_results_match_schema() {
+        let replies = [
+            r#"{"room_type": "kitchen", "description": "An open kitchen with an island.",
+                "features": ["kitchen island", "Quartz counters"]}"#,
+            r#"{"room_type": "patio", "description": "A paved courtyard.", "features": []}"#,
+            r#"{"room_type": "kitchen", "description": "A breakfast nook by the window.",
+                "features": ["quartz counters", "window seat"]}"#,
+        ];
+        let mut photos: Vec<BatchItem> = replies
+            .iter()
+            .map(|reply| BatchItem {
+                file_name: Some("room.jpg".into()),
+                response: Some(response(Mode::RealEstate, false, reply)),
+                xmp: None,
+                error: None,
+            })
+            .collect();
+        photos.push(BatchItem {
+            file_name: Some("blurry.jpg".into()),
+            response: None,
+            xmp: None,
+            error: Some("Gemini didn't answer within 60 s".into()),
+        });
+        let rooms = crate::listing::rooms(&photos);
+        assert_eq!(rooms.len(), 2);
+        assert_eq!(rooms[0].photos, [0, 2]);
+        assert_eq!(rooms[0].features, ["kitchen island", "Quartz counters", "window seat"]);
+        assert_eq!(rooms[1].room_type, "other");
+
+        let listing = crate::listing::ListingResponse {
+            listing: "Step into a bright open kitchen with a quartz-topped island.".into(),
+            tone: crate::listing::Tone::Warm,
+            length: crate::listing::Length::Short,
+            model: "test".into(),
+            processing_time_ms: 5400,
+            rooms,
+            photos,
+        };
+        assert_valid(
+            &validator("listing-result.v1.json"),
+            &serde_json::to_value(&listing).unwrap(),
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_match_schema() {
+        use axum::response::IntoResponse;
+
+        let validator = validator("error.v1.json");
+        let errors = [
+            CaptionError::BadRequest("No file in the upload".into()),
+            CaptionError::Unauthorized("Missing or invalid API key".into()),
+            CaptionError::Forbidden("This API key can't caption in comic mode".into()),
+            CaptionError::Refused("This server doesn't describe images of this kind".into()),
+            CaptionError::QuotaExceeded("This API key has used its 500 captions for today".into()),
+            CaptionError::RateLimited("Gemini returned 429 Too Many Requests".into()),
+            CaptionError::Provider {
+                status: None,
+                message: "connection reset".into(),
+            },
+            CaptionError::InvalidReply("missing field `alt_text`".into()),
+            CaptionError::ProviderUnavailable {
+                retry_after: std::time::Duration::from_millis(12_300),
+            },
+            CaptionError::Timeout("Gemini didn't answer within 60 s".into()),
+            CaptionError::Busy {
+                retry_after: std::time::Duration::from_secs(4),
+            },
+        ];
+        for error in errors {
+            let status = error.status();
+            let response = error.into_response();
+            assert_eq!(response.status(), status);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_valid(&validator, &serde_json::from_slice(&body).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn status_matches_schema() {
+        let limits = crate::scheduler::Limits {
+            concurrency: 2,
+            queue: Some(4),
+        };
+        let scheduler = crate::scheduler::Scheduler::new(limits, Default::default());
+        let validator = validator("status.v1.json");
+        let status = |scheduler: &crate::scheduler::Scheduler| {
+            serde_json::to_value(StatusResponse {
+                status: "ok",
+                model: "test",
+                provider_circuit: "closed",
+                queue: scheduler.status(),
+                buffer_pool: crate::buffers::POOL.stats(),
+            })
+            .unwrap()
+        };
+
+        assert_valid(&validator, &status(&scheduler));
+
+        let tenant = crate::scheduler::Tenant::new("tenant");
+        let caller = crate::scheduler::Caller::interactive(tenant);
+        drop(scheduler.acquire(&caller).await.unwrap());
+        let _slot = scheduler.acquire(&caller).await.unwrap();
+        let busy = status(&scheduler);
+        assert_eq!(busy["in_flight"], 1);
+        assert!(busy["average_caption_ms"].is_u64());
+        assert_valid(&validator, &busy);
+    }
+
+    #[test]
+    fn usage_matches_schema() {
+        use crate::usage::{PeriodUsage, Totals, UsageResponse};
+        let totals = Totals {
+            captions: 120,
+            input_tokens: 310_000,
+            output_tokens: 9_400,
+            cost_usd: 0.1165,
+        };
+        let usage = UsageResponse {
+            tenant: "marketing".into(),
+            today: PeriodUsage {
+                period: "2026-10-16".into(),
+                totals,
+                quota: Some(500),
+                remaining: Some(380),
+                resets_at: "2026-10-17T00:00:00+00:00".into(),
+            },
+            this_month: PeriodUsage {
+                period: "2026-10".into(),
+                totals,
+                quota: None,
+                remaining: None,
+                resets_at: "2026-11-01T00:00:00+00:00".into(),
+            },
+        };
+        let value = serde_json::to_value(usage).unwrap();
+        assert_eq!(value["today"]["captions"], 120);
+        assert_valid(&validator("usage.v1.json"), &value);
+    }
+
+    #[test]
+    fn history_matches_schema() {
+        use crate::history::{HistoryEntry, HistoryPage};
+        let page = HistoryPage {
+            entries: sample_responses()
+                .into_iter()
+                .take(2)
+                .zip([12, 9])
+                .map(|(response, id)| {
+                    let provenance = response.provenance.unwrap();
+                    HistoryEntry {
+                        id,
+                        created_at: provenance.generated_at,
+                        image_sha256: provenance.content_hash.replace("sha256:", ""),
+                        thumbnail_path: (id == 12).then(|| "thumbnails/ab12.jpg".into()),
+                        mode: provenance.mode.name().into(),
+                        tags: vec!["bicycle".into(), "street".into()],
+                        caption: response.caption,
+                        model: response.model,
+                        latency_ms: response.processing_time_ms,
+                    }
+                })
+                .collect(),
+            next_before: Some(9),
+        };
+        assert_valid(&validator("history.v1.json"), &serde_json::to_value(page).unwrap());
+    }
+
+    #[test]
+    fn vehicle_results_never_contain_plates() {
+        let result = serde_json::to_value(response(Mode::Vehicle, false, VEHICLE_REPLY)).unwrap();
+        assert_eq!(
+            result["caption"],
+            "A white Mercedes GLC300 with plate [plate redacted], parked behind a red van \
+             ([plate redacted])."
+        );
+        let vehicles = result["vehicles"].as_array().unwrap();
+        assert_eq!(vehicles[0]["model"], "GLC300");
+        assert_eq!(vehicles[0]["damage"], "Dented rear bumper below the plate [plate redacted].");
+        assert_eq!(vehicles[1]["damage_severity"], "minor");
+        assert_eq!(vehicles[2]["make"], Value::Null);
+        assert_eq!(vehicles[2]["color"], Value::Null);
+        assert_eq!(vehicles[2]["damage"], Value::Null);
+    }
+
+    #[test]
+    fn nature_results_rank_species_and_always_caution() {
+        let result = serde_json::to_value(response(Mode::Nature, false, NATURE_REPLY)).unwrap();
+        let species = result["species"].as_array().unwrap();
+        let names: Vec<_> = species.iter().map(|candidate| &candidate["scientific_name"]).collect();
+        assert_eq!(names, ["Vulpes vulpes", "Vulpes", "Urocyon cinereoargenteus"]);
+        assert_eq!(species[0]["confidence"], 1.0);
+        assert_eq!(species[1]["common_name"], Value::Null);
+        assert_eq!(
+            result["caution"],
+            format!("Grey foxes look similar at night. {}", crate::nature::CAUTION)
+        );
+        assert!(result.get("observation").is_none());
+
+        let empty = r#"{"summary": "An empty clearing.", "species": []}"#;
+        let empty = response(Mode::Nature, false, empty);
+        assert_eq!(empty.details["caution"], crate::nature::CAUTION);
+
+        let observed = CaptionOptions {
+            mode: Mode::Nature,
+            observation: true,
+            ..Default::default()
+        };
+        let observation = &observed.parse_output(NATURE_REPLY).unwrap().details["observation"];
+        assert_eq!(observation["iconic_taxon"], "mammalia");
+        assert_eq!(observation["sex"], Value::Null);
+        assert_eq!(observation["captive"], Value::Null);
+        let misplaced = CaptionOptions {
+            observation: true,
+            ..Default::default()
+        };
+        assert!(misplaced.validate().is_err());
+    }
+
+    #[test]
+    fn astronomy_annotations_keep_what_can_be_placed_or_named() {
+        let result = response(Mode::Astronomy, false, ASTRONOMY_REPLY);
+        let result = serde_json::to_value(result).unwrap();
+        assert_eq!(result["moon_phase"], "waxing_gibbous");
+        let objects = result["sky_objects"].as_array().unwrap();
+        let names: Vec<_> = objects.iter().map(|object| &object["name"]).collect();
+        assert_eq!(names, ["Moon", "Jupiter", "Orion Nebula", "Milky Way"]);
+        assert_eq!(objects[2]["catalog_id"], "M42");
+        assert_eq!(objects[3]["kind"], "milky_way");
+        assert_eq!(objects[3]["box"], Value::Null);
+
+        let moonless = r#"{"summary": "Star trails.", "moon_phase": "full", "sky_objects": []}"#;
+        let moonless = response(Mode::Astronomy, false, moonless);
+        assert_eq!(moonless.details["moon_phase"], Value::Null);
+    }
+
+    #[test]
+    fn art_attribution_is_a_guess_apart_from_the_caption() {
+        let result = serde_json::to_value(response(Mode::Art, false, ART_REPLY)).unwrap();
+        assert_eq!(result["medium"], "oil");
+        assert_eq!(result["styles"], json!(["impressionism", "post_impressionism"]));
+        assert_eq!(result["composition"].as_array().unwrap().len(), 2);
+        assert_eq!(result["era"]["from_year"], 1885);
+        assert_eq!(result["attribution_guess"]["artist"], "Claude Monet");
+        assert_eq!(result["attribution_guess"]["uncertain"], true);
+    }
+}
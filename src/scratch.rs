@@ -0,0 +1,24 @@
+// Temporary working directories for tools that read and write files (ffmpeg, pdftoppm).
+
+use std::path::{Path, PathBuf};
+
+/// A uniquely named directory under the system temp dir, removed with its contents on drop.
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn new() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("captioner-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&path)?;
+        Ok(ScratchDir(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,190 @@
+// The web server: shared state and the routes.
+
+use axum::{
+    middleware,
+    routing::{delete, get, post},
+    Router,
+};
+use std::sync::Arc;
+use tower_http::cors::CorsLayer;
+
+use crate::config::{DEFAULT_HOST, DEFAULT_PORT};
+use crate::handlers::{
+    batch_caption, caption_listing, caption_pdf, caption_video, caption_video_frames, caption_zip,
+    create_job, embed_caption, index, server_status, upload_image, xmp_sidecar,
+};
+use crate::limits::Upload;
+use crate::{
+    assets, audit, auth, cache, fashion, gallery, history, jobs, jpeg, keys, logging, policy,
+    pricing, ratelimit, reload, scheduler, schemas, shared, shutdown, storage, timeouts, translate,
+    uploads, usage, voices, CaptionOptions,
+};
+
+pub(crate) struct AppState {
+    /// Behind a lock so SIGHUP can rotate it.
+    pub(crate) api_key: std::sync::RwLock<String>,
+    pub(crate) jobs: jobs::JobStore,
+    pub(crate) scheduler: scheduler::Scheduler,
+    pub(crate) uploads: uploads::UploadTokens,
+    pub(crate) translators: translate::Providers,
+    pub(crate) voices: voices::BrandVoices,
+    /// Where captions are kept, when DATABASE_URL or HISTORY_DB names a database.
+    pub(crate) history: Option<history::History>,
+    /// Captions to reuse for repeated images, kept in the same database.
+    pub(crate) cache: Option<cache::CaptionCache>,
+    /// Every caption asked for, when AUDIT_LOG is on; in the same database too.
+    pub(crate) audit: Option<audit::AuditLog>,
+}
+
+impl AppState {
+    pub(crate) fn api_key(&self) -> String {
+        self.api_key.read().expect("api key lock poisoned").clone()
+    }
+}
+
+/// Runs the web server on HOST:PORT (0.0.0.0:3000 by default) until it is stopped, then shuts
+/// down gracefully (see `shutdown`).
+pub async fn serve() {
+    let api_key = crate::settings::var("GEMINI_API_KEY")
+        .expect("GEMINI_API_KEY must be set in .env file");
+    let state = setup(api_key).await;
+    let app = routes(state.clone());
+
+    let host = crate::settings::var("HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string());
+    let port = match crate::settings::var("PORT") {
+        Ok(port) => port.trim().parse().unwrap_or_else(|_| panic!("PORT must be a port number")),
+        Err(_) => DEFAULT_PORT,
+    };
+    let listener = tokio::net::TcpListener::bind((host.trim(), port))
+        .await
+        .unwrap_or_else(|e| panic!("Could not listen on {}:{}: {}", host.trim(), port, e));
+    let port = listener.local_addr().unwrap().port();
+
+    tracing::info!(
+        jpeg_backend = jpeg::backend().name(),
+        "Server running on http://localhost:{}",
+        port
+    );
+
+    let (stop, stopping) = tokio::sync::oneshot::channel();
+    let server = tokio::spawn(async move {
+        // Connection info lets the scheduler tell callers without an API key apart by IP.
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            shutdown::signal().await;
+            let _ = stop.send(());
+        })
+        .await
+    });
+    if stopping.await.is_err() {
+        // The server stopped by itself, which only an error does.
+        server.await.unwrap().unwrap();
+        return;
+    }
+
+    tracing::info!("Shutting down: finishing requests and jobs in progress");
+    state.jobs.close();
+    let deadline = tokio::time::Instant::now() + shutdown::timeout();
+    let requests_finished = tokio::time::timeout_at(deadline, server).await.is_ok();
+    let unfinished_jobs = state.jobs.drain(deadline).await;
+    state.jobs.save();
+
+    if !requests_finished {
+        tracing::warn!("Shutdown timed out; cut short the requests still in progress");
+    }
+    if unfinished_jobs > 0 {
+        tracing::warn!(
+            "Shutdown timed out; saved {} unfinished jobs as interrupted",
+            unfinished_jobs
+        );
+    }
+    tracing::info!("Server stopped");
+}
+
+/// The web server's routes, captioning with the Gemini `api_key`, with everything the
+/// environment configures set up; panics when that configuration is unusable. Serve it with
+/// connection info (`into_make_service_with_connect_info::<SocketAddr>()`), as `serve()` does.
+pub async fn app(api_key: String) -> Router {
+    routes(setup(api_key).await)
+}
+
+/// Applies the configuration and opens everything the routes share.
+async fn setup(api_key: String) -> Arc<AppState> {
+    reload::apply_log_settings();
+    CaptionOptions::configured().unwrap_or_else(|e| panic!("{}", e));
+    auth::prepare_database().unwrap_or_else(|e| panic!("{}", e));
+    fashion::reload().unwrap_or_else(|e| panic!("{}", e));
+    pricing::reload().unwrap_or_else(|e| panic!("{}", e));
+    policy::reload().unwrap_or_else(|e| panic!("{}", e));
+    let storage = storage::open().await.unwrap_or_else(|e| panic!("{}", e));
+    let shared = shared::open().await.unwrap_or_else(|e| panic!("{}", e));
+    let state = Arc::new(AppState {
+        api_key: std::sync::RwLock::new(api_key),
+        jobs: jobs::JobStore::load(),
+        scheduler: scheduler::Scheduler::new(
+            scheduler::Limits::from_env(),
+            scheduler::weights_from_env(),
+        ),
+        uploads: uploads::UploadTokens::default(),
+        translators: translate::Providers::from_env(),
+        voices: voices::BrandVoices::load().unwrap_or_else(|e| panic!("{}", e)),
+        history: history::open(storage.clone()).unwrap_or_else(|e| panic!("{}", e)),
+        audit: audit::open(storage.clone()).unwrap_or_else(|e| panic!("{}", e)),
+        cache: cache::open(storage, shared).await,
+    });
+    tokio::spawn(reload::reload_on_sighup(state.clone()));
+    tokio::spawn(history::purge_expired(state.clone()));
+    state
+}
+
+fn routes(state: Arc<AppState>) -> Router {
+    // Captioning routes, behind API keys when they're configured.
+    let api = Router::new()
+        .route("/upload", post(upload_image).layer(Upload::Image.body_layer()))
+        .route("/embed", post(embed_caption).layer(Upload::Image.body_layer()))
+        .route("/xmp", post(xmp_sidecar).layer(Upload::Image.body_layer()))
+        .route("/video", post(caption_video).layer(Upload::Video.body_layer()))
+        .route("/video/frames", post(caption_video_frames))
+        .route("/jobs", post(create_job).layer(Upload::Job.body_layer()))
+        .route("/jobs/:id", get(jobs::get_job))
+        .route("/usage", get(usage::get_usage))
+        .route("/history", get(history::get_history))
+        .route("/history/:id", delete(history::delete_entry))
+        .route("/history/:id/thumbnail", get(history::get_thumbnail))
+        .route("/thumb/:id", get(history::get_thumbnail))
+        .route("/search", get(history::search_history))
+        .route("/pdf", post(caption_pdf).layer(Upload::Pdf.body_layer()))
+        .route("/zip", post(caption_zip).layer(Upload::Zip.body_layer()))
+        .route("/batch", post(batch_caption).layer(Upload::Batch.body_layer()))
+        .route("/listing", post(caption_listing).layer(Upload::Batch.body_layer()))
+        .route_layer(middleware::from_fn(auth::require_key));
+
+    Router::new()
+        .route("/", get(index))
+        .route("/gallery", get(gallery::gallery))
+        .route("/status", get(server_status))
+        .route("/schemas", get(schemas::list_schemas))
+        .route("/schemas/:name", get(schemas::get_schema))
+        .route("/admin/voices", get(voices::list_voices))
+        .route("/admin/keys", get(keys::list_keys).post(keys::create_key))
+        .route("/admin/keys/:name", delete(keys::revoke_key))
+        .route("/admin/usage", get(usage::admin_usage))
+        .route("/admin/audit", get(audit::export))
+        .route("/tenant/:id/data", delete(history::delete_tenant_data))
+        .route(
+            "/admin/tenants/:tenant/voice",
+            get(voices::get_voice)
+                .put(voices::put_voice)
+                .delete(voices::delete_voice),
+        )
+        .merge(api)
+        .merge(assets::routes())
+        .layer(middleware::from_fn(timeouts::limit_request))
+        .layer(middleware::from_fn(ratelimit::limit_rate))
+        .layer(CorsLayer::permissive())
+        .layer(middleware::from_fn(logging::trace_request))
+        .with_state(state)
+}
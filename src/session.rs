@@ -0,0 +1,152 @@
+// Provider context shared by the images of an album. A batch, ZIP, job, listing, PDF or video
+// sends every image with the same instructions: the mode's prompt and the tenant's brand voice.
+// With PROVIDER_CONTEXT_CACHE=true an album of two or more images uploads them once, as a Gemini
+// cached content, and each image's request names it instead of carrying them again, so the
+// shared part is read from the cache (and billed at its rate) rather than sent with every call.
+// The cached content is deleted once the album is done, and expires after `CONTEXT_TTL` should
+// the server not get to that.
+//
+// Gemini only caches contexts of a minimum size (a thousand tokens or more, depending on the
+// model), so a short prompt and voice are turned down. The album's requests then carry the
+// instructions as before, and the server doesn't ask for that context again until it restarts.
+// A context the provider has lost (expired, say) is dropped and the image sent with its
+// instructions. PROVIDER_CONTEXT_CACHE is read per album, so a SIGHUP reload applies it.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+use crate::config::{parse_flag, MODEL_ID};
+use crate::providers::api_base;
+use crate::timeouts;
+
+/// How long a cached content lives unless the album deletes it first.
+const CONTEXT_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Contexts the provider wouldn't cache, by fingerprint.
+static REFUSED: LazyLock<Mutex<HashSet<u64>>> = LazyLock::new(Mutex::default);
+
+/// The cached contents of one album, made as its images need them.
+pub(crate) struct ProviderSession {
+    api_key: String,
+    /// Each context the images have asked for, by fingerprint: the cached content's name, or
+    /// `None` when it isn't cached.
+    contexts: Mutex<HashMap<u64, Arc<OnceCell<Option<String>>>>>,
+}
+
+impl ProviderSession {
+    /// A session for an album of `images` captioned with `api_key`, when PROVIDER_CONTEXT_CACHE
+    /// asks for one and there is more than one image to share it.
+    pub(crate) fn open(api_key: String, images: usize) -> Option<ProviderSession> {
+        let enabled = crate::settings::var("PROVIDER_CONTEXT_CACHE")
+            .ok()
+            .and_then(|value| parse_flag(&value))
+            .unwrap_or(false);
+        (enabled && images > 1).then(|| ProviderSession {
+            api_key,
+            contexts: Mutex::default(),
+        })
+    }
+
+    /// The name of the cached content holding `system` and `prompt`, made on first use; `None`
+    /// when the provider won't cache them.
+    pub(crate) async fn context(&self, system: Option<&str>, prompt: &str) -> Option<String> {
+        let fingerprint = fingerprint(system, prompt);
+        if REFUSED.lock().expect("refused contexts lock poisoned").contains(&fingerprint) {
+            return None;
+        }
+        let cell = {
+            let mut contexts = self.contexts.lock().expect("session lock poisoned");
+            contexts.entry(fingerprint).or_default().clone()
+        };
+        let create = || create(&self.api_key, system, prompt, fingerprint);
+        cell.get_or_init(create).await.clone()
+    }
+
+    /// Stops using the cached content `name`, which the provider no longer has; the next image
+    /// that needs its context makes it again.
+    pub(crate) fn forget(&self, name: &str) {
+        let mut contexts = self.contexts.lock().expect("session lock poisoned");
+        contexts.retain(|_, cell| cell.get().and_then(Option::as_deref) != Some(name));
+    }
+}
+
+impl Drop for ProviderSession {
+    /// Deletes the album's cached contents in the background.
+    fn drop(&mut self) {
+        let contexts = std::mem::take(self.contexts.get_mut().expect("session lock poisoned"));
+        let names: Vec<String> =
+            contexts.into_values().filter_map(|cell| cell.get().cloned().flatten()).collect();
+        if names.is_empty() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let api_key = std::mem::take(&mut self.api_key);
+        runtime.spawn(async move {
+            let client = reqwest::Client::new();
+            for name in names {
+                let url = format!("{}/v1beta/{}?key={}", api_base(), name, api_key);
+                let deleted = client.delete(url).send().await.and_then(|r| r.error_for_status());
+                if let Err(e) = deleted {
+                    tracing::warn!(context = name, error = %e, "Can't delete a provider context");
+                }
+            }
+        });
+    }
+}
+
+fn fingerprint(system: Option<&str>, prompt: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (MODEL_ID, system, prompt).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Asks the provider to cache `system` and `prompt`; returns the cached content's name.
+async fn create(
+    api_key: &str,
+    system: Option<&str>,
+    prompt: &str,
+    fingerprint: u64,
+) -> Option<String> {
+    let mut payload = serde_json::json!({
+        "model": format!("models/{}", MODEL_ID),
+        "contents": [{ "role": "user", "parts": [{ "text": prompt }] }],
+        "ttl": format!("{}s", CONTEXT_TTL.as_secs()),
+    });
+    if let Some(system) = system {
+        payload["systemInstruction"] = serde_json::json!({ "parts": [{ "text": system }] });
+    }
+    let url = format!("{}/v1beta/cachedContents?key={}", api_base(), api_key);
+    let mut request = reqwest::Client::new().post(url).json(&payload);
+    if let Some(limit) = timeouts::provider_call() {
+        request = request.timeout(limit);
+    }
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!(error = %e, "Can't cache the provider context; sending it per image");
+            return None;
+        }
+    };
+    let status = response.status();
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    if status == reqwest::StatusCode::BAD_REQUEST {
+        // Too small to cache, most likely; that won't change.
+        REFUSED.lock().expect("refused contexts lock poisoned").insert(fingerprint);
+        let message = body["error"]["message"].as_str().unwrap_or_default();
+        tracing::info!(message, "The provider won't cache this context; sending it per image");
+        return None;
+    }
+    let name = body["name"].as_str().filter(|_| status.is_success());
+    if name.is_none() {
+        tracing::warn!(
+            status = status.as_u16(),
+            "Can't cache the provider context; sending it per image"
+        );
+    }
+    name.map(str::to_string)
+}
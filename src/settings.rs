@@ -0,0 +1,407 @@
+// Layered configuration: a TOML file, then the environment (and `.env`), then command-line
+// flags, each overriding the one before. The layers are merged into one set of values by
+// environment variable name, which the server reads with `var`, per request where it can: a
+// file value only where the environment has none, a flag value always. The process environment
+// itself is never written, since other threads read it; a reload builds a new set and swaps it
+// in whole, so a request sees either the old values or the new ones.
+//
+// The file is `--config <FILE>`, else CONFIG_FILE, else `ai-image-captioner.toml` in the
+// working directory when there is one. Its sections and keys are in `SETTINGS`, e.g.
+//
+//   [server]
+//   port = 8080
+//
+//   [provider]
+//   timeout_secs = 30
+//
+//   [limits]
+//   tenant_weights = { partner-key = 3 }
+//
+// Arrays are joined with commas and tables into `name=value` pairs, as the variables expect.
+// `--set provider.max_attempts=5` sets any key from the command line, and `--print-config`
+// prints every setting with its value and where it came from. A SIGHUP reload re-reads the file
+// along with `.env` and applies the flags on top again.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env::VarError;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+use crate::config::{
+    BATCH_BODY_LIMIT, BULK_REQUEST_TIMEOUT, DEFAULT_HOST, DEFAULT_PORT, JOB_BODY_LIMIT,
+    MAX_UPLOAD_BYTES, PDF_BODY_LIMIT, PROVIDER_CONCURRENCY, PROVIDER_QUEUE_LIMIT, PROVIDER_TIMEOUT,
+    RATE_LIMIT_PER_MINUTE, REQUEST_TIMEOUT, SHUTDOWN_TIMEOUT, VIDEO_BODY_LIMIT, ZIP_BODY_LIMIT,
+};
+use crate::modes::Mode;
+use crate::providers::{DEFAULT_API_URL, MAX_ATTEMPTS, RETRY_BASE_MS};
+use crate::{breaker, buffers, cache, imageproc, jobs, spool, voices};
+
+/// The config file read when neither `--config` nor CONFIG_FILE names one.
+const DEFAULT_FILE: &str = "ai-image-captioner.toml";
+
+/// One setting: its key in the file (`section.name`), its environment variable, the default
+/// when neither sets it, and whether `--print-config` hides its value.
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    default: Fallback,
+    secret: bool,
+}
+
+/// A setting's default as `--print-config` shows it: spelled out, or made from the constant the
+/// code falls back on, so the two can't disagree.
+enum Fallback {
+    Text(&'static str),
+    Derived(fn() -> String),
+}
+
+impl Fallback {
+    fn text(&self) -> String {
+        match self {
+            Fallback::Text(text) => text.to_string(),
+            Fallback::Derived(make) => make(),
+        }
+    }
+}
+
+const fn setting(key: &'static str, env: &'static str, default: &'static str) -> Setting {
+    Setting {
+        key,
+        env,
+        default: Fallback::Text(default),
+        secret: false,
+    }
+}
+
+/// A setting whose default comes from a constant.
+const fn derived(key: &'static str, env: &'static str, default: fn() -> String) -> Setting {
+    Setting {
+        key,
+        env,
+        default: Fallback::Derived(default),
+        secret: false,
+    }
+}
+
+const fn secret(key: &'static str, env: &'static str) -> Setting {
+    Setting {
+        key,
+        env,
+        default: Fallback::Text(""),
+        secret: true,
+    }
+}
+
+/// `bytes` as whole megabytes.
+fn mb(bytes: usize) -> String {
+    (bytes / (1024 * 1024)).to_string()
+}
+
+const SETTINGS: &[Setting] = &[
+    derived("server.host", "HOST", || DEFAULT_HOST.to_string()),
+    derived("server.port", "PORT", || DEFAULT_PORT.to_string()),
+    derived("server.request_timeout_secs", "REQUEST_TIMEOUT_SECS", || {
+        REQUEST_TIMEOUT.as_secs().to_string()
+    }),
+    derived("server.bulk_request_timeout_secs", "BULK_REQUEST_TIMEOUT_SECS", || {
+        BULK_REQUEST_TIMEOUT.as_secs().to_string()
+    }),
+    derived("server.shutdown_timeout_secs", "SHUTDOWN_TIMEOUT_SECS", || {
+        SHUTDOWN_TIMEOUT.as_secs().to_string()
+    }),
+    setting("server.trust_proxy", "TRUST_PROXY", "false"),
+    setting("server.log", "RUST_LOG", "info"),
+    setting("server.log_format", "LOG_FORMAT", "text"),
+    setting("server.otlp_endpoint", "OTEL_EXPORTER_OTLP_ENDPOINT", ""),
+    setting("server.log_provider_traffic", "LOG_PROVIDER_TRAFFIC", "true"),
+    setting("server.jpeg_backend", "JPEG_BACKEND", ""),
+    setting("server.enable_vehicle_mode", "ENABLE_VEHICLE_MODE", "false"),
+    setting("server.assets_dir", "ASSETS_DIR", ""),
+    secret("server.admin_token", "ADMIN_TOKEN"),
+    secret("provider.api_key", "GEMINI_API_KEY"),
+    derived("provider.api_url", "GEMINI_API_URL", || DEFAULT_API_URL.to_string()),
+    derived("provider.timeout_secs", "PROVIDER_TIMEOUT_SECS", || {
+        PROVIDER_TIMEOUT.as_secs().to_string()
+    }),
+    derived("provider.max_attempts", "PROVIDER_MAX_ATTEMPTS", || MAX_ATTEMPTS.to_string()),
+    derived("provider.retry_base_ms", "PROVIDER_RETRY_BASE_MS", || RETRY_BASE_MS.to_string()),
+    setting("provider.context_cache", "PROVIDER_CONTEXT_CACHE", "false"),
+    derived("provider.circuit_breaker_threshold", "CIRCUIT_BREAKER_THRESHOLD", || {
+        breaker::THRESHOLD.to_string()
+    }),
+    derived("provider.circuit_breaker_cooldown_secs", "CIRCUIT_BREAKER_COOLDOWN_SECS", || {
+        breaker::COOLDOWN_SECS.to_string()
+    }),
+    setting("provider.translation", "TRANSLATION_PROVIDER", "llm"),
+    setting("provider.tenant_translators", "TENANT_TRANSLATORS", ""),
+    setting("provider.ffmpeg", "FFMPEG", "ffmpeg"),
+    setting("provider.pdftoppm", "PDFTOPPM", "pdftoppm"),
+    derived("prompt.mode", "DEFAULT_MODE", || Mode::default().name().to_string()),
+    setting("prompt.language", "DEFAULT_LANGUAGE", ""),
+    derived("prompt.brand_voices_file", "BRAND_VOICES_FILE", || voices::DEFAULT_FILE.into()),
+    setting("prompt.fashion_attributes_file", "FASHION_ATTRIBUTES_FILE", ""),
+    setting("prompt.policy_file", "POLICY_FILE", ""),
+    setting("prompt.price_table_file", "PRICE_TABLE_FILE", ""),
+    derived("limits.rate_limit_per_minute", "RATE_LIMIT_PER_MINUTE", || {
+        RATE_LIMIT_PER_MINUTE.to_string()
+    }),
+    derived("limits.max_upload_mb", "MAX_UPLOAD_MB", || mb(MAX_UPLOAD_BYTES)),
+    derived("limits.max_batch_mb", "MAX_BATCH_MB", || mb(BATCH_BODY_LIMIT)),
+    derived("limits.max_job_mb", "MAX_JOB_MB", || mb(JOB_BODY_LIMIT)),
+    derived("limits.max_video_mb", "MAX_VIDEO_MB", || mb(VIDEO_BODY_LIMIT)),
+    derived("limits.max_pdf_mb", "MAX_PDF_MB", || mb(PDF_BODY_LIMIT)),
+    derived("limits.max_zip_mb", "MAX_ZIP_MB", || mb(ZIP_BODY_LIMIT)),
+    derived("limits.upload_memory_mb", "UPLOAD_MEMORY_MB", || spool::MEMORY_MB.to_string()),
+    derived("limits.provider_concurrency", "PROVIDER_CONCURRENCY", || {
+        PROVIDER_CONCURRENCY.to_string()
+    }),
+    derived("limits.provider_queue_limit", "PROVIDER_QUEUE_LIMIT", || {
+        PROVIDER_QUEUE_LIMIT.to_string()
+    }),
+    setting("limits.tenant_weights", "TENANT_WEIGHTS", ""),
+    derived("limits.passthrough_max_bytes", "PASSTHROUGH_MAX_BYTES", || {
+        imageproc::PASSTHROUGH_MAX_BYTES.to_string()
+    }),
+    derived("limits.max_image_dimension", "MAX_IMAGE_DIMENSION", || {
+        imageproc::MAX_IMAGE_DIMENSION.to_string()
+    }),
+    derived("limits.passthrough_max_dimension", "PASSTHROUGH_MAX_DIMENSION", || {
+        imageproc::MAX_IMAGE_DIMENSION.to_string()
+    }),
+    setting("limits.passthrough_formats", "PASSTHROUGH_FORMATS", "jpeg,png,webp"),
+    derived("limits.buffer_pool_max_mb", "BUFFER_POOL_MAX_MB", || buffers::MAX_MB.to_string()),
+    secret("storage.database_url", "DATABASE_URL"),
+    setting("storage.history_db", "HISTORY_DB", ""),
+    setting("storage.audit_log", "AUDIT_LOG", "false"),
+    setting("storage.history_thumbnails_dir", "HISTORY_THUMBNAILS_DIR", ""),
+    setting("storage.history_retention_days", "HISTORY_RETENTION_DAYS", "0"),
+    secret("storage.redis_url", "REDIS_URL"),
+    derived("storage.jobs_file", "JOBS_FILE", || jobs::DEFAULT_FILE.to_string()),
+    derived("storage.caption_cache_days", "CAPTION_CACHE_DAYS", || {
+        cache::DEFAULT_DAYS.to_string()
+    }),
+    derived("storage.caption_cache_memory_entries", "CAPTION_CACHE_MEMORY_ENTRIES", || {
+        cache::DEFAULT_MEMORY_ENTRIES.to_string()
+    }),
+    derived("storage.caption_cache_memory_ttl_secs", "CAPTION_CACHE_MEMORY_TTL_SECS", || {
+        cache::DEFAULT_MEMORY_TTL_SECS.to_string()
+    }),
+    secret("auth.api_keys", "API_KEYS"),
+    setting("auth.api_keys_db", "API_KEYS_DB", ""),
+    setting("auth.oidc_issuer", "OIDC_ISSUER", ""),
+    setting("auth.oidc_audience", "OIDC_AUDIENCE", ""),
+    setting("auth.oidc_jwks_url", "OIDC_JWKS_URL", ""),
+    setting("auth.oidc_identity_claim", "OIDC_IDENTITY_CLAIM", ""),
+];
+
+/// Where a setting's value came from, for `--print-config`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Default,
+    File,
+    Environment,
+    Flag,
+}
+
+/// What `load` applied, kept for reloads and `--print-config`.
+struct Layers {
+    file: Option<PathBuf>,
+    /// The environment the process started with, `.env` included.
+    process: HashMap<String, String>,
+    /// That environment with `.env` as last read over it, which the file doesn't override.
+    environment: HashMap<String, String>,
+    /// What the file gave when it was last read.
+    file_values: Vec<(&'static str, String)>,
+    /// Variables the file set.
+    from_file: HashSet<&'static str>,
+    /// Variables the command line set, with their values.
+    flags: Vec<(&'static str, String)>,
+}
+
+static LAYERS: OnceLock<Mutex<Layers>> = OnceLock::new();
+
+/// The merged values in effect, replaced whole by `apply`; `None` until `load`.
+static CURRENT: RwLock<Option<Arc<HashMap<String, String>>>> = RwLock::new(None);
+
+/// The value of the setting (or any other variable) `name` in the configuration in effect.
+/// Before `load`, as in tests, it is read from the process environment.
+pub(crate) fn var(name: &str) -> Result<String, VarError> {
+    let current = CURRENT.read().expect("settings lock poisoned").clone();
+    match current {
+        Some(values) => values.get(name).cloned().ok_or(VarError::NotPresent),
+        None => std::env::var(name),
+    }
+}
+
+/// Applies the config file at `file` (or the default one) and then the command-line `flags`,
+/// given as `(key, value)` with keys as in the file, on top of the environment. Call once, after
+/// loading `.env` and before anything reads a setting.
+pub fn load(file: Option<PathBuf>, flags: Vec<(String, String)>) -> Result<(), String> {
+    let flags = flags
+        .into_iter()
+        .map(|(key, value)| Ok((find(&key)?.env, value)))
+        .collect::<Result<Vec<_>, String>>()?;
+    let process: HashMap<String, String> = std::env::vars().collect();
+    let file = file
+        .or_else(|| std::env::var_os("CONFIG_FILE").map(PathBuf::from))
+        .or_else(|| Some(PathBuf::from(DEFAULT_FILE)).filter(|path| path.exists()));
+
+    let mut layers = Layers {
+        file,
+        environment: process.clone(),
+        process,
+        file_values: Vec::new(),
+        from_file: HashSet::new(),
+        flags,
+    };
+    apply(&mut layers)?;
+    LAYERS
+        .set(Mutex::new(layers))
+        .map_err(|_| "The configuration is already loaded".to_string())
+}
+
+/// Re-reads `.env`, over the environment the process started with, and the config file, and
+/// applies the flags again. A file that can't be read keeps the values it gave before.
+pub(crate) fn reload() -> Result<(), String> {
+    let Some(layers) = LAYERS.get() else {
+        return Ok(());
+    };
+    let mut layers = layers.lock().expect("settings lock poisoned");
+    // `.env` may set variables it didn't before, which then win over the file.
+    layers.environment = layers.process.clone();
+    match dotenvy::dotenv_iter() {
+        Ok(entries) => layers.environment.extend(entries.flatten()),
+        // No .env is fine; the process environment still applies.
+        Err(e) if e.not_found() => {}
+        Err(e) => tracing::warn!("Could not re-read .env: {}", e),
+    }
+    apply(&mut layers)
+}
+
+/// Makes the values in effect: the environment, the file's values where it has none, then the
+/// flags'. The file's values from before stay when it can't be read, and the error is returned.
+fn apply(layers: &mut Layers) -> Result<(), String> {
+    let read = match &layers.file {
+        Some(path) => read_file(path).map(|values| layers.file_values = values),
+        None => Ok(()),
+    };
+    let mut values = layers.environment.clone();
+    layers.from_file.clear();
+    for (env, value) in &layers.file_values {
+        if !layers.environment.contains_key(*env) {
+            values.insert(env.to_string(), value.clone());
+            layers.from_file.insert(env);
+        }
+    }
+    for (env, value) in &layers.flags {
+        values.insert(env.to_string(), value.clone());
+    }
+    *CURRENT.write().expect("settings lock poisoned") = Some(Arc::new(values));
+    read
+}
+
+/// The variables the config file at `path` sets, with their values.
+fn read_file(path: &std::path::Path) -> Result<Vec<(&'static str, String)>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read the config file {}: {}", path.display(), e))?;
+    let table: toml::Table = text
+        .parse()
+        .map_err(|e| format!("The config file {} is not valid TOML: {}", path.display(), e))?;
+
+    let mut values = Vec::new();
+    for (section, entries) in table {
+        let toml::Value::Table(entries) = entries else {
+            return Err(format!(
+                "{}: `{}` must be a section such as [server]",
+                path.display(),
+                section
+            ));
+        };
+        for (name, value) in entries {
+            let key = format!("{}.{}", section, name);
+            let setting = find(&key).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let value = to_variable(&value)
+                .ok_or_else(|| format!("{}: `{}` can't be {}", path.display(), key, value))?;
+            values.push((setting.env, value));
+        }
+    }
+    Ok(values)
+}
+
+fn find(key: &str) -> Result<&'static Setting, String> {
+    SETTINGS
+        .iter()
+        .find(|setting| setting.key == key)
+        .ok_or_else(|| format!("Unknown setting `{}`; see --print-config for the keys", key))
+}
+
+/// A TOML value as its environment variable spells it: arrays joined with commas, tables as
+/// `name=value` pairs.
+fn to_variable(value: &toml::Value) -> Option<String> {
+    let scalar = |value: &toml::Value| match value {
+        toml::Value::String(text) => Some(text.clone()),
+        toml::Value::Integer(number) => Some(number.to_string()),
+        toml::Value::Float(number) => Some(number.to_string()),
+        toml::Value::Boolean(flag) => Some(flag.to_string()),
+        _ => None,
+    };
+    match value {
+        toml::Value::Array(items) => {
+            let items: Option<Vec<_>> = items.iter().map(scalar).collect();
+            Some(items?.join(","))
+        }
+        toml::Value::Table(entries) => {
+            let pairs: Option<Vec<_>> = entries
+                .iter()
+                .map(|(name, value)| Some(format!("{}={}", name, scalar(value)?)))
+                .collect();
+            Some(pairs?.join(","))
+        }
+        value => scalar(value),
+    }
+}
+
+/// Every setting with its value and where it came from, as `--print-config` shows them.
+pub fn describe() -> String {
+    let layers = LAYERS.get().map(|layers| layers.lock().expect("settings lock poisoned"));
+    let source = |env: &str| match &layers {
+        Some(layers) if layers.flags.iter().any(|(flag, _)| *flag == env) => Source::Flag,
+        Some(layers) if layers.from_file.contains(env) => Source::File,
+        _ if var(env).is_ok() => Source::Environment,
+        _ => Source::Default,
+    };
+
+    let mut sections: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for setting in SETTINGS {
+        let (section, name) = setting.key.split_once('.').expect("keys have a section");
+        let default = setting.default.text();
+        let line = match source(setting.env) {
+            Source::Default if default.is_empty() => {
+                format!("# {} is unset ({})", name, setting.env)
+            }
+            Source::Default => format!("{} = {:?}  # {}, default", name, default, setting.env),
+            source => {
+                let value = match setting.secret {
+                    true => "********".to_string(),
+                    false => var(setting.env).unwrap_or_default(),
+                };
+                let from = match source {
+                    Source::File => "config file",
+                    Source::Flag => "command line",
+                    _ => "environment",
+                };
+                format!("{} = {:?}  # {}, {}", name, value, setting.env, from)
+            }
+        };
+        sections.entry(section).or_default().push(line);
+    }
+
+    let mut text = match layers.as_ref().and_then(|layers| layers.file.as_ref()) {
+        Some(file) => format!("# Config file: {}\n", file.display()),
+        None => "# No config file\n".to_string(),
+    };
+    for (section, lines) in sections {
+        text.push_str(&format!("\n[{}]\n{}\n", section, lines.join("\n")));
+    }
+    text
+}
@@ -0,0 +1,83 @@
+// State replicas share (the `redis` feature): with REDIS_URL set, the caption cache (see `cache`)
+// and the rate-limit buckets (see `ratelimit`) live in Redis, so every replica behind a load
+// balancer hands out the same cached captions and counts a client's requests together. Without
+// it, the cache stays in the history's database and each process keeps its own buckets.
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::CaptionError;
+
+#[cfg(feature = "redis")]
+mod redis;
+
+static SHARED: OnceLock<Arc<dyn SharedState>> = OnceLock::new();
+
+#[async_trait]
+pub(crate) trait SharedState: Send + Sync {
+    /// The backend's name, for the startup log.
+    fn backend(&self) -> &'static str;
+
+    /// The result `tenant` got for cache `key` no earlier than `since`, with when it was made.
+    async fn cached_caption(
+        &self,
+        tenant: &str,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Option<(DateTime<Utc>, String)>, CaptionError>;
+
+    /// Keeps `response` (a result as JSON) as `tenant`'s for cache `key` for `max_age`,
+    /// replacing any before.
+    async fn cache_caption(
+        &self,
+        tenant: &str,
+        key: &str,
+        created_at: DateTime<Utc>,
+        response: String,
+        max_age: Duration,
+    ) -> Result<(), CaptionError>;
+
+    /// Removes `tenant`'s cached results; returns how many there were.
+    async fn forget_cached_captions(&self, tenant: &str) -> Result<u64, CaptionError>;
+
+    /// Takes a token from `client`'s bucket of `per_minute`: whether there was one, and how
+    /// many are left.
+    async fn take_token(&self, client: IpAddr, per_minute: u32)
+        -> Result<(bool, f64), CaptionError>;
+}
+
+fn setting(name: &str) -> Option<String> {
+    crate::settings::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+/// Connects to the Redis REDIS_URL names and makes it the shared state; `None` when it isn't
+/// set.
+pub(crate) async fn open() -> Result<Option<Arc<dyn SharedState>>, String> {
+    let Some(url) = setting("REDIS_URL") else {
+        return Ok(None);
+    };
+    let shared = redis_state(url).await?;
+    let _ = SHARED.set(shared.clone());
+    Ok(Some(shared))
+}
+
+/// The shared state, once `open()` has found one.
+pub(crate) fn get() -> Option<&'static Arc<dyn SharedState>> {
+    SHARED.get()
+}
+
+#[cfg(feature = "redis")]
+async fn redis_state(url: String) -> Result<Arc<dyn SharedState>, String> {
+    let state = redis::RedisState::connect(&url)
+        .await
+        .map_err(|e| format!("Can't connect to Redis: {}", e))?;
+    Ok(Arc::new(state))
+}
+
+#[cfg(not(feature = "redis"))]
+async fn redis_state(_url: String) -> Result<Arc<dyn SharedState>, String> {
+    Err("this build has no Redis support; rebuild with `--features redis`".into())
+}
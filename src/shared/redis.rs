@@ -0,0 +1,170 @@
+// Redis shared state (the `redis` feature): one multiplexed connection, shared by all requests
+// and made again when it drops. Cached captions are hashes that Redis expires by itself; rate
+// limits are token buckets updated by a Lua script, so replicas taking from the same bucket at
+// once can't both get its last token, and timed by the Redis clock, so replicas' clocks don't
+// need to agree.
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::{AsyncCommands, Script};
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use super::SharedState;
+use crate::CaptionError;
+
+/// How long to wait for Redis before the cache misses and rate limits fall back to the process.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECTION_RETRIES: usize = 2;
+/// Longest wait between connection attempts, in milliseconds.
+const MAX_RETRY_DELAY_MS: u64 = 1000;
+
+/// Every key starts with this, so the server can share a Redis with other applications.
+const PREFIX: &str = "ai-image-captioner";
+
+/// Refills the bucket in KEYS[1] of ARGV[1] tokens a minute, then takes one if it can.
+/// Returns whether it did and the tokens left (as a string, since Lua numbers come back as
+/// integers). A bucket that would be full again is forgotten.
+static TAKE_TOKEN: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+local capacity = tonumber(ARGV[1])
+local rate = capacity / 60000
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or capacity
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - updated) * rate)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil((capacity - tokens) / rate) + 1)
+return {allowed, tostring(tokens)}
+",
+    )
+});
+
+pub(super) struct RedisState {
+    connection: ConnectionManager,
+}
+
+impl RedisState {
+    pub(super) async fn connect(url: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let config = ConnectionManagerConfig::new()
+            .set_response_timeout(RESPONSE_TIMEOUT)
+            .set_connection_timeout(CONNECTION_TIMEOUT)
+            .set_number_of_retries(CONNECTION_RETRIES)
+            .set_max_delay(MAX_RETRY_DELAY_MS);
+        let connection = ConnectionManager::new_with_config(client, config).await?;
+        Ok(RedisState { connection })
+    }
+}
+
+/// Logs a Redis failure and turns it into the error the request fails with.
+fn redis_error(e: redis::RedisError) -> CaptionError {
+    tracing::error!(error = %e, "Redis error");
+    CaptionError::Internal(format!("Redis error: {}", e))
+}
+
+fn cache_key(tenant: &str, key: &str) -> String {
+    format!("{}:cache:{}:{}", PREFIX, tenant, key)
+}
+
+#[async_trait]
+impl SharedState for RedisState {
+    fn backend(&self) -> &'static str {
+        "Redis"
+    }
+
+    async fn cached_caption(
+        &self,
+        tenant: &str,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Option<(DateTime<Utc>, String)>, CaptionError> {
+        let mut connection = self.connection.clone();
+        let (created_at, response): (Option<String>, Option<String>) = connection
+            .hget(cache_key(tenant, key), &["created_at", "response"])
+            .await
+            .map_err(redis_error)?;
+        let created_at = created_at
+            .and_then(|created_at| DateTime::parse_from_rfc3339(&created_at).ok())
+            .map(|created_at| created_at.with_timezone(&Utc))
+            .filter(|created_at| *created_at >= since);
+        Ok(created_at.zip(response))
+    }
+
+    async fn cache_caption(
+        &self,
+        tenant: &str,
+        key: &str,
+        created_at: DateTime<Utc>,
+        response: String,
+        max_age: Duration,
+    ) -> Result<(), CaptionError> {
+        let key = cache_key(tenant, key);
+        let fields = [("created_at", created_at.to_rfc3339()), ("response", response)];
+        let mut connection = self.connection.clone();
+        redis::pipe()
+            .atomic()
+            .hset_multiple(&key, &fields)
+            .ignore()
+            .expire(&key, max_age.as_secs() as i64)
+            .ignore()
+            .query_async::<()>(&mut connection)
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn forget_cached_captions(&self, tenant: &str) -> Result<u64, CaptionError> {
+        // The tenant's glob characters escaped, and exactly a hex SHA-256 after it, so no other
+        // tenant whose name starts with this one's matches.
+        let mut pattern = format!("{}:cache:", PREFIX);
+        for c in tenant.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push(':');
+        pattern.push_str(&"?".repeat(64));
+
+        let mut connection = self.connection.clone();
+        let keys: Vec<String> = {
+            let mut scan = connection.scan_match(&pattern).await.map_err(redis_error)?;
+            let mut keys = Vec::new();
+            while let Some(key) = scan.next_item().await {
+                keys.push(key);
+            }
+            keys
+        };
+        let mut removed = 0;
+        for batch in keys.chunks(500) {
+            removed += connection.del::<_, u64>(batch).await.map_err(redis_error)?;
+        }
+        Ok(removed)
+    }
+
+    async fn take_token(
+        &self,
+        client: IpAddr,
+        per_minute: u32,
+    ) -> Result<(bool, f64), CaptionError> {
+        let mut connection = self.connection.clone();
+        let (allowed, tokens): (i64, String) = TAKE_TOKEN
+            .key(format!("{}:ratelimit:{}", PREFIX, client))
+            .arg(per_minute)
+            .invoke_async(&mut connection)
+            .await
+            .map_err(redis_error)?;
+        Ok((allowed == 1, tokens.parse().unwrap_or(0.0)))
+    }
+}
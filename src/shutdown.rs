@@ -0,0 +1,46 @@
+// Graceful shutdown on SIGTERM or Ctrl-C (SIGINT), for rolling deploys: the server stops
+// accepting connections, lets the requests in flight and the running jobs finish, saves the jobs
+// (see `jobs`) and exits. Whatever hasn't finished within SHUTDOWN_TIMEOUT_SECS (default 25, so
+// it fits in a container platform's usual 30-second grace period) is cut short; running jobs
+// are saved with the images they didn't get to as failed.
+
+use std::time::Duration;
+
+use crate::config::SHUTDOWN_TIMEOUT;
+
+/// How long requests and jobs get to finish once a shutdown starts.
+pub fn timeout() -> Duration {
+    crate::settings::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(SHUTDOWN_TIMEOUT)
+}
+
+/// Resolves once the process is asked to stop.
+#[cfg(unix)]
+pub async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::warn!(
+                "Could not listen for SIGTERM; only Ctrl-C shuts down gracefully: {}",
+                e
+            );
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// SIGTERM doesn't exist here; Ctrl-C is the only way to ask.
+#[cfg(not(unix))]
+pub async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
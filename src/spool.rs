@@ -0,0 +1,220 @@
+// Uploaded files, kept in memory up to a point and on disk beyond it. Reading whole uploads into
+// memory lets a few concurrent big ones (a 500-image job, a 200 MB video) take the process
+// down, so each form may keep UPLOAD_MEMORY_MB (default 4) of its files in memory, and a file
+// that would take it over is written to a temporary file as it arrives instead. Images on disk
+// are read back one at a time as they are captioned, ZIP archives are read from the file, and
+// PDFs and videos are handed to their tools as the file itself.
+//
+// Temporary files go in the system temp directory (TMPDIR), readable only by the server's user
+// (mode 0600), and are removed once the request, or the job, is done with them.
+// UPLOAD_MEMORY_MB is read per form, so a SIGHUP reload applies it; 0 puts every file on disk.
+
+use bytes::{Bytes, BytesMut};
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::CaptionError;
+
+/// The default for UPLOAD_MEMORY_MB.
+pub(crate) const MEMORY_MB: usize = 4;
+
+/// An uploaded file's contents.
+pub(crate) enum Spooled {
+    Memory(Bytes),
+    Disk(SpoolFile),
+}
+
+/// A temporary file holding an upload, removed on drop.
+pub(crate) struct SpoolFile {
+    path: PathBuf,
+    len: usize,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Spooled {
+    /// The whole contents, read from disk when they are there.
+    pub(crate) async fn read(&self) -> Result<Bytes, CaptionError> {
+        match self {
+            Spooled::Memory(data) => Ok(data.clone()),
+            Spooled::Disk(file) => {
+                let data = tokio::fs::read(&file.path).await.map_err(failed)?;
+                Ok(data.into())
+            }
+        }
+    }
+
+    /// Up to the first `len` bytes, e.g. to check what kind of file this is.
+    pub(crate) async fn head(&self, len: usize) -> Result<Bytes, CaptionError> {
+        match self {
+            Spooled::Memory(data) => Ok(data.slice(..len.min(data.len()))),
+            Spooled::Disk(file) => {
+                let mut head = Vec::with_capacity(len);
+                let opened = tokio::fs::File::open(&file.path).await.map_err(failed)?;
+                opened.take(len as u64).read_to_end(&mut head).await.map_err(failed)?;
+                Ok(head.into())
+            }
+        }
+    }
+
+    /// Puts the contents at `path`, for a tool that reads files: linked when they are on disk
+    /// already, copied when that can't be done, written out otherwise.
+    pub(crate) async fn write_to(&self, path: &Path) -> std::io::Result<()> {
+        match self {
+            Spooled::Memory(data) => tokio::fs::write(path, data).await,
+            Spooled::Disk(file) => {
+                if tokio::fs::hard_link(&file.path, path).await.is_err() {
+                    tokio::fs::copy(&file.path, path).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The contents as something to read and seek in, for parsers that don't need them all
+    /// in memory at once.
+    pub(crate) fn reader(&self) -> std::io::Result<Box<dyn ReadSeek>> {
+        match self {
+            Spooled::Memory(data) => Ok(Box::new(std::io::Cursor::new(data.clone()))),
+            Spooled::Disk(file) => Ok(Box::new(std::fs::File::open(&file.path)?)),
+        }
+    }
+}
+
+pub(crate) trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+impl From<Bytes> for Spooled {
+    fn from(data: Bytes) -> Self {
+        Spooled::Memory(data)
+    }
+}
+
+/// Memory a form's files may still take; each file that fits comes out of it.
+pub(crate) struct Budget(usize);
+
+impl Budget {
+    /// UPLOAD_MEMORY_MB, for a new form.
+    pub(crate) fn configured() -> Self {
+        let mb = crate::settings::var("UPLOAD_MEMORY_MB")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(MEMORY_MB);
+        Budget(mb.saturating_mul(1024 * 1024))
+    }
+}
+
+/// One file of a form as it arrives: in memory while it fits the form's budget, then on disk.
+pub(crate) struct Spooler<'a> {
+    budget: &'a mut Budget,
+    memory: BytesMut,
+    disk: Option<(tokio::fs::File, SpoolFile)>,
+}
+
+impl<'a> Spooler<'a> {
+    pub(crate) fn new(budget: &'a mut Budget) -> Self {
+        Spooler {
+            budget,
+            memory: BytesMut::new(),
+            disk: None,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.disk {
+            Some((_, file)) => file.len,
+            None => self.memory.len(),
+        }
+    }
+
+    pub(crate) async fn push(&mut self, chunk: &[u8]) -> Result<(), CaptionError> {
+        if self.disk.is_none() && self.memory.len() + chunk.len() > self.budget.0 {
+            let path = std::env::temp_dir()
+                .join(format!("captioner-upload-{:016x}", rand::random::<u64>()));
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            // Uploads are the clients' images; other users of the machine mustn't read them.
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut writer = options.open(&path).await.map_err(failed)?;
+            let file = SpoolFile {
+                path,
+                len: self.memory.len(),
+            };
+            writer.write_all(&self.memory).await.map_err(failed)?;
+            self.memory = BytesMut::new();
+            self.disk = Some((writer, file));
+        }
+        match &mut self.disk {
+            Some((writer, file)) => {
+                writer.write_all(chunk).await.map_err(failed)?;
+                file.len += chunk.len();
+            }
+            None => self.memory.extend_from_slice(chunk),
+        }
+        Ok(())
+    }
+
+    /// The file, once all of it has arrived.
+    pub(crate) async fn finish(self) -> Result<Spooled, CaptionError> {
+        match self.disk {
+            Some((mut writer, file)) => {
+                writer.flush().await.map_err(failed)?;
+                Ok(Spooled::Disk(file))
+            }
+            None => {
+                self.budget.0 -= self.memory.len();
+                Ok(Spooled::Memory(self.memory.freeze()))
+            }
+        }
+    }
+}
+
+fn failed(e: std::io::Error) -> CaptionError {
+    tracing::error!(error = %e, "Can't spool an upload");
+    CaptionError::Internal(format!("Can't spool the upload: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn files_past_the_budget_go_to_disk() {
+        let mut budget = Budget(10);
+        let mut small = Spooler::new(&mut budget);
+        small.push(b"012345").await.unwrap();
+        let small = small.finish().await.unwrap();
+        let mut big = Spooler::new(&mut budget);
+        big.push(b"0123").await.unwrap();
+        big.push(b"4567").await.unwrap();
+        assert_eq!(big.len(), 8);
+        let big = big.finish().await.unwrap();
+
+        assert!(matches!(small, Spooled::Memory(_)));
+        let Spooled::Disk(file) = &big else {
+            panic!("the second file doesn't fit in what the first left");
+        };
+        let path = file.path.clone();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(&big.read().await.unwrap()[..], b"01234567");
+        assert_eq!(&big.head(3).await.unwrap()[..], b"012");
+        let mut read = String::new();
+        big.reader().unwrap().read_to_string(&mut read).unwrap();
+        assert_eq!(read, "01234567");
+
+        drop(big);
+        assert!(!path.exists());
+    }
+}
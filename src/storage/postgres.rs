@@ -0,0 +1,466 @@
+// PostgreSQL storage (the `postgres` feature): one connection, shared by all requests since
+// it pipelines their queries, and made again when it drops. Captions and tags are searched
+// through a generated tsvector column with a GIN index.
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, NoTls, Row};
+
+use super::{database_error, tags_from_json, tags_to_json, NewAuditEntry, NewCaption, Storage};
+use crate::audit::{AuditEntry, AuditFilter};
+use crate::history::HistoryEntry;
+use crate::CaptionError;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS caption_history (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL,
+    tenant TEXT NOT NULL,
+    image_sha256 TEXT NOT NULL,
+    thumbnail_path TEXT,
+    mode TEXT NOT NULL,
+    caption TEXT NOT NULL,
+    model TEXT NOT NULL,
+    latency_ms BIGINT NOT NULL,
+    tags TEXT NOT NULL DEFAULT '[]'
+)";
+
+const CREATE_INDEX: &str =
+    "CREATE INDEX IF NOT EXISTS caption_history_tenant ON caption_history (tenant, id)";
+
+/// Columns added since the table was first created, then the search index over them.
+const MIGRATIONS: &str = "
+ALTER TABLE caption_history ADD COLUMN IF NOT EXISTS tags TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE caption_history ADD COLUMN IF NOT EXISTS search TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('english', caption || ' ' || tags)) STORED;
+CREATE INDEX IF NOT EXISTS caption_history_search ON caption_history USING GIN (search);";
+
+const CREATE_CACHE: &str = "CREATE TABLE IF NOT EXISTS caption_cache (
+    tenant TEXT NOT NULL,
+    key TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    response TEXT NOT NULL,
+    PRIMARY KEY (tenant, key)
+)";
+
+const COLUMNS: &str =
+    "id, created_at, image_sha256, thumbnail_path, mode, caption, model, latency_ms, tags";
+
+/// The audit log, with a trigger turning away updates and deletes.
+const CREATE_AUDIT: &str = "CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL,
+    request_id TEXT,
+    tenant TEXT NOT NULL,
+    authenticated BOOLEAN NOT NULL,
+    client_ip TEXT,
+    image_sha256 TEXT NOT NULL,
+    mode TEXT NOT NULL,
+    language TEXT,
+    voice TEXT,
+    instructions TEXT,
+    outcome TEXT NOT NULL,
+    model TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_log_created_at ON audit_log (created_at);
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger LANGUAGE plpgsql AS $$
+BEGIN
+    RAISE EXCEPTION 'the audit log is append-only';
+END $$;
+DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log;
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE ON audit_log
+    FOR EACH ROW EXECUTE FUNCTION audit_log_append_only()";
+
+const AUDIT_COLUMNS: &str = "id, created_at, request_id, tenant, authenticated, client_ip,
+    image_sha256, mode, language, voice, instructions, outcome, model";
+
+pub(super) struct PostgresStorage {
+    url: String,
+    session: Mutex<Option<Session>>,
+}
+
+/// The open connection, and the leases (advisory locks) it holds.
+struct Session {
+    client: Arc<Client>,
+    leases: HashSet<String>,
+}
+
+impl PostgresStorage {
+    pub(super) fn new(url: String) -> Self {
+        PostgresStorage {
+            url,
+            session: Mutex::new(None),
+        }
+    }
+
+    /// The open connection, or a new one, holding no leases, when there is none.
+    async fn client(&self) -> Result<Arc<Client>, CaptionError> {
+        let mut session = self.session.lock().await;
+        if let Some(open) = session.as_ref().filter(|open| !open.client.is_closed()) {
+            return Ok(open.client.clone());
+        }
+        let (connected, connection) = tokio_postgres::connect(&self.url, NoTls)
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::error!(error = %e, "PostgreSQL connection closed");
+            }
+        });
+        let connected = Arc::new(connected);
+        *session = Some(Session {
+            client: connected.clone(),
+            leases: HashSet::new(),
+        });
+        Ok(connected)
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    fn backend(&self) -> &'static str {
+        "PostgreSQL"
+    }
+
+    async fn prepare(&self) -> Result<(), CaptionError> {
+        let client = self.client().await?;
+        client
+            .batch_execute(&format!(
+                "{};\n{};\n{};\n{};\n{}",
+                CREATE_TABLE, CREATE_INDEX, MIGRATIONS, CREATE_CACHE, CREATE_AUDIT
+            ))
+            .await
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn add_caption(&self, caption: NewCaption) -> Result<(), CaptionError> {
+        let client = self.client().await?;
+        client
+            .execute(
+                "INSERT INTO caption_history
+                     (created_at, tenant, image_sha256, thumbnail_path, mode, caption, model,
+                      latency_ms, tags)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                &[
+                    &caption.created_at,
+                    &caption.tenant,
+                    &caption.image_sha256,
+                    &caption.thumbnail_path,
+                    &caption.mode,
+                    &caption.caption,
+                    &caption.model,
+                    &(caption.latency_ms as i64),
+                    &tags_to_json(&caption.tags),
+                ],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        Ok(())
+    }
+
+    async fn captions(
+        &self,
+        tenant: &str,
+        before: i64,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, CaptionError> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM caption_history
+                     WHERE tenant = $1 AND id < $2 ORDER BY id DESC LIMIT $3",
+                    COLUMNS
+                ),
+                &[&tenant, &before, &(limit as i64)],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        rows.iter()
+            .map(entry)
+            .collect::<Result<_, tokio_postgres::Error>>()
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn search(
+        &self,
+        tenant: &str,
+        terms: &[String],
+        before: i64,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, CaptionError> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM caption_history
+                     WHERE tenant = $1 AND search @@ plainto_tsquery('english', $2) AND id < $3
+                     ORDER BY id DESC LIMIT $4",
+                    COLUMNS
+                ),
+                &[&tenant, &terms.join(" "), &before, &(limit as i64)],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        rows.iter()
+            .map(entry)
+            .collect::<Result<_, tokio_postgres::Error>>()
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn caption(&self, tenant: &str, id: i64) -> Result<Option<HistoryEntry>, CaptionError> {
+        let client = self.client().await?;
+        let row = client
+            .query_opt(
+                &format!("SELECT {} FROM caption_history WHERE tenant = $1 AND id = $2", COLUMNS),
+                &[&tenant, &id],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        row.as_ref()
+            .map(entry)
+            .transpose()
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn delete_caption(&self, tenant: &str, id: i64) -> Result<bool, CaptionError> {
+        let client = self.client().await?;
+        let removed = client
+            .execute(
+                "DELETE FROM caption_history WHERE tenant = $1 AND id = $2",
+                &[&tenant, &id],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        Ok(removed > 0)
+    }
+
+    async fn delete_tenant_captions(
+        &self,
+        tenant: &str,
+    ) -> Result<Vec<Option<String>>, CaptionError> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "DELETE FROM caption_history WHERE tenant = $1 RETURNING thumbnail_path",
+                &[&tenant],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        rows.iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<_, tokio_postgres::Error>>()
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn prune_captions(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<Option<String>>, CaptionError> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "DELETE FROM caption_history WHERE created_at < $1 RETURNING thumbnail_path",
+                &[&before],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        rows.iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<_, tokio_postgres::Error>>()
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn hold_lease(&self, task: &str) -> Result<bool, CaptionError> {
+        // A session advisory lock, which goes with the connection, so a replica that stops or
+        // loses the database gives it up. It is taken once per connection: asking again while
+        // holding it would succeed too, but stack up locks that are never released.
+        let client = self.client().await?;
+        let this_session = |session: &Session| Arc::ptr_eq(&session.client, &client);
+        let held = self.session.lock().await.as_ref().is_some_and(|session| {
+            this_session(session) && session.leases.contains(task)
+        });
+        if held {
+            return Ok(true);
+        }
+        let taken: bool = client
+            .query_one(
+                "SELECT pg_try_advisory_lock(hashtext('ai-image-captioner'), hashtext($1))",
+                &[&task],
+            )
+            .await
+            .and_then(|row| row.try_get(0))
+            .map_err(|e| database_error(self.backend(), e))?;
+        if taken {
+            if let Some(session) = self.session.lock().await.as_mut().filter(|s| this_session(s)) {
+                session.leases.insert(task.to_string());
+            }
+        }
+        Ok(taken)
+    }
+
+    async fn thumbnail_in_use(&self, name: &str) -> Result<bool, CaptionError> {
+        let client = self.client().await?;
+        client
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM caption_history
+                                WHERE thumbnail_path = $1 OR thumbnail_path LIKE '%/' || $1)",
+                &[&name],
+            )
+            .await
+            .and_then(|row| row.try_get(0))
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn cached_caption(
+        &self,
+        tenant: &str,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Option<(DateTime<Utc>, String)>, CaptionError> {
+        let client = self.client().await?;
+        let row = client
+            .query_opt(
+                "SELECT created_at, response FROM caption_cache
+                 WHERE tenant = $1 AND key = $2 AND created_at >= $3",
+                &[&tenant, &key, &since],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        row.map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+            .transpose()
+            .map_err(|e: tokio_postgres::Error| database_error(self.backend(), e))
+    }
+
+    async fn cache_caption(
+        &self,
+        tenant: &str,
+        key: &str,
+        created_at: DateTime<Utc>,
+        response: String,
+    ) -> Result<(), CaptionError> {
+        let client = self.client().await?;
+        client
+            .execute(
+                "INSERT INTO caption_cache (tenant, key, created_at, response)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (tenant, key) DO UPDATE SET
+                     created_at = excluded.created_at, response = excluded.response",
+                &[&tenant, &key, &created_at, &response],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        Ok(())
+    }
+
+    async fn prune_cache(&self, before: DateTime<Utc>) -> Result<u64, CaptionError> {
+        let client = self.client().await?;
+        client
+            .execute("DELETE FROM caption_cache WHERE created_at < $1", &[&before])
+            .await
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn delete_tenant_cache(&self, tenant: &str) -> Result<u64, CaptionError> {
+        let client = self.client().await?;
+        client
+            .execute("DELETE FROM caption_cache WHERE tenant = $1", &[&tenant])
+            .await
+            .map_err(|e| database_error(self.backend(), e))
+    }
+
+    async fn add_audit_entry(&self, entry: NewAuditEntry) -> Result<(), CaptionError> {
+        let client = self.client().await?;
+        client
+            .execute(
+                "INSERT INTO audit_log
+                     (created_at, request_id, tenant, authenticated, client_ip, image_sha256,
+                      mode, language, voice, instructions, outcome, model)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+                &[
+                    &entry.created_at,
+                    &entry.request_id,
+                    &entry.tenant,
+                    &entry.authenticated,
+                    &entry.client_ip,
+                    &entry.image_sha256,
+                    &entry.mode,
+                    &entry.language,
+                    &entry.voice,
+                    &entry.instructions,
+                    &entry.outcome,
+                    &entry.model,
+                ],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        Ok(())
+    }
+
+    async fn audit_entries(
+        &self,
+        filter: &AuditFilter,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, CaptionError> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM audit_log
+                     WHERE id > $1 AND ($2::timestamptz IS NULL OR created_at >= $2)
+                       AND ($3::timestamptz IS NULL OR created_at < $3)
+                       AND ($4::text IS NULL OR tenant = $4)
+                     ORDER BY id LIMIT $5",
+                    AUDIT_COLUMNS
+                ),
+                &[&after, &filter.since, &filter.until, &filter.tenant, &(limit as i64)],
+            )
+            .await
+            .map_err(|e| database_error(self.backend(), e))?;
+        rows.iter()
+            .map(audit_entry)
+            .collect::<Result<_, tokio_postgres::Error>>()
+            .map_err(|e| database_error(self.backend(), e))
+    }
+}
+
+/// A `caption_history` row selected as `COLUMNS`.
+fn entry(row: &Row) -> Result<HistoryEntry, tokio_postgres::Error> {
+    let tags: String = row.try_get(8)?;
+    let created_at: DateTime<Utc> = row.try_get(1)?;
+    let latency_ms: i64 = row.try_get(7)?;
+    Ok(HistoryEntry {
+        id: row.try_get(0)?,
+        created_at: created_at.to_rfc3339(),
+        image_sha256: row.try_get(2)?,
+        thumbnail_path: row.try_get(3)?,
+        mode: row.try_get(4)?,
+        caption: row.try_get(5)?,
+        tags: tags_from_json(&tags),
+        model: row.try_get(6)?,
+        latency_ms: latency_ms.max(0) as u64,
+    })
+}
+
+/// An `audit_log` row selected as `AUDIT_COLUMNS`.
+fn audit_entry(row: &Row) -> Result<AuditEntry, tokio_postgres::Error> {
+    let created_at: DateTime<Utc> = row.try_get(1)?;
+    Ok(AuditEntry {
+        id: row.try_get(0)?,
+        created_at: created_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        request_id: row.try_get(2)?,
+        tenant: row.try_get(3)?,
+        authenticated: row.try_get(4)?,
+        client_ip: row.try_get(5)?,
+        image_sha256: row.try_get(6)?,
+        mode: row.try_get(7)?,
+        language: row.try_get(8)?,
+        voice: row.try_get(9)?,
+        instructions: row.try_get(10)?,
+        outcome: row.try_get(11)?,
+        model: row.try_get(12)?,
+    })
+}
@@ -0,0 +1,455 @@
+// SQLite storage: one database file, opened per query on a blocking thread. Captions and tags
+// are searched with an FTS5 index that triggers keep in step with the table.
+
+use axum::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::time::Duration;
+
+use super::{database_error, tags_from_json, tags_to_json, NewAuditEntry, NewCaption, Storage};
+use crate::audit::{AuditEntry, AuditFilter};
+use crate::history::HistoryEntry;
+use crate::CaptionError;
+
+/// How long a query waits for another request's write to finish, since each has its own
+/// connection.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS caption_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TEXT NOT NULL,
+    tenant TEXT NOT NULL,
+    image_sha256 TEXT NOT NULL,
+    thumbnail_path TEXT,
+    mode TEXT NOT NULL,
+    caption TEXT NOT NULL,
+    model TEXT NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    tags TEXT NOT NULL DEFAULT '[]'
+)";
+
+const CREATE_INDEX: &str =
+    "CREATE INDEX IF NOT EXISTS caption_history_tenant ON caption_history (tenant, id)";
+
+/// Columns added since the table was first created, as (name, type).
+const ADDED_COLUMNS: &[(&str, &str)] = &[("tags", "TEXT NOT NULL DEFAULT '[]'")];
+
+/// The search index, over the table's captions and tags. The porter stemmer lets "dogs" find
+/// "dog".
+const CREATE_SEARCH: &str = "CREATE VIRTUAL TABLE caption_search USING fts5(
+    caption, tags, content='caption_history', content_rowid='id', tokenize='porter unicode61'
+)";
+
+const CREATE_SEARCH_TRIGGERS: &str = "
+CREATE TRIGGER IF NOT EXISTS caption_search_insert AFTER INSERT ON caption_history BEGIN
+    INSERT INTO caption_search (rowid, caption, tags) VALUES (new.id, new.caption, new.tags);
+END;
+CREATE TRIGGER IF NOT EXISTS caption_search_delete AFTER DELETE ON caption_history BEGIN
+    INSERT INTO caption_search (caption_search, rowid, caption, tags)
+    VALUES ('delete', old.id, old.caption, old.tags);
+END;";
+
+/// Cache times are kept as UTC to the second, so they compare as text.
+const CREATE_CACHE: &str = "CREATE TABLE IF NOT EXISTS caption_cache (
+    tenant TEXT NOT NULL,
+    key TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    response TEXT NOT NULL,
+    PRIMARY KEY (tenant, key)
+)";
+
+const COLUMNS: &str =
+    "id, created_at, image_sha256, thumbnail_path, mode, caption, model, latency_ms, tags";
+
+/// Audit times are kept as UTC to the millisecond, so they compare as text.
+const CREATE_AUDIT: &str = "CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TEXT NOT NULL,
+    request_id TEXT,
+    tenant TEXT NOT NULL,
+    authenticated INTEGER NOT NULL,
+    client_ip TEXT,
+    image_sha256 TEXT NOT NULL,
+    mode TEXT NOT NULL,
+    language TEXT,
+    voice TEXT,
+    instructions TEXT,
+    outcome TEXT NOT NULL,
+    model TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_log_created_at ON audit_log (created_at);
+CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
+CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append-only');
+END;";
+
+const AUDIT_COLUMNS: &str = "id, created_at, request_id, tenant, authenticated, client_ip,
+    image_sha256, mode, language, voice, instructions, outcome, model";
+
+pub(super) struct SqliteStorage {
+    path: String,
+}
+
+impl SqliteStorage {
+    pub(super) fn new(path: String) -> Self {
+        SqliteStorage { path }
+    }
+
+    /// Runs `query` on the database on a blocking thread.
+    async fn with_database<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, CaptionError> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let db = Connection::open(path)?;
+            db.busy_timeout(BUSY_TIMEOUT)?;
+            query(&db)
+        })
+            .await
+            .map_err(|e| CaptionError::Internal(e.to_string()))?
+            .map_err(|e| database_error(self.backend(), e))
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn backend(&self) -> &'static str {
+        "SQLite"
+    }
+
+    async fn prepare(&self) -> Result<(), CaptionError> {
+        self.with_database(|db| {
+            db.execute(CREATE_TABLE, [])?;
+            db.execute(CREATE_INDEX, [])?;
+            for (column, kind) in ADDED_COLUMNS {
+                let exists: bool = db.query_row(
+                    "SELECT count(*) > 0 FROM pragma_table_info('caption_history') WHERE name = ?1",
+                    [column],
+                    |row| row.get(0),
+                )?;
+                if !exists {
+                    let alter = format!("ALTER TABLE caption_history ADD COLUMN {} {}", column, kind);
+                    db.execute(&alter, [])?;
+                }
+            }
+            let indexed: bool = db.query_row(
+                "SELECT count(*) > 0 FROM sqlite_master WHERE name = 'caption_search'",
+                [],
+                |row| row.get(0),
+            )?;
+            if !indexed {
+                // Captions recorded before there was an index are indexed too.
+                db.execute(CREATE_SEARCH, [])?;
+                db.execute("INSERT INTO caption_search (caption_search) VALUES ('rebuild')", [])?;
+            }
+            db.execute_batch(CREATE_SEARCH_TRIGGERS)?;
+            db.execute(CREATE_CACHE, [])?;
+            db.execute_batch(CREATE_AUDIT)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn add_caption(&self, caption: NewCaption) -> Result<(), CaptionError> {
+        self.with_database(move |db| {
+            db.execute(
+                "INSERT INTO caption_history
+                     (created_at, tenant, image_sha256, thumbnail_path, mode, caption, model,
+                      latency_ms, tags)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    caption.created_at.to_rfc3339(),
+                    caption.tenant,
+                    caption.image_sha256,
+                    caption.thumbnail_path,
+                    caption.mode,
+                    caption.caption,
+                    caption.model,
+                    caption.latency_ms,
+                    tags_to_json(&caption.tags)
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn captions(
+        &self,
+        tenant: &str,
+        before: i64,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, CaptionError> {
+        let tenant = tenant.to_string();
+        self.with_database(move |db| {
+            let mut rows = db.prepare(&format!(
+                "SELECT {} FROM caption_history
+                 WHERE tenant = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+                COLUMNS
+            ))?;
+            let entries = rows.query_map(params![tenant, before, limit], entry)?;
+            entries.collect()
+        })
+        .await
+    }
+
+    async fn search(
+        &self,
+        tenant: &str,
+        terms: &[String],
+        before: i64,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, CaptionError> {
+        let tenant = tenant.to_string();
+        // Every term quoted, so none is read as FTS5 syntax; terms side by side must all match.
+        let query: Vec<String> = terms.iter().map(|term| format!("\"{}\"", term)).collect();
+        let query = query.join(" ");
+        self.with_database(move |db| {
+            let mut rows = db.prepare(&format!(
+                "SELECT {} FROM caption_history
+                 WHERE id IN (SELECT rowid FROM caption_search WHERE caption_search MATCH ?1)
+                   AND tenant = ?2 AND id < ?3
+                 ORDER BY id DESC LIMIT ?4",
+                COLUMNS
+            ))?;
+            let entries = rows.query_map(params![query, tenant, before, limit], entry)?;
+            entries.collect()
+        })
+        .await
+    }
+
+    async fn caption(&self, tenant: &str, id: i64) -> Result<Option<HistoryEntry>, CaptionError> {
+        let tenant = tenant.to_string();
+        self.with_database(move |db| {
+            db.query_row(
+                &format!("SELECT {} FROM caption_history WHERE tenant = ?1 AND id = ?2", COLUMNS),
+                params![tenant, id],
+                entry,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn delete_caption(&self, tenant: &str, id: i64) -> Result<bool, CaptionError> {
+        let tenant = tenant.to_string();
+        self.with_database(move |db| {
+            db.execute(
+                "DELETE FROM caption_history WHERE tenant = ?1 AND id = ?2",
+                params![tenant, id],
+            )
+            .map(|removed| removed > 0)
+        })
+        .await
+    }
+
+    async fn delete_tenant_captions(
+        &self,
+        tenant: &str,
+    ) -> Result<Vec<Option<String>>, CaptionError> {
+        let tenant = tenant.to_string();
+        self.with_database(move |db| {
+            let mut rows = db.prepare(
+                "DELETE FROM caption_history WHERE tenant = ?1 RETURNING thumbnail_path",
+            )?;
+            let thumbnails = rows.query_map([tenant], |row| row.get(0))?;
+            thumbnails.collect()
+        })
+        .await
+    }
+
+    async fn prune_captions(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<Option<String>>, CaptionError> {
+        self.with_database(move |db| {
+            // Compared as times, since stored ones have as many decimals as they need.
+            let mut rows = db.prepare(
+                "DELETE FROM caption_history WHERE julianday(created_at) < julianday(?1)
+                 RETURNING thumbnail_path",
+            )?;
+            let thumbnails = rows.query_map([before.to_rfc3339()], |row| row.get(0))?;
+            thumbnails.collect()
+        })
+        .await
+    }
+
+    async fn hold_lease(&self, _task: &str) -> Result<bool, CaptionError> {
+        // A SQLite history can't be shared: it is a local file, for one process on one host, so
+        // there is no other replica to take turns with. Replicas need PostgreSQL (see
+        // `postgres`), whose advisory locks lease work to one of them. SQLite's own locking
+        // still keeps a second process that opens the file by mistake from writing at the
+        // same time.
+        Ok(true)
+    }
+
+    async fn thumbnail_in_use(&self, name: &str) -> Result<bool, CaptionError> {
+        let name = name.to_string();
+        self.with_database(move |db| {
+            db.query_row(
+                "SELECT EXISTS (SELECT 1 FROM caption_history
+                                WHERE thumbnail_path = ?1 OR thumbnail_path LIKE '%/' || ?1)",
+                [name],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    async fn cached_caption(
+        &self,
+        tenant: &str,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Option<(DateTime<Utc>, String)>, CaptionError> {
+        let (tenant, key) = (tenant.to_string(), key.to_string());
+        let cached = self
+            .with_database(move |db| {
+                db.query_row(
+                    "SELECT created_at, response FROM caption_cache
+                     WHERE tenant = ?1 AND key = ?2 AND created_at >= ?3",
+                    params![tenant, key, cache_time(since)],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+                )
+                .optional()
+            })
+            .await?;
+        Ok(cached.and_then(|(created_at, response)| {
+            let created_at = DateTime::parse_from_rfc3339(&created_at).ok()?;
+            Some((created_at.with_timezone(&Utc), response))
+        }))
+    }
+
+    async fn cache_caption(
+        &self,
+        tenant: &str,
+        key: &str,
+        created_at: DateTime<Utc>,
+        response: String,
+    ) -> Result<(), CaptionError> {
+        let (tenant, key) = (tenant.to_string(), key.to_string());
+        self.with_database(move |db| {
+            db.execute(
+                "INSERT OR REPLACE INTO caption_cache (tenant, key, created_at, response)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![tenant, key, cache_time(created_at), response],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn prune_cache(&self, before: DateTime<Utc>) -> Result<u64, CaptionError> {
+        self.with_database(move |db| {
+            db.execute("DELETE FROM caption_cache WHERE created_at < ?1", [cache_time(before)])
+                .map(|removed| removed as u64)
+        })
+        .await
+    }
+
+    async fn delete_tenant_cache(&self, tenant: &str) -> Result<u64, CaptionError> {
+        let tenant = tenant.to_string();
+        self.with_database(move |db| {
+            db.execute("DELETE FROM caption_cache WHERE tenant = ?1", [tenant])
+                .map(|removed| removed as u64)
+        })
+        .await
+    }
+
+    async fn add_audit_entry(&self, entry: NewAuditEntry) -> Result<(), CaptionError> {
+        self.with_database(move |db| {
+            db.execute(
+                "INSERT INTO audit_log
+                     (created_at, request_id, tenant, authenticated, client_ip, image_sha256,
+                      mode, language, voice, instructions, outcome, model)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    audit_time(entry.created_at),
+                    entry.request_id,
+                    entry.tenant,
+                    entry.authenticated,
+                    entry.client_ip,
+                    entry.image_sha256,
+                    entry.mode,
+                    entry.language,
+                    entry.voice,
+                    entry.instructions,
+                    entry.outcome,
+                    entry.model
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn audit_entries(
+        &self,
+        filter: &AuditFilter,
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, CaptionError> {
+        let since = filter.since.map(audit_time);
+        let until = filter.until.map(audit_time);
+        let tenant = filter.tenant.clone();
+        self.with_database(move |db| {
+            let mut rows = db.prepare(&format!(
+                "SELECT {} FROM audit_log
+                 WHERE id > ?1 AND (?2 IS NULL OR created_at >= ?2)
+                   AND (?3 IS NULL OR created_at < ?3) AND (?4 IS NULL OR tenant = ?4)
+                 ORDER BY id LIMIT ?5",
+                AUDIT_COLUMNS
+            ))?;
+            let entries =
+                rows.query_map(params![after, since, until, tenant, limit], audit_entry)?;
+            entries.collect()
+        })
+        .await
+    }
+}
+
+fn audit_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn cache_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// A `caption_history` row selected as `COLUMNS`.
+fn entry(row: &rusqlite::Row) -> rusqlite::Result<HistoryEntry> {
+    let tags: String = row.get(8)?;
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        image_sha256: row.get(2)?,
+        thumbnail_path: row.get(3)?,
+        mode: row.get(4)?,
+        caption: row.get(5)?,
+        tags: tags_from_json(&tags),
+        model: row.get(6)?,
+        latency_ms: row.get(7)?,
+    })
+}
+
+/// An `audit_log` row selected as `AUDIT_COLUMNS`.
+fn audit_entry(row: &rusqlite::Row) -> rusqlite::Result<AuditEntry> {
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        request_id: row.get(2)?,
+        tenant: row.get(3)?,
+        authenticated: row.get(4)?,
+        client_ip: row.get(5)?,
+        image_sha256: row.get(6)?,
+        mode: row.get(7)?,
+        language: row.get(8)?,
+        voice: row.get(9)?,
+        instructions: row.get(10)?,
+        outcome: row.get(11)?,
+        model: row.get(12)?,
+    })
+}
@@ -0,0 +1,118 @@
+// SRT and WebVTT caption tracks built from timed descriptions, so a described frame
+// sequence can be attached to a player as a descriptive track.
+
+use std::time::Duration;
+
+/// One described stretch of the timeline.
+pub struct Segment {
+    pub start: Duration,
+    pub end: Duration,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackFormat {
+    Srt,
+    Vtt,
+}
+
+impl TrackFormat {
+    /// Picks the format from a file extension (`.srt` or `.vtt`).
+    pub fn from_path(path: &std::path::Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "srt" => Some(TrackFormat::Srt),
+            "vtt" => Some(TrackFormat::Vtt),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TrackFormat::Srt => "application/x-subrip",
+            TrackFormat::Vtt => "text/vtt; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TrackFormat::Srt => "srt",
+            TrackFormat::Vtt => "vtt",
+        }
+    }
+}
+
+/// Turns captions for evenly spaced frames into segments. `None` marks a frame that
+/// failed and leaves a gap; consecutive frames with the same caption become one segment.
+pub fn frame_segments(captions: &[Option<&str>], interval: Duration) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for (index, caption) in captions.iter().enumerate() {
+        let Some(text) = caption.map(str::trim).filter(|text| !text.is_empty()) else {
+            continue;
+        };
+        let start = interval * index as u32;
+        let end = start + interval;
+
+        match segments.last_mut() {
+            Some(last) if last.end == start && last.text == text => last.end = end,
+            _ => segments.push(Segment {
+                start,
+                end,
+                text: text.to_string(),
+            }),
+        }
+    }
+
+    segments
+}
+
+fn timestamp(time: Duration, decimal: char) -> String {
+    let millis = time.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        decimal,
+        millis % 1000
+    )
+}
+
+/// Cue text can't contain blank lines (they end the cue) or, in WebVTT, the "-->" arrow.
+fn cue_text(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .replace("-->", "->")
+}
+
+pub fn render(segments: &[Segment], format: TrackFormat) -> String {
+    let mut out = String::new();
+    if format == TrackFormat::Vtt {
+        out.push_str("WEBVTT\n\n");
+    }
+
+    for (index, segment) in segments.iter().enumerate() {
+        let (decimal, text) = match format {
+            TrackFormat::Srt => (',', cue_text(&segment.text)),
+            TrackFormat::Vtt => (
+                '.',
+                cue_text(&segment.text)
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;"),
+            ),
+        };
+        out.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            index + 1,
+            timestamp(segment.start, decimal),
+            timestamp(segment.end, decimal),
+            text
+        ));
+    }
+
+    out
+}
@@ -0,0 +1,87 @@
+// Trace export with OpenTelemetry, behind the `otel` feature. With OTEL_EXPORTER_OTLP_ENDPOINT
+// (or OTEL_EXPORTER_OTLP_TRACES_ENDPOINT) set, the spans `tracing` records are sent over OTLP
+// (HTTP/protobuf, e.g. `http://localhost:4318`) to a collector, Jaeger or Tempo: a trace per
+// request, with spans for decoding, resizing and encoding the image, the wait for a provider
+// slot and each provider call. The other standard variables apply too: OTEL_SERVICE_NAME
+// (default `ai-image-captioner`), OTEL_EXPORTER_OTLP_HEADERS, OTEL_TRACES_SAMPLER and so on.
+//
+// RUST_LOG filters spans as it does logs; the ones above are all at `info`.
+
+#[cfg(feature = "otel")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use std::sync::OnceLock;
+    use tracing::Subscriber;
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    const SERVICE_NAME: &str = "ai-image-captioner";
+
+    /// Flushed and shut down by `shutdown`.
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+    /// The layer exporting spans, when an OTLP endpoint is configured.
+    pub(crate) fn layer<S>() -> Result<Option<impl Layer<S>>, String>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        if !super::configured() {
+            return Ok(None);
+        }
+        // The endpoint may come from the config file, which the exporter doesn't read.
+        let endpoint = match crate::settings::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+            Ok(endpoint) if !endpoint.trim().is_empty() => endpoint,
+            _ => {
+                let base = crate::settings::var("OTEL_EXPORTER_OTLP_ENDPOINT").unwrap_or_default();
+                format!("{}/v1/traces", base.trim().trim_end_matches('/'))
+            }
+        };
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| format!("Not exporting traces: {}", e))?;
+        let mut resource = Resource::builder()
+            .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")));
+        if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+            resource = resource.with_service_name(SERVICE_NAME);
+        }
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource.build())
+            .build();
+        let tracer = provider.tracer(SERVICE_NAME);
+        let _ = PROVIDER.set(provider);
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+
+    pub(crate) fn shutdown() {
+        if let Some(Err(e)) = PROVIDER.get().map(SdkTracerProvider::shutdown) {
+            tracing::warn!("Could not export the last traces: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+pub(crate) use otlp::{layer, shutdown};
+
+/// Whether an OTLP endpoint for traces is set.
+fn configured() -> bool {
+    ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+        .iter()
+        .any(|name| crate::settings::var(name).is_ok_and(|value| !value.trim().is_empty()))
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn layer() -> Result<Option<tracing_subscriber::layer::Identity>, String> {
+    if configured() {
+        return Err("Not exporting traces: built without the `otel` feature".into());
+    }
+    Ok(None)
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn shutdown() {}
@@ -0,0 +1,131 @@
+// A read-only view of TIFF structures: camera RAW files, and the EXIF block of JPEG, PNG and
+// WebP files (which is a TIFF without the image data). Offsets are relative to the start of
+// the TIFF header, and every read is bounds-checked, since the data comes from uploads.
+
+pub(crate) struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+pub(crate) struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// Offset of the entry's 4-byte value/offset field.
+    field: usize,
+}
+
+impl<'a> Tiff<'a> {
+    pub(crate) fn parse(data: &'a [u8]) -> Option<(Self, usize)> {
+        let big_endian = match data.get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        let tiff = Tiff { data, big_endian };
+        let first_ifd = tiff.u32(4)? as usize;
+        Some((tiff, first_ifd))
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// The entries of the IFD at `offset`, and the offset of the next IFD (0 for none).
+    pub(crate) fn ifd(&self, offset: usize) -> Option<(Vec<Entry>, usize)> {
+        let count = self.u16(offset)? as usize;
+        let entries = (0..count)
+            .map(|index| {
+                let start = offset + 2 + index * 12;
+                Some(Entry {
+                    tag: self.u16(start)?,
+                    kind: self.u16(start + 2)?,
+                    count: self.u32(start + 4)?,
+                    field: start + 8,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let next = self.u32(offset + 2 + count * 12)? as usize;
+        Some((entries, next))
+    }
+
+    /// The `index`th value of a SHORT, LONG or IFD entry.
+    pub(crate) fn value(&self, entry: &Entry, index: u32) -> Option<u32> {
+        if index >= entry.count {
+            return None;
+        }
+        let size = match entry.kind {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return None,
+        };
+        let base = if entry.count as usize * size <= 4 {
+            entry.field
+        } else {
+            self.u32(entry.field)? as usize
+        };
+        let offset = base + index as usize * size;
+        match size {
+            2 => self.u16(offset).map(u32::from),
+            _ => self.u32(offset),
+        }
+    }
+
+    pub(crate) fn values(&self, entries: &[Entry], tag: u16) -> Vec<u32> {
+        entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| (0..entry.count.min(64)).filter_map(|i| self.value(entry, i)).collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn first(&self, entries: &[Entry], tag: u16) -> Option<u32> {
+        self.values(entries, tag).first().copied()
+    }
+
+    /// The text of an ASCII entry, without its NUL terminator.
+    pub(crate) fn ascii(&self, entries: &[Entry], tag: u16) -> Option<String> {
+        let entry = entries.iter().find(|entry| entry.tag == tag && entry.kind == 2)?;
+        let start = if entry.count <= 4 {
+            entry.field
+        } else {
+            self.u32(entry.field)? as usize
+        };
+        let bytes = self.data.get(start..start.checked_add(entry.count as usize)?)?;
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_end_matches('\0').trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+
+    /// The values of a RATIONAL entry, e.g. GPS degrees, minutes and seconds. Values with a
+    /// zero denominator are skipped.
+    pub(crate) fn rationals(&self, entries: &[Entry], tag: u16) -> Vec<f64> {
+        let Some(entry) = entries.iter().find(|entry| entry.tag == tag && entry.kind == 5) else {
+            return Vec::new();
+        };
+        let Some(base) = self.u32(entry.field) else {
+            return Vec::new();
+        };
+        (0..entry.count.min(8) as usize)
+            .filter_map(|index| {
+                let numerator = self.u32(base as usize + index * 8)?;
+                let denominator = self.u32(base as usize + index * 8 + 4)?;
+                (denominator != 0).then(|| f64::from(numerator) / f64::from(denominator))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,68 @@
+// Timeouts, so a provider that stops answering can't hang clients. Two limits apply, both
+// failing with 504 and a `timeout` error:
+//
+// - Each provider call (one Gemini or translation request, per attempt) is limited by
+//   PROVIDER_TIMEOUT_SECS. A timed-out Gemini call is retried like a network error.
+// - Each HTTP request as a whole is limited by REQUEST_TIMEOUT_SECS, or by
+//   BULK_REQUEST_TIMEOUT_SECS for the routes that caption many images per request.
+//
+// All three are read per request, so a SIGHUP reload applies them; 0 turns a limit off.
+
+use axum::{
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::time::Duration;
+
+use crate::config::{BULK_REQUEST_TIMEOUT, PROVIDER_TIMEOUT, REQUEST_TIMEOUT};
+use crate::CaptionError;
+
+/// Routes whose requests carry or produce many captions at once.
+const BULK_ROUTES: &[&str] = &["/batch", "/jobs", "/pdf", "/video", "/video/frames", "/zip"];
+
+/// The limit in `name` (whole seconds), `default` when unset or unreadable, `None` for 0.
+fn read(name: &str, default: Duration) -> Option<Duration> {
+    let limit = crate::settings::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .map_or(default, Duration::from_secs);
+    (!limit.is_zero()).then_some(limit)
+}
+
+/// How long one provider call may take.
+pub(crate) fn provider_call() -> Option<Duration> {
+    read("PROVIDER_TIMEOUT_SECS", PROVIDER_TIMEOUT)
+}
+
+/// A failed provider call: a timeout when the limit was hit, otherwise a provider error.
+pub(crate) fn provider_error(service: &str, error: reqwest::Error) -> CaptionError {
+    match provider_call().filter(|_| error.is_timeout()) {
+        Some(limit) => CaptionError::Timeout(format!(
+            "{} didn't answer within {} s",
+            service,
+            limit.as_secs()
+        )),
+        None => error.into(),
+    }
+}
+
+/// Middleware failing requests that run longer than their route's limit.
+pub(crate) async fn limit_request(request: Request, next: Next) -> Response {
+    let (name, default) = if BULK_ROUTES.contains(&request.uri().path()) {
+        ("BULK_REQUEST_TIMEOUT_SECS", BULK_REQUEST_TIMEOUT)
+    } else {
+        ("REQUEST_TIMEOUT_SECS", REQUEST_TIMEOUT)
+    };
+    let Some(limit) = read(name, default) else {
+        return next.run(request).await;
+    };
+    match tokio::time::timeout(limit, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => CaptionError::Timeout(format!(
+            "The request took longer than {} s",
+            limit.as_secs()
+        ))
+        .into_response(),
+    }
+}
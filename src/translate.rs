@@ -0,0 +1,376 @@
+// Multilingual output: with a `language` option, captions are generated in English and then
+// translated. Dedicated machine translation is cheaper and more consistent than asking the
+// vision model to write in another language, so the translation step sits behind a trait
+// with DeepL, Google Translate and LLM (Gemini, text only) implementations. Translated text
+// is finished for its script (punctuation, direction, length limits) by locale.rs.
+//
+// TRANSLATION_PROVIDER picks the default (deepl, google or llm; llm if unset) and
+// TENANT_TRANSLATORS overrides it per tenant, e.g. `acme=deepl,beta=google`. DeepL needs
+// DEEPL_API_KEY and Google needs GOOGLE_TRANSLATE_API_KEY; the LLM uses GEMINI_API_KEY.
+
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::modes::{CaptionOptions, ModeOutput};
+use crate::providers::generate_text;
+use crate::{locale, sanitize, timeouts, CaptionError};
+
+/// Mode fields translated along with the caption. Hashtags stay as they are.
+const TRANSLATED_FIELDS: &[&str] = &["alt_text", "title", "description", "caution"];
+const TRANSLATED_LIST_FIELDS: &[&str] =
+    &["keywords", "uncertainties", "features", "composition"];
+/// Lists of objects, with the text field of each item.
+const TRANSLATED_ITEM_FIELDS: &[(&str, &str)] =
+    &[("panels", "caption"), ("comic_panels", "description"), ("vehicles", "damage")];
+
+#[async_trait]
+pub trait Translator: Send + Sync {
+    fn provider(&self) -> Provider;
+
+    /// Translates each text into `language` (a code such as `de` or `pt-BR`), in order.
+    async fn translate(&self, texts: &[String], language: &str) -> Result<Vec<String>, CaptionError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    Deepl,
+    Google,
+    Llm,
+}
+
+impl std::str::FromStr for Provider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "deepl" => Ok(Provider::Deepl),
+            "google" => Ok(Provider::Google),
+            "llm" | "gemini" => Ok(Provider::Llm),
+            other => Err(format!("unknown translation provider '{}'", other)),
+        }
+    }
+}
+
+/// Recorded on translated results.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Translation {
+    pub language: String,
+    pub provider: Provider,
+}
+
+fn env_key(name: &str) -> Result<String, CaptionError> {
+    crate::settings::var(name)
+        .ok()
+        .filter(|key| !key.trim().is_empty())
+        .ok_or_else(|| {
+            CaptionError::Config(format!("{} must be set to translate with this provider", name))
+        })
+}
+
+/// A POST to a translation service, limited by the provider call timeout.
+fn post(url: &str) -> reqwest::RequestBuilder {
+    let request = reqwest::Client::new().post(url);
+    match timeouts::provider_call() {
+        Some(limit) => request.timeout(limit),
+        None => request,
+    }
+}
+
+/// Turns an unsuccessful translation response into an error carrying the service's reply.
+async fn check(response: reqwest::Response, service: &str) -> Result<reqwest::Response, CaptionError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(CaptionError::from_response(
+        &format!("{} translation", service),
+        status,
+        &body,
+    ))
+}
+
+fn wrong_count(service: &str) -> CaptionError {
+    CaptionError::InvalidReply(format!(
+        "{} returned a different number of translations",
+        service
+    ))
+}
+
+pub struct DeepL {
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct DeepLResponse {
+    translations: Vec<DeepLTranslation>,
+}
+
+#[derive(Deserialize)]
+struct DeepLTranslation {
+    text: String,
+}
+
+#[async_trait]
+impl Translator for DeepL {
+    fn provider(&self) -> Provider {
+        Provider::Deepl
+    }
+
+    async fn translate(&self, texts: &[String], language: &str) -> Result<Vec<String>, CaptionError> {
+        // Free-plan keys end in ":fx" and have their own endpoint.
+        let url = if self.api_key.ends_with(":fx") {
+            "https://api-free.deepl.com/v2/translate"
+        } else {
+            "https://api.deepl.com/v2/translate"
+        };
+        let failed = |e| timeouts::provider_error("DeepL translation", e);
+        let response = post(url)
+            .header("Authorization", format!("DeepL-Auth-Key {}", self.api_key))
+            .json(&json!({
+                "text": texts,
+                "source_lang": "EN",
+                "target_lang": language.to_ascii_uppercase(),
+            }))
+            .send()
+            .await
+            .map_err(failed)?;
+        let reply: DeepLResponse = check(response, "DeepL").await?.json().await.map_err(failed)?;
+        if reply.translations.len() != texts.len() {
+            return Err(wrong_count("DeepL"));
+        }
+        Ok(reply.translations.into_iter().map(|t| t.text).collect())
+    }
+}
+
+pub struct Google {
+    api_key: String,
+}
+
+#[async_trait]
+impl Translator for Google {
+    fn provider(&self) -> Provider {
+        Provider::Google
+    }
+
+    async fn translate(&self, texts: &[String], language: &str) -> Result<Vec<String>, CaptionError> {
+        let failed = |e| timeouts::provider_error("Google translation", e);
+        let response = post("https://translation.googleapis.com/language/translate/v2")
+            .query(&[("key", &self.api_key)])
+            .json(&json!({
+                "q": texts,
+                "source": "en",
+                "target": language,
+                "format": "text",
+            }))
+            .send()
+            .await
+            .map_err(failed)?;
+        let reply: Value = check(response, "Google").await?.json().await.map_err(failed)?;
+        let translations: Vec<String> = reply["data"]["translations"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|t| t["translatedText"].as_str().map(str::to_string))
+            .collect();
+        if translations.len() != texts.len() {
+            return Err(wrong_count("Google"));
+        }
+        Ok(translations)
+    }
+}
+
+/// Translation by the captioning model itself, as a plain text request.
+pub struct Llm {
+    api_key: String,
+}
+
+#[async_trait]
+impl Translator for Llm {
+    fn provider(&self) -> Provider {
+        Provider::Llm
+    }
+
+    async fn translate(&self, texts: &[String], language: &str) -> Result<Vec<String>, CaptionError> {
+        let prompt = format!(
+            "Translate each string in this JSON array from English into the language with \
+             code {}. Reply with only a JSON array of the translations, in the same order and \
+             with the same number of items.\n{}",
+            language,
+            serde_json::to_string(texts)?
+        );
+        let (reply, _usage) = generate_text(&prompt, None, &self.api_key).await?;
+        let reply = reply
+            .trim()
+            .trim_start_matches("```json")
+            .trim_start_matches("```")
+            .trim_end_matches("```");
+        let translations: Vec<String> = serde_json::from_str(reply.trim())?;
+        if translations.len() != texts.len() {
+            return Err(wrong_count("The model"));
+        }
+        Ok(translations)
+    }
+}
+
+/// A translator for `provider`. Missing keys are reported when a translation is attempted,
+/// so a tenant that never asks for translations doesn't need one.
+fn translator(provider: Provider, gemini_key: &str) -> Result<Box<dyn Translator>, CaptionError> {
+    Ok(match provider {
+        Provider::Deepl => Box::new(DeepL {
+            api_key: env_key("DEEPL_API_KEY")?,
+        }),
+        Provider::Google => Box::new(Google {
+            api_key: env_key("GOOGLE_TRANSLATE_API_KEY")?,
+        }),
+        Provider::Llm => Box::new(Llm {
+            api_key: gemini_key.to_string(),
+        }),
+    })
+}
+
+/// TRANSLATION_PROVIDER, the provider for tenants without their own (and for the CLI).
+pub fn default_provider() -> Provider {
+    match crate::settings::var("TRANSLATION_PROVIDER") {
+        Ok(value) => value.parse().unwrap_or_else(|e| {
+            tracing::warn!("Ignoring TRANSLATION_PROVIDER: {}", e);
+            Provider::Llm
+        }),
+        Err(_) => Provider::Llm,
+    }
+}
+
+/// Which provider each tenant translates with.
+pub struct Providers {
+    default: Provider,
+    tenants: HashMap<String, Provider>,
+}
+
+impl Providers {
+    /// Reads TRANSLATION_PROVIDER and TENANT_TRANSLATORS, skipping (and reporting)
+    /// malformed entries.
+    pub fn from_env() -> Self {
+        let mut tenants = HashMap::new();
+        for entry in crate::settings::var("TENANT_TRANSLATORS").unwrap_or_default().split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let parsed = entry
+                .rsplit_once('=')
+                .and_then(|(tenant, provider)| Some((tenant.trim(), provider.parse().ok()?)));
+            match parsed {
+                Some((tenant, provider)) => {
+                    tenants.insert(tenant.to_string(), provider);
+                }
+                None => tracing::warn!("Ignoring malformed TENANT_TRANSLATORS entry: {}", entry),
+            }
+        }
+
+        Providers {
+            default: default_provider(),
+            tenants,
+        }
+    }
+
+    pub fn for_tenant(&self, tenant: &str) -> Provider {
+        self.tenants.get(tenant).copied().unwrap_or(self.default)
+    }
+}
+
+/// Translates `output` with `provider` when the options ask for a language.
+pub async fn apply(
+    output: &mut ModeOutput,
+    options: &CaptionOptions,
+    provider: Provider,
+    gemini_key: &str,
+) -> Result<Option<Translation>, CaptionError> {
+    let Some(language) = &options.language else {
+        return Ok(None);
+    };
+    let translator = translator(provider, gemini_key)?;
+    let translation = translate_output(output, translator.as_ref(), language).await?;
+    options.apply_limits(output);
+    Ok(Some(translation))
+}
+
+/// Translates the caption and the mode's text fields in place, in one request.
+async fn translate_output(
+    output: &mut ModeOutput,
+    translator: &dyn Translator,
+    language: &str,
+) -> Result<Translation, CaptionError> {
+    let mut texts = vec![output.caption.clone()];
+    for field in TRANSLATED_FIELDS {
+        if let Some(text) = output.details.get(*field).and_then(Value::as_str) {
+            texts.push(text.to_string());
+        }
+    }
+    for field in TRANSLATED_LIST_FIELDS {
+        if let Some(items) = output.details.get(*field).and_then(Value::as_array) {
+            texts.extend(items.iter().filter_map(Value::as_str).map(str::to_string));
+        }
+    }
+    for (field, text_field) in TRANSLATED_ITEM_FIELDS {
+        if let Some(items) = output.details.get(*field).and_then(Value::as_array) {
+            texts.extend(
+                items
+                    .iter()
+                    .filter_map(|item| item[*text_field].as_str())
+                    .map(str::to_string),
+            );
+        }
+    }
+
+    // Empty strings (a decorative image's alt text) have nothing to translate.
+    let non_empty: Vec<String> = texts.iter().filter(|t| !t.is_empty()).cloned().collect();
+    let mut translated = if non_empty.is_empty() {
+        Vec::new()
+    } else {
+        translator.translate(&non_empty, language).await?
+    }
+    .into_iter();
+    let mut next = |text: &str| {
+        if text.is_empty() {
+            String::new()
+        } else {
+            let text = sanitize::clean(&translated.next().unwrap_or_default());
+            locale::normalize(&text, Some(language))
+        }
+    };
+
+    output.caption = next(&output.caption.clone());
+    for field in TRANSLATED_FIELDS {
+        if let Some(value) = output.details.get_mut(*field) {
+            if let Some(text) = value.as_str() {
+                *value = next(text).into();
+            }
+        }
+    }
+    for field in TRANSLATED_LIST_FIELDS {
+        if let Some(Value::Array(items)) = output.details.get_mut(*field) {
+            for item in items.iter_mut() {
+                if let Some(text) = item.as_str() {
+                    *item = next(text).into();
+                }
+            }
+        }
+    }
+    for (field, text_field) in TRANSLATED_ITEM_FIELDS {
+        if let Some(Value::Array(items)) = output.details.get_mut(*field) {
+            for item in items.iter_mut() {
+                if let Some(text) = item[*text_field].as_str() {
+                    item[*text_field] = next(text).into();
+                }
+            }
+        }
+    }
+
+    Ok(Translation {
+        language: language.to_string(),
+        provider: translator.provider(),
+    })
+}
@@ -0,0 +1,146 @@
+// Retry-safe uploads: the web UI tags each upload with a client-generated token
+// (`Idempotency-Key`) and resends the same token when it retries. If the first attempt's
+// response was lost after the provider call succeeded, the retry gets that caption instead
+// of paying for a second one. A token sent again with a different image or options is a
+// client bug, and is refused rather than answered with the other upload's caption.
+//
+// The caption runs in its own task, so it finishes (and is remembered) even when the client
+// disconnects mid-request. Failed attempts aren't remembered; retrying them runs again.
+
+use axum::http::HeaderMap;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::{request_id, CaptionError, CaptionOptions, CaptionResponse};
+
+/// How long a token is remembered after its first use.
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Tokens remembered at once; past this, uploads are simply not deduplicated.
+const MAX_TOKENS: usize = 10_000;
+
+const MAX_TOKEN_LEN: usize = 128;
+
+pub type CaptionResult = Result<CaptionResponse, CaptionError>;
+
+struct Attempt {
+    started: Instant,
+    /// What was uploaded with the token (see `request_hash`).
+    request: String,
+    /// None until the caption finishes.
+    result: watch::Receiver<Option<CaptionResult>>,
+}
+
+impl Attempt {
+    fn failed(&self) -> bool {
+        matches!(*self.result.borrow(), Some(Err(_)))
+    }
+}
+
+#[derive(Default)]
+pub struct UploadTokens {
+    /// Keyed by tenant and token, so one tenant can't collect another's caption.
+    attempts: Mutex<HashMap<(String, String), Attempt>>,
+}
+
+/// The request's `Idempotency-Key`, if any. A malformed key is a client bug worth surfacing.
+pub fn token(headers: &HeaderMap) -> Result<Option<String>, CaptionError> {
+    let Some(value) = headers.get("idempotency-key") else {
+        return Ok(None);
+    };
+    let token = value.to_str().unwrap_or_default().trim();
+    let visible = token.bytes().all(|b| b.is_ascii_graphic());
+    if token.is_empty() || token.len() > MAX_TOKEN_LEN || !visible {
+        return Err(CaptionError::BadRequest(format!(
+            "Idempotency-Key must be 1 to {} visible ASCII characters",
+            MAX_TOKEN_LEN
+        )));
+    }
+    Ok(Some(token.to_string()))
+}
+
+/// A hash of the uploaded image and the options it is captioned with, which a retry sending
+/// the same token must match.
+pub fn request_hash(image: &[u8], options: &CaptionOptions) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update((image.len() as u64).to_le_bytes());
+    hasher.update(image);
+    hasher.update(format!("{:?}", options));
+    hex::encode(hasher.finalize())
+}
+
+impl UploadTokens {
+    /// Runs `caption` unless the same tenant already sent `token` within the window, in
+    /// which case the earlier attempt's result is returned (waiting for it if needed). The
+    /// earlier attempt must have had the same `request` (see `request_hash`).
+    pub async fn run<F>(
+        &self,
+        tenant: &str,
+        token: String,
+        request: String,
+        caption: F,
+    ) -> CaptionResult
+    where
+        F: Future<Output = CaptionResult> + Send + 'static,
+    {
+        let (result, sender) = {
+            let mut attempts = self.attempts.lock().expect("upload token lock poisoned");
+            let now = Instant::now();
+            attempts.retain(|_, attempt| now - attempt.started < WINDOW && !attempt.failed());
+
+            let key = (tenant.to_string(), token);
+            match attempts.get(&key) {
+                Some(attempt) if attempt.request != request => {
+                    return Err(CaptionError::Unprocessable(
+                        "This Idempotency-Key was already used for a different image or \
+                         options; send a new key with each upload"
+                            .into(),
+                    ));
+                }
+                Some(attempt) => (Some(attempt.result.clone()), None),
+                None if attempts.len() >= MAX_TOKENS => (None, None),
+                None => {
+                    let (sender, result) = watch::channel(None);
+                    let attempt = Attempt {
+                        started: now,
+                        request,
+                        result: result.clone(),
+                    };
+                    attempts.insert(key, attempt);
+                    (Some(result), Some(sender))
+                }
+            }
+        };
+
+        let Some(result) = result else {
+            return caption.await;
+        };
+        if let Some(sender) = sender {
+            tokio::spawn(request_id::carry(async move {
+                let _ = sender.send(Some(caption.await));
+            }));
+        }
+        wait(result).await
+    }
+
+    /// Forgets `tenant`'s tokens and the captions kept with them; returns how many there were.
+    pub fn forget(&self, tenant: &str) -> u64 {
+        let mut attempts = self.attempts.lock().expect("upload token lock poisoned");
+        let before = attempts.len();
+        attempts.retain(|(owner, _), _| owner != tenant);
+        (before - attempts.len()) as u64
+    }
+}
+
+/// Waits for an attempt to finish. The sender only goes away without a result if the
+/// caption task panicked.
+async fn wait(mut result: watch::Receiver<Option<CaptionResult>>) -> CaptionResult {
+    match result.wait_for(Option::is_some).await {
+        Ok(finished) => finished.clone().expect("waited for a result"),
+        Err(_) => Err(CaptionError::Internal("The caption task failed".into())),
+    }
+}
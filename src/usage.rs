@@ -0,0 +1,300 @@
+// Usage accounting per authenticated caller, kept in the `key_usage` table of API_KEYS_DB:
+// captions, the provider tokens they used (prompt and output, as Gemini reports them) and
+// their estimated cost (see `pricing`) per UTC day. Video summaries add their tokens without
+// counting as captions. Callers told apart only by header or address aren't tracked there,
+// but count towards the totals since startup that `GET /admin/usage` shows with the rest.
+//
+// Keys from the database can have a `daily_quota` and a `monthly_quota` of captions (see
+// `keys`). Both are checked before each caption and the caption is counted once it succeeds,
+// so requests running at the same time can go a little over. Over either, captions fail with
+// 429 `quota_exceeded` until the day or month is over. `GET /usage` shows callers their own
+// consumption and what is left, so they can slow down before hitting a quota.
+
+use axum::{http::HeaderMap, response::Json};
+use chrono::{Datelike, NaiveDate, NaiveTime, Utc};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::sync::Mutex;
+
+use crate::auth;
+use crate::pricing::{self, Price};
+use crate::providers::Usage;
+use crate::scheduler::{Caller, Tenant};
+use crate::voices::authorize;
+use crate::CaptionError;
+
+/// Everything captioned since the server started, whoever asked.
+static SINCE_START: Mutex<Totals> = Mutex::new(Totals {
+    captions: 0,
+    input_tokens: 0,
+    output_tokens: 0,
+    cost_usd: 0.0,
+});
+
+/// Captions, tokens and their estimated cost over one day or month.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub(crate) struct Totals {
+    pub(crate) captions: u64,
+    pub(crate) input_tokens: u64,
+    pub(crate) output_tokens: u64,
+    pub(crate) cost_usd: f64,
+}
+
+impl std::ops::AddAssign for Totals {
+    fn add_assign(&mut self, other: Self) {
+        self.captions += other.captions;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cost_usd += other.cost_usd;
+    }
+}
+
+#[derive(Serialize)]
+pub struct PeriodUsage {
+    /// `2026-10-16` for a day, `2026-10` for a month.
+    pub(crate) period: String,
+    #[serde(flatten)]
+    pub(crate) totals: Totals,
+    pub(crate) quota: Option<u64>,
+    pub(crate) remaining: Option<u64>,
+    /// When the counts start again from zero (RFC 3339, UTC).
+    pub(crate) resets_at: String,
+}
+
+#[derive(Serialize)]
+pub struct UsageResponse {
+    pub(crate) tenant: String,
+    pub(crate) today: PeriodUsage,
+    pub(crate) this_month: PeriodUsage,
+}
+
+#[derive(Serialize)]
+pub struct TenantTotals {
+    tenant: String,
+    #[serde(flatten)]
+    totals: Totals,
+}
+
+/// Every tracked caller's usage over a day or month, most expensive first.
+#[derive(Serialize)]
+pub struct PeriodTotals {
+    period: String,
+    #[serde(flatten)]
+    totals: Totals,
+    tenants: Vec<TenantTotals>,
+}
+
+#[derive(Serialize)]
+pub struct AdminUsage {
+    /// The prices new captions are costed at.
+    prices: Price,
+    since_start: Totals,
+    /// Only with API_KEYS_DB.
+    today: Option<PeriodTotals>,
+    this_month: Option<PeriodTotals>,
+}
+
+pub(crate) fn today() -> String {
+    Utc::now().date_naive().to_string()
+}
+
+fn this_month() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+fn database_error(e: rusqlite::Error) -> CaptionError {
+    tracing::error!(error = %e, "API key database error");
+    CaptionError::Internal(format!("API key database error: {}", e))
+}
+
+/// Runs `query` on the key database at `path` on a blocking thread.
+pub(crate) async fn with_database<T: Send + 'static>(
+    path: String,
+    query: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, CaptionError> {
+    tokio::task::spawn_blocking(move || Connection::open(path).and_then(|db| query(&db)))
+        .await
+        .map_err(|e| CaptionError::Internal(e.to_string()))?
+        .map_err(database_error)
+}
+
+/// `name`'s usage on a day (`2026-10-16`) or in a month (`2026-10`).
+pub(crate) fn totals(db: &Connection, name: &str, period: &str) -> rusqlite::Result<Totals> {
+    db.query_row(
+        &format!("SELECT {} FROM key_usage WHERE name = ?1 AND day LIKE ?2 || '%'", SUMS),
+        params![name, period],
+        |row| totals_from_row(row, 0),
+    )
+}
+
+/// The sums `totals_from_row` reads.
+const SUMS: &str = "coalesce(sum(captions), 0), coalesce(sum(input_tokens), 0),
+    coalesce(sum(output_tokens), 0), coalesce(sum(cost_usd), 0.0)";
+
+fn totals_from_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<Totals> {
+    Ok(Totals {
+        captions: row.get(first)?,
+        input_tokens: row.get(first + 1)?,
+        output_tokens: row.get(first + 2)?,
+        cost_usd: row.get(first + 3)?,
+    })
+}
+
+/// Each caller's usage on a day or in a month, most expensive first.
+fn period_totals(db: &Connection, period: String) -> rusqlite::Result<PeriodTotals> {
+    let mut query = db.prepare(&format!(
+        "SELECT name, {} FROM key_usage WHERE day LIKE ?1 || '%'
+         GROUP BY name ORDER BY sum(cost_usd) DESC, name",
+        SUMS
+    ))?;
+    let tenants = query
+        .query_map([&period], |row| {
+            Ok(TenantTotals {
+                tenant: row.get(0)?,
+                totals: totals_from_row(row, 1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut totals = Totals::default();
+    for tenant in &tenants {
+        totals += tenant.totals;
+    }
+    Ok(PeriodTotals {
+        period,
+        totals,
+        tenants,
+    })
+}
+
+/// Fails when the caller's key has no captions left today or this month.
+pub(crate) async fn check_quota(caller: &Caller) -> Result<(), CaptionError> {
+    let Some(policy) = caller.policy() else {
+        return Ok(());
+    };
+    let (daily, monthly) = (policy.daily_quota, policy.monthly_quota);
+    let Some(path) = auth::database().filter(|_| daily.is_some() || monthly.is_some()) else {
+        return Ok(());
+    };
+    let name = caller.tenant().to_string();
+    let (day, month) = with_database(path, move |db| {
+        Ok((totals(db, &name, &today())?, totals(db, &name, &this_month())?))
+    })
+    .await?;
+
+    if let Some(quota) = daily.filter(|quota| day.captions >= *quota) {
+        return Err(CaptionError::QuotaExceeded(format!(
+            "This API key has used its {} captions for today; the quota resets at midnight UTC",
+            quota
+        )));
+    }
+    if let Some(quota) = monthly.filter(|quota| month.captions >= *quota) {
+        return Err(CaptionError::QuotaExceeded(format!(
+            "This API key has used its {} captions for this month; the quota resets on the 1st \
+             (UTC)",
+            quota
+        )));
+    }
+    Ok(())
+}
+
+/// Adds `captions`, the tokens in `usage` and their cost to the totals since startup and to
+/// the caller's totals for today.
+pub(crate) async fn record(caller: &Caller, captions: u64, usage: &Usage) {
+    let totals = Totals {
+        captions,
+        input_tokens: usage.prompt_tokens,
+        output_tokens: usage.output_tokens,
+        cost_usd: usage.cost_usd(),
+    };
+    *SINCE_START.lock().expect("usage totals lock poisoned") += totals;
+
+    let Some(path) = auth::database().filter(|_| caller.policy().is_some()) else {
+        return;
+    };
+    let name = caller.tenant().to_string();
+    let recorded = with_database(path, move |db| {
+        db.execute(
+            "INSERT INTO key_usage (name, day, captions, input_tokens, output_tokens, cost_usd)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (name, day) DO UPDATE SET
+                 captions = captions + excluded.captions,
+                 input_tokens = input_tokens + excluded.input_tokens,
+                 output_tokens = output_tokens + excluded.output_tokens,
+                 cost_usd = cost_usd + excluded.cost_usd",
+            params![
+                name,
+                today(),
+                totals.captions,
+                totals.input_tokens,
+                totals.output_tokens,
+                totals.cost_usd
+            ],
+        )
+    })
+    .await;
+    if let Err(e) = recorded {
+        tracing::error!(tenant = caller.tenant(), error = %e, "Can't record usage");
+    }
+}
+
+fn period(period: String, totals: Totals, quota: Option<u64>, resets: NaiveDate) -> PeriodUsage {
+    PeriodUsage {
+        period,
+        totals,
+        quota,
+        remaining: quota.map(|quota| quota.saturating_sub(totals.captions)),
+        resets_at: resets.and_time(NaiveTime::MIN).and_utc().to_rfc3339(),
+    }
+}
+
+/// `GET /usage`
+pub async fn get_usage(tenant: Tenant) -> Result<Json<UsageResponse>, CaptionError> {
+    let Some(policy) = tenant.policy.clone() else {
+        return Err(CaptionError::Unauthorized(
+            "Usage is tracked per API key; send one to see it".into(),
+        ));
+    };
+    let path = auth::database()
+        .ok_or_else(|| CaptionError::Unavailable("Usage is tracked only with API_KEYS_DB".into()))?;
+    let (day, month) = (today(), this_month());
+    let (name, queried) = (tenant.name.clone(), (day.clone(), month.clone()));
+    let (day_totals, month_totals) = with_database(path, move |db| {
+        Ok((totals(db, &name, &queried.0)?, totals(db, &name, &queried.1)?))
+    })
+    .await?;
+
+    let date = Utc::now().date_naive();
+    let tomorrow = date.succ_opt().unwrap_or(date);
+    let next_month = date
+        .with_day(1)
+        .and_then(|first| first.checked_add_months(chrono::Months::new(1)))
+        .unwrap_or(date);
+    Ok(Json(UsageResponse {
+        tenant: tenant.name,
+        today: period(day, day_totals, policy.daily_quota, tomorrow),
+        this_month: period(month, month_totals, policy.monthly_quota, next_month),
+    }))
+}
+
+/// `GET /admin/usage`
+pub async fn admin_usage(headers: HeaderMap) -> Result<Json<AdminUsage>, CaptionError> {
+    authorize(&headers)?;
+    let since_start = *SINCE_START.lock().expect("usage totals lock poisoned");
+    let (today, this_month) = match auth::database() {
+        Some(path) => {
+            let (day, month) = (today(), this_month());
+            let (today, this_month) = with_database(path, move |db| {
+                Ok((period_totals(db, day)?, period_totals(db, month)?))
+            })
+            .await?;
+            (Some(today), Some(this_month))
+        }
+        None => (None, None),
+    };
+    Ok(Json(AdminUsage {
+        prices: pricing::current(),
+        since_start,
+        today,
+        this_month,
+    }))
+}
@@ -0,0 +1,181 @@
+// Vehicle descriptions for fleet and insurance work (the `vehicle` mode): make, model, color,
+// body type and visible damage of each vehicle in the photo. The server only offers the mode
+// when ENABLE_VEHICLE_MODE is set; the CLI, run by the operator, always can.
+//
+// License plates identify people, so the mode never returns them, whatever else the request
+// asks for: the model is told to leave them out, and anything in its reply that still looks
+// like a plate is replaced with `[plate redacted]`. That is either a word mixing capitals and
+// digits the way plates do ("ABC-1234", "KA01AB1234") or the capitals and digits right after
+// "plate" or "registration" ("plate reads AB12 CDE"). Words of the make and model are kept,
+// since some model names look like plates ("GLC300").
+
+use serde_json::Value;
+
+use crate::config::parse_flag;
+use crate::modes::{Mode, ModeOutput};
+use crate::CaptionError;
+
+pub(crate) const BODY_TYPES: &[&str] = &[
+    "sedan", "hatchback", "wagon", "coupe", "convertible", "suv", "pickup", "van", "minivan",
+    "truck", "bus", "motorcycle", "other",
+];
+
+pub(crate) const COLORS: &[&str] = &[
+    "black", "white", "grey", "silver", "beige", "brown", "red", "orange", "yellow", "green",
+    "blue", "purple", "gold", "multicolor",
+];
+
+pub(crate) const DAMAGE_SEVERITIES: &[&str] = &["none", "minor", "moderate", "severe"];
+
+/// Most vehicles described per photo.
+pub(crate) const MAX_VEHICLES: usize = 10;
+
+pub(crate) const REDACTED: &str = "[plate redacted]";
+
+/// Words after which a plate is expected.
+const PLATE_WORDS: &[&str] = &["plate", "plates", "registration", "reg", "tag", "tags"];
+
+/// Words that may sit between one of `PLATE_WORDS` and the plate itself.
+const FILLER_WORDS: &[&str] =
+    &["number", "no", "is", "of", "reads", "reading", "read", "shows", "showing", "says"];
+
+/// Most words a plate is written in ("KA 01 AB 1234").
+const MAX_PLATE_WORDS: usize = 4;
+
+/// Fails unless the server offers the vehicle mode (ENABLE_VEHICLE_MODE).
+pub(crate) fn check_enabled(mode: Mode) -> Result<(), CaptionError> {
+    let enabled = crate::settings::var("ENABLE_VEHICLE_MODE")
+        .ok()
+        .and_then(|value| parse_flag(&value))
+        .unwrap_or(false);
+    if mode == Mode::Vehicle && !enabled {
+        return Err(CaptionError::Forbidden(
+            "The vehicle mode is off on this server; set ENABLE_VEHICLE_MODE to offer it".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// `word` without the punctuation around it, and where that starts in `word`.
+fn core(word: &str) -> (usize, &str) {
+    let start = word.len() - word.trim_start_matches(|c: char| !c.is_alphanumeric()).len();
+    (start, word[start..].trim_end_matches(|c: char| !c.is_alphanumeric()))
+}
+
+/// Letters and digits of `word` in uppercase, to compare with the make and model.
+fn squeezed(word: &str) -> String {
+    word.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_uppercase()
+}
+
+/// Whether `word` is written the way plates are: capitals, digits and separators.
+fn plate_part(word: &str) -> bool {
+    word.chars().any(|c| c.is_ascii_alphanumeric())
+        && word
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || matches!(c, '-' | '·' | '.'))
+}
+
+/// Whether `word` on its own looks like a whole plate: five to ten capitals and digits, with
+/// at least two digits and a letter.
+fn whole_plate(word: &str) -> bool {
+    let letters = word.chars().filter(char::is_ascii_uppercase).count();
+    let digits = word.chars().filter(char::is_ascii_digit).count();
+    plate_part(word) && letters >= 1 && digits >= 2 && (5..=10).contains(&(letters + digits))
+}
+
+/// `text` with anything that looks like a license plate replaced by `REDACTED`, except words of
+/// `keep` (uppercased letters and digits).
+pub(crate) fn redact_plates(text: &str, keep: &[String]) -> String {
+    let words: Vec<(usize, &str)> = text
+        .split_whitespace()
+        .map(|word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+        .collect();
+    let kept = |word: &str| keep.contains(&squeezed(word));
+    let listed = |list: &[&str], word: &str| list.contains(&core(word).1.to_lowercase().as_str());
+    let mut plates: Vec<(usize, usize)> = Vec::new();
+
+    let mut i = 0;
+    while i < words.len() {
+        let (at, word) = words[i];
+        let (offset, core_word) = core(word);
+        i += 1;
+        if listed(PLATE_WORDS, word) {
+            while i < words.len() && listed(FILLER_WORDS, words[i].1) {
+                i += 1;
+            }
+            let first = i;
+            while i < words.len() && i - first < MAX_PLATE_WORDS {
+                let (offset, part) = core(words[i].1);
+                if !plate_part(part) || kept(part) {
+                    break;
+                }
+                i += 1;
+                if offset + part.len() < words[i - 1].1.len() {
+                    break; // punctuation after it ends the plate
+                }
+            }
+            if i > first {
+                let (start, first_word) = words[first];
+                let (last_at, last_word) = words[i - 1];
+                let (last_offset, last_core) = core(last_word);
+                plates.push((start + core(first_word).0, last_at + last_offset + last_core.len()));
+            }
+            continue;
+        }
+        if whole_plate(core_word) && !kept(core_word) {
+            plates.push((at + offset, at + offset + core_word.len()));
+        }
+    }
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, end) in plates {
+        redacted.push_str(&text[copied..start]);
+        redacted.push_str(REDACTED);
+        copied = end;
+    }
+    redacted.push_str(&text[copied..]);
+    redacted
+}
+
+/// Redacts plates from every string in `value` but the vehicles' make and model.
+fn redact_value(value: &mut Value, keep: &[String]) {
+    match value {
+        Value::String(text) => *text = redact_plates(text, keep),
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_value(item, keep)),
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if !matches!(name.as_str(), "make" | "model") {
+                    redact_value(field, keep);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Redacts plates from the caption, every field and the AI-generation indicators of a
+/// vehicle-mode result.
+pub(crate) fn redact(output: &mut ModeOutput) {
+    let keep: Vec<String> = output
+        .details
+        .get("vehicles")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .flat_map(|vehicle| [vehicle["make"].as_str(), vehicle["model"].as_str()])
+        .flatten()
+        .flat_map(str::split_whitespace)
+        .map(squeezed)
+        .filter(|word| !word.is_empty())
+        .collect();
+    output.caption = redact_plates(&output.caption, &keep);
+    for field in output.details.values_mut() {
+        redact_value(field, &keep);
+    }
+    if let Some(assessment) = &mut output.assessment {
+        for indicator in &mut assessment.indicators {
+            *indicator = redact_plates(indicator, &keep);
+        }
+    }
+}
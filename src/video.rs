@@ -0,0 +1,382 @@
+// Keyframe sampling for short video uploads, and single frames at exact timestamps from a
+// video URL, using the ffmpeg command-line tool.
+//
+// ffmpeg must be on PATH (or FFMPEG must point at it). Frames are extracted as JPEG into a
+// scratch directory that is removed afterwards. An upload is only handed to ffmpeg as one of
+// the container formats in `UPLOAD_FORMATS`, recognised from its first bytes, and ffmpeg may
+// open no other file or URL while reading it: an HLS or concat playlist could otherwise have it
+// read the server's files or fetch from its network.
+//
+// Video URLs are downloaded by the server, up to MAX_VIDEO_MB, and ffmpeg reads the download
+// like an upload. Only http(s) URLs whose host resolves to public addresses are fetched; the
+// connection goes to the address that was checked, so the name can't be rebound to another in
+// between, and each redirect is checked the same way before it is followed.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::scratch::ScratchDir;
+use crate::spool::Spooled;
+
+pub const DEFAULT_FRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Upper bound on sampled frames per video, which also bounds provider calls per request.
+pub const MAX_FRAMES: usize = 60;
+
+/// The ffmpeg demuxers uploads may be read with; playlists and other formats that point at
+/// further files aren't among them.
+const UPLOAD_FORMATS: &str = "mov,matroska,avi,mpeg,mpegts,flv,ogg";
+
+#[derive(Debug)]
+pub enum VideoError {
+    /// ffmpeg isn't installed or couldn't be started.
+    FfmpegMissing(std::io::Error),
+    /// ffmpeg ran but couldn't decode the upload.
+    Decode(String),
+    /// The video URL isn't one the server will fetch.
+    Source(String),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for VideoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VideoError::FfmpegMissing(e) => write!(f, "ffmpeg is not available: {}", e),
+            VideoError::Decode(message) => write!(f, "Could not decode video: {}", message),
+            VideoError::Source(message) => write!(f, "Unusable video URL: {}", message),
+            VideoError::Io(e) => write!(f, "I/O error while sampling frames: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VideoError {}
+
+impl From<std::io::Error> for VideoError {
+    fn from(e: std::io::Error) -> Self {
+        VideoError::Io(e)
+    }
+}
+
+pub struct Frame {
+    pub timestamp: Duration,
+    pub jpeg: Vec<u8>,
+}
+
+fn ffmpeg_command() -> tokio::process::Command {
+    let program = crate::settings::var("FFMPEG").unwrap_or_else(|_| "ffmpeg".into());
+    let mut command = tokio::process::Command::new(program);
+    // If the request is dropped (client gone), don't leave ffmpeg running.
+    command.kill_on_drop(true);
+    command
+}
+
+/// Extracts one frame every `interval`, starting at the first frame, up to `max_frames`.
+pub(crate) async fn sample_frames(
+    video: &Spooled,
+    interval: Duration,
+    max_frames: usize,
+) -> Result<Vec<Frame>, VideoError> {
+    let head = video.head(UPLOAD_SNIFF_BYTES).await;
+    let head = head.map_err(|e| VideoError::Io(std::io::Error::other(e.to_string())))?;
+    let format = container(&head).ok_or_else(|| {
+        VideoError::Decode(
+            "the upload is not an MP4, MOV, Matroska, WebM, AVI, MPEG, FLV or Ogg video".into(),
+        )
+    })?;
+    let scratch = ScratchDir::new()?;
+    let input = scratch.path().join("input");
+    video.write_to(&input).await?;
+
+    let output = ffmpeg_command()
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args(["-protocol_whitelist", "file", "-format_whitelist", UPLOAD_FORMATS])
+        .args(["-f", format, "-i"])
+        .arg(&input)
+        .args(["-vf", &format!("fps={}", 1.0 / interval.as_secs_f64())])
+        .args(["-frames:v", &max_frames.to_string(), "-q:v", "3"])
+        .arg(scratch.path().join("frame_%04d.jpg"))
+        .output()
+        .await
+        .map_err(VideoError::FfmpegMissing)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(VideoError::Decode(stderr.trim().to_string()));
+    }
+
+    let mut frames = Vec::new();
+    for index in 0..max_frames {
+        let path = scratch.path().join(format!("frame_{:04}.jpg", index + 1));
+        match tokio::fs::read(&path).await {
+            Ok(jpeg) => frames.push(Frame {
+                timestamp: interval * index as u32,
+                jpeg,
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if frames.is_empty() {
+        return Err(VideoError::Decode("no frames could be extracted".into()));
+    }
+    Ok(frames)
+}
+
+/// Redirects followed when fetching a video URL.
+const MAX_REDIRECTS: usize = 5;
+
+/// How long connecting to a video URL's host may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes of an upload read to tell its container format.
+const UPLOAD_SNIFF_BYTES: usize = 512;
+
+/// The demuxer in `UPLOAD_FORMATS` for a video starting with `head`, if it is one of them.
+fn container(head: &[u8]) -> Option<&'static str> {
+    const MP4_BOXES: [&[u8]; 6] = [b"ftyp", b"moov", b"mdat", b"free", b"wide", b"skip"];
+    let box_type = head.get(4..8);
+    if box_type.is_some_and(|kind| MP4_BOXES.contains(&kind)) {
+        Some("mov")
+    } else if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("matroska")
+    } else if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"AVI ") {
+        Some("avi")
+    } else if head.starts_with(&[0, 0, 1, 0xBA]) {
+        Some("mpeg")
+    } else if head.first() == Some(&0x47) && head.get(188) == Some(&0x47) {
+        Some("mpegts")
+    } else if head.starts_with(b"FLV") {
+        Some("flv")
+    } else if head.starts_with(b"OggS") {
+        Some("ogg")
+    } else {
+        None
+    }
+}
+
+/// A video downloaded from a URL, for `extract_frame`; removed on drop.
+pub struct Fetched {
+    _scratch: ScratchDir,
+    path: PathBuf,
+    format: &'static str,
+}
+
+/// Downloads the video at `url`, at most `limit` bytes, following redirects only to public
+/// addresses.
+pub async fn fetch(url: &str, limit: usize) -> Result<Fetched, VideoError> {
+    let source = |message: String| VideoError::Source(message);
+    let mut url = reqwest::Url::parse(url).map_err(|e| source(e.to_string()))?;
+    let mut redirects = 0;
+    let mut response = loop {
+        let response = get_public(&url).await?;
+        if !response.status().is_redirection() {
+            break response;
+        }
+        let location = response.headers().get(reqwest::header::LOCATION);
+        let location = location.and_then(|location| location.to_str().ok());
+        let location = location.ok_or_else(|| source("a redirect has no Location".into()))?;
+        url = url.join(location).map_err(|e| source(e.to_string()))?;
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(source(format!("more than {} redirects", MAX_REDIRECTS)));
+        }
+    };
+    if !response.status().is_success() {
+        return Err(source(format!("the server answered {}", response.status())));
+    }
+    let too_large = || {
+        let limit = crate::limits::megabytes(limit);
+        source(format!("the video is larger than the {} limit (MAX_VIDEO_MB)", limit))
+    };
+    if response.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(too_large());
+    }
+
+    let scratch = ScratchDir::new()?;
+    let path = scratch.path().join("input");
+    let mut file = tokio::fs::File::create(&path).await?;
+    let mut written = 0;
+    while let Some(chunk) = response.chunk().await.map_err(|e| source(e.to_string()))? {
+        written += chunk.len();
+        if written > limit {
+            return Err(too_large());
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    let mut head = Vec::with_capacity(UPLOAD_SNIFF_BYTES);
+    let opened = tokio::fs::File::open(&path).await?;
+    opened.take(UPLOAD_SNIFF_BYTES as u64).read_to_end(&mut head).await?;
+    let format = container(&head).ok_or_else(|| {
+        VideoError::Decode(
+            "the URL is not an MP4, MOV, Matroska, WebM, AVI, MPEG, FLV or Ogg video".into(),
+        )
+    })?;
+    Ok(Fetched {
+        _scratch: scratch,
+        path,
+        format,
+    })
+}
+
+/// GETs `url` without following redirects, from an address its host resolves to once all of
+/// them are public.
+async fn get_public(url: &reqwest::Url) -> Result<reqwest::Response, VideoError> {
+    let source = |message: &str| VideoError::Source(message.to_string());
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(source("only http and https URLs are supported"));
+    }
+    let host = url.host_str().ok_or_else(|| source("the URL has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|_| source("the host could not be resolved"))?
+        .collect();
+    if addresses.is_empty() || !addresses.iter().all(|address| is_public(address.ip())) {
+        return Err(source("the host is not a public address"));
+    }
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(host, addresses[0])
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .map_err(|e| VideoError::Source(e.to_string()))?;
+    client.get(url.clone()).send().await.map_err(|e| VideoError::Source(e.to_string()))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 0.0.0.0/8 is "this network", 100.64.0.0/10 carrier-grade NAT, 198.18.0.0/15
+            // benchmarking and 240.0.0.0/4 reserved (with the broadcast address).
+            let reserved = first == 0
+                || (first == 100 && (second & 0xC0) == 64)
+                || (first == 198 && (second & 0xFE) == 18)
+                || first >= 240;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || reserved)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            // fc00::/7 is unique local, fe80::/10 link local, ff00::/8 multicast, and NAT64
+            // (64:ff9b::/96, and 64:ff9b:1::/48 for local use) and 6to4 (2002::/16) reach IPv4
+            // addresses of any kind.
+            None => {
+                let [first, second, ..] = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || (first & 0xFE00) == 0xFC00
+                    || (first & 0xFFC0) == 0xFE80
+                    || (first & 0xFF00) == 0xFF00
+                    || first == 0x2002
+                    || (first == 0x64 && second == 0xff9b))
+            }
+        },
+    }
+}
+
+/// Extracts the frame shown at `timestamp` in `video`. ffmpeg decodes from the preceding
+/// keyframe, so this is the exact frame rather than the nearest keyframe.
+pub async fn extract_frame(video: &Fetched, timestamp: Duration) -> Result<Frame, VideoError> {
+    let scratch = ScratchDir::new()?;
+    let path = scratch.path().join("frame.jpg");
+
+    let output = ffmpeg_command()
+        .args(["-hide_banner", "-loglevel", "error"])
+        .args(["-protocol_whitelist", "file", "-format_whitelist", UPLOAD_FORMATS])
+        .args(["-ss", &format!("{:.3}", timestamp.as_secs_f64())])
+        .args(["-f", video.format, "-i"])
+        .arg(&video.path)
+        .args(["-frames:v", "1", "-q:v", "2"])
+        .arg(&path)
+        .output()
+        .await
+        .map_err(VideoError::FfmpegMissing)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(VideoError::Decode(stderr.trim().to_string()));
+    }
+    match tokio::fs::read(&path).await {
+        Ok(jpeg) => Ok(Frame { timestamp, jpeg }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(VideoError::Decode(format!(
+            "no frame at {:.3} s; the video may be shorter",
+            timestamp.as_secs_f64()
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `seconds` as a timestamp, or `HH:MM:SS.mmm` / `MM:SS.mmm` text.
+pub fn parse_timestamp(text: &str) -> Option<Duration> {
+    let parts: Vec<&str> = text.trim().split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    let mut seconds = 0.0;
+    for part in parts {
+        let value: f64 = part.parse().ok()?;
+        if !(0.0..).contains(&value) {
+            return None;
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Prompt for the overall summary, built from the per-frame captions in order.
+pub fn summary_prompt(captions: &[(Duration, &str)]) -> String {
+    let mut prompt = String::from(
+        "These are descriptions of frames sampled in order from a short video, with their \
+         timestamps. Write a two to three sentence summary of what happens in the video. \
+         Describe the action and any changes over time rather than listing the frames.\n",
+    );
+    for (timestamp, caption) in captions {
+        let seconds = timestamp.as_secs();
+        prompt.push_str(&format!("\n[{:02}:{:02}] {}", seconds / 60, seconds % 60, caption));
+    }
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_fetched_from() {
+        let public = ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"];
+        let internal = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "169.254.169.254",
+            "0.1.2.3",
+            "100.64.0.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::ffff:192.168.0.1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:a9fe:a9fe::1",
+        ];
+        for address in public {
+            assert!(is_public(address.parse().unwrap()), "{}", address);
+        }
+        for address in internal {
+            assert!(!is_public(address.parse().unwrap()), "{}", address);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// Per-tenant brand voice: a system instruction such as "we never use exclamation points;
+// refer to users as 'members'" that is sent with every caption request the tenant makes,
+// whatever the mode. Voices are kept in a JSON file (BRAND_VOICES_FILE, default
+// brand-voices.json) and edited through the admin API:
+//
+//   GET    /admin/voices                  every tenant's voice
+//   GET    /admin/tenants/:tenant/voice   one tenant's voice
+//   PUT    /admin/tenants/:tenant/voice   set it: {"instruction": "..."}
+//   DELETE /admin/tenants/:tenant/voice   remove it
+//
+// Admin requests need `Authorization: Bearer $ADMIN_TOKEN`; without ADMIN_TOKEN set the admin
+// API is disabled. The token is read per request, so a SIGHUP reload can rotate it.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::server::AppState;
+use crate::{BoxError, CaptionError};
+
+pub(crate) const DEFAULT_FILE: &str = "brand-voices.json";
+
+/// Longest instruction accepted, in characters; it is sent with every request.
+const MAX_INSTRUCTION_CHARS: usize = 2000;
+
+pub struct BrandVoices {
+    path: PathBuf,
+    /// Tenant to instruction. Sorted so the file diffs cleanly.
+    voices: RwLock<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize)]
+pub struct Voice {
+    tenant: String,
+    instruction: String,
+}
+
+#[derive(Deserialize)]
+pub struct VoiceUpdate {
+    instruction: String,
+}
+
+impl BrandVoices {
+    /// Loads BRAND_VOICES_FILE. A missing file means no voices yet; an unreadable one is an
+    /// error, so a later save can't silently replace it.
+    pub fn load() -> Result<Self, BoxError> {
+        let path = PathBuf::from(
+            crate::settings::var("BRAND_VOICES_FILE").unwrap_or_else(|_| DEFAULT_FILE.to_string()),
+        );
+        let voices = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| format!("{} is not valid: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("Can't read {}: {}", path.display(), e).into()),
+        };
+        Ok(BrandVoices {
+            path,
+            voices: RwLock::new(voices),
+        })
+    }
+
+    pub fn get(&self, tenant: &str) -> Option<String> {
+        self.voices
+            .read()
+            .expect("brand voice lock poisoned")
+            .get(tenant)
+            .cloned()
+    }
+
+    /// Applies `change` and writes the result to disk; the change is kept only if the write
+    /// succeeds.
+    fn update(&self, change: impl FnOnce(&mut BTreeMap<String, String>)) -> std::io::Result<()> {
+        let mut voices = self.voices.write().expect("brand voice lock poisoned");
+        let mut updated = voices.clone();
+        change(&mut updated);
+
+        // Write a temporary file and rename it over the old one, so a crash can't leave
+        // half a file behind.
+        let json = serde_json::to_vec_pretty(&updated).expect("voices always serialize");
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, json)?;
+        std::fs::rename(&temporary, &self.path)?;
+
+        *voices = updated;
+        Ok(())
+    }
+}
+
+/// Checks the admin bearer token. Comparing digests keeps the comparison time independent
+/// of how much of the token matched.
+pub(crate) fn authorize(headers: &HeaderMap) -> Result<(), CaptionError> {
+    let Some(expected) = crate::settings::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+    else {
+        return Err(CaptionError::NotFound("The admin API is disabled".into()));
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(bad_token)?;
+    if Sha256::digest(given.trim()) == Sha256::digest(expected.trim()) {
+        Ok(())
+    } else {
+        Err(bad_token())
+    }
+}
+
+fn bad_token() -> CaptionError {
+    CaptionError::Unauthorized("Missing or invalid admin token".into())
+}
+
+fn save_error(e: std::io::Error) -> CaptionError {
+    tracing::error!(error = %e, "Can't save brand voices");
+    CaptionError::Internal(format!("Can't save brand voices: {}", e))
+}
+
+fn no_voice(tenant: &str) -> CaptionError {
+    CaptionError::NotFound(format!("No brand voice for tenant {}", tenant))
+}
+
+/// `GET /admin/voices`
+pub async fn list_voices(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Voice>>, CaptionError> {
+    authorize(&headers)?;
+    let voices = state
+        .voices
+        .voices
+        .read()
+        .expect("brand voice lock poisoned");
+    Ok(Json(
+        voices
+            .iter()
+            .map(|(tenant, instruction)| Voice {
+                tenant: tenant.clone(),
+                instruction: instruction.clone(),
+            })
+            .collect(),
+    ))
+}
+
+/// `GET /admin/tenants/:tenant/voice`
+pub async fn get_voice(
+    State(state): State<Arc<AppState>>,
+    Path(tenant): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Voice>, CaptionError> {
+    authorize(&headers)?;
+    let instruction = state.voices.get(&tenant).ok_or_else(|| no_voice(&tenant))?;
+    Ok(Json(Voice {
+        tenant,
+        instruction,
+    }))
+}
+
+/// `PUT /admin/tenants/:tenant/voice`
+pub async fn put_voice(
+    State(state): State<Arc<AppState>>,
+    Path(tenant): Path<String>,
+    headers: HeaderMap,
+    Json(update): Json<VoiceUpdate>,
+) -> Result<Json<Voice>, CaptionError> {
+    authorize(&headers)?;
+    let instruction = update.instruction.trim().to_string();
+    if instruction.is_empty() || instruction.chars().count() > MAX_INSTRUCTION_CHARS {
+        return Err(CaptionError::Unprocessable(format!(
+            "instruction must be 1 to {} characters",
+            MAX_INSTRUCTION_CHARS
+        )));
+    }
+
+    state
+        .voices
+        .update(|voices| {
+            voices.insert(tenant.clone(), instruction.clone());
+        })
+        .map_err(save_error)?;
+    Ok(Json(Voice {
+        tenant,
+        instruction,
+    }))
+}
+
+/// `DELETE /admin/tenants/:tenant/voice`
+pub async fn delete_voice(
+    State(state): State<Arc<AppState>>,
+    Path(tenant): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, CaptionError> {
+    authorize(&headers)?;
+    if state.voices.get(&tenant).is_none() {
+        return Err(no_voice(&tenant));
+    }
+    state
+        .voices
+        .update(|voices| {
+            voices.remove(&tenant);
+        })
+        .map_err(save_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,234 @@
+// `watch DIR`: a long-running mode for scanner and screenshot folders. New images are
+// captioned as they appear and the results written next to them, as a JSON sidecar
+// (photo.jpg.json, the same format `batch --sidecar` writes) and/or a plain-text caption
+// (photo.txt).
+//
+// Scanners and screenshot tools often create a file and then keep writing to it, so an
+// image is only captioned once it has gone quiet for a moment.
+
+use clap::{Args, ValueEnum};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::batch::is_image;
+use crate::batch::sidecar::{self, Sidecar};
+use crate::cli::{fail, ErrorKind, OptionArgs, OutputFormat};
+use crate::modes::CaptionOptions;
+use crate::caption_image_bytes;
+use crate::config::LOG_PROVIDER_TRAFFIC;
+use crate::imageproc::content_hash;
+
+/// How long a file must go without changes before it is captioned.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// How often pending files are checked for having settled.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Args)]
+pub struct WatchArgs {
+    /// Directory to watch, including subdirectories
+    pub dir: PathBuf,
+
+    /// Which sidecar files to write next to each image
+    #[arg(long, value_enum, default_value_t = SidecarKind::Json)]
+    pub sidecar: SidecarKind,
+
+    /// Only caption images that appear from now on, not the ones already there
+    #[arg(long)]
+    pub new_only: bool,
+
+    #[command(flatten)]
+    pub options: OptionArgs,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SidecarKind {
+    /// photo.jpg.json with the full result, content hash and prompt fingerprint
+    Json,
+    /// photo.txt with just the caption (the layout image-training tools expect)
+    Txt,
+    /// Both files
+    Both,
+}
+
+impl SidecarKind {
+    fn json(self) -> bool {
+        self != SidecarKind::Txt
+    }
+
+    fn txt(self) -> bool {
+        self != SidecarKind::Json
+    }
+}
+
+fn txt_path(image: &Path) -> PathBuf {
+    image.with_extension("txt")
+}
+
+/// Settings shared by every image captioned while watching.
+struct Settings {
+    options: CaptionOptions,
+    api_key: String,
+    prompt_hash: String,
+    sidecar: SidecarKind,
+}
+
+/// Whether `path` is an image worth looking at (not a hidden or temporary file).
+fn is_candidate(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_none_or(|name| name.starts_with('.') || name.starts_with('~'));
+    !hidden && is_image(path)
+}
+
+/// Whether the requested sidecars already exist and, for JSON, are current for these bytes.
+fn is_current(path: &Path, data: &[u8], settings: &Settings) -> bool {
+    let json_current = !settings.sidecar.json()
+        || sidecar::is_current(path, &content_hash(data), &settings.prompt_hash);
+    let txt_current = !settings.sidecar.txt() || txt_path(path).exists();
+    json_current && txt_current
+}
+
+async fn caption_file(path: &Path, settings: &Settings) {
+    // The file may have been moved away or deleted again before it settled.
+    let Ok(data) = tokio::fs::read(path).await else {
+        return;
+    };
+    if is_current(path, &data, settings) {
+        return;
+    }
+
+    let response = match caption_image_bytes(&data, &settings.options, &settings.api_key).await {
+        Ok((response, _usage)) => response,
+        Err(e) => {
+            eprintln!("❌ {}: {}", path.display(), e);
+            return;
+        }
+    };
+    println!("{}: {}", path.display(), response.caption);
+
+    if settings.sidecar.txt() {
+        if let Err(e) = std::fs::write(txt_path(path), format!("{}\n", response.caption)) {
+            eprintln!("Can't write {}: {}", txt_path(path).display(), e);
+        }
+    }
+    if settings.sidecar.json() {
+        let sidecar = Sidecar {
+            content_hash: content_hash(&data),
+            prompt_hash: settings.prompt_hash.clone(),
+            response,
+        };
+        if let Err(e) = sidecar::write(path, &sidecar) {
+            eprintln!(
+                "Can't write {}: {}",
+                sidecar::sidecar_path(path).display(),
+                e
+            );
+        }
+    }
+}
+
+/// Runs the `watch` subcommand until interrupted and returns the process exit code.
+pub async fn run_watch(args: WatchArgs) -> i32 {
+    let format = OutputFormat::Text;
+    let Ok(api_key) = crate::settings::var("GEMINI_API_KEY") else {
+        let message = "GEMINI_API_KEY must be set in the environment or .env file";
+        return fail(format, ErrorKind::ConfigError, message);
+    };
+    let options = match args.options.to_options() {
+        Ok(options) => options,
+        Err(message) => return fail(format, ErrorKind::InvalidInput, &message),
+    };
+    // Absolute, so the initial scan and watch events report files the same way.
+    let dir = match std::fs::canonicalize(&args.dir) {
+        Ok(dir) if dir.is_dir() => dir,
+        _ => {
+            let message = format!("{} is not a directory", args.dir.display());
+            return fail(format, ErrorKind::InvalidInput, &message);
+        }
+    };
+
+    // notify calls back on its own thread; hand events over to the async loop.
+    let (events, mut changes) = mpsc::unbounded_channel();
+    let mut watcher =
+        match notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                let _ = events.send(event);
+            }
+            Err(e) => eprintln!("Watch error: {}", e),
+        }) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                let message = format!("Can't watch {}: {}", dir.display(), e);
+                return fail(format, ErrorKind::ConfigError, &message);
+            }
+        };
+    if let Err(e) = watcher.watch(&dir, RecursiveMode::Recursive) {
+        let message = format!("Can't watch {}: {}", dir.display(), e);
+        return fail(format, ErrorKind::InvalidInput, &message);
+    }
+
+    LOG_PROVIDER_TRAFFIC.store(false, Ordering::Relaxed);
+    let settings = Settings {
+        prompt_hash: content_hash(options.fingerprint().as_bytes()),
+        options,
+        api_key,
+        sidecar: args.sidecar,
+    };
+
+    // Files waiting to settle, by when they last changed.
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    if !args.new_only {
+        for entry in walkdir::WalkDir::new(&dir)
+            .sort_by_file_name()
+            .into_iter()
+            .flatten()
+        {
+            if entry.file_type().is_file() && is_candidate(entry.path()) {
+                caption_file(entry.path(), &settings).await;
+            }
+        }
+    }
+    eprintln!(
+        "👀 Watching {} for new images (Ctrl-C to stop)",
+        dir.display()
+    );
+
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    // Created once so a Ctrl-C that arrives mid-caption is still seen afterwards.
+    let mut interrupted = std::pin::pin!(tokio::signal::ctrl_c());
+    loop {
+        tokio::select! {
+            Some(event) = changes.recv() => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths.into_iter().filter(|path| is_candidate(path)) {
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            }
+            _ = poll.tick() => {
+                let mut settled: Vec<PathBuf> = pending
+                    .iter()
+                    .filter(|(_, changed)| changed.elapsed() >= SETTLE_TIME)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                settled.sort();
+                for path in settled {
+                    pending.remove(&path);
+                    if path.is_file() {
+                        caption_file(&path, &settings).await;
+                    }
+                }
+            }
+            _ = &mut interrupted => {
+                eprintln!("Stopped watching {}", dir.display());
+                return 0;
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+// XMP sidecar packets for RAW workflows (Lightroom, darktable, Capture One).
+//
+// The caption goes into dc:description, keywords into dc:subject and the title (when the
+// mode produced one) into dc:title. Model provenance lives in our own namespace so other
+// tools leave it alone and we can recognise sidecars we wrote ourselves.
+
+use crate::{CaptionResponse, Provenance};
+
+pub const NAMESPACE: &str = "https://github.com/eexanem/ai-image-captioner-rust/ns/1.0/";
+
+/// Everything that goes into one XMP packet.
+pub struct XmpFields<'a> {
+    pub description: &'a str,
+    pub title: Option<&'a str>,
+    pub keywords: Vec<String>,
+    pub model: &'a str,
+    pub provenance: Option<&'a Provenance>,
+}
+
+impl<'a> XmpFields<'a> {
+    pub fn from_response(response: &'a CaptionResponse) -> Self {
+        let keywords = response
+            .tags()
+            .into_iter()
+            .map(|tag| tag.trim_start_matches('#').to_string())
+            .collect();
+
+        XmpFields {
+            description: &response.caption,
+            title: response.details.get("title").and_then(|title| title.as_str()),
+            keywords,
+            model: &response.model,
+            provenance: response.provenance.as_ref(),
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // XML 1.0 forbids most control characters outright.
+            c if c.is_control() && !matches!(c, '\n' | '\t') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn lang_alt(tag: &str, text: &str) -> String {
+    format!(
+        "   <{tag}>\n    <rdf:Alt>\n     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n    </rdf:Alt>\n   </{tag}>\n",
+        escape(text)
+    )
+}
+
+/// Renders a complete XMP sidecar packet.
+pub fn render(fields: &XmpFields) -> String {
+    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+
+    let mut body = String::new();
+    body.push_str(&lang_alt("dc:description", fields.description));
+    if let Some(title) = fields.title {
+        body.push_str(&lang_alt("dc:title", title));
+    }
+    if !fields.keywords.is_empty() {
+        body.push_str("   <dc:subject>\n    <rdf:Bag>\n");
+        for keyword in &fields.keywords {
+            body.push_str(&format!("     <rdf:li>{}</rdf:li>\n", escape(keyword)));
+        }
+        body.push_str("    </rdf:Bag>\n   </dc:subject>\n");
+    }
+
+    let provenance = fields
+        .provenance
+        .map(|provenance| {
+            [
+                ("Provider", provenance.provider.clone()),
+                ("ModelId", provenance.model.clone()),
+                ("Mode", provenance.mode.to_string()),
+                ("PromptVersion", provenance.prompt_version.to_string()),
+                ("PromptHash", provenance.prompt_hash.clone()),
+                ("ContentHash", provenance.content_hash.clone()),
+                ("CrateVersion", provenance.crate_version.clone()),
+            ]
+            .iter()
+            .map(|(name, value)| format!("\n    aicap:{}=\"{}\"", name, escape(value)))
+            .collect::<String>()
+        })
+        .unwrap_or_default();
+
+    format!(
+        r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="ai-image-captioner {version}">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:aicap="{namespace}"
+    xmp:MetadataDate="{now}"
+    aicap:Model="{model}"
+    aicap:Generator="ai-image-captioner {version}"
+    aicap:GeneratedAt="{now}"{provenance}>
+{body}  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#,
+        version = env!("CARGO_PKG_VERSION"),
+        namespace = NAMESPACE,
+        model = escape(fields.model),
+    )
+}
+
+/// Whether an existing sidecar was written by us (and is therefore safe to replace).
+pub fn is_ours(existing: &str) -> bool {
+    existing.contains(NAMESPACE)
+}
+
+/// Line diff between an existing packet and a new one, skipping the timestamps that
+/// change on every run.
+pub fn diff(old: Option<&str>, new: &str) -> Vec<String> {
+    let volatile = |line: &&str| line.contains("MetadataDate=") || line.contains("GeneratedAt=");
+    let old_lines: Vec<&str> = old.unwrap_or_default().lines().map(str::trim).collect();
+    let new_lines: Vec<&str> = new.lines().map(str::trim).collect();
+
+    let removed = old_lines
+        .iter()
+        .filter(|line| !volatile(line) && !new_lines.contains(line))
+        .map(|line| format!("- {}", line));
+    let added = new_lines
+        .iter()
+        .filter(|line| !volatile(line) && !old_lines.contains(line))
+        .map(|line| format!("+ {}", line));
+
+    removed.chain(added).collect()
+}
@@ -0,0 +1,501 @@
+// End-to-end tests of the web API against the mock provider (see `common`).
+
+mod common;
+
+use axum::http::StatusCode;
+use reqwest::multipart::Form;
+use serde_json::Value;
+
+use common::{file, png, ContextCalls, TestServer, ADMIN_TOKEN, MOCK_CAPTION};
+
+#[tokio::test]
+async fn upload_returns_the_caption() {
+    let server = TestServer::start("upload").await;
+
+    let (status, body) = server.upload(png(1), &[("verbose", "true")]).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["caption"], MOCK_CAPTION);
+    assert_eq!(body["input_tokens"], 258);
+    assert_eq!(body["provenance"]["mode"], "caption");
+    assert_eq!(body["dimensions"]["sent"], serde_json::json!({"width": 16, "height": 16}));
+    assert_eq!(server.provider_calls(), 1);
+    server.assert_matches_schema("caption-result.v1.json", &body).await;
+}
+
+#[tokio::test]
+async fn request_id_follows_the_upload_to_the_provider() {
+    let server = TestServer::start("request-id").await;
+
+    let form = Form::new().part("image", file("image.png", png(13)));
+    let response = server
+        .client
+        .post(server.url("/upload"))
+        .header("X-Request-Id", "trace-42")
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.headers()["x-request-id"], "trace-42");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["request_id"], "trace-42");
+    assert_eq!(server.provider_request_ids(), ["trace-42"]);
+    server.assert_matches_schema("caption-result.v1.json", &body).await;
+}
+
+#[tokio::test]
+async fn errors_carry_a_new_request_id() {
+    let server = TestServer::start("request-id-error").await;
+
+    let form = Form::new().text("mode", "alt_text");
+    let response = server.client.post(server.url("/upload")).multipart(form).send().await;
+    let response = response.unwrap();
+    let header = response.headers()["x-request-id"].to_str().unwrap().to_string();
+    let body: Value = response.json().await.unwrap();
+
+    assert_eq!(header.len(), 32, "{}", header);
+    assert_eq!(body["request_id"], header.as_str());
+    server.assert_matches_schema("error.v1.json", &body).await;
+}
+
+#[tokio::test]
+async fn upload_without_an_image_is_a_bad_request() {
+    let server = TestServer::start("no-image").await;
+
+    let form = Form::new().text("mode", "alt_text");
+    let (status, body) = server.post_form("/upload", form).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "bad_request");
+    assert_eq!(body["retryable"], false);
+    assert_eq!(server.provider_calls(), 0);
+    server.assert_matches_schema("error.v1.json", &body).await;
+}
+
+#[tokio::test]
+async fn unknown_mode_is_a_bad_request() {
+    let server = TestServer::start("unknown-mode").await;
+
+    let (status, body) = server.upload(png(2), &[("mode", "interpretive_dance")]).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "bad_request");
+    assert_eq!(server.provider_calls(), 0);
+}
+
+#[tokio::test]
+async fn options_can_come_as_json() {
+    let server = TestServer::start("form-options").await;
+
+    let options = r#"{"mode": "caption", "verbose": true, "language": null}"#;
+    let (status, body) = server.upload(png(19), &[("options", options)]).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["input_tokens"], 258, "{}", body);
+}
+
+#[tokio::test]
+async fn unknown_and_repeated_fields_are_bad_requests() {
+    let server = TestServer::start("form-contract").await;
+
+    for fields in [
+        &[("moed", "alt_text")][..],
+        &[("mode", "alt_text"), ("mode", "caption")],
+        &[("mode", "alt_text"), ("options", r#"{"mode": "caption"}"#)],
+        &[("options", r#"{"tone": "warm", "colour": true}"#)],
+        // Options of other endpoints: `/listing` and `/batch`.
+        &[("tone", "warm")],
+        &[("xmp", "true")],
+    ] {
+        let (status, body) = server.upload(png(20), fields).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}: {}", fields, body);
+    }
+    let form = Form::new()
+        .part("image", file("one.png", png(20)))
+        .part("image", file("two.png", png(21)));
+    let (status, body) = server.post_form("/upload", form).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(server.provider_calls(), 0);
+}
+
+#[tokio::test]
+async fn provider_outage_is_a_retryable_error() {
+    let server = TestServer::start("outage").await;
+
+    let (status, body) = server.upload(png(3), &[]).await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY, "{}", body);
+    assert_eq!(body["code"], "provider_error");
+    assert_eq!(body["retryable"], true);
+    server.assert_matches_schema("error.v1.json", &body).await;
+}
+
+#[tokio::test]
+async fn reply_without_a_caption_is_an_invalid_reply() {
+    let server = TestServer::start("blocked").await;
+
+    let (status, body) = server.upload(png(4), &[]).await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY, "{}", body);
+    assert_eq!(body["code"], "invalid_reply");
+    server.assert_matches_schema("error.v1.json", &body).await;
+}
+
+/// A 64 by 64 BMP, which (unlike PNG) is always decoded rather than passed through.
+fn bmp() -> Vec<u8> {
+    let mut bmp = Vec::new();
+    image::RgbImage::from_pixel(64, 64, image::Rgb([200, 30, 30]))
+        .write_to(&mut std::io::Cursor::new(&mut bmp), image::ImageOutputFormat::Bmp)
+        .unwrap();
+    bmp
+}
+
+#[tokio::test]
+async fn damaged_upload_is_unsupported_media() {
+    let server = TestServer::start("damaged").await;
+
+    let mut image = bmp();
+    image.truncate(image.len() / 2);
+    let (status, body) = server.upload(image, &[]).await;
+
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE, "{}", body);
+    assert_eq!(body["code"], "unsupported_media_type");
+    assert_eq!(server.provider_calls(), 0);
+}
+
+#[tokio::test]
+async fn image_claiming_to_be_huge_is_too_large() {
+    let server = TestServer::start("huge").await;
+
+    // The header says 60000 by 60000 pixels.
+    let mut image = bmp();
+    image[18..26].copy_from_slice(&[0x60, 0xea, 0, 0, 0x60, 0xea, 0, 0]);
+    let (status, body) = server.upload(image, &[]).await;
+
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{}", body);
+    assert_eq!(body["code"], "payload_too_large");
+    assert_eq!(server.provider_calls(), 0);
+}
+
+#[tokio::test]
+async fn upload_over_the_size_limit_is_too_large() {
+    let server = TestServer::start("oversized").await;
+
+    // A byte over the default MAX_UPLOAD_MB of 10.
+    let (status, body) = server.upload(vec![0; 10 * 1024 * 1024 + 1], &[]).await;
+
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{}", body);
+    assert_eq!(body["code"], "payload_too_large");
+    assert_eq!(body["limit_bytes"], 10 * 1024 * 1024);
+    server.assert_matches_schema("error.v1.json", &body).await;
+    assert_eq!(server.provider_calls(), 0);
+}
+
+#[tokio::test]
+async fn repeat_upload_comes_from_the_cache() {
+    let server = TestServer::start("cache").await;
+
+    let (_, first) = server.upload(png(5), &[]).await;
+    let (status, second) = server.upload(png(5), &[]).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(first.get("cached").is_none());
+    assert_eq!(second["cached"], true);
+    assert_eq!(second["caption"], first["caption"]);
+    assert_eq!(server.provider_calls(), 1);
+}
+
+#[tokio::test]
+async fn retried_uploads_share_one_caption() {
+    let server = TestServer::start("idempotency").await;
+    let upload = |image: Vec<u8>, key: &'static str| {
+        let form = Form::new().part("image", file("image.png", image));
+        server.client.post(server.url("/upload")).header("Idempotency-Key", key).multipart(form)
+    };
+
+    let first = upload(png(22), "retry-1").send().await.unwrap();
+    let retry = upload(png(22), "retry-1").send().await.unwrap();
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(retry.status().as_u16(), 200);
+    assert_eq!(server.provider_calls(), 1);
+
+    let other_image = upload(png(23), "retry-1").send().await.unwrap();
+    assert_eq!(other_image.status().as_u16(), 422);
+    let spaced = upload(png(23), "retry 2").send().await.unwrap();
+    assert_eq!(spaced.status().as_u16(), 400);
+    assert_eq!(server.provider_calls(), 1);
+}
+
+#[tokio::test]
+async fn captions_are_kept_in_the_history() {
+    let server = TestServer::start("history").await;
+
+    let (_, body) = server.upload(png(6), &[]).await;
+    let hash = body["provenance"]["content_hash"].as_str().unwrap();
+    let (status, history) = server.get("/history?limit=200").await;
+
+    assert_eq!(status, StatusCode::OK);
+    let entries = history["entries"].as_array().unwrap();
+    let entry = entries
+        .iter()
+        .find(|entry| format!("sha256:{}", entry["image_sha256"].as_str().unwrap()) == hash)
+        .expect("the caption is in the history");
+    assert_eq!(entry["caption"], MOCK_CAPTION);
+    server.assert_matches_schema("history.v1.json", &history).await;
+
+    let thumb = server.url(&format!("/thumb/{}", entry["id"]));
+    let response = server.client.get(&thumb).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["content-type"], "image/jpeg");
+    let etag = response.headers()["etag"].clone();
+    let jpeg = response.bytes().await.unwrap();
+    assert_eq!(&jpeg[..2], b"\xff\xd8");
+    let etag = etag.to_str().unwrap();
+    let revalidate = |tags: String| async {
+        let request = server.client.get(&thumb).header("If-None-Match", tags);
+        request.send().await.unwrap().status().as_u16()
+    };
+    assert_eq!(revalidate(etag.to_string()).await, 304);
+    assert_eq!(revalidate(format!("W/{}", etag)).await, 304);
+    assert_eq!(revalidate(format!("\"a,b\", W/\"c\",{}", etag)).await, 304);
+    assert_eq!(revalidate("*".to_string()).await, 304);
+    assert_eq!(revalidate("\"other\"".to_string()).await, 200);
+
+    // A thumbnail that is gone isn't current, whatever the client has.
+    std::fs::remove_file(entry["thumbnail_path"].as_str().unwrap()).unwrap();
+    assert_eq!(revalidate(etag.to_string()).await, 404);
+}
+
+#[tokio::test]
+async fn tenant_data_can_be_removed() {
+    let server = TestServer::start("gdpr").await;
+    let upload = || {
+        let form = Form::new().part("image", file("image.png", png(15)));
+        let request = server.client.post(server.url("/upload")).header("X-Api-Key", "gdpr");
+        request.header("Idempotency-Key", "gdpr-1").multipart(form).send()
+    };
+    let history = || server.client.get(server.url("/history")).header("X-Api-Key", "gdpr").send();
+
+    upload().await.unwrap();
+    let form = Form::new().part("images", file("image.png", png(15)));
+    let request = server.client.post(server.url("/jobs")).header("X-Api-Key", "gdpr");
+    let job = request.multipart(form).send().await.unwrap();
+    let job = job.headers()["location"].to_str().unwrap().to_string();
+    let get_job = |wait| {
+        let url = server.url(&format!("{}?wait={}", job, wait));
+        server.client.get(url).header("X-Api-Key", "gdpr").send()
+    };
+    // Its image comes from the cache, and goes into the history too.
+    let finished: Value = get_job(5).await.unwrap().json().await.unwrap();
+    assert_eq!(finished["status"], "completed", "{}", finished);
+    let removed: Value = server
+        .client
+        .delete(server.url("/tenant/gdpr/data"))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(removed["captions"], 2, "{}", removed);
+    assert_eq!(removed["thumbnails"], 1);
+    assert_eq!(removed["cached_captions"], 1);
+    assert_eq!(removed["jobs"], 1);
+    assert_eq!(removed["upload_retries"], 1);
+    assert_eq!(removed["kept"][0]["data"], "audit_log");
+    assert_eq!(get_job(0).await.unwrap().status().as_u16(), 404);
+    let page: Value = history().await.unwrap().json().await.unwrap();
+    assert_eq!(page["entries"], Value::Array(vec![]));
+    // Nothing cached is left, so the same image goes to the provider again.
+    upload().await.unwrap();
+    assert_eq!(server.provider_calls(), 2);
+}
+
+#[tokio::test]
+async fn captions_are_audited() {
+    let server = TestServer::start("audit").await;
+
+    for id in ["audit-1", "audit-2"] {
+        let form = Form::new().part("image", file("image.png", png(14)));
+        let request = server.client.post(server.url("/upload")).header("X-Request-Id", id);
+        request.multipart(form).send().await.unwrap();
+    }
+    let export = server
+        .client
+        .get(server.url("/admin/audit?tenant=127.0.0.1&since=2020-01-01"))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+
+    assert!(export.status().is_success(), "{}", export.status());
+    let entries: Vec<Value> = export
+        .text()
+        .await
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let entry = |id: &str| entries.iter().find(|entry| entry["request_id"] == id).unwrap();
+    assert_eq!(entry("audit-1")["outcome"], "captioned");
+    assert_eq!(entry("audit-1")["client_ip"], "127.0.0.1");
+    assert_eq!(entry("audit-1")["authenticated"], false);
+    assert_eq!(entry("audit-2")["outcome"], "cached");
+    assert_eq!(entry("audit-2")["image_sha256"], entry("audit-1")["image_sha256"]);
+}
+
+#[tokio::test]
+async fn batch_captions_every_image() {
+    let server = TestServer::start("batch").await;
+
+    let form = Form::new()
+        .part("images", file("one.png", png(7)))
+        .part("images", file("two.png", png(8)))
+        .part("images", file("notes.txt", b"not an image".to_vec()));
+    let (status, body) = server.post_form("/batch", form).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["succeeded"], 2);
+    assert_eq!(body["failed"], 1);
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results[0]["file_name"], "one.png");
+    assert_eq!(results[1]["caption"], MOCK_CAPTION);
+    assert!(results[2]["error"].is_string());
+    server.assert_matches_schema("batch-result.v1.json", &body).await;
+}
+
+#[tokio::test]
+async fn batch_shares_one_provider_context() {
+    let server = TestServer::start("album").await;
+
+    let form = Form::new()
+        .part("images", file("one.png", png(16)))
+        .part("images", file("two.png", png(17)))
+        .part("images", file("three.png", png(18)));
+    let (status, body) = server.post_form("/batch", form).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["succeeded"], 3);
+    assert_eq!(server.provider_calls(), 3);
+    // The context is deleted in the background once the batch is done.
+    for _ in 0..50 {
+        if server.provider_contexts().deleted > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let expected = ContextCalls {
+        created: 1,
+        deleted: 1,
+        used: 3,
+    };
+    assert_eq!(server.provider_contexts(), expected);
+}
+
+#[tokio::test]
+async fn batch_exports_json_lines() {
+    let server = TestServer::start("batch-jsonl").await;
+
+    let form = Form::new()
+        .part("images", file("one.png", png(9)))
+        .part("images", file("two.png", png(10)));
+    let response = server
+        .client
+        .post(server.url("/batch?format=jsonl"))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    let text = response.text().await.unwrap();
+    let rows: Vec<Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(rows.len(), 2);
+    for row in &rows {
+        server.assert_matches_schema("batch-export-row.v1.json", row).await;
+    }
+}
+
+#[tokio::test]
+async fn job_reports_its_results() {
+    let server = TestServer::start("job").await;
+
+    let form = Form::new()
+        .part("images", file("one.png", png(11)))
+        .part("images", file("two.png", png(12)));
+    let response = server.client.post(server.url("/jobs")).multipart(form).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 202);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+
+    let mut job = Value::Null;
+    for _ in 0..10 {
+        let (status, body) = server.get(&format!("{}?wait=5", location)).await;
+        assert_eq!(status, StatusCode::OK);
+        job = body;
+        if job["status"] == "completed" {
+            break;
+        }
+    }
+
+    assert_eq!(job["status"], "completed", "{}", job);
+    assert_eq!(job["succeeded"], 2);
+    assert_eq!(job["results"][1]["caption"], MOCK_CAPTION);
+    server.assert_matches_schema("job.v1.json", &job).await;
+
+    // Only the tenant that sent it may see it.
+    let other = server.client.get(server.url(&location)).header("X-Api-Key", "someone-else");
+    assert_eq!(other.send().await.unwrap().status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn video_frames_refuse_private_addresses() {
+    let server = TestServer::start("video-url").await;
+
+    let request = serde_json::json!({"url": "http://127.0.0.1/video.mp4", "timestamps": [1.5]});
+    let response = server.client.post(server.url("/video/frames")).json(&request).send().await;
+    let status = response.as_ref().unwrap().status().as_u16();
+    let body: Value = response.unwrap().json().await.unwrap();
+
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "bad_request");
+    assert!(body["message"].as_str().unwrap().contains("public"), "{}", body);
+    assert_eq!(server.provider_calls(), 0);
+}
+
+#[tokio::test]
+async fn video_playlists_are_refused() {
+    let server = TestServer::start("video-playlist").await;
+    let playlist = b"#EXTM3U\n#EXT-X-TARGETDURATION:1\n#EXTINF:1,\nfile:///etc/passwd\n\
+        #EXT-X-ENDLIST\n";
+
+    let form = Form::new().part("video", file("clip.m3u8", playlist.to_vec()));
+    let request = server.client.post(server.url("/video")).header("X-Api-Key", "video-playlist");
+    let response = request.multipart(form).send().await.unwrap();
+    let status = response.status().as_u16();
+    let body: Value = response.json().await.unwrap();
+
+    assert_eq!(status, 415, "{}", body);
+    assert_eq!(body["code"], "unsupported_media_type");
+    assert_eq!(server.provider_calls(), 0);
+}
+
+#[tokio::test]
+async fn upload_page_loads_its_assets() {
+    let server = TestServer::start("ui").await;
+
+    let page = server.client.get(server.url("/")).send().await.unwrap().text().await.unwrap();
+    assert!(page.contains(r#"<script src="/assets/app.js">"#), "{}", page);
+    let script = server.client.get(server.url("/assets/app.js")).send().await.unwrap();
+    assert_eq!(script.status().as_u16(), 200);
+    assert_eq!(script.headers()["content-type"], "text/javascript");
+    let etag = script.headers()["etag"].clone();
+
+    let again = server.client.get(server.url("/assets/app.js")).header("If-None-Match", etag);
+    assert_eq!(again.send().await.unwrap().status().as_u16(), 304);
+    let missing = server.client.get(server.url("/assets/nothing.js")).send().await.unwrap();
+    assert_eq!(missing.status().as_u16(), 404);
+}
@@ -0,0 +1,278 @@
+// End-to-end test harness: the server's routes on a local port, captioning through an
+// in-process mock of the Gemini API (GEMINI_API_URL) and keeping history in a temporary SQLite
+// database. Each test starts its own server with its own provider key, which the mock counts
+// calls by and which picks how it answers:
+//
+// - `outage`: 503, as when the model is overloaded
+// - `blocked`: a reply without a candidate, as when a safety filter blocks the image
+// - anything else: `MOCK_CAPTION`, as text or as JSON as the request asks
+//
+// It caches contexts (`cachedContents`) for any key, and counts those made and deleted and the
+// calls that named one.
+//
+// The environment is process-wide and read per request, so it is set once for all tests and
+// only to values they all want. Tests share the history and the caption cache too, so each
+// uses images of its own (`png(seed)` with a seed no other test uses).
+
+#![allow(dead_code)]
+
+use axum::extract::Query;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex, OnceLock};
+
+pub const MOCK_CAPTION: &str = "A mock caption of a test image";
+
+/// The admin token every test server has.
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+/// Provider calls the mock has had, per API key.
+static CALLS: LazyLock<Mutex<HashMap<String, usize>>> = LazyLock::new(Mutex::default);
+
+/// The `X-Request-Id` of each provider call, per API key.
+static REQUEST_IDS: LazyLock<Mutex<HashMap<String, Vec<String>>>> =
+    LazyLock::new(Mutex::default);
+
+/// Cached contexts the mock has made, deleted and been called with, per API key.
+static CONTEXTS: LazyLock<Mutex<HashMap<String, ContextCalls>>> = LazyLock::new(Mutex::default);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ContextCalls {
+    pub created: usize,
+    pub deleted: usize,
+    pub used: usize,
+}
+
+static ENVIRONMENT: OnceLock<PathBuf> = OnceLock::new();
+
+/// `generateContent`, as the mock answers it.
+async fn generate(
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    Json(request): Json<Value>,
+) -> Response {
+    let key = query.get("key").cloned().unwrap_or_default();
+    *CALLS.lock().unwrap().entry(key.clone()).or_default() += 1;
+    if let Some(id) = headers.get("x-request-id").and_then(|id| id.to_str().ok()) {
+        REQUEST_IDS.lock().unwrap().entry(key.clone()).or_default().push(id.to_string());
+    }
+    if request["cachedContent"].is_string() {
+        CONTEXTS.lock().unwrap().entry(key.clone()).or_default().used += 1;
+    }
+
+    match key.as_str() {
+        "outage" => {
+            let error = json!({"code": 503, "message": "The model is overloaded"});
+            (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "error": error }))).into_response()
+        }
+        "blocked" => Json(json!({"promptFeedback": {"blockReason": "SAFETY"}})).into_response(),
+        _ => {
+            let text = if request["generationConfig"]["responseMimeType"] == "application/json" {
+                json!({ "caption": MOCK_CAPTION }).to_string()
+            } else {
+                MOCK_CAPTION.to_string()
+            };
+            Json(json!({
+                "candidates": [{"content": {"parts": [{"text": text}]}, "finishReason": "STOP"}],
+                "usageMetadata": {"promptTokenCount": 258, "candidatesTokenCount": 12},
+            }))
+            .into_response()
+        }
+    }
+}
+
+/// `POST cachedContents`, as the mock answers it.
+async fn create_context(Query(query): Query<HashMap<String, String>>) -> Json<Value> {
+    let key = query.get("key").cloned().unwrap_or_default();
+    let mut contexts = CONTEXTS.lock().unwrap();
+    let calls = contexts.entry(key.clone()).or_default();
+    calls.created += 1;
+    Json(json!({ "name": format!("cachedContents/{}-{}", key, calls.created) }))
+}
+
+/// `DELETE cachedContents/{name}`, as the mock answers it.
+async fn delete_context(Query(query): Query<HashMap<String, String>>) -> Json<Value> {
+    let key = query.get("key").cloned().unwrap_or_default();
+    CONTEXTS.lock().unwrap().entry(key).or_default().deleted += 1;
+    Json(json!({}))
+}
+
+/// Starts the mock provider on a thread of its own, so it outlives each test's runtime.
+fn start_mock() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let mock = Router::new()
+                .route("/v1beta/cachedContents", post(create_context))
+                .route("/v1beta/cachedContents/:name", delete(delete_context))
+                .fallback(generate);
+            axum::serve(listener, mock).await.unwrap();
+        });
+    });
+    address
+}
+
+/// Sets up the mock and the environment all tests share; returns the temporary directory.
+fn environment() -> &'static PathBuf {
+    ENVIRONMENT.get_or_init(|| {
+        let name = format!("ai-image-captioner-tests-{}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mock = start_mock();
+        let unset = [
+            "DATABASE_URL",
+            "REDIS_URL",
+            "API_KEYS",
+            "API_KEYS_DB",
+            "OIDC_ISSUER",
+            "POLICY_FILE",
+        ];
+        for name in unset {
+            std::env::remove_var(name);
+        }
+        std::env::set_var("GEMINI_API_URL", format!("http://{}", mock));
+        std::env::set_var("HISTORY_DB", dir.join("history.db"));
+        std::env::set_var("HISTORY_THUMBNAILS_DIR", dir.join("thumbnails"));
+        std::env::set_var("JOBS_FILE", dir.join("jobs.json"));
+        std::env::set_var("AUDIT_LOG", "true");
+        std::env::set_var("ADMIN_TOKEN", ADMIN_TOKEN);
+        std::env::set_var("LOG_PROVIDER_TRAFFIC", "false");
+        std::env::set_var("PROVIDER_CONTEXT_CACHE", "true");
+        // Failures come back at once, and one test's failures don't trip the others' breaker.
+        std::env::set_var("PROVIDER_MAX_ATTEMPTS", "1");
+        std::env::set_var("CIRCUIT_BREAKER_THRESHOLD", "0");
+        // Every test calls from 127.0.0.1.
+        std::env::set_var("RATE_LIMIT_PER_MINUTE", "0");
+        // Uploads go through temporary files, as big ones do.
+        std::env::set_var("UPLOAD_MEMORY_MB", "0");
+        dir
+    })
+}
+
+/// A PNG no other seed makes.
+pub fn png(seed: u32) -> Vec<u8> {
+    let [r, g, b, _] = seed.to_le_bytes();
+    let image = image::RgbImage::from_fn(16, 16, |x, y| {
+        image::Rgb([r.wrapping_add(x as u8), g.wrapping_add(y as u8), b])
+    });
+    let mut png = Vec::new();
+    image
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png)
+        .unwrap();
+    png
+}
+
+/// A form field holding `data` as the file `name`.
+pub fn file(name: &str, data: Vec<u8>) -> reqwest::multipart::Part {
+    reqwest::multipart::Part::bytes(data).file_name(name.to_string())
+}
+
+/// The server, running for the rest of the test.
+pub struct TestServer {
+    address: SocketAddr,
+    api_key: String,
+    pub client: reqwest::Client,
+}
+
+impl TestServer {
+    /// Starts a server calling the mock provider with `api_key`.
+    pub async fn start(api_key: &str) -> TestServer {
+        environment();
+        let app = ai_image_captioner::server::app(api_key.to_string()).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await.unwrap();
+        });
+        TestServer {
+            address,
+            api_key: api_key.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    /// How many times the mock provider has been called with this server's key.
+    pub fn provider_calls(&self) -> usize {
+        CALLS.lock().unwrap().get(&self.api_key).copied().unwrap_or(0)
+    }
+
+    /// The request IDs the mock provider was called with, with this server's key.
+    pub fn provider_request_ids(&self) -> Vec<String> {
+        REQUEST_IDS.lock().unwrap().get(&self.api_key).cloned().unwrap_or_default()
+    }
+
+    /// The cached contexts the mock provider has had, with this server's key.
+    pub fn provider_contexts(&self) -> ContextCalls {
+        CONTEXTS.lock().unwrap().get(&self.api_key).copied().unwrap_or_default()
+    }
+
+    /// Posts `form` to `path`; returns the status and the JSON body.
+    pub async fn post_form(
+        &self,
+        path: &str,
+        form: reqwest::multipart::Form,
+    ) -> (StatusCode, Value) {
+        let response = self.client.post(self.url(path)).multipart(form).send().await.unwrap();
+        json_body(response).await
+    }
+
+    /// Uploads `image` to `/upload` with the fields in `fields`.
+    pub async fn upload(&self, image: Vec<u8>, fields: &[(&str, &str)]) -> (StatusCode, Value) {
+        let mut form = reqwest::multipart::Form::new().part("image", file("image.png", image));
+        for (name, value) in fields {
+            form = form.text(name.to_string(), value.to_string());
+        }
+        self.post_form("/upload", form).await
+    }
+
+    pub async fn get(&self, path: &str) -> (StatusCode, Value) {
+        json_body(self.client.get(self.url(path)).send().await.unwrap()).await
+    }
+
+    /// Fails the test unless `instance` matches the server's published schema `name`.
+    pub async fn assert_matches_schema(&self, name: &str, instance: &Value) {
+        let (_, names) = self.get("/schemas").await;
+        let mut options = jsonschema::options().should_validate_formats(true);
+        let mut schemas = HashMap::new();
+        for file in names.as_array().unwrap() {
+            let file = file.as_str().unwrap();
+            let (_, schema) = self.get(&format!("/schemas/{}", file)).await;
+            let id = schema["$id"].as_str().unwrap().to_string();
+            let resource = jsonschema::Resource::from_contents(schema.clone()).unwrap();
+            options = options.with_resource(id, resource);
+            schemas.insert(file.to_string(), schema);
+        }
+        let validator = options.build(&schemas[name]).unwrap();
+        let errors: Vec<String> = validator
+            .iter_errors(instance)
+            .map(|error| format!("{} at {}", error, error.instance_path))
+            .collect();
+        assert!(errors.is_empty(), "{}\n{:#}", errors.join("\n"), instance);
+    }
+}
+
+async fn json_body(response: reqwest::Response) -> (StatusCode, Value) {
+    let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+    let body = response.json().await.unwrap_or(Value::Null);
+    (status, body)
+}